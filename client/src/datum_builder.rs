@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use communication::proto::goodmetrics::{
+    dimension, measurement, Datum, Dimension, ExponentialHistogram, Histogram, Measurement, Ratio,
+    StatisticSet, TDigest,
+};
+
+/// Builds a `Datum` without spelling out the protobuf structs.
+///
+/// ```
+/// use std::time::SystemTime;
+/// use client::DatumBuilder;
+///
+/// let datum = DatumBuilder::new("cpu_usage")
+///     .dim("host", "server1")
+///     .dim("region", "us-east-1")
+///     .measure("user_pct", 42.5f64)
+///     .measure("sys_pct", 3.1f64)
+///     .at(SystemTime::now())
+///     .build();
+/// assert_eq!(datum.metric, "cpu_usage");
+/// assert_eq!(datum.dimensions.len(), 2);
+/// assert_eq!(datum.measurements.len(), 2);
+/// ```
+#[derive(Debug, Clone)]
+pub struct DatumBuilder {
+    datum: Datum,
+}
+
+impl DatumBuilder {
+    /// A datum for `metric`, at the time it's created unless `at` says otherwise
+    pub fn new(metric: impl Into<String>) -> Self {
+        Self {
+            datum: Datum {
+                metric: metric.into(),
+                unix_nanos: unix_nanos(SystemTime::now()),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// Strings, numbers and booleans become the matching dimension type.
+    ///
+    /// ```
+    /// use client::DatumBuilder;
+    /// use communication::proto::goodmetrics::dimension;
+    ///
+    /// let datum = DatumBuilder::new("requests").dim("canary", true).build();
+    /// assert_eq!(datum.dimensions["canary"].value, Some(dimension::Value::Boolean(true)));
+    /// ```
+    pub fn dim(mut self, name: impl Into<String>, value: impl DimensionValue) -> Self {
+        self.datum
+            .dimensions
+            .insert(name.into(), value.into_dimension());
+        self
+    }
+
+    /// Labels too unique to be dimensions, like trace ids. goodmetricsd keeps them all in 1
+    /// jsonb column instead of a column each.
+    ///
+    /// ```
+    /// use client::DatumBuilder;
+    ///
+    /// let datum = DatumBuilder::new("requests").tag("trace_id", "4bf92f3577b34da6").build();
+    /// assert_eq!(datum.tags["trace_id"], "4bf92f3577b34da6");
+    /// ```
+    pub fn tag(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.datum.tags.insert(name.into(), value.into());
+        self
+    }
+
+    /// 32 and 64 bit integers and floats become the matching measurement type.
+    ///
+    /// ```
+    /// use client::DatumBuilder;
+    /// use communication::proto::goodmetrics::measurement;
+    ///
+    /// let datum = DatumBuilder::new("requests").measure("bytes", 1024i64).build();
+    /// assert_eq!(datum.measurements["bytes"].value, Some(measurement::Value::I64(1024)));
+    /// ```
+    pub fn measure(mut self, name: impl Into<String>, value: impl MeasurementValue) -> Self {
+        self.datum
+            .measurements
+            .insert(name.into(), value.into_measurement());
+        self
+    }
+
+    /// A summary of many observations of the same thing.
+    ///
+    /// ```
+    /// use client::DatumBuilder;
+    /// use communication::proto::goodmetrics::measurement;
+    ///
+    /// let datum = DatumBuilder::new("requests")
+    ///     .statistic_set("latency_ms", 1.0, 9.0, 30.0, 6)
+    ///     .build();
+    /// assert!(matches!(
+    ///     datum.measurements["latency_ms"].value,
+    ///     Some(measurement::Value::StatisticSet(_))
+    /// ));
+    /// ```
+    pub fn statistic_set(
+        self,
+        name: impl Into<String>,
+        minimum: f64,
+        maximum: f64,
+        samplesum: f64,
+        samplecount: u64,
+    ) -> Self {
+        self.measure(
+            name,
+            StatisticSet {
+                minimum,
+                maximum,
+                samplesum,
+                samplecount,
+            },
+        )
+    }
+
+    /// Observations counted by bucket. Repeated buckets are added together.
+    ///
+    /// ```
+    /// use client::DatumBuilder;
+    /// use communication::proto::goodmetrics::measurement;
+    ///
+    /// let datum = DatumBuilder::new("requests")
+    ///     .histogram("latency_ms", [(10, 3), (100, 1), (10, 2)])
+    ///     .build();
+    /// match &datum.measurements["latency_ms"].value {
+    ///     Some(measurement::Value::Histogram(histogram)) => assert_eq!(histogram.buckets[&10], 5),
+    ///     other => panic!("not a histogram: {other:?}"),
+    /// }
+    /// ```
+    pub fn histogram(
+        self,
+        name: impl Into<String>,
+        buckets: impl IntoIterator<Item = (i64, u64)>,
+    ) -> Self {
+        let mut histogram = Histogram {
+            buckets: HashMap::new(),
+        };
+        for (bucket, count) in buckets {
+            *histogram.buckets.entry(bucket).or_default() += count;
+        }
+        self.measure(name, histogram)
+    }
+
+    pub fn at(mut self, time: SystemTime) -> Self {
+        self.datum.unix_nanos = unix_nanos(time);
+        self
+    }
+
+    pub fn build(self) -> Datum {
+        self.datum
+    }
+}
+
+// Times before 1970 are clamped to it
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::ZERO)
+        .as_nanos() as u64
+}
+
+/// Rust types that can be a dimension's value
+pub trait DimensionValue {
+    fn into_dimension(self) -> Dimension;
+}
+
+impl DimensionValue for &str {
+    fn into_dimension(self) -> Dimension {
+        self.to_string().into_dimension()
+    }
+}
+
+impl DimensionValue for String {
+    fn into_dimension(self) -> Dimension {
+        Dimension {
+            value: Some(dimension::Value::String(self)),
+        }
+    }
+}
+
+impl DimensionValue for u64 {
+    fn into_dimension(self) -> Dimension {
+        Dimension {
+            value: Some(dimension::Value::Number(self)),
+        }
+    }
+}
+
+/// Sent as its two's complement u64; goodmetricsd stores Number dimensions as int8, so it reads
+/// back negative.
+impl DimensionValue for i64 {
+    fn into_dimension(self) -> Dimension {
+        (self as u64).into_dimension()
+    }
+}
+
+impl DimensionValue for bool {
+    fn into_dimension(self) -> Dimension {
+        Dimension {
+            value: Some(dimension::Value::Boolean(self)),
+        }
+    }
+}
+
+/// Rust types that can be a measurement's value
+pub trait MeasurementValue {
+    fn into_measurement(self) -> Measurement;
+}
+
+macro_rules! measurement_value {
+    ($rust_type:ty, $variant:ident) => {
+        impl MeasurementValue for $rust_type {
+            fn into_measurement(self) -> Measurement {
+                Measurement {
+                    value: Some(measurement::Value::$variant(self)),
+                }
+            }
+        }
+    };
+}
+
+measurement_value!(i64, I64);
+measurement_value!(i32, I32);
+measurement_value!(f64, F64);
+measurement_value!(f32, F32);
+measurement_value!(StatisticSet, StatisticSet);
+measurement_value!(Histogram, Histogram);
+measurement_value!(TDigest, Tdigest);
+measurement_value!(Ratio, Ratio);
+measurement_value!(ExponentialHistogram, ExponentialHistogram);
@@ -0,0 +1,121 @@
+use std::{collections::HashMap, time::Duration};
+
+use communication::proto::goodmetrics::{metrics_client::MetricsClient, Datum, MetricsRequest};
+use tonic::{
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint},
+    Code, Status,
+};
+
+// Doubles per retry
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    #[error("bad goodmetrics endpoint: {0}")]
+    Endpoint(#[from] tonic::transport::Error),
+    // Boxed, since a Status is big enough to bloat every Result that carries one
+    #[error("goodmetrics rejected the datums: {0}")]
+    Status(Box<Status>),
+}
+
+impl From<Status> for ClientError {
+    fn from(status: Status) -> Self {
+        ClientError::Status(Box::new(status))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    /// Like `https://goodmetrics.example.com:9573`
+    pub endpoint: String,
+    /// A pem certificate to trust for tls, instead of the system roots
+    pub tls_root_certificate: Option<Vec<u8>>,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    /// Extra attempts after an UNAVAILABLE or DEADLINE_EXCEEDED
+    pub max_retries: u32,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: "http://localhost:9573".to_string(),
+            tls_root_certificate: None,
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(10),
+            max_retries: 3,
+        }
+    }
+}
+
+/// Sends datums to goodmetricsd, reconnecting and retrying through transient failures.
+///
+/// ```no_run
+/// use client::{ClientConfig, DatumBuilder, GoodMetricsClient};
+///
+/// # async fn example() -> Result<(), client::ClientError> {
+/// let client = GoodMetricsClient::new(ClientConfig {
+///     endpoint: "https://goodmetrics.example.com:9573".to_string(),
+///     ..Default::default()
+/// })?;
+/// client
+///     .send_datum(DatumBuilder::new("requests").measure("latency_ms", 12i64).build())
+///     .await?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct GoodMetricsClient {
+    client: MetricsClient<Channel>,
+    max_retries: u32,
+}
+
+impl GoodMetricsClient {
+    /// Doesn't connect until the first send, and reconnects whenever the connection drops.
+    /// Has to be called inside a tokio runtime.
+    pub fn new(config: ClientConfig) -> Result<Self, ClientError> {
+        let mut endpoint = Endpoint::from_shared(config.endpoint)?
+            .connect_timeout(config.connect_timeout)
+            .timeout(config.request_timeout);
+        if let Some(certificate) = config.tls_root_certificate {
+            endpoint = endpoint.tls_config(
+                ClientTlsConfig::new().ca_certificate(Certificate::from_pem(certificate)),
+            )?;
+        }
+
+        Ok(Self {
+            client: MetricsClient::new(endpoint.connect_lazy()),
+            max_retries: config.max_retries,
+        })
+    }
+
+    pub async fn send_datums(&self, datums: Vec<Datum>) -> Result<(), ClientError> {
+        let request = MetricsRequest {
+            shared_dimensions: HashMap::new(),
+            metrics: datums,
+        };
+        let mut delay = FIRST_RETRY_DELAY;
+        let mut attempt = 0;
+        loop {
+            // Clones share the underlying channel
+            let mut client = self.client.clone();
+            match client.send_metrics(request.clone()).await {
+                Ok(_) => return Ok(()),
+                Err(status) if is_transient(&status) && attempt < self.max_retries => {
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                Err(status) => return Err(status.into()),
+            }
+        }
+    }
+
+    pub async fn send_datum(&self, datum: Datum) -> Result<(), ClientError> {
+        self.send_datums(vec![datum]).await
+    }
+}
+
+fn is_transient(status: &Status) -> bool {
+    matches!(status.code(), Code::Unavailable | Code::DeadlineExceeded)
+}
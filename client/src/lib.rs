@@ -0,0 +1,7 @@
+mod datum_builder;
+mod grpc_client;
+mod sampled_sink;
+
+pub use datum_builder::{DatumBuilder, DimensionValue, MeasurementValue};
+pub use grpc_client::{ClientConfig, ClientError, GoodMetricsClient};
+pub use sampled_sink::SampledSink;
@@ -0,0 +1,131 @@
+use std::collections::HashMap;
+
+use communication::proto::goodmetrics::{measurement, Datum};
+use rand::Rng;
+
+use crate::{ClientError, GoodMetricsClient};
+
+/// Sends only a fraction of datums, for metrics too frequent to send every time.
+///
+/// Kept datums are marked with their `sample_rate`, and their histogram, statistic set and
+/// tdigest counts are scaled up by `1 / sample_rate` so sums and counts come out about right
+/// downstream. Other measurements are sent as they are.
+///
+/// ```
+/// use client::{DatumBuilder, SampledSink};
+///
+/// let sink = SampledSink::new(1.0).with_metric_rate("request_latency", 0.0);
+/// let kept = sink.sample(vec![
+///     DatumBuilder::new("request_latency").measure("ms", 3i64).build(),
+///     DatumBuilder::new("deploys").measure("count", 1i64).build(),
+/// ]);
+/// assert_eq!(kept.len(), 1);
+/// assert_eq!(kept[0].metric, "deploys");
+/// ```
+#[derive(Debug, Clone)]
+pub struct SampledSink {
+    client: Option<GoodMetricsClient>,
+    default_rate: f32,
+    metric_rates: HashMap<String, f32>,
+}
+
+impl SampledSink {
+    /// Rates are clamped to 0..=1. 1 sends everything and 0 sends nothing.
+    pub fn new(default_rate: f32) -> Self {
+        Self {
+            client: None,
+            default_rate: clamp_rate(default_rate),
+            metric_rates: HashMap::new(),
+        }
+    }
+
+    /// Sends what's kept with `client`
+    pub fn with_client(mut self, client: GoodMetricsClient) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// Overrides the default rate for 1 metric
+    pub fn with_metric_rate(mut self, metric: impl Into<String>, rate: f32) -> Self {
+        self.metric_rates.insert(metric.into(), clamp_rate(rate));
+        self
+    }
+
+    /// Samples `datums` and sends what's kept. Does nothing without a client.
+    pub async fn send_datums(&self, datums: Vec<Datum>) -> Result<(), ClientError> {
+        let kept = self.sample(datums);
+        match &self.client {
+            Some(client) if !kept.is_empty() => client.send_datums(kept).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// The datums that survive sampling, with their counts scaled up
+    pub fn sample(&self, datums: Vec<Datum>) -> Vec<Datum> {
+        let mut rng = rand::thread_rng();
+        datums
+            .into_iter()
+            .filter_map(|mut datum| {
+                let rate = self.rate(&datum.metric);
+                if rate >= 1.0 {
+                    return Some(datum);
+                }
+                if rate <= 0.0 || rng.gen::<f32>() >= rate {
+                    return None;
+                }
+                scale_counts(&mut datum, 1.0 / rate as f64);
+                datum.sample_rate = rate;
+                Some(datum)
+            })
+            .collect()
+    }
+
+    fn rate(&self, metric: &str) -> f32 {
+        self.metric_rates
+            .get(metric)
+            .copied()
+            .unwrap_or(self.default_rate)
+    }
+}
+
+fn clamp_rate(rate: f32) -> f32 {
+    if rate.is_nan() {
+        return 1.0;
+    }
+    rate.clamp(0.0, 1.0)
+}
+
+fn scale_counts(datum: &mut Datum, scale: f64) {
+    let scaled = |count: u64| (count as f64 * scale).round() as u64;
+    for measurement in datum.measurements.values_mut() {
+        match &mut measurement.value {
+            Some(measurement::Value::Histogram(histogram)) => {
+                for count in histogram.buckets.values_mut() {
+                    *count = scaled(*count);
+                }
+            }
+            Some(measurement::Value::ExponentialHistogram(histogram)) => {
+                histogram.zero_count = scaled(histogram.zero_count);
+                for count in histogram
+                    .positive
+                    .iter_mut()
+                    .chain(histogram.negative.iter_mut())
+                {
+                    *count = scaled(*count);
+                }
+            }
+            Some(measurement::Value::StatisticSet(statistic_set)) => {
+                statistic_set.samplesum *= scale;
+                statistic_set.samplecount = scaled(statistic_set.samplecount);
+            }
+            Some(measurement::Value::Tdigest(tdigest)) => {
+                for centroid in tdigest.centroids.iter_mut() {
+                    centroid.weight = scaled(centroid.weight);
+                }
+                tdigest.sum *= scale;
+                tdigest.count = scaled(tdigest.count);
+            }
+            _ => {}
+        }
+    }
+}
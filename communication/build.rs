@@ -8,11 +8,17 @@ fn main() {
         .build_server(true)
         .type_attribute(".", "#[derive(serde::Deserialize, serde::Serialize)]")
         .file_descriptor_set_path(out_dir.join("goodmetrics_descriptor.bin"))
-        .compile(&["../proto/metrics/goodmetrics.proto"], &["../proto"])
+        .compile(
+            &[
+                "../proto/metrics/goodmetrics.proto",
+                "../proto/admin/goodmetrics_admin.proto",
+            ],
+            &["../proto"],
+        )
         .unwrap();
 
     tonic_build::configure()
-        .build_server(false)
+        .build_server(true)
         // .type_attribute(".", "#[derive(Debug)]")
         .compile(
             &[
@@ -0,0 +1,87 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use crate::proto::goodmetrics::{dimension, measurement, Datum};
+
+impl Datum {
+    /// Hashes everything that makes two datums the same datum: metric, time, dimensions, tags and
+    /// measurements. Map entries are hashed in key order, so it doesn't depend on map iteration.
+    /// Only stable within a process.
+    pub fn content_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.metric.hash(&mut hasher);
+        self.unix_nanos.hash(&mut hasher);
+
+        let mut dimensions: Vec<_> = self.dimensions.iter().collect();
+        dimensions.sort_unstable_by_key(|(name, _)| *name);
+        for (name, dimension) in dimensions {
+            name.hash(&mut hasher);
+            match &dimension.value {
+                Some(dimension::Value::String(s)) => (1u8, s).hash(&mut hasher),
+                Some(dimension::Value::Number(n)) => (2u8, n).hash(&mut hasher),
+                Some(dimension::Value::Boolean(b)) => (3u8, b).hash(&mut hasher),
+                None => 0u8.hash(&mut hasher),
+            }
+        }
+
+        let mut tags: Vec<_> = self.tags.iter().collect();
+        tags.sort_unstable();
+        tags.hash(&mut hasher);
+
+        let mut measurements: Vec<_> = self.measurements.iter().collect();
+        measurements.sort_unstable_by_key(|(name, _)| *name);
+        for (name, measurement) in measurements {
+            name.hash(&mut hasher);
+            match &measurement.value {
+                Some(measurement::Value::I64(i)) => (1u8, i).hash(&mut hasher),
+                Some(measurement::Value::I32(i)) => (2u8, i).hash(&mut hasher),
+                Some(measurement::Value::F64(f)) => (4u8, f.to_bits()).hash(&mut hasher),
+                Some(measurement::Value::F32(f)) => (5u8, f.to_bits()).hash(&mut hasher),
+                Some(measurement::Value::StatisticSet(s)) => (
+                    6u8,
+                    s.minimum.to_bits(),
+                    s.maximum.to_bits(),
+                    s.samplesum.to_bits(),
+                    s.samplecount,
+                )
+                    .hash(&mut hasher),
+                Some(measurement::Value::Histogram(h)) => {
+                    7u8.hash(&mut hasher);
+                    let mut buckets: Vec<_> = h.buckets.iter().collect();
+                    buckets.sort_unstable();
+                    buckets.hash(&mut hasher);
+                }
+                Some(measurement::Value::Tdigest(t)) => {
+                    (
+                        8u8,
+                        t.sum.to_bits(),
+                        t.count,
+                        t.max.to_bits(),
+                        t.min.to_bits(),
+                    )
+                        .hash(&mut hasher);
+                    for centroid in &t.centroids {
+                        (centroid.mean.to_bits(), centroid.weight).hash(&mut hasher);
+                    }
+                }
+                Some(measurement::Value::Ratio(r)) => {
+                    (9u8, r.numerator, r.denominator).hash(&mut hasher)
+                }
+                Some(measurement::Value::ExponentialHistogram(e)) => (
+                    10u8,
+                    e.scale,
+                    e.zero_count,
+                    e.positive_offset,
+                    &e.positive,
+                    e.negative_offset,
+                    &e.negative,
+                )
+                    .hash(&mut hasher),
+                None => 0u8.hash(&mut hasher),
+            }
+        }
+        hasher.finish()
+    }
+}
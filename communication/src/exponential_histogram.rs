@@ -0,0 +1,58 @@
+use std::collections::HashMap;
+
+use crate::proto::goodmetrics::{ExponentialHistogram, Histogram};
+
+impl ExponentialHistogram {
+    /// The distance between bucket bounds: base = 2^(2^-scale)
+    pub fn base(&self) -> f64 {
+        2f64.powf(2f64.powi(-self.scale))
+    }
+
+    /// Each bucket's upper bound and count, the zero bucket included. Empty buckets are skipped.
+    pub fn upper_bounds(&self) -> Vec<(f64, u64)> {
+        let base = self.base();
+        let mut bounds = Vec::with_capacity(1 + self.positive.len() + self.negative.len());
+        if 0 < self.zero_count {
+            bounds.push((0.0, self.zero_count));
+        }
+        for (index, count) in self.positive.iter().enumerate() {
+            if 0 < *count {
+                bounds.push((base.powi(self.positive_offset + index as i32 + 1), *count));
+            }
+        }
+        for (index, count) in self.negative.iter().enumerate() {
+            if 0 < *count {
+                bounds.push((-base.powi(self.negative_offset + index as i32), *count));
+            }
+        }
+        bounds
+    }
+
+    /// Spreads the range from the lowest to the highest upper bound over num_buckets evenly
+    /// sized buckets, keyed by their upper bound rounded up like other goodmetrics histograms.
+    /// Each exponential bucket's count goes in the linear bucket holding its upper bound, so
+    /// counts are only ever reported in a bucket at least as high as where they were.
+    pub fn to_linear_histogram(&self, num_buckets: usize) -> Histogram {
+        let bounds = self.upper_bounds();
+        let mut buckets: HashMap<i64, u64> = HashMap::new();
+        let lowest = bounds
+            .iter()
+            .map(|(bound, _)| *bound)
+            .fold(f64::INFINITY, f64::min);
+        let highest = bounds
+            .iter()
+            .map(|(bound, _)| *bound)
+            .fold(f64::NEG_INFINITY, f64::max);
+        let width = (highest - lowest) / num_buckets.max(1) as f64;
+        for (bound, count) in bounds {
+            let bucket = if 0.0 < width {
+                let index = ((bound - lowest) / width).ceil();
+                lowest + index * width
+            } else {
+                bound
+            };
+            *buckets.entry(bucket.ceil() as i64).or_default() += count;
+        }
+        Histogram { buckets }
+    }
+}
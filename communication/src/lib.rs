@@ -1,4 +1,6 @@
 mod channel_connection;
+mod content_hash;
+mod exponential_histogram;
 
 pub use channel_connection::get_channel;
 pub use channel_connection::ChannelType;
@@ -8,6 +10,10 @@ pub mod proto {
     pub mod goodmetrics {
         tonic::include_proto!("goodmetrics");
         pub const DESCRIPTOR: &[u8] = tonic::include_file_descriptor_set!("goodmetrics_descriptor");
+
+        pub mod admin {
+            tonic::include_proto!("goodmetrics.admin");
+        }
     }
 
     pub mod opentelemetry {
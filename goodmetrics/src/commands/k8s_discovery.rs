@@ -0,0 +1,181 @@
+use std::{
+    collections::{HashMap, HashSet},
+    time::Duration,
+};
+
+use communication::proto::goodmetrics::{dimension, Dimension};
+use k8s_openapi::api::core::v1::Pod;
+use kube::{api::ListParams, Api, Client};
+use tokio::{task::JoinHandle, time};
+
+use crate::{
+    commands::poll_prometheus::poll_prometheus,
+    config::options::{KubernetesDiscoveryConfig, PrometheusPollConfig},
+    prometheus::reader::PrometheusSource,
+};
+
+const SCRAPE_ANNOTATION: &str = "prometheus.io/scrape";
+const PATH_ANNOTATION: &str = "prometheus.io/path";
+const PORT_ANNOTATION: &str = "prometheus.io/port";
+const SCHEME_ANNOTATION: &str = "prometheus.io/scheme";
+
+/// A pod that asked to be scraped
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct ScrapeTarget {
+    url: String,
+    pod: String,
+    namespace: String,
+    node: String,
+}
+
+/// Lists pods annotated with `prometheus.io/scrape: "true"` every discovery interval and keeps
+/// 1 poll_prometheus task running per pod. Each pod's datums get pod, namespace and node
+/// dimensions on top of the bonus dimensions.
+pub async fn poll_kubernetes(
+    discovery_config: KubernetesDiscoveryConfig,
+    poll_config: PrometheusPollConfig,
+    bonus_dimensions: HashMap<String, Dimension>,
+    table_prefix: String,
+    goodmetrics_endpoint: &str,
+    insecure_goodmetrics: bool,
+) {
+    let client = match Client::try_default().await {
+        Ok(client) => client,
+        Err(e) => {
+            log::error!("could not make a kubernetes client: {e:?}");
+            return;
+        }
+    };
+    let pods: Api<Pod> = match &discovery_config.kubernetes_namespace {
+        Some(namespace) => Api::namespaced(client, namespace),
+        None => Api::all(client),
+    };
+    log::info!(
+        "discovering pods to poll every: {}s",
+        discovery_config.discovery_interval_secs
+    );
+
+    let mut polling: HashMap<ScrapeTarget, JoinHandle<()>> = HashMap::new();
+    let mut interval = time::interval(Duration::from_secs(
+        discovery_config.discovery_interval_secs,
+    ));
+    loop {
+        interval.tick().await;
+        let targets = match discover(&pods).await {
+            Ok(targets) => targets,
+            Err(e) => {
+                // Keep polling what we already know about until the api server is back
+                log::error!("could not list pods: {e:?}");
+                continue;
+            }
+        };
+
+        polling.retain(|target, task| {
+            let keep = targets.contains(target) && !task.is_finished();
+            if !keep {
+                log::info!("stopped polling pod {}/{}", target.namespace, target.pod);
+                task.abort();
+            }
+            keep
+        });
+        for target in targets {
+            if polling.contains_key(&target) {
+                continue;
+            }
+            log::info!(
+                "polling pod {}/{} at {}",
+                target.namespace,
+                target.pod,
+                target.url
+            );
+            let mut pod_dimensions = bonus_dimensions.clone();
+            for (name, value) in [
+                ("pod", &target.pod),
+                ("namespace", &target.namespace),
+                ("node", &target.node),
+            ] {
+                pod_dimensions.insert(
+                    name.to_string(),
+                    Dimension {
+                        value: Some(dimension::Value::String(value.clone())),
+                    },
+                );
+            }
+            let source = PrometheusSource::Http(target.url.clone());
+            let poll_config = poll_config.clone();
+            let table_prefix = table_prefix.clone();
+            let goodmetrics_endpoint = goodmetrics_endpoint.to_string();
+            let task = tokio::spawn(async move {
+                poll_prometheus(
+                    source,
+                    poll_config,
+                    pod_dimensions,
+                    table_prefix,
+                    &goodmetrics_endpoint,
+                    insecure_goodmetrics,
+                )
+                .await
+            });
+            polling.insert(target, task);
+        }
+    }
+}
+
+async fn discover(pods: &Api<Pod>) -> Result<HashSet<ScrapeTarget>, kube::Error> {
+    let pod_list = pods.list(&ListParams::default()).await?;
+    Ok(pod_list.items.iter().filter_map(scrape_target).collect())
+}
+
+fn scrape_target(pod: &Pod) -> Option<ScrapeTarget> {
+    let annotations = pod.metadata.annotations.as_ref()?;
+    if annotations.get(SCRAPE_ANNOTATION).map(String::as_str) != Some("true") {
+        return None;
+    }
+    let status = pod.status.as_ref()?;
+    if status.phase.as_deref() != Some("Running") {
+        return None;
+    }
+    let pod_ip = status.pod_ip.as_ref()?;
+    let pod_name = pod.metadata.name.clone().unwrap_or_default();
+
+    // Like prometheus, fall back to the first port a container declares
+    let port = match annotations.get(PORT_ANNOTATION) {
+        Some(port) => port.clone(),
+        None => {
+            let declared = pod
+                .spec
+                .as_ref()?
+                .containers
+                .iter()
+                .flat_map(|container| container.ports.iter().flatten())
+                .map(|port| port.container_port)
+                .next();
+            match declared {
+                Some(port) => port.to_string(),
+                None => {
+                    log::warn!("pod {pod_name} has no {PORT_ANNOTATION} and no container ports");
+                    return None;
+                }
+            }
+        }
+    };
+    let path = annotations
+        .get(PATH_ANNOTATION)
+        .map(String::as_str)
+        .unwrap_or("/metrics");
+    let scheme = annotations
+        .get(SCHEME_ANNOTATION)
+        .map(String::as_str)
+        .unwrap_or("http");
+
+    Some(ScrapeTarget {
+        url: format!("{scheme}://{pod_ip}:{port}{path}"),
+        pod: pod_name,
+        namespace: pod.metadata.namespace.clone().unwrap_or_default(),
+        node: pod
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.node_name.clone())
+            .unwrap_or_default(),
+    })
+}
@@ -1,2 +1,3 @@
+pub mod k8s_discovery;
 pub mod poll_prometheus;
 pub mod send_metrics;
@@ -1,68 +1,321 @@
 use std::{
     collections::HashMap,
+    sync::atomic::{AtomicU64, Ordering},
     time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
-use tokio::time;
+use tokio::{
+    sync::mpsc::{self, error::TrySendError},
+    time,
+};
 
 use communication::{
     get_channel,
-    proto::goodmetrics::{metrics_client::MetricsClient, Dimension, MetricsRequest},
+    proto::goodmetrics::{metrics_client::MetricsClient, Datum, Dimension, MetricsRequest},
+    ChannelType,
+};
+
+use crate::{
+    config::options::PrometheusPollConfig,
+    prometheus::reader::{read_prometheus, PrometheusSource},
 };
 
-use crate::prometheus::reader::read_prometheus;
+// Scrapes waiting for goodmetrics. When it's slow or down, newer scrapes are dropped.
+const SEND_QUEUE_CAPACITY: usize = 16;
+
+#[derive(Default)]
+struct PollErrors {
+    scrape_failures: AtomicU64,
+    queue_full: AtomicU64,
+    send_failures: AtomicU64,
+}
+
+impl PollErrors {
+    fn count(&self, counter: &AtomicU64) -> String {
+        counter.fetch_add(1, Ordering::Relaxed);
+        format!(
+            "scrape_failures={} queue_full={} send_failures={}",
+            self.scrape_failures.load(Ordering::Relaxed),
+            self.queue_full.load(Ordering::Relaxed),
+            self.send_failures.load(Ordering::Relaxed),
+        )
+    }
+}
 
+/// Scrapes `source` every interval and queues each scrape's datums for goodmetrics,
+/// so a slow goodmetrics server doesn't delay the next scrape.
 pub async fn poll_prometheus(
-    poll_endpoint: String,
-    interval_seconds: u32,
+    source: PrometheusSource,
+    poll_config: PrometheusPollConfig,
     bonus_dimensions: HashMap<String, Dimension>,
     table_prefix: String,
     goodmetrics_endpoint: &str,
     insecure_goodmetrics: bool,
 ) {
-    log::info!("polling: {} every: {}s", poll_endpoint, interval_seconds);
-    let mut interval = time::interval(time::Duration::from_secs(interval_seconds as u64));
+    log::info!(
+        "polling: {} every: {}s",
+        source,
+        poll_config.interval_seconds
+    );
+    let errors = PollErrors::default();
 
+    let (send_queue, receive_queue) = mpsc::channel(SEND_QUEUE_CAPACITY);
+    tokio::join!(
+        scrape_forever(send_queue, source, poll_config, table_prefix, &errors),
+        send_scrapes(
+            receive_queue,
+            bonus_dimensions,
+            goodmetrics_endpoint,
+            insecure_goodmetrics,
+            &errors
+        ),
+    );
+}
+
+async fn scrape_forever(
+    send_queue: mpsc::Sender<Vec<Datum>>,
+    source: PrometheusSource,
+    poll_config: PrometheusPollConfig,
+    table_prefix: String,
+    errors: &PollErrors,
+) {
+    let client = match reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(poll_config.connect_timeout_seconds))
+        .timeout(Duration::from_secs(poll_config.request_timeout_seconds))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            log::error!("could not make an http client: {e:?}");
+            return;
+        }
+    };
+    let mut interval = time::interval(time::Duration::from_secs(
+        poll_config.interval_seconds as u64,
+    ));
     loop {
-        match read_prometheus(
-            &poll_endpoint,
-            SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap_or_else(|_| Duration::from_secs(0))
-                .as_nanos() as u64,
-            &table_prefix,
-        )
-        .await
-        {
+        match scrape(&client, &source, &poll_config, &table_prefix).await {
             Ok(datums) => {
                 log::debug!("lines: {:?}", datums);
-
-                match get_channel(goodmetrics_endpoint, insecure_goodmetrics).await {
-                    Ok(channel) => {
-                        log::debug!("connected: {}", goodmetrics_endpoint);
-                        let mut client = MetricsClient::new(channel);
-                        let result = client
-                            .send_metrics(MetricsRequest {
-                                shared_dimensions: bonus_dimensions.clone(),
-                                metrics: datums,
-                            })
-                            .await;
-                        match result {
-                            Ok(r) => {
-                                log::info!("result: {:?}", r);
-                            }
-                            Err(e) => {
-                                log::error!("error: {:?}", e);
-                            }
-                        }
+                match send_queue.try_send(datums) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(dropped)) => {
+                        let counts = errors.count(&errors.queue_full);
+                        log::error!(
+                            "send queue is full, dropping {} datums: {counts}",
+                            dropped.len()
+                        );
                     }
-                    Err(e) => {
-                        log::error!("failed to connect to goodmetrics: {:?}", e);
+                    Err(TrySendError::Closed(_)) => {
+                        log::error!("goodmetrics sender stopped");
+                        return;
                     }
-                };
+                }
+            }
+            Err(error) => {
+                let counts = errors.count(&errors.scrape_failures);
+                log::error!("giving up on this scrape: {error:?}: {counts}")
             }
-            Err(error) => log::error!("error talking to prometheus endpoint: {:?}", error),
         }
         interval.tick().await;
     }
 }
+
+// Retries what might go away on its own: connection trouble, timeouts and 5xx responses
+async fn scrape(
+    client: &reqwest::Client,
+    source: &PrometheusSource,
+    poll_config: &PrometheusPollConfig,
+    table_prefix: &str,
+) -> Result<Vec<Datum>, Box<dyn std::error::Error + Send + Sync>> {
+    let max_attempts = poll_config.max_attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        let now_nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_else(|_| Duration::from_secs(0))
+            .as_nanos() as u64;
+        let error = match read_prometheus(client, source, now_nanos, table_prefix).await {
+            Ok(datums) => return Ok(datums),
+            Err(error) => error,
+        };
+        log::error!(
+            "error reading prometheus metrics from {source}, attempt {attempt} of {max_attempts}: {error:?}"
+        );
+        if attempt >= max_attempts || is_permanent(error.as_ref()) {
+            return Err(error);
+        }
+        attempt += 1;
+        time::sleep(Duration::from_millis(poll_config.retry_delay_millis)).await;
+    }
+}
+
+fn is_permanent(error: &(dyn std::error::Error + 'static)) -> bool {
+    match error.downcast_ref::<reqwest::Error>() {
+        Some(http_error) => http_error
+            .status()
+            .map(|status| status.is_client_error())
+            .unwrap_or(false),
+        None => false,
+    }
+}
+
+async fn send_scrapes(
+    mut receive_queue: mpsc::Receiver<Vec<Datum>>,
+    bonus_dimensions: HashMap<String, Dimension>,
+    goodmetrics_endpoint: &str,
+    insecure_goodmetrics: bool,
+    errors: &PollErrors,
+) {
+    let mut client: Option<MetricsClient<ChannelType>> = None;
+    while let Some(datums) = receive_queue.recv().await {
+        if client.is_none() {
+            match get_channel(goodmetrics_endpoint, insecure_goodmetrics).await {
+                Ok(channel) => {
+                    log::debug!("connected: {}", goodmetrics_endpoint);
+                    client = Some(MetricsClient::new(channel));
+                }
+                Err(e) => {
+                    let counts = errors.count(&errors.send_failures);
+                    log::error!("failed to connect to goodmetrics: {e:?}: {counts}");
+                    continue;
+                }
+            }
+        }
+        let Some(connected) = &mut client else {
+            continue;
+        };
+
+        let result = connected
+            .send_metrics(MetricsRequest {
+                shared_dimensions: bonus_dimensions.clone(),
+                metrics: datums,
+            })
+            .await;
+        match result {
+            Ok(r) => {
+                log::info!("result: {:?}", r);
+            }
+            Err(e) => {
+                let counts = errors.count(&errors.send_failures);
+                log::error!("error: {e:?}: {counts}");
+                // Reconnect for the next scrape
+                client = None;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::scrape;
+    use crate::{config::options::PrometheusPollConfig, prometheus::reader::PrometheusSource};
+
+    const METRICS: &str = "# TYPE requests_total counter\nrequests_total{path=\"/\"} 12\n";
+
+    // An exporter on a local port that answers the first `failures` scrapes with `failure`,
+    // then serves METRICS. Returns its url and how many scrapes it has seen.
+    async fn exporter(failures: usize, failure: &'static str) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/metrics", listener.local_addr().unwrap());
+        let scrapes = Arc::new(AtomicUsize::new(0));
+        let counted = scrapes.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = Vec::new();
+                let mut buffer = [0; 1024];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let read = stream.read(&mut buffer).await.unwrap();
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                let (status, body) = if counted.fetch_add(1, Ordering::Relaxed) < failures {
+                    (failure, "")
+                } else {
+                    ("200 OK", METRICS)
+                };
+                let response = format!(
+                    "HTTP/1.1 {status}\r\ncontent-type: text/plain; version=0.0.4\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                    body.len()
+                );
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        (url, scrapes)
+    }
+
+    fn poll_config(max_attempts: u32) -> PrometheusPollConfig {
+        PrometheusPollConfig {
+            interval_seconds: 10,
+            max_attempts,
+            retry_delay_millis: 10,
+            connect_timeout_seconds: 5,
+            request_timeout_seconds: 5,
+        }
+    }
+
+    #[tokio::test]
+    async fn server_errors_are_retried_until_a_scrape_works() {
+        let (url, scrapes) = exporter(2, "503 Service Unavailable").await;
+
+        let datums = scrape(
+            &reqwest::Client::new(),
+            &PrometheusSource::Http(url),
+            &poll_config(3),
+            "",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(3, scrapes.load(Ordering::Relaxed));
+        assert_eq!(1, datums.len());
+        assert_eq!("requests_total", datums[0].metric);
+    }
+
+    #[tokio::test]
+    async fn scrape_gives_up_after_max_attempts() {
+        let (url, scrapes) = exporter(usize::MAX, "500 Internal Server Error").await;
+
+        let error = scrape(
+            &reqwest::Client::new(),
+            &PrometheusSource::Http(url),
+            &poll_config(3),
+            "",
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(3, scrapes.load(Ordering::Relaxed));
+        assert!(error.to_string().contains("500"), "{error}");
+    }
+
+    #[tokio::test]
+    async fn client_errors_are_not_retried() {
+        let (url, scrapes) = exporter(usize::MAX, "404 Not Found").await;
+
+        scrape(
+            &reqwest::Client::new(),
+            &PrometheusSource::Http(url),
+            &poll_config(3),
+            "",
+        )
+        .await
+        .unwrap_err();
+
+        assert_eq!(1, scrapes.load(Ordering::Relaxed));
+    }
+}
@@ -0,0 +1,110 @@
+use std::{collections::HashMap, path::Path};
+
+use client::DimensionValue;
+use communication::proto::goodmetrics::Dimension;
+
+/// `KEY=VALUE`, where values that look like unsigned integers become numbers
+pub fn parse_bonus_dimension(value: &str) -> anyhow::Result<(String, Dimension)> {
+    let (key, value) = value
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("expected KEY=VALUE, got {value:?}"))?;
+    let key = key.trim();
+    if key.is_empty() {
+        anyhow::bail!("missing dimension name in {value:?}");
+    }
+    let value = value.trim();
+    let dimension = match value.parse::<u64>() {
+        Ok(number) => number.into_dimension(),
+        Err(_) => value.into_dimension(),
+    };
+    Ok((key.to_string(), dimension))
+}
+
+/// One `KEY=VALUE` per line. Blank lines and lines starting with `#` are skipped.
+pub fn read_bonus_dimensions_file(path: &Path) -> anyhow::Result<Vec<(String, Dimension)>> {
+    let contents = std::fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("could not read {path:?}: {e}"))?;
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_bonus_dimension)
+        .collect()
+}
+
+/// Later sources win: the json map, then the file, then each `--bonus-dimension`
+pub fn merge_bonus_dimensions(
+    mut bonus_dimensions: HashMap<String, Dimension>,
+    from_file: Vec<(String, Dimension)>,
+    from_flags: Vec<(String, Dimension)>,
+) -> HashMap<String, Dimension> {
+    bonus_dimensions.extend(from_file);
+    bonus_dimensions.extend(from_flags);
+    bonus_dimensions
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use communication::proto::goodmetrics::{dimension::Value, Dimension};
+
+    use super::{merge_bonus_dimensions, parse_bonus_dimension, read_bonus_dimensions_file};
+
+    fn value(dimensions: &HashMap<String, Dimension>, key: &str) -> Value {
+        dimensions[key]
+            .value
+            .clone()
+            .expect("dimension has a value")
+    }
+
+    #[test]
+    fn values_parse_as_strings_or_numbers() {
+        let (key, dimension) = parse_bonus_dimension("host=server1").unwrap();
+        assert_eq!("host", key);
+        assert_eq!(Some(Value::String("server1".to_string())), dimension.value);
+
+        let (key, dimension) = parse_bonus_dimension(" shard = 42 ").unwrap();
+        assert_eq!("shard", key);
+        assert_eq!(Some(Value::Number(42)), dimension.value);
+
+        // Negative numbers aren't unsigned integers
+        let (_, dimension) = parse_bonus_dimension("offset=-1").unwrap();
+        assert_eq!(Some(Value::String("-1".to_string())), dimension.value);
+
+        assert!(parse_bonus_dimension("host").is_err());
+        assert!(parse_bonus_dimension("=server1").is_err());
+    }
+
+    #[test]
+    fn flags_win_over_the_file() {
+        let path = std::env::temp_dir().join(format!(
+            "goodmetrics-bonus-dimensions-{}",
+            std::process::id()
+        ));
+        std::fs::write(
+            &path,
+            "# where this runs\nhost=from_file\n\nregion=us-east-1\n",
+        )
+        .unwrap();
+        let from_file = read_bonus_dimensions_file(&path);
+        std::fs::remove_file(&path).unwrap();
+
+        let json = HashMap::from([parse_bonus_dimension("region=from_json").unwrap()]);
+        let from_flags = vec![
+            parse_bonus_dimension("host=from_flag").unwrap(),
+            parse_bonus_dimension("shard=42").unwrap(),
+        ];
+        let merged = merge_bonus_dimensions(json, from_file.unwrap(), from_flags);
+        assert_eq!(3, merged.len());
+        assert_eq!(
+            Value::String("from_flag".to_string()),
+            value(&merged, "host")
+        );
+        assert_eq!(
+            Value::String("us-east-1".to_string()),
+            value(&merged, "region")
+        );
+        assert_eq!(Value::Number(42), value(&merged, "shard"));
+    }
+}
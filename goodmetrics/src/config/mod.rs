@@ -1,2 +1,3 @@
+pub mod bonus_dimensions;
 pub mod cli_config;
 pub mod options;
@@ -1,10 +1,10 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::PathBuf};
 
 use clap::Parser;
 use lazy_static::lazy_static;
 use serde::Deserialize;
 
-use super::cli_config::default_dir;
+use super::{bonus_dimensions::parse_bonus_dimension, cli_config::default_dir};
 use communication::proto::goodmetrics::{Datum, Dimension};
 
 lazy_static! {
@@ -32,6 +32,8 @@ pub struct Options {
     pub command: Subcommand,
 }
 
+// Parsed once per run, so the size of its biggest variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, Deserialize, Parser)]
 pub enum Subcommand {
     #[clap(about = "Send measurements")]
@@ -54,8 +56,25 @@ pub enum Subcommand {
         #[arg(default_value = "http://127.0.0.1:9100/metrics")]
         poll_endpoint: String,
 
-        #[arg(long, default_value = "10")]
-        interval_seconds: u32,
+        #[arg(
+            long,
+            conflicts_with = "poll_endpoint",
+            help = "Read the prometheus text format from this file instead of an endpoint, like one written for node_exporter's textfile collector"
+        )]
+        prometheus_file: Option<PathBuf>,
+
+        #[arg(
+            long,
+            conflicts_with_all = ["poll_endpoint", "prometheus_file"],
+            help = "Poll every pod annotated with prometheus.io/scrape: \"true\" instead of 1 endpoint. prometheus.io/port and prometheus.io/path pick the url"
+        )]
+        kubernetes_discovery: bool,
+
+        #[command(flatten)]
+        discovery_config: KubernetesDiscoveryConfig,
+
+        #[command(flatten)]
+        poll_config: PrometheusPollConfig,
 
         #[arg(
             long,
@@ -65,9 +84,66 @@ pub enum Subcommand {
 
         #[arg(long, default_value = "{}", value_parser = parse_dimensions)]
         bonus_dimensions: HashMap<String, Dimension>,
+
+        #[arg(
+            long,
+            value_parser = parse_bonus_dimension,
+            help = "KEY=VALUE string dimension added to every datum. Integer values become numbers. Repeatable"
+        )]
+        bonus_dimension: Vec<(String, Dimension)>,
+
+        #[arg(
+            long,
+            help = "File of KEY=VALUE bonus dimensions, 1 per line. --bonus-dimension flags win over it"
+        )]
+        bonus_dimensions_file: Option<PathBuf>,
     },
 }
 
+/// How often to scrape, and how hard to try before a scrape is given up on
+#[derive(Debug, Deserialize, clap::Args, Clone)]
+pub struct PrometheusPollConfig {
+    #[arg(long, default_value = "10")]
+    pub interval_seconds: u32,
+
+    #[arg(
+        long,
+        default_value = "3",
+        help = "Tries per scrape. 4xx responses are not retried"
+    )]
+    pub max_attempts: u32,
+
+    #[arg(long, default_value = "1000", help = "Wait between tries of a scrape")]
+    pub retry_delay_millis: u64,
+
+    #[arg(long, default_value = "5")]
+    pub connect_timeout_seconds: u64,
+
+    #[arg(
+        long,
+        default_value = "10",
+        help = "Limit on each try of a scrape, including reading the body"
+    )]
+    pub request_timeout_seconds: u64,
+}
+
+/// Where to look for pods to poll, and how often
+#[derive(Debug, Deserialize, clap::Args, Clone)]
+pub struct KubernetesDiscoveryConfig {
+    #[arg(
+        long,
+        default_value = "30",
+        help = "How often to list pods when --kubernetes-discovery is on"
+    )]
+    pub discovery_interval_secs: u64,
+
+    #[arg(
+        long,
+        help = "Only discover pods in this namespace. Defaults to all of them"
+    )]
+    pub kubernetes_namespace: Option<String>,
+}
+
 fn parse_dimensions(value: &str) -> anyhow::Result<HashMap<String, Dimension>> {
     serde_json::from_str(value).map_err(|e| anyhow::anyhow!("could not parse dimensions: {e:?}"))
 }
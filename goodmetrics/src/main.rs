@@ -1,10 +1,22 @@
-use commands::{poll_prometheus::poll_prometheus, send_metrics::send_metrics};
-use config::{cli_config::get_args, options::Subcommand};
+use commands::{
+    k8s_discovery::poll_kubernetes, poll_prometheus::poll_prometheus, send_metrics::send_metrics,
+};
+use config::{
+    bonus_dimensions::{merge_bonus_dimensions, read_bonus_dimensions_file},
+    cli_config::get_args,
+    options::Subcommand,
+};
+
+use prometheus::reader::PrometheusSource;
 
 mod commands;
 mod config;
 mod prometheus;
 
+#[cfg(feature = "dhat-heap")]
+#[global_allocator]
+static ALLOC: dhat::Alloc = dhat::Alloc;
+
 #[tokio::main]
 async fn main() {
     let args = get_args();
@@ -28,14 +40,46 @@ async fn main() {
         }
         Subcommand::PollPrometheus {
             poll_endpoint,
-            interval_seconds,
+            prometheus_file,
+            kubernetes_discovery,
+            discovery_config,
+            poll_config,
             insecure,
             bonus_dimensions,
+            bonus_dimension,
+            bonus_dimensions_file,
             prefix,
         } => {
+            let from_file = match bonus_dimensions_file {
+                Some(path) => match read_bonus_dimensions_file(&path) {
+                    Ok(from_file) => from_file,
+                    Err(e) => {
+                        log::error!("bad bonus dimensions file: {e}");
+                        std::process::exit(1);
+                    }
+                },
+                None => Vec::new(),
+            };
+            let bonus_dimensions =
+                merge_bonus_dimensions(bonus_dimensions, from_file, bonus_dimension);
+            if kubernetes_discovery {
+                return poll_kubernetes(
+                    discovery_config,
+                    poll_config,
+                    bonus_dimensions,
+                    underscore_suffix(prefix),
+                    &args.goodmetrics_server,
+                    insecure,
+                )
+                .await;
+            }
+            let source = match prometheus_file {
+                Some(path) => PrometheusSource::File(path),
+                None => PrometheusSource::Http(poll_endpoint),
+            };
             poll_prometheus(
-                poll_endpoint,
-                interval_seconds,
+                source,
+                poll_config,
                 bonus_dimensions,
                 underscore_suffix(prefix),
                 &args.goodmetrics_server,
@@ -0,0 +1,376 @@
+use std::borrow::Cow;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{escaped, is_not, tag, take_while, take_while1},
+    character::complete::{char, digit1, one_of, space0, space1},
+    combinator::{all_consuming, map, map_res, opt, recognize, rest},
+    multi::separated_list0,
+    sequence::{delimited, pair, preceded, separated_pair, terminated, tuple},
+    IResult,
+};
+
+/// One line of the prometheus text exposition format, or of OpenMetrics, which extends it.
+/// Names and label values borrow from the scraped body; only a label value or help text
+/// with escapes in it gets its own allocation.
+#[derive(Debug, PartialEq)]
+pub enum Line<'a> {
+    Type {
+        name: &'a str,
+        metric_type: &'a str,
+    },
+    Help {
+        name: &'a str,
+        text: Cow<'a, str>,
+    },
+    /// OpenMetrics only. The unit is already the metric name's suffix.
+    Unit {
+        name: &'a str,
+        unit: &'a str,
+    },
+    /// OpenMetrics ends every exposition with `# EOF`
+    Eof,
+    Comment,
+    Blank,
+    Sample(Sample<'a>),
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Sample<'a> {
+    pub name: &'a str,
+    pub labels: Vec<(&'a str, Cow<'a, str>)>,
+    pub value: f64,
+    pub exemplar: Option<Exemplar<'a>>,
+}
+
+/// OpenMetrics' `# {trace_id="KOO5S4vxi0o"} 0.67` after a sample, pointing at an example of
+/// what was counted
+#[derive(Debug, PartialEq)]
+pub struct Exemplar<'a> {
+    pub labels: Vec<(&'a str, Cow<'a, str>)>,
+    pub value: f64,
+}
+
+impl Sample<'_> {
+    pub fn label(&self, name: &str) -> Option<&str> {
+        self.labels
+            .iter()
+            .find(|(label_name, _value)| *label_name == name)
+            .map(|(_name, value)| value.as_ref())
+    }
+}
+
+/// A line that isn't valid prometheus text
+#[derive(Debug)]
+pub struct ParseError<'a> {
+    pub line: &'a str,
+}
+
+/// Parses the body lazily, a line at a time
+pub fn lines(body: &str) -> impl Iterator<Item = Result<Line<'_>, ParseError<'_>>> {
+    body.lines().map(parse_line)
+}
+
+pub fn parse_line(line: &str) -> Result<Line<'_>, ParseError<'_>> {
+    match all_consuming(terminated(
+        alt((
+            blank,
+            type_line,
+            help_line,
+            unit_line,
+            eof,
+            comment,
+            map(sample, Line::Sample),
+        )),
+        space0,
+    ))(line)
+    {
+        Ok((_rest, parsed)) => Ok(parsed),
+        Err(_) => Err(ParseError { line }),
+    }
+}
+
+fn blank(input: &str) -> IResult<&str, Line<'_>> {
+    map(all_consuming(space0), |_| Line::Blank)(input)
+}
+
+// # TYPE http_request_duration_seconds histogram
+fn type_line(input: &str) -> IResult<&str, Line<'_>> {
+    map(
+        preceded(
+            tuple((char('#'), space1, tag("TYPE"), space1)),
+            separated_pair(metric_name, space1, take_while1(is_label_name_char)),
+        ),
+        |(name, metric_type)| Line::Type { name, metric_type },
+    )(input)
+}
+
+// # HELP http_requests_total The total number of HTTP requests.
+fn help_line(input: &str) -> IResult<&str, Line<'_>> {
+    map(
+        preceded(
+            tuple((char('#'), space1, tag("HELP"), space1)),
+            pair(metric_name, opt(preceded(space1, rest))),
+        ),
+        |(name, text)| Line::Help {
+            name,
+            text: unescape(text.unwrap_or_default()),
+        },
+    )(input)
+}
+
+// # UNIT http_request_duration_seconds seconds
+fn unit_line(input: &str) -> IResult<&str, Line<'_>> {
+    map(
+        preceded(
+            tuple((char('#'), space1, tag("UNIT"), space1)),
+            separated_pair(metric_name, space1, take_while1(is_label_name_char)),
+        ),
+        |(name, unit)| Line::Unit { name, unit },
+    )(input)
+}
+
+fn eof(input: &str) -> IResult<&str, Line<'_>> {
+    map(
+        all_consuming(tuple((char('#'), space1, tag("EOF"), space0))),
+        |_| Line::Eof,
+    )(input)
+}
+
+fn comment(input: &str) -> IResult<&str, Line<'_>> {
+    map(preceded(char('#'), rest), |_| Line::Comment)(input)
+}
+
+// http_request_duration_seconds_bucket{le="0.05",method="GET"} 24054 1395066363000
+// OpenMetrics: http_request_duration_seconds_bucket{le="0.05"} 24054 1520879607.789 # {trace_id="KOO5S4vxi0o"} 0.04
+fn sample(input: &str) -> IResult<&str, Sample<'_>> {
+    map(
+        tuple((
+            preceded(space0, metric_name),
+            opt(labels),
+            preceded(space1, value),
+            // Samples are stamped with the scrape time, so an exposed timestamp is only validated
+            opt(preceded(space1, timestamp)),
+            opt(preceded(tuple((space1, char('#'), space1)), exemplar)),
+        )),
+        |(name, labels, value, _timestamp, exemplar)| Sample {
+            name,
+            labels: labels.unwrap_or_default(),
+            value,
+            exemplar,
+        },
+    )(input)
+}
+
+fn exemplar(input: &str) -> IResult<&str, Exemplar<'_>> {
+    map(
+        tuple((
+            labels,
+            preceded(space1, value),
+            opt(preceded(space1, timestamp)),
+        )),
+        |(labels, value, _timestamp)| Exemplar { labels, value },
+    )(input)
+}
+
+fn metric_name(input: &str) -> IResult<&str, &str> {
+    recognize(pair(
+        take_while1(|c: char| c.is_ascii_alphabetic() || c == '_' || c == ':'),
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_' || c == ':'),
+    ))(input)
+}
+
+fn labels(input: &str) -> IResult<&str, Vec<(&str, Cow<'_, str>)>> {
+    delimited(
+        pair(char('{'), space0),
+        terminated(
+            separated_list0(tuple((space0, char(','), space0)), label),
+            // A trailing comma is allowed
+            opt(pair(space0, char(','))),
+        ),
+        pair(space0, char('}')),
+    )(input)
+}
+
+fn label(input: &str) -> IResult<&str, (&str, Cow<'_, str>)> {
+    separated_pair(
+        take_while1(is_label_name_char),
+        tuple((space0, char('='), space0)),
+        label_value,
+    )(input)
+}
+
+fn label_value(input: &str) -> IResult<&str, Cow<'_, str>> {
+    map(
+        delimited(
+            char('"'),
+            opt(escaped(is_not("\\\"\n"), '\\', one_of("\\\"n"))),
+            char('"'),
+        ),
+        |raw: Option<&str>| unescape(raw.unwrap_or_default()),
+    )(input)
+}
+
+fn value(input: &str) -> IResult<&str, f64> {
+    // Go's float formatting as well as +Inf, -Inf and NaN all parse as rust floats
+    map_res(take_while1(|c: char| !c.is_whitespace()), str::parse::<f64>)(input)
+}
+
+// Milliseconds in prometheus text, and seconds with an optional fraction in OpenMetrics
+fn timestamp(input: &str) -> IResult<&str, f64> {
+    map_res(
+        recognize(tuple((
+            opt(char('-')),
+            digit1,
+            opt(pair(char('.'), digit1)),
+        ))),
+        str::parse::<f64>,
+    )(input)
+}
+
+fn is_label_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+// Label values escape \, " and newline. Help text escapes \ and newline.
+fn unescape(raw: &str) -> Cow<'_, str> {
+    if !raw.contains('\\') {
+        return Cow::Borrowed(raw);
+    }
+    let mut unescaped = String::with_capacity(raw.len());
+    let mut chars = raw.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\'),
+        }
+    }
+    Cow::Owned(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::{parse_line, Exemplar, Line, Sample};
+
+    fn sample(line: &str) -> Sample<'_> {
+        match parse_line(line) {
+            Ok(Line::Sample(sample)) => sample,
+            other => panic!("{line} is not a sample: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn plain_values_are_borrowed() {
+        let parsed = sample(r#"http_requests_total{method="post",code="200"} 1027 1395066363000"#);
+        assert_eq!(parsed.name, "http_requests_total");
+        assert_eq!(parsed.value, 1027.0);
+        assert_eq!(
+            parsed.labels,
+            vec![
+                ("method", Cow::Borrowed("post")),
+                ("code", Cow::Borrowed("200"))
+            ]
+        );
+        assert!(parsed
+            .labels
+            .iter()
+            .all(|(_name, value)| matches!(value, Cow::Borrowed(_))));
+    }
+
+    #[test]
+    fn label_values_are_unescaped() {
+        let parsed = sample(
+            r#"msdos_file_access_time_seconds{path="C:\\DIR\\FILE.TXT",error="Cannot find file:\n\"FILE.TXT\""} 1.458255915e9"#,
+        );
+        assert_eq!(parsed.label("path"), Some(r"C:\DIR\FILE.TXT"));
+        assert_eq!(
+            parsed.label("error"),
+            Some("Cannot find file:\n\"FILE.TXT\"")
+        );
+        assert!(matches!(parsed.labels[0].1, Cow::Owned(_)));
+    }
+
+    #[test]
+    fn help_text_is_unescaped() {
+        assert_eq!(
+            parse_line(r"# HELP metric_without_labels A backslash \\ and a\nnewline").ok(),
+            Some(Line::Help {
+                name: "metric_without_labels",
+                text: Cow::Owned("A backslash \\ and a\nnewline".to_string()),
+            })
+        );
+    }
+
+    #[test]
+    fn trailing_comma_in_labels() {
+        let parsed = sample(r#"http_requests_total{method="post",code="400",} 3"#);
+        assert_eq!(parsed.label("code"), Some("400"));
+        assert_eq!(parsed.labels.len(), 2);
+        assert_eq!(sample(r#"up{job="node" , } 1"#).label("job"), Some("node"));
+    }
+
+    #[test]
+    fn empty_labels_and_special_values() {
+        assert!(sample("metric{} 1").labels.is_empty());
+        assert_eq!(sample("metric +Inf").value, f64::INFINITY);
+        assert_eq!(sample("metric -Inf").value, f64::NEG_INFINITY);
+        assert!(sample("metric NaN").value.is_nan());
+        assert_eq!(sample(r#"metric{le=""} 2"#).label("le"), Some(""));
+    }
+
+    #[test]
+    fn openmetrics_exemplar() {
+        let parsed =
+            sample(r#"foo_bucket{le="0.01"} 0 1520879607.789 # {trace_id="KOO5S4vxi0o"} 0.67"#);
+        assert_eq!(
+            parsed.exemplar,
+            Some(Exemplar {
+                labels: vec![("trace_id", Cow::Borrowed("KOO5S4vxi0o"))],
+                value: 0.67,
+            })
+        );
+    }
+
+    #[test]
+    fn metadata_lines() {
+        assert_eq!(
+            parse_line("# TYPE http_request_duration_seconds histogram").ok(),
+            Some(Line::Type {
+                name: "http_request_duration_seconds",
+                metric_type: "histogram",
+            })
+        );
+        assert_eq!(
+            parse_line("# UNIT request_seconds seconds").ok(),
+            Some(Line::Unit {
+                name: "request_seconds",
+                unit: "seconds",
+            })
+        );
+        assert_eq!(parse_line("# EOF").ok(), Some(Line::Eof));
+        assert_eq!(parse_line("# just a comment").ok(), Some(Line::Comment));
+        assert_eq!(parse_line("   ").ok(), Some(Line::Blank));
+    }
+
+    #[test]
+    fn malformed_lines() {
+        for line in [
+            r#"metric{label=unquoted} 1"#,
+            r#"metric{label="unterminated} 1"#,
+            r#"metric{label="bad escape \t"} 1"#,
+            "metric not_a_number",
+            "metric 1 not_a_timestamp",
+            "0metric 1",
+            "metric",
+        ] {
+            assert!(parse_line(line).is_err(), "{line} should not parse");
+        }
+    }
+}
@@ -1,248 +1,189 @@
-use std::{collections::HashMap, str::Chars};
-
-use lazy_static::lazy_static;
-use regex::Regex;
+use std::{collections::HashMap, fmt::Display, path::PathBuf};
 
 use communication::proto::goodmetrics::{
     dimension, measurement, Datum, Dimension, Histogram, Measurement,
 };
 
-lazy_static! {
-    // # TYPE go_memstats_alloc_bytes gauge
-    static ref MEASUREMENT_TYPE: Regex = Regex::new(r"^# TYPE (?P<measurement>\w+) (?P<type>[\w]+)$").expect("regex compiles");
-    static ref MEASUREMENT_NAME: Regex = Regex::new(r"^(?P<measurement>\w+)").expect("regex compiles");
-
-    // http_request_duration_seconds_bucket{le="0.05"} 24054
-    static ref HISTOGRAM_BUCKET: Regex = Regex::new(r#"\{.*le="(?P<le_bucket>[^"])".*\}\s*(?P<count>\d+)"#).expect("regex compiles");
-}
+use super::parser::{self, Line, Sample};
 
-pub async fn read_prometheus(
-    location: &str,
-    now_nanos: u64,
-    table_prefix: &str,
-) -> Result<Vec<Datum>, Box<dyn std::error::Error>> {
-    let response = reqwest::get(location).await?.text().await?;
-    Ok(decode_prometheus(response, now_nanos, table_prefix))
+/// Which of the exposition formats a body is in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ExpositionFormat {
+    Prometheus,
+    /// A superset of prometheus text: counters' samples end in `_total` and bodies end in `# EOF`
+    OpenMetrics,
 }
 
-fn decode_prometheus(body: String, now_nanos: u64, table_prefix: &str) -> Vec<Datum> {
-    let mut parse_state = ParseState::LookingForType;
-    let mut measurement_name: &str = "";
-    let mut datums: Vec<Datum> = vec![];
-    let mut partial_datum: Option<Datum> = None;
-
-    for line in body.lines() {
-        log::trace!("{:?}", line);
-        let parse_function: fn(&str, &str, u64, Option<Datum>) -> LineState = match parse_state {
-            ParseState::LookingForType => {
-                let (pstate, mname) = look_for_type(line);
-                measurement_name = mname;
-                parse_state = pstate;
-                continue;
-            }
-            ParseState::ReadingGauge => read_gauge,
-            ParseState::ReadingCounter => read_counter,
-            ParseState::ReadingHistogram => read_histogram,
-            ParseState::ReadingSummary => read_summary,
-        };
-        let line_state = parse_function(measurement_name, line, now_nanos, partial_datum.take());
-        if let Some(partial) = line_state.partial_datum {
-            partial_datum = Some(partial);
-            continue;
-        }
-        match line_state.complete_datum {
-            Some(mut datum) => {
-                datum.metric = format!("{}{}", table_prefix, datum.metric);
-                log::trace!("datum: {:?}", datum);
-                datums.push(datum);
-            }
-            None => {
-                let (pstate, mname) = look_for_type(line);
-                measurement_name = mname;
-                parse_state = pstate;
+impl ExpositionFormat {
+    fn of_content_type(content_type: Option<&reqwest::header::HeaderValue>) -> Self {
+        match content_type.and_then(|value| value.to_str().ok()) {
+            Some(content_type) if content_type.starts_with("application/openmetrics-text") => {
+                ExpositionFormat::OpenMetrics
             }
+            _ => ExpositionFormat::Prometheus,
         }
     }
-    datums
-}
-
-struct LineState {
-    pub complete_datum: Option<Datum>,
-    pub partial_datum: Option<Datum>,
-}
 
-fn read_summary(
-    measurement_name: &str,
-    line: &str,
-    unix_nanos: u64,
-    _partial: Option<Datum>,
-) -> LineState {
-    // You should not use summaries. They are awful. Shame on Prometheus for leading you astray.
-    LineState {
-        complete_datum: read_a_thing(measurement_name, line, unix_nanos),
-        partial_datum: None,
+    // Files don't come with a content type, but OpenMetrics has to end with # EOF
+    fn of_file(body: &str) -> Self {
+        if body.trim_end().ends_with("# EOF") {
+            ExpositionFormat::OpenMetrics
+        } else {
+            ExpositionFormat::Prometheus
+        }
     }
 }
 
-fn read_counter(
-    measurement_name: &str,
-    line: &str,
-    unix_nanos: u64,
-    _partial: Option<Datum>,
-) -> LineState {
-    LineState {
-        complete_datum: read_a_thing(measurement_name, line, unix_nanos),
-        partial_datum: None,
-    }
+/// Where to read prometheus' text exposition format from
+#[derive(Debug, Clone)]
+pub enum PrometheusSource {
+    Http(String),
+    /// Like a file written for node_exporter's textfile collector
+    File(PathBuf),
 }
 
-fn read_gauge(
-    measurement_name: &str,
-    line: &str,
-    unix_nanos: u64,
-    _partial: Option<Datum>,
-) -> LineState {
-    LineState {
-        complete_datum: read_a_thing(measurement_name, line, unix_nanos),
-        partial_datum: None,
+impl Display for PrometheusSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrometheusSource::Http(location) => f.write_str(location),
+            PrometheusSource::File(path) => write!(f, "{}", path.display()),
+        }
     }
 }
 
-fn read_a_thing(measurement_name: &str, line: &str, unix_nanos: u64) -> Option<Datum> {
-    match MEASUREMENT_NAME.captures(line) {
-        Some(capture) => {
-            if capture
-                .name("measurement")
-                .expect("measurement line must have measurement")
-                .as_str()
-                != measurement_name
-            {
-                return None;
-            }
+pub async fn read_prometheus(
+    client: &reqwest::Client,
+    source: &PrometheusSource,
+    now_nanos: u64,
+    table_prefix: &str,
+) -> Result<Vec<Datum>, Box<dyn std::error::Error + Send + Sync>> {
+    let (body, format) = match source {
+        PrometheusSource::Http(location) => {
+            let response = client.get(location).send().await?.error_for_status()?;
+            let format = ExpositionFormat::of_content_type(
+                response.headers().get(reqwest::header::CONTENT_TYPE),
+            );
+            (response.text().await?, format)
         }
-        None => return None,
-    }
+        PrometheusSource::File(path) => {
+            let body = tokio::fs::read_to_string(path).await?;
+            let format = ExpositionFormat::of_file(&body);
+            (body, format)
+        }
+    };
+    Ok(decode_prometheus(&body, format, now_nanos, table_prefix))
+}
 
-    // FIXME: Need to make a DatumFactory and pass it through so I can do host dimensions
+fn decode_prometheus(
+    body: &str,
+    format: ExpositionFormat,
+    now_nanos: u64,
+    table_prefix: &str,
+) -> Vec<Datum> {
+    let mut parse_state = ParseState::LookingForType;
+    let mut measurement_name: &str = "";
+    let mut datums: Vec<Datum> = vec![];
+    let mut partial_histogram: Option<Datum> = None;
 
-    let mut datum = Datum {
-        metric: measurement_name.to_string(),
-        unix_nanos,
-        ..Default::default()
-    };
-    let mut chars = line.chars();
+    for line in parser::lines(body) {
+        log::trace!("{:?}", line);
+        let sample = match line {
+            Ok(Line::Type { name, metric_type }) => {
+                datums.extend(partial_histogram.take());
+                measurement_name = name;
+                parse_state = next_parse_state(metric_type, measurement_name);
+                continue;
+            }
+            Ok(Line::Help { name, text }) => {
+                log::trace!("help for {name}: {text}");
+                continue;
+            }
+            Ok(Line::Unit { name, unit }) => {
+                log::trace!("unit for {name}: {unit}");
+                continue;
+            }
+            Ok(Line::Eof) if format == ExpositionFormat::OpenMetrics => break,
+            Ok(Line::Eof) | Ok(Line::Comment) | Ok(Line::Blank) => continue,
+            Ok(Line::Sample(sample)) => sample,
+            Err(e) => {
+                log::error!("bad line: {}", e.line);
+                continue;
+            }
+        };
 
-    if chars.nth(measurement_name.len()).unwrap_or(' ') == '{' {
-        // Dimensions local to the measurement
-        enum TagReadState {
-            Name,
-            Value,
-            Delimiter,
-        }
-        let mut tagstate = TagReadState::Name;
-        let mut tag_name = String::default();
-        let mut tag_value = String::default();
-        while let Some(c) = chars.next() {
-            match tagstate {
-                // why, prometheus people, why... did you forget that json exists? I mean you knew at
-                // one point and somehow decided that rolling your own was better?
-                // https://www.youtube.com/watch?v=4DzoajMs4DM&t=719s
-                // This video is hilarious. A perfect demonstration of poor decision making by a
-                // community! "You usually have to parse the entire request body with json[...]"
-                // and zero people taught this fellow that ndjson exists. Just astounding that the
-                // prometheus line protocol was allowed to get to this point.
-                // Rolling one's own ascii protocol in the modern world is not an act that should
-                // be undertaken lightly. Json parsers have had tremendous effort placed on speed
-                // and efficiency. Protocol buffers <exists>. Choosing an alternative like this
-                // prometheus text line protocol is the worst possible choice in this age.
-                TagReadState::Name => {
-                    if c == '=' {
-                        chars.next(); // Discard leading "
-                        tagstate = TagReadState::Value;
-                    } else if c == '}' {
-                        chars.next(); // Discard trailing " "
-                        break;
-                    } else {
-                        push_escaped(c, &mut tag_name, &mut chars);
-                    }
+        match parse_state {
+            ParseState::LookingForType => {
+                log::debug!("skipping sample: {}", sample.name);
+            }
+            // OpenMetrics names a counter without the _total its samples have, and its _created
+            // samples are timestamps rather than anything counted
+            ParseState::ReadingCounter if format == ExpositionFormat::OpenMetrics => {
+                let metric = measurement_name
+                    .strip_suffix("_total")
+                    .unwrap_or(measurement_name);
+                if sample.name.strip_prefix(metric) == Some("_total") {
+                    datums.push(read_a_thing(metric, &sample, now_nanos));
                 }
-                TagReadState::Value => {
-                    // All prometheus tags are strings because what else could you ever possibly want...
-                    if c == '"' {
-                        datum.dimensions.insert(
-                            tag_name,
-                            Dimension {
-                                value: Some(dimension::Value::String(tag_value)),
-                            },
-                        );
-                        tag_name = String::default();
-                        tag_value = String::default();
-                        tagstate = TagReadState::Delimiter;
-                    } else {
-                        push_escaped(c, &mut tag_value, &mut chars);
-                    }
+            }
+            // You should not use summaries. They are awful. Shame on Prometheus for leading you astray.
+            ParseState::ReadingGauge | ParseState::ReadingCounter | ParseState::ReadingSummary => {
+                if sample.name == measurement_name {
+                    datums.push(read_a_thing(measurement_name, &sample, now_nanos));
                 }
-                TagReadState::Delimiter => {
-                    if c == ',' {
-                        tagstate = TagReadState::Name;
-                    } else if c == '}' {
-                        chars.next(); // Discard trailing " "
-                        break;
-                    }
+            }
+            ParseState::ReadingHistogram => {
+                if let Some(datum) =
+                    read_histogram(measurement_name, &sample, now_nanos, &mut partial_histogram)
+                {
+                    datums.push(datum);
                 }
             }
         }
     }
-    // Here, we've named the datum and put whatever dimensions it has into it.
-    // All that's left is the value and an optional timestamp but I don't use
-    // the optional timestamp because.
-    let number_string: String = chars.take_while(|c| *c != ' ').collect();
-    let number: f64 = number_string.parse().unwrap_or_else(|err| {
-        log::error!("bad number format in line. Error: {}, line: {}", err, line);
-        -1.0
-    });
-    // Measurements can be repeated with any tags, so we emit 1 row per dimension position. Idk what else
-    // to call the value column... there's not a good choice here that's obvious to me. Prometheus metrics
-    // are ass though so yeah sorry about this & I hope you're not stuck with them for important stuff.
-    datum.measurements.insert(
-        "value".to_string(),
-        Measurement {
-            value: Some(measurement::Value::F64(number)),
-        },
-    );
-
-    Some(datum)
-}
+    datums.extend(partial_histogram);
 
-fn push_escaped(c: char, target: &mut String, chars: &mut Chars) {
-    if c == '\\' {
-        target.push(c);
-        target.push(chars.next().unwrap_or('\\'));
-    } else {
-        target.push(c);
+    for datum in &mut datums {
+        datum.metric = format!("{}{}", table_prefix, datum.metric);
+        log::trace!("datum: {:?}", datum);
     }
+    datums
 }
 
-fn look_for_type(line: &str) -> (ParseState, &str) {
-    match MEASUREMENT_TYPE.captures(line) {
-        Some(capture) => {
-            let measurement_name = capture
-                .name("measurement")
-                .expect("line must have measurement")
-                .as_str();
-            let metric_type = capture
-                .name("type")
-                .expect("measurement must have type")
-                .as_str();
+fn read_a_thing(metric: &str, sample: &Sample, unix_nanos: u64) -> Datum {
+    // FIXME: Need to make a DatumFactory and pass it through so I can do host dimensions
+
+    // All prometheus tags are strings because what else could you ever possibly want...
+    // An exemplar's labels, like a trace id, go first so the sample's own labels win a clash.
+    let exemplar_labels = sample
+        .exemplar
+        .iter()
+        .flat_map(|exemplar| exemplar.labels.iter());
+    let dimensions = exemplar_labels
+        .chain(sample.labels.iter())
+        .map(|(name, value)| {
             (
-                next_parse_state(metric_type, measurement_name),
-                measurement_name,
+                name.to_string(),
+                Dimension {
+                    value: Some(dimension::Value::String(value.to_string())),
+                },
             )
-        }
-        None => {
-            log::debug!("skipping line: {}", line);
-            (ParseState::LookingForType, "")
-        }
+        })
+        .collect();
+
+    // Measurements can be repeated with any tags, so we emit 1 row per dimension position. Idk what else
+    // to call the value column... there's not a good choice here that's obvious to me. Prometheus metrics
+    // are ass though so yeah sorry about this & I hope you're not stuck with them for important stuff.
+    Datum {
+        metric: metric.to_string(),
+        unix_nanos,
+        dimensions,
+        measurements: HashMap::from([(
+            "value".to_string(),
+            Measurement {
+                value: Some(measurement::Value::F64(sample.value)),
+            },
+        )]),
+        ..Default::default()
     }
 }
 
@@ -256,11 +197,13 @@ fn next_parse_state(metric_type: &str, measurement_name: &str) -> ParseState {
             log::trace!("found metric type {}: counter", measurement_name);
             ParseState::ReadingCounter
         }
-        "histogram" => {
+        // A gaugehistogram's buckets read the same, and its _gsum and _gcount are ignored the same
+        "histogram" | "gaugehistogram" => {
             log::trace!("found metric type {}: histogram", measurement_name);
             ParseState::ReadingHistogram
         }
-        "untyped" => {
+        // OpenMetrics calls it unknown
+        "untyped" | "unknown" => {
             log::trace!(
                 "found metric type {}: untyped - treating as gauge",
                 measurement_name
@@ -301,111 +244,153 @@ enum ParseState {
 // http_request_duration_seconds_bucket{le="+Inf"} 144320
 // http_request_duration_seconds_sum 53423
 // http_request_duration_seconds_count 144320
+//
+// Returns the histogram once its buckets are over.
 fn read_histogram(
     measurement_name: &str,
-    line: &str,
+    sample: &Sample,
     unix_nanos: u64,
-    partial: Option<Datum>,
-) -> LineState {
-    let mut datum = match partial {
-        Some(p) => p,
-        None => Datum {
-            metric: measurement_name.to_string(),
-            unix_nanos,
-            measurements: HashMap::from([(
-                "value".to_string(),
-                Measurement {
-                    value: Some(measurement::Value::Histogram(Histogram {
-                        buckets: HashMap::new(),
-                    })),
-                },
-            )]),
-            ..Default::default()
-        },
-    };
-    return if line.starts_with(&format!("{}_bucket{{", measurement_name)) {
-        let v = datum
-            .measurements
-            .get_mut("value")
-            .expect("line must have value");
-        let histogram = match v.value.as_mut().expect("value must have value") {
-            measurement::Value::Histogram(h) => h,
-            _ => {
-                log::error!("Bad histogram type in datum {:?}", datum);
-                return LineState {
-                    complete_datum: None,
-                    partial_datum: None,
-                };
-            }
-        };
-        match HISTOGRAM_BUCKET.captures(line) {
-            Some(capture) => {
-                let bucket_str = match capture.name("le_bucket") {
-                    Some(b) => b.as_str(),
-                    None => {
-                        return LineState {
-                            complete_datum: None,
-                            partial_datum: None,
-                        }
-                    }
-                };
-                let raw_count: u64 = match capture.name("count") {
-                    Some(c) => c.as_str().parse::<u64>().unwrap_or(0),
-                    None => {
-                        return LineState {
-                            complete_datum: None,
-                            partial_datum: None,
-                        }
-                    }
-                };
-                let raw_bucket: f64 = match bucket_str.parse() {
-                    Ok(b) => b,
-                    Err(e) => {
-                        log::error!("bad histogram bucket line: {}, {:?}", line, e);
-                        return LineState {
-                            complete_datum: None,
-                            partial_datum: None,
-                        };
-                    }
-                };
-                let values_below_bucket: u64 = histogram
-                    .buckets
-                    .iter()
-                    .map(|(k, v)| if (*k as f64) < raw_bucket { *v } else { 0 })
-                    .sum();
-
-                let actual_count = raw_count - values_below_bucket;
-                let actual_bucket = raw_bucket.ceil() as i64;
-                let bucket_value = histogram
-                    .buckets
-                    .remove(&actual_bucket)
-                    .map_or(actual_count, |v| v + actual_count);
-                histogram.buckets.insert(actual_bucket, bucket_value);
-                LineState {
-                    complete_datum: None,
-                    partial_datum: Some(datum),
-                }
-            }
-            None => LineState {
-                complete_datum: None,
-                partial_datum: None,
-            },
-        }
-    } else {
-        // if line.starts_with(&format!("{}_sum", measurement_name))
-        // then we'll guess that it's the sum thing from the histogram.
+    partial: &mut Option<Datum>,
+) -> Option<Datum> {
+    let is_bucket = sample.name.strip_prefix(measurement_name) == Some("_bucket");
+    if !is_bucket {
+        // if the sample is the _sum then we'll guess that it's the sum thing from the histogram.
         // goodmetrics histograms are for distributions though, so we
         // don't really care about the raw sum.
 
-        // if line.starts_with(&format!("{}_count", measurement_name))
+        // if the sample is the _count
         // i mean, the histogram already has the raw count... c'mon prometheus...
 
         // else
         // Maybe there was no sum or count? I dunno, the line protocol is not
         // well-specified.
-        LineState {
-            complete_datum: Some(datum),
-            partial_datum: None,
+        return partial.take();
+    }
+
+    let raw_bucket: f64 = match sample.label("le").map(str::parse) {
+        Some(Ok(bucket)) => bucket,
+        Some(Err(e)) => {
+            log::error!("bad histogram bucket: {:?}, {:?}", sample, e);
+            return None;
+        }
+        None => {
+            log::error!("histogram bucket without le: {:?}", sample);
+            return None;
+        }
+    };
+    let raw_count = sample.value as u64;
+
+    let datum = partial.get_or_insert_with(|| Datum {
+        metric: measurement_name.to_string(),
+        unix_nanos,
+        measurements: HashMap::from([(
+            "value".to_string(),
+            Measurement {
+                value: Some(measurement::Value::Histogram(Histogram {
+                    buckets: HashMap::new(),
+                })),
+            },
+        )]),
+        ..Default::default()
+    });
+    let histogram = match datum
+        .measurements
+        .get_mut("value")
+        .and_then(|v| v.value.as_mut())
+    {
+        Some(measurement::Value::Histogram(h)) => h,
+        _ => {
+            log::error!("Bad histogram type in datum {:?}", datum);
+            return None;
         }
     };
+
+    let values_below_bucket: u64 = histogram
+        .buckets
+        .iter()
+        .map(|(k, v)| if (*k as f64) < raw_bucket { *v } else { 0 })
+        .sum();
+
+    let actual_count = raw_count.saturating_sub(values_below_bucket);
+    let actual_bucket = raw_bucket.ceil() as i64;
+    *histogram.buckets.entry(actual_bucket).or_default() += actual_count;
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Write;
+
+    use super::{decode_prometheus, ExpositionFormat};
+
+    const DATUMS_PER_FAMILY: usize = 34;
+
+    // Something like a node_exporter scrape, repeated until it's about target_bytes
+    fn scrape_body(target_bytes: usize) -> String {
+        let mut body = String::with_capacity(target_bytes + 4096);
+        let mut family = 0;
+        while body.len() < target_bytes {
+            let _ = writeln!(body, "# HELP node_cpu_seconds_{family} Seconds the cpus spent in each mode.\n# TYPE node_cpu_seconds_{family} counter");
+            for cpu in 0..8 {
+                for mode in ["idle", "iowait", "system", "user"] {
+                    let _ = writeln!(
+                        body,
+                        r#"node_cpu_seconds_{family}{{cpu="{cpu}",mode="{mode}"}} {}.25"#,
+                        cpu * 1000 + family
+                    );
+                }
+            }
+            let _ = writeln!(body, "# TYPE node_filesystem_avail_bytes_{family} gauge");
+            let _ = writeln!(
+                body,
+                r#"node_filesystem_avail_bytes_{family}{{device="/dev/sda1",mountpoint="C:\\data \"main\""}} 1.5e10"#
+            );
+            let _ = writeln!(body, "# TYPE http_request_seconds_{family} histogram");
+            for (le, count) in [
+                ("0.005", 3),
+                ("0.01", 7),
+                ("0.1", 30),
+                ("1", 41),
+                ("+Inf", 42),
+            ] {
+                let _ = writeln!(
+                    body,
+                    r#"http_request_seconds_{family}_bucket{{le="{le}"}} {count}"#
+                );
+            }
+            let _ = writeln!(
+                body,
+                "http_request_seconds_{family}_sum 12.5\nhttp_request_seconds_{family}_count 42"
+            );
+            family += 1;
+        }
+        body
+    }
+
+    #[test]
+    fn decodes_a_big_scrape() {
+        let body = scrape_body(1 << 20);
+        let families = body.matches("# TYPE node_cpu_seconds_").count();
+        let datums = decode_prometheus(&body, ExpositionFormat::Prometheus, 0, "node_");
+        assert_eq!(datums.len(), families * DATUMS_PER_FAMILY);
+        assert!(datums.iter().all(|datum| datum.metric.starts_with("node_")));
+    }
+
+    // cargo test -p goodmetrics --features dhat-heap allocations -- --nocapture
+    #[cfg(feature = "dhat-heap")]
+    #[test]
+    fn allocations_decoding_1mb() {
+        let body = scrape_body(1 << 20);
+        let _profiler = dhat::Profiler::builder().testing().build();
+        let datums = decode_prometheus(&body, ExpositionFormat::Prometheus, 0, "");
+        let stats = dhat::HeapStats::get();
+        println!(
+            "{} bytes, {} datums: {} allocations, {} bytes allocated, {} bytes at peak",
+            body.len(),
+            datums.len(),
+            stats.total_blocks,
+            stats.total_bytes,
+            stats.max_bytes,
+        );
+    }
 }
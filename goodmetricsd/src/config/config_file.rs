@@ -0,0 +1,237 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use clap::{error::ErrorKind, CommandFactory};
+
+use super::options::Options;
+
+/// Turns a toml config file into command line arguments, ahead of the real ones so those win.
+///
+/// Keys are the long flag names with underscores for dashes: `--kafka-bootstrap-servers`
+/// is `kafka_bootstrap_servers = "kafka-1:9092"`. Switches like `--tokio-console` take
+/// `true` or `false`, and flags that can be repeated, like `--api-keys`, take arrays.
+pub fn with_config_file(command_line: Vec<OsString>) -> Vec<OsString> {
+    let Some(path) = config_path(&command_line) else {
+        return command_line;
+    };
+    let file_args = match read_config_file(&path, &command_line) {
+        Ok(file_args) => file_args,
+        Err(message) => Options::command()
+            .error(ErrorKind::Io, format!("config file {path:?}: {message}"))
+            .exit(),
+    };
+
+    let mut args = command_line;
+    let rest = args.split_off(1.min(args.len()));
+    args.extend(file_args);
+    args.extend(rest);
+    args
+}
+
+// Has to be found before clap runs, since the file can hold required arguments
+fn config_path(command_line: &[OsString]) -> Option<PathBuf> {
+    let mut args = command_line.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--" {
+            break;
+        }
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    std::env::var_os("GOODMETRICSD_CONFIG").map(PathBuf::from)
+}
+
+fn read_config_file(path: &Path, command_line: &[OsString]) -> Result<Vec<OsString>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+    let table: toml::Table = contents
+        .parse()
+        .map_err(|e: toml::de::Error| e.to_string())?;
+
+    let command = Options::command();
+    let mut args = Vec::new();
+    for (key, value) in table {
+        let long = key.replace('_', "-");
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(long.as_str()))
+        else {
+            return Err(format!("unknown key {key}"));
+        };
+        // Repeatable flags would otherwise collect values from both places
+        if given_on_command_line(command_line, &long) {
+            continue;
+        }
+        let values = match value {
+            toml::Value::Array(values) => values,
+            value => vec![value],
+        };
+        for value in values {
+            let value = match value {
+                toml::Value::String(s) => s,
+                toml::Value::Integer(i) => i.to_string(),
+                toml::Value::Float(f) => f.to_string(),
+                toml::Value::Boolean(b) if !arg.get_action().takes_values() => {
+                    if b {
+                        args.push(OsString::from(format!("--{long}")));
+                    }
+                    continue;
+                }
+                toml::Value::Boolean(b) => b.to_string(),
+                other => return Err(format!("{key} can't be a {}", other.type_str())),
+            };
+            args.push(OsString::from(format!("--{long}={value}")));
+        }
+    }
+    Ok(args)
+}
+
+fn given_on_command_line(command_line: &[OsString], long: &str) -> bool {
+    let flag = format!("--{long}");
+    let flag_with_value = format!("--{long}=");
+    command_line.iter().skip(1).any(|arg| {
+        let arg = arg.to_string_lossy();
+        arg == flag || arg.starts_with(&flag_with_value)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::OsString,
+        path::{Path, PathBuf},
+    };
+
+    use clap::{CommandFactory, Parser};
+
+    use super::with_config_file;
+    use crate::config::options::{IdentifierMode, Options};
+
+    fn config_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "goodmetrics-config-{name}-{}.toml",
+            std::process::id()
+        ));
+        std::fs::write(&path, contents).expect("temp file can be written");
+        path
+    }
+
+    fn parse_with_config_file(path: &Path, flags: &[&str]) -> Options {
+        let mut command_line: Vec<OsString> = vec![
+            "goodmetricsd".into(),
+            "--config".into(),
+            path.as_os_str().to_owned(),
+        ];
+        command_line.extend(flags.iter().map(OsString::from));
+        Options::try_parse_from(with_config_file(command_line)).expect("options parse")
+    }
+
+    #[test]
+    fn every_flag_can_come_from_the_file() {
+        // Each flag's own default, written out in the file, has to read back the same
+        let mut table = toml::Table::new();
+        for arg in Options::command().get_arguments() {
+            let Some(long) = arg.get_long() else {
+                continue;
+            };
+            let defaults: Vec<String> = arg
+                .get_default_values()
+                .iter()
+                .map(|value| value.to_string_lossy().into_owned())
+                .collect();
+            if long == "config" || defaults.is_empty() {
+                continue;
+            }
+            let value = if arg.get_action().takes_values() {
+                toml::Value::Array(defaults.into_iter().map(toml::Value::String).collect())
+            } else {
+                toml::Value::Boolean(defaults == ["true"])
+            };
+            table.insert(long.replace('-', "_"), value);
+        }
+        let path = config_file("defaults", &table.to_string());
+        // A sink is required, and has no default
+        let sink = ["--connection-string", "host=test"];
+        let mut from_file = parse_with_config_file(&path, &sink);
+        std::fs::remove_file(&path).expect("temp file can be removed");
+        from_file.config = None;
+
+        let without_file = Options::parse_from(["goodmetricsd"].iter().chain(&sink));
+        assert_eq!(format!("{without_file:?}"), format!("{from_file:?}"));
+    }
+
+    #[test]
+    fn sample_file_sets_options() {
+        let path = config_file(
+            "sample",
+            r#"
+connection_string = "host=db user=metrics"
+max_threads = 3
+dry_run = true
+timescale_mode = false
+identifier_mode = "preserve-case-quoted"
+dedup_window = "5s"
+wal_path = "/var/lib/goodmetrics/wal.dat"
+schema_name = "metrics"
+kafka_bootstrap_servers = "kafka-1:9092"
+api_keys = ["a", "b"]
+"#,
+        );
+        let options = parse_with_config_file(&path, &[]);
+        std::fs::remove_file(&path).expect("temp file can be removed");
+
+        assert_eq!(
+            Some("host=db user=metrics"),
+            options.connection_string.as_deref()
+        );
+        assert_eq!(3, options.max_threads);
+        assert!(options.dry_run);
+        assert!(!options.timescale_mode.enabled);
+        assert_eq!(IdentifierMode::PreserveCaseQuoted, options.identifier_mode);
+        assert_eq!(std::time::Duration::from_secs(5), options.dedup_window);
+        assert_eq!(
+            Some(PathBuf::from("/var/lib/goodmetrics/wal.dat")),
+            options.wal_path
+        );
+        assert_eq!(Some("metrics"), options.schema_name.as_deref());
+        assert_eq!(
+            Some("kafka-1:9092"),
+            options.kafka.bootstrap_servers.as_deref()
+        );
+        assert_eq!(["a", "b"], options.api_keys[..]);
+    }
+
+    #[test]
+    fn command_line_wins() {
+        let path = config_file(
+            "precedence",
+            r#"
+max_threads = 3
+schema_name = "from_file"
+api_keys = ["from_file"]
+"#,
+        );
+        let options = parse_with_config_file(
+            &path,
+            &[
+                "--connection-string",
+                "host=test",
+                "--max-threads",
+                "5",
+                "--api-keys=from_flag",
+            ],
+        );
+        std::fs::remove_file(&path).expect("temp file can be removed");
+
+        assert_eq!(5, options.max_threads);
+        // Repeatable flags take only the command line's values
+        assert_eq!(["from_flag"], options.api_keys[..]);
+        assert_eq!(Some("from_file"), options.schema_name.as_deref());
+    }
+}
@@ -1,8 +1,10 @@
-use std::time::Duration;
+use std::{path::PathBuf, time::Duration};
 
 use clap::Parser;
 use serde_derive::Deserialize;
 
+use super::config_file::with_config_file;
+
 #[derive(Debug, Deserialize, Parser, Clone)]
 #[clap(
     author = "Kenny",
@@ -10,21 +12,78 @@ use serde_derive::Deserialize;
         clap::ArgGroup::new("remote")
             .required(true)
             .multiple(true)
-            .args(["connection_string", "otlp_remote"]),
+            .args(["connection_string", "otlp_remote", "bootstrap_servers"]),
     )
 )]
 pub struct Options {
-    // #[arg(long, help = "A config file")]
-    // pub config: Option<String>,
+    #[arg(
+        long,
+        help = "A toml file of arguments, keyed by flag name with underscores. Command line flags take precedence",
+        env = "GOODMETRICSD_CONFIG"
+    )]
+    pub config: Option<PathBuf>,
+
     #[arg(long, default_value = "0.0.0.0:9573", env = "LISTEN_SOCKET_ADDRESS")]
     pub listen_socket_address: String,
 
+    #[arg(
+        long,
+        default_value = "0.0.0.0:9090",
+        help = "Serves /healthz, /readyz, /metrics and POST /flush over plain http",
+        env = "HEALTH_LISTEN_SOCKET_ADDRESS"
+    )]
+    pub health_listen_socket_address: String,
+
+    #[arg(
+        long,
+        help = "Send http2 and tcp keepalives on idle grpc connections this often, so firewalls and NAT don't drop clients that report rarely. 0s turns this off. Example: 60s",
+        default_value = "0s",
+        env = "GRPC_KEEPALIVE_INTERVAL",
+        value_parser = humantime::parse_duration,
+    )]
+    pub grpc_keepalive_interval: Duration,
+
+    #[arg(
+        long,
+        help = "Close a grpc connection whose client doesn't answer a keepalive ping within this long",
+        default_value = "20s",
+        env = "GRPC_KEEPALIVE_TIMEOUT",
+        value_parser = humantime::parse_duration,
+    )]
+    pub grpc_keepalive_timeout: Duration,
+
+    #[arg(
+        long,
+        help = "How long to wait for in-flight metrics to be written after SIGTERM or SIGINT before exiting anyway",
+        default_value = "30s",
+        env = "SHUTDOWN_DRAIN_WINDOW",
+        value_parser = humantime::parse_duration,
+    )]
+    pub shutdown_drain_window: Duration,
+
     #[arg(long, default_value = "1", env = "MAX_THREADS")]
     pub max_threads: usize,
 
+    #[arg(
+        long,
+        default_value = "1",
+        help = "Threads for the postgres sink. With more than 1, a batch's tables are written in parallel instead of taking turns on 1 thread",
+        env = "SINK_RUNTIME_THREADS"
+    )]
+    pub sink_runtime_threads: usize,
+
     #[arg(long, default_value = "debug", env = "LOG_LEVEL")]
     pub log_level: String,
 
+    #[arg(
+        long,
+        value_enum,
+        default_value = "text",
+        help = "text for humans, json for log aggregators",
+        env = "LOG_FORMAT"
+    )]
+    pub log_format: LogFormat,
+
     #[arg(
         long,
         help = "enable the tokio-console listener? (doesn't work on docker)"
@@ -62,6 +121,13 @@ pub struct Options {
     )]
     pub api_keys: Vec<String>,
 
+    #[arg(
+        long,
+        help = "Required as the authorization header of admin service calls. If not supplied, the admin service is open to anyone who can reach it",
+        env = "ADMIN_TOKEN"
+    )]
+    pub admin_token: Option<String>,
+
     #[arg(
         long,
         help = "Example: 7d",
@@ -79,6 +145,100 @@ pub struct Options {
     )]
     pub compress_new_tables: bool,
 
+    #[arg(
+        long,
+        help = "Index new text and integer dimension columns in the background, so queries filtering on them don't scan the whole table",
+        env = "AUTO_INDEX_DIMENSIONS"
+    )]
+    pub auto_index_dimensions: bool,
+
+    #[arg(
+        long,
+        help = "Give metrics tables' jsonb tags column a GIN index, for lookups like tags @> '{\"trace_id\": \"...\"}'. Existing tables are indexed in the background once tags are written to them",
+        env = "INDEX_TAGS"
+    )]
+    pub index_tags: bool,
+
+    #[arg(
+        long,
+        help = "Record every table and column the postgres sender creates or changes, and which metric needed it, in goodmetrics_meta.goodmetrics_ddl_audit",
+        default_value = "true",
+        action = clap::ArgAction::Set,
+        env = "ENABLE_DDL_AUDIT"
+    )]
+    pub enable_ddl_audit: bool,
+
+    #[arg(
+        long,
+        help = "ANALYZE a table in the background each time this many more rows have been written to it, so the query planner's statistics keep up with big COPYs. 0 disables",
+        default_value = "1000000",
+        env = "VACUUM_ANALYZE_THRESHOLD"
+    )]
+    pub vacuum_analyze_threshold: u64,
+
+    #[command(flatten)]
+    pub time_constraint: TimeConstraint,
+
+    #[command(flatten)]
+    pub timescale_mode: TimescaleMode,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "csv",
+        help = "How rows are encoded for COPY. text uses backslash escapes, for proxies that mangle csv",
+        env = "TIMESCALE_COPY_FORMAT"
+    )]
+    pub copy_format: CopyFormat,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "microseconds",
+        help = "timestamptz keeps microseconds. nanoseconds-in-separate-column also writes the rest of each datum's nanoseconds to a time_ns_remainder column",
+        env = "TIMESTAMP_PRECISION"
+    )]
+    pub timestamp_precision: TimestampPrecision,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "text-fallback",
+        help = "The column type for a dimension that datums in the same batch send as different types. Values that don't match a first-wins or last-wins column are written as null",
+        env = "DIMENSION_TYPE_CONFLICT"
+    )]
+    pub dimension_type_conflict: DimensionTypeConflict,
+
+    #[arg(
+        long,
+        default_value = "10",
+        help = "Write a table's rows with INSERT instead of COPY when there are fewer than this many, skipping COPY's extra round trip. 0 always uses COPY",
+        env = "INSERT_BELOW_ROWS"
+    )]
+    pub insert_below_rows: usize,
+
+    #[arg(
+        long,
+        help = "COPY batches into a temporary staging table and only move over the rows whose time and dimensions aren't already in the table, so retrying a batch that partly made it in doesn't write duplicates. Costs a lookup per row; also turns off --insert-below-rows",
+        env = "IDEMPOTENT_WRITES"
+    )]
+    pub idempotent_writes: bool,
+
+    #[arg(
+        long,
+        help = "Log the tables and columns the postgres sender would create and the rows it would write, without changing or writing to postgres. For checking a configuration or a new database before using it for real",
+        env = "DRY_RUN"
+    )]
+    pub dry_run: bool,
+
+    #[arg(
+        long,
+        help = "How many goodmetricsd servers share the postgres. Used to recommend a connection pool size at startup",
+        default_value = "1",
+        env = "NUM_INSTANCES"
+    )]
+    pub num_instances: u32,
+
     #[arg(
         long,
         help = "Example: host=localhost port=2345 user=metrics password=metrics connect_timeout=10",
@@ -86,6 +246,308 @@ pub struct Options {
     )]
     pub connection_string: Option<String>,
 
+    #[arg(
+        long,
+        help = "How often new postgres connections look up the connection string again, to pick up rotated credentials",
+        default_value = "5m",
+        env = "TIMESCALE_CONNECTION_STRING_REFRESH_INTERVAL",
+        value_parser = humantime::parse_duration,
+    )]
+    pub connection_string_refresh_interval: Duration,
+
+    #[arg(
+        long,
+        help = "How often idle postgres connections are checked with select 1, so ones dropped by a firewall are replaced before a batch needs them. 0s turns this off",
+        default_value = "30s",
+        env = "TIMESCALE_CONNECTION_HEALTH_CHECK_INTERVAL",
+        value_parser = humantime::parse_duration,
+    )]
+    pub connection_health_check_interval: Duration,
+
+    #[arg(
+        long,
+        help = "Postgres schema for metric tables, e.g. metrics for metrics.cpu_usage. Otherwise tables are unqualified",
+        env = "TIMESCALE_SCHEMA_NAME"
+    )]
+    pub schema_name: Option<String>,
+
+    #[arg(
+        long,
+        help = "Prepended to every metric's table name, e.g. gm_ for gm_cpu_usage. Letters, digits and underscores only",
+        default_value = "",
+        env = "TIMESCALE_TABLE_PREFIX",
+        value_parser = parse_table_prefix,
+    )]
+    pub table_prefix: String,
+
+    #[arg(
+        long,
+        value_enum,
+        default_value = "lowercase-unquoted",
+        help = "How metric, dimension and measurement names become table and column names. preserve-case-quoted keeps CPUUsage and cpuusage apart, but the tables have to be quoted in queries too",
+        env = "IDENTIFIER_MODE"
+    )]
+    pub identifier_mode: IdentifierMode,
+
+    #[arg(
+        long,
+        help = "Skip datums already written by any goodmetricsd sharing this redis. Example: redis://127.0.0.1/",
+        env = "REDIS_DEDUP_URL"
+    )]
+    pub redis_dedup_url: Option<String>,
+
+    #[arg(
+        long,
+        help = "How long written datums are remembered in redis. Example: 5m",
+        default_value = "5m",
+        env = "REDIS_DEDUP_TTL",
+        value_parser = humantime::parse_duration,
+    )]
+    pub redis_dedup_ttl: Duration,
+
+    #[arg(
+        long,
+        help = "Fold datums with matching dimensions in the same time window into 1 row, when all of their measurements are statistic_sets or histograms. Example: 10s",
+        env = "PRE_AGGREGATION_WINDOW",
+        value_parser = humantime::parse_duration,
+    )]
+    pub pre_aggregation_window: Option<Duration>,
+
+    #[arg(
+        long,
+        help = "Merge the least populated histogram buckets into the next bucket up until at most this many are left. Keeps sparse histograms small in postgres",
+        env = "HISTOGRAM_MAX_BUCKETS"
+    )]
+    pub histogram_max_buckets: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Warn about numeric measurements far outside what their column has seen so far",
+        env = "DETECT_ANOMALIES"
+    )]
+    pub detect_anomalies: bool,
+
+    #[arg(
+        long,
+        help = "How many standard deviations from the mean make a measurement anomalous",
+        default_value = "10",
+        env = "ANOMALY_SIGMA_THRESHOLD"
+    )]
+    pub anomaly_sigma_threshold: f64,
+
+    #[arg(
+        long,
+        help = "Drop anomalous datums instead of only warning about them",
+        env = "DROP_ANOMALIES"
+    )]
+    pub drop_anomalies: bool,
+
+    #[arg(
+        long,
+        help = "Drop a metric's datums past this many per second, so 1 noisy client can't crowd out the rest. 0 turns this off",
+        default_value = "10000",
+        env = "MAX_DATUMS_PER_METRIC_PER_SECOND"
+    )]
+    pub max_datums_per_metric_per_second: u64,
+
+    #[arg(
+        long,
+        help = "Drop datums of metric names the rate limiter isn't tracking past this many names per second, after a burst of --rate-limiter-tracked-metrics, so 1 client making up names can't create tables without end. 0 turns this off",
+        default_value = "100",
+        env = "MAX_NEW_METRICS_PER_SECOND"
+    )]
+    pub max_new_metrics_per_second: u64,
+
+    #[arg(
+        long,
+        help = "How many metrics' rates are remembered for --max-datums-per-metric-per-second. The least recently seen are forgotten, and are new again when they come back",
+        default_value = "10000",
+        env = "RATE_LIMITER_TRACKED_METRICS"
+    )]
+    pub rate_limiter_tracked_metrics: usize,
+
+    #[arg(
+        long,
+        help = "Save batches here when postgres can't be reached, and replay them once it's back",
+        env = "FALLBACK_DIRECTORY"
+    )]
+    pub fallback_directory: Option<String>,
+
+    #[arg(
+        long,
+        help = "When the fallback directory grows past this many bytes, the oldest files are deleted",
+        default_value = "1073741824",
+        env = "FALLBACK_MAX_BYTES"
+    )]
+    pub fallback_max_bytes: u64,
+
+    #[arg(
+        long,
+        help = "How many permanently failed batches can wait to be recorded before more are lost unrecorded",
+        default_value = "64",
+        env = "DEAD_LETTER_CAPACITY"
+    )]
+    pub dead_letter_capacity: usize,
+
+    #[arg(
+        long,
+        help = "Save permanently failed batches here as ndjson, bounded by fallback-max-bytes. Otherwise they are only logged",
+        env = "DEAD_LETTER_DIRECTORY"
+    )]
+    pub dead_letter_directory: Option<String>,
+
+    #[arg(
+        long,
+        help = "Log every batch to this file before writing it to postgres, and replay what never made it on startup. Rotated on SIGUSR1, and rotated files are removed once all of their batches are committed",
+        env = "WAL_PATH"
+    )]
+    pub wal_path: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Distinct values a string dimension column may hold before new values are written as __cardinality_exceeded__",
+        default_value = "100000",
+        env = "MAX_DIMENSION_CARDINALITY"
+    )]
+    pub max_dimension_cardinality: usize,
+
+    #[arg(
+        long,
+        help = "Route datums to a database per value of this dimension, like tenant_id. Datums without it go to --connection-string",
+        env = "TENANT_DIMENSION"
+    )]
+    pub tenant_dimension: Option<String>,
+
+    #[arg(
+        long,
+        help = "Toml file of tenant = \"postgres connection string\", 1 per tenant, for --tenant-dimension",
+        env = "TENANT_REGISTRY"
+    )]
+    pub tenant_registry: Option<PathBuf>,
+
+    #[arg(
+        long,
+        help = "Give tenants missing from the registry their own schema in the --connection-string database, rather than dropping their datums",
+        env = "PROVISION_UNKNOWN_TENANTS"
+    )]
+    pub provision_unknown_tenants: bool,
+
+    #[arg(
+        long,
+        help = "Drop datums of new tenants once this many have senders, since each has its own connection pool, and its own schema with --provision-unknown-tenants. 0 allows any number",
+        default_value = "1000",
+        env = "MAX_TENANTS"
+    )]
+    pub max_tenants: usize,
+
+    #[arg(
+        long,
+        help = "Drop exact duplicate datums within a batch, so client retries don't double count",
+        default_value = "true",
+        action = clap::ArgAction::Set,
+        env = "DEDUP_WITHIN_BATCH"
+    )]
+    pub dedup_within_batch: bool,
+
+    #[arg(
+        long,
+        help = "Also drop datums identical to one received this recently, like a client resending a whole batch. 0s turns this off. Example: 5m",
+        default_value = "5m",
+        env = "DEDUP_WINDOW",
+        value_parser = humantime::parse_duration,
+    )]
+    pub dedup_window: Duration,
+
+    #[arg(
+        long,
+        help = "Most datums remembered for --dedup-window. The oldest are forgotten first",
+        default_value = "1000000",
+        env = "MAX_DEDUP_ENTRIES"
+    )]
+    pub max_dedup_entries: usize,
+
+    #[arg(
+        long,
+        help = "Batches collect fewer datums while the p95 postgres COPY takes longer than this, and more while it's well under",
+        default_value = "2s",
+        env = "TARGET_COPY_DURATION",
+        value_parser = humantime::parse_duration,
+    )]
+    pub target_copy_duration: Duration,
+
+    #[arg(
+        long,
+        help = "Write a batch once its first datum has waited this long, rather than collecting for up to 5s. For low-throughput deployments that want metrics in postgres sooner. Example: 500ms",
+        default_value = "5s",
+        env = "MAX_BATCH_AGE",
+        value_parser = humantime::parse_duration,
+    )]
+    pub max_batch_age: Duration,
+
+    #[arg(
+        long,
+        help = "Hold a table's rows across batches until there are this many, so tables that only get a few rows a batch are written with fewer, bigger COPYs. Held rows are written at shutdown, but lost if goodmetricsd crashes, so there's no holding with --wal-path. 0 writes every batch's rows right away",
+        default_value = "100",
+        env = "MIN_COPY_ROWS"
+    )]
+    pub min_copy_rows: usize,
+
+    #[arg(
+        long,
+        help = "Write a table's held rows once the oldest has waited this long, even if there are fewer than min-copy-rows. Example: 30s",
+        default_value = "30s",
+        env = "MAX_BUFFER_AGE",
+        value_parser = humantime::parse_duration,
+    )]
+    pub max_buffer_age: Duration,
+
+    #[arg(
+        long,
+        help = "Give up on a table's COPY after this long, so 1 slow table doesn't hold up the rest of the batch",
+        default_value = "30s",
+        env = "PER_TABLE_WRITE_TIMEOUT",
+        value_parser = humantime::parse_duration,
+    )]
+    pub per_table_write_timeout: Duration,
+
+    #[arg(
+        long,
+        help = "Label goodmetrics_copy_duration_seconds \"all\" instead of by metric, for deployments with too many metrics to keep a histogram each",
+        env = "DISABLE_PER_METRIC_LATENCY"
+    )]
+    pub disable_per_metric_latency: bool,
+
+    #[arg(
+        long,
+        help = "Tables to COPY into at once. Defaults to the connection pool size; more than that just waits on the pool",
+        env = "MAX_CONCURRENT_COPIES"
+    )]
+    pub max_concurrent_copies: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Halve postgres write concurrency while more than this many active connections wait on locks or IO",
+        env = "MAX_CONTENDED_CONNECTIONS"
+    )]
+    pub max_contended_connections: Option<usize>,
+
+    #[arg(
+        long,
+        help = "Consecutive postgres connection failures before sends stop trying to connect for a while",
+        default_value = "5",
+        env = "CIRCUIT_BREAKER_FAILURES"
+    )]
+    pub circuit_breaker_failures: u32,
+
+    #[arg(
+        long,
+        help = "How long sends stop trying to connect to postgres once the circuit breaker opens",
+        default_value = "30s",
+        env = "CIRCUIT_BREAKER_BACKOFF",
+        value_parser = humantime::parse_duration,
+    )]
+    pub circuit_breaker_backoff: Duration,
+
     #[arg(
         long,
         help = "Send dumbed down metrics via otel metrics format. Example: https://my.opentelemetry:4317",
@@ -99,11 +561,304 @@ pub struct Options {
         env = "OTLP_INSECURE"
     )]
     pub otlp_insecure: bool,
+
+    #[command(flatten)]
+    pub kafka: KafkaOptions,
+
+    #[command(flatten)]
+    pub statsd: StatsdOptions,
+
+    #[command(flatten)]
+    pub influx: InfluxOptions,
+
+    #[command(flatten)]
+    pub graphite: GraphiteOptions,
+
+    #[command(flatten)]
+    pub alerting: EmailAlertConfig,
+
+    // Only from the command line, after the flags
+    #[command(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
+}
+
+/// Without a subcommand, goodmetricsd serves
+#[derive(Debug, clap::Subcommand, Clone)]
+pub enum Command {
+    /// Write a file of newline-delimited json datums, like a fallback directory's, to
+    /// --connection-string and exit. The file is renamed with a .replayed suffix once it's in
+    Replay {
+        #[arg(long, help = "The ndjson file to replay")]
+        file: PathBuf,
+    },
+}
+
+/// Emails about postgres write errors. Enabled by setting the smtp server, which needs a from address.
+#[derive(Debug, Deserialize, clap::Args, Clone)]
+pub struct EmailAlertConfig {
+    #[arg(
+        long = "alert-smtp-server",
+        help = "Email alerts go through this smtp relay, over tls. Example: smtp.example.com",
+        requires = "from_address",
+        env = "ALERT_SMTP_SERVER"
+    )]
+    pub smtp_server: Option<String>,
+
+    #[arg(
+        long = "alert-from-address",
+        help = "Example: goodmetrics <goodmetrics@example.com>",
+        env = "ALERT_FROM_ADDRESS"
+    )]
+    pub from_address: Option<String>,
+
+    #[arg(
+        long = "alert-to-addresses",
+        help = "Comma separated. Example: oncall@example.com,metrics@example.com",
+        value_delimiter = ',',
+        env = "ALERT_TO_ADDRESSES"
+    )]
+    pub to_addresses: Vec<String>,
+
+    #[arg(
+        long = "alert-error-rate-threshold",
+        help = "Alert when more than this fraction of postgres writes fail over 5 minutes. 0.01 is 1%",
+        default_value = "0.01",
+        env = "ALERT_ERROR_RATE_THRESHOLD"
+    )]
+    pub error_rate_threshold: f64,
+
+    #[arg(
+        long = "alert-interval",
+        help = "Send at most one alert email per this long",
+        default_value = "15m",
+        env = "ALERT_INTERVAL",
+        value_parser = humantime::parse_duration,
+    )]
+    pub alert_interval: Duration,
+}
+
+/// Publishing to kafka is enabled by setting the bootstrap servers.
+#[derive(Debug, Deserialize, clap::Args, Clone)]
+pub struct KafkaOptions {
+    #[arg(
+        long = "kafka-bootstrap-servers",
+        help = "Publish metrics to kafka, one topic per metric. Example: kafka-1:9092,kafka-2:9092",
+        env = "KAFKA_BOOTSTRAP_SERVERS"
+    )]
+    pub bootstrap_servers: Option<String>,
+
+    #[arg(
+        long = "kafka-topic-prefix",
+        help = "Metrics are published to {prefix}.{metric}",
+        default_value = "goodmetrics",
+        env = "KAFKA_TOPIC_PREFIX"
+    )]
+    pub topic_prefix: String,
+
+    #[arg(
+        long = "kafka-acks",
+        help = "How many replicas must acknowledge a publish: 0, 1 or all",
+        default_value = "all",
+        env = "KAFKA_ACKS"
+    )]
+    pub acks: String,
+
+    #[arg(
+        long = "kafka-compression",
+        help = "none, gzip, snappy, lz4 or zstd",
+        default_value = "lz4",
+        env = "KAFKA_COMPRESSION"
+    )]
+    pub compression: String,
+
+    #[arg(
+        long = "kafka-schema-registry-url",
+        help = "Register the goodmetrics proto with this Confluent Schema Registry and frame records for it. Example: http://schema-registry:8081",
+        env = "KAFKA_SCHEMA_REGISTRY_URL"
+    )]
+    pub schema_registry_url: Option<String>,
+}
+
+/// Taking statsd over udp is enabled by setting the listen address.
+#[derive(Debug, Deserialize, clap::Args, Clone)]
+pub struct StatsdOptions {
+    #[arg(
+        id = "statsd_listen_socket_address",
+        long = "statsd-listen-socket-address",
+        help = "Accept statsd and DogStatsD packets over udp. Example: 0.0.0.0:8125",
+        env = "STATSD_LISTEN_SOCKET_ADDRESS"
+    )]
+    pub listen_socket_address: Option<String>,
+
+    #[arg(
+        long = "statsd-flush-interval",
+        help = "Statsd samples are aggregated for this long before they are sent on",
+        default_value = "10s",
+        env = "STATSD_FLUSH_INTERVAL",
+        value_parser = humantime::parse_duration,
+    )]
+    pub flush_interval: Duration,
+}
+
+/// Taking InfluxDB line protocol is enabled by setting either listen address.
+#[derive(Debug, Deserialize, clap::Args, Clone)]
+pub struct InfluxOptions {
+    #[arg(
+        long = "influx-udp-listen-socket-address",
+        help = "Accept InfluxDB line protocol over udp. Example: 0.0.0.0:4444",
+        env = "INFLUX_UDP_LISTEN_SOCKET_ADDRESS"
+    )]
+    pub udp_listen_socket_address: Option<String>,
+
+    #[arg(
+        long = "influx-http-listen-socket-address",
+        help = "Accept InfluxDB line protocol POSTed to /write. Example: 0.0.0.0:8086",
+        env = "INFLUX_HTTP_LISTEN_SOCKET_ADDRESS"
+    )]
+    pub http_listen_socket_address: Option<String>,
+}
+
+/// Taking graphite is enabled by setting either listen address.
+#[derive(Debug, Deserialize, clap::Args, Clone)]
+pub struct GraphiteOptions {
+    #[arg(
+        id = "graphite_listen_socket_address",
+        long = "graphite-listen-socket-address",
+        help = "Accept graphite's plaintext protocol over tcp. Example: 0.0.0.0:2003",
+        env = "GRAPHITE_LISTEN_SOCKET_ADDRESS"
+    )]
+    pub listen_socket_address: Option<String>,
+
+    #[arg(
+        long = "graphite-pickle-listen-socket-address",
+        help = "Accept graphite's pickle protocol over tcp. Example: 0.0.0.0:2004",
+        env = "GRAPHITE_PICKLE_LISTEN_SOCKET_ADDRESS"
+    )]
+    pub pickle_listen_socket_address: Option<String>,
+}
+
+/// Guards new tables' time column against garbage timestamps from client bugs, and optionally
+/// against the same row being written twice.
+/// Only applied when a table is created - existing tables are left alone.
+#[derive(Debug, Deserialize, clap::Args, Clone)]
+pub struct TimeConstraint {
+    #[arg(
+        long = "time-not-null",
+        help = "Reject rows without a time in new metrics tables",
+        env = "TIMESCALE_TIME_NOT_NULL"
+    )]
+    pub not_null: bool,
+
+    #[arg(
+        long = "time-min-year",
+        help = "Reject rows in new metrics tables from before January 1st of this year. Example: 2000",
+        env = "TIMESCALE_TIME_MIN_YEAR"
+    )]
+    pub min_year: Option<i32>,
+
+    #[arg(
+        long = "time-max-year",
+        help = "Reject rows in new metrics tables from after January 1st of this year. Example: 2100",
+        env = "TIMESCALE_TIME_MAX_YEAR"
+    )]
+    pub max_year: Option<i32>,
+
+    #[arg(
+        long = "time-primary-key",
+        help = "Make time the primary key of new metrics tables, so a row repeated at the same time is rejected. Only for plain postgres tables: needs --timescale-mode=false",
+        conflicts_with = "unique_index",
+        env = "TIMESCALE_TIME_PRIMARY_KEY"
+    )]
+    pub primary_key: bool,
+
+    #[arg(
+        long = "time-unique-index",
+        help = "Give new metrics tables a unique index on time, so a row repeated at the same time is rejected. Works with hypertables",
+        env = "TIMESCALE_TIME_UNIQUE_INDEX"
+    )]
+    pub unique_index: bool,
+}
+
+/// New tables become TimescaleDB hypertables, partitioned on time, when this is enabled.
+/// If the extension turns out to be missing, tables are left as plain postgres tables.
+#[derive(Debug, Deserialize, clap::Args, Clone)]
+pub struct TimescaleMode {
+    #[arg(
+        long = "timescale-mode",
+        help = "Create new metrics tables as TimescaleDB hypertables, with retention and compression policies",
+        default_value = "true",
+        action = clap::ArgAction::Set,
+        env = "TIMESCALE_MODE"
+    )]
+    pub enabled: bool,
+
+    #[arg(
+        long = "timescale-chunk-time-interval",
+        help = "Time range covered by each chunk of a new hypertable. Example: 4h",
+        default_value = "4h",
+        env = "TIMESCALE_CHUNK_TIME_INTERVAL",
+        value_parser = humantime::parse_duration,
+    )]
+    pub chunk_time_interval: Duration,
+}
+
+#[derive(Debug, Deserialize, clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CopyFormat {
+    Csv,
+    Text,
+}
+
+#[derive(Debug, Deserialize, clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TimestampPrecision {
+    Microseconds,
+    NanosecondsInSeparateColumn,
+}
+
+#[derive(Debug, Deserialize, clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DimensionTypeConflict {
+    /// The type of the first datum with the dimension
+    FirstWins,
+    /// The type of the last datum with the dimension
+    LastWins,
+    /// text, with numbers and booleans written as their text
+    TextFallback,
+}
+
+#[derive(Debug, Deserialize, clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IdentifierMode {
+    /// Lowercase letters, digits and underscores, with everything else replaced by _
+    LowercaseUnquoted,
+    /// The name as it was sent, double quoted
+    PreserveCaseQuoted,
+}
+
+#[derive(Debug, Deserialize, clap::ValueEnum, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+// The prefix goes into table names unquoted, so it can't be allowed to carry sql
+fn parse_table_prefix(prefix: &str) -> Result<String, String> {
+    if prefix
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '_')
+    {
+        Ok(prefix.to_string())
+    } else {
+        Err("only letters, digits and underscores are allowed".to_string())
+    }
 }
 
 pub fn get_args() -> Options {
-    let command_line_args = Options::parse();
-    log::info!("Args: {:?}", command_line_args);
+    let command_line_args = Options::parse_from(with_config_file(std::env::args_os().collect()));
+    tracing::info!("Args: {:?}", command_line_args);
 
     command_line_args
 }
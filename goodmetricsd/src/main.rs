@@ -1,26 +1,51 @@
+use communication::proto::goodmetrics::admin::admin_server::AdminServer as AdminService;
 use communication::proto::goodmetrics::metrics_server::MetricsServer;
-use config::options::Options;
+use communication::proto::opentelemetry::collector::metrics::v1::metrics_service_server::MetricsServiceServer;
+use config::options::{Command, KafkaOptions, LogFormat, Options};
+use sink::kafka_sink::KafkaSender;
 use sink::metricssendqueue::{MetricsReceiveQueue, MetricsSendQueue};
+use sink::multitenant_sink::MultiTenantRouter;
 use sink::opentelemetry_sink::OtelSender;
 use sink::postgres_sink::PostgresSender;
-use sink::sink_error::SinkError;
+use sink::sink_error::{SinkError, StringError};
 use tonic::transport::{Identity, Server, ServerTlsConfig};
 
+use shutdown::{shutdown_token, ShutdownToken};
 use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
 use std::{cmp::min, net::SocketAddr};
 use tokio::net::TcpListener;
+use tokio::signal::unix::{signal, SignalKind};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
 
 use crate::config::options::get_args;
+use crate::servers::admin::AdminServer;
+use crate::servers::batch_size_histograms::BatchSizeHistograms;
 use crate::servers::goodmetrics::GoodmetricsServer;
+use crate::servers::graphite_server::{serve_graphite_pickle, serve_graphite_plaintext};
+use crate::servers::health::{serve_health, Readiness};
+use crate::servers::influxdb_server::{serve_influx_http, serve_influx_udp};
+use crate::servers::otlp_server::OtlpServer;
+use crate::servers::statsd_server::serve_statsd;
 
 mod config;
+mod fnv;
 mod postgres_things;
+mod proto;
+mod self_metrics;
 mod servers;
+mod shutdown;
 mod sink;
 
+// Interceptors have to reject requests with tonic's Status, big as it is
+#[allow(clippy::result_large_err)]
 async fn serve(
     args: Options,
     send_queue: MetricsSendQueue,
+    batch_sizes: Arc<BatchSizeHistograms>,
+    readiness: Readiness,
+    shutdown: ShutdownToken,
 ) -> Result<(), Box<dyn std::error::Error>> {
     let address: std::net::SocketAddr = args.listen_socket_address.parse()?;
     let socket = socket2::Socket::new(
@@ -42,7 +67,12 @@ async fn serve(
     let incoming = tokio_stream::wrappers::TcpListenerStream::new(listener);
 
     let one_server_thread = GoodmetricsServer {
+        metrics_sink: send_queue.clone(),
+        batch_sizes: batch_sizes.clone(),
+    };
+    let otlp_server = OtlpServer {
         metrics_sink: send_queue,
+        batch_sizes,
     };
 
     let identity = get_identity(&args).await?;
@@ -54,19 +84,25 @@ async fn serve(
         .filter(|k| !k.is_empty())
         .collect();
 
-    let mut server_builder =
-        Server::builder().tls_config(ServerTlsConfig::new().identity(identity))?;
+    let keepalive_interval =
+        (!args.grpc_keepalive_interval.is_zero()).then_some(args.grpc_keepalive_interval);
+    let mut server_builder = Server::builder()
+        .tcp_keepalive(keepalive_interval)
+        .http2_keepalive_interval(keepalive_interval)
+        .http2_keepalive_timeout(Some(args.grpc_keepalive_timeout))
+        .tls_config(ServerTlsConfig::new().identity(identity))?;
 
     let service_router = if keys.is_empty() {
-        log::info!("configuring unauthorized metrics server");
-        server_builder.add_service(MetricsServer::new(one_server_thread))
+        tracing::info!("configuring unauthorized metrics server");
+        server_builder
+            .add_service(MetricsServer::new(one_server_thread))
+            .add_service(MetricsServiceServer::new(otlp_server))
     } else {
-        log::info!(
+        tracing::info!(
             "configuring authorized metrics server with {} access keys",
             keys.len()
         );
-        server_builder.add_service(MetricsServer::with_interceptor(
-            one_server_thread,
+        let authorize =
             move |request: tonic::Request<()>| match request.metadata().get("authorization") {
                 Some(authorization_header) => match authorization_header.to_str() {
                     Ok(token) => {
@@ -85,17 +121,56 @@ async fn serve(
                 None => Err(tonic::Status::unauthenticated(
                     "authorization token is required",
                 )),
-            },
-        ))
+            };
+        server_builder
+            .add_service(MetricsServer::with_interceptor(
+                one_server_thread,
+                authorize.clone(),
+            ))
+            .add_service(MetricsServiceServer::with_interceptor(
+                otlp_server,
+                authorize,
+            ))
+    };
+    let admin_server = AdminServer {
+        readiness,
+        schema_name: args.schema_name.clone(),
+        identifier_mode: args.identifier_mode,
+    };
+    let service_router = match args.admin_token.clone() {
+        Some(admin_token) => {
+            tracing::info!("configuring authorized admin server");
+            service_router.add_service(AdminService::with_interceptor(
+                admin_server,
+                move |request: tonic::Request<()>| match request
+                    .metadata()
+                    .get("authorization")
+                    .map(|header| header.to_str())
+                {
+                    Some(Ok(token)) if token == admin_token => Ok(request),
+                    Some(Ok(_)) => {
+                        Err(tonic::Status::unauthenticated("admin token is not allowed"))
+                    }
+                    Some(Err(e)) => Err(tonic::Status::invalid_argument(format!(
+                        "admin token is not well-formed: {e:?}"
+                    ))),
+                    None => Err(tonic::Status::unauthenticated("admin token is required")),
+                },
+            ))
+        }
+        None => {
+            tracing::info!("configuring unauthorized admin server");
+            service_router.add_service(AdminService::new(admin_server))
+        }
     };
     let reflection = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(communication::proto::goodmetrics::DESCRIPTOR)
         .build()?;
     let service_router = service_router.add_service(reflection);
 
-    log::info!("entering serve function");
+    tracing::info!("entering serve function");
     service_router
-        .serve_with_incoming(incoming)
+        .serve_with_incoming_shutdown(incoming, shutdown.wait())
         .await
         .expect("service comples");
 
@@ -121,20 +196,48 @@ async fn get_identity(options: &Options) -> Result<Identity, Box<dyn std::error:
     Ok(identity)
 }
 
+fn init_logging(args: &Options) {
+    // RUST_LOG still wins over --log-level, like it did with env_logger
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(&args.log_level));
+    // The console layer gets its own unfiltered view of tokio's instrumentation
+    let console_layer = args.tokio_console.then(console_subscriber::spawn);
+    let registry = tracing_subscriber::registry().with(console_layer);
+
+    // The log crate's records are forwarded into tracing by init()
+    match args.log_format {
+        LogFormat::Text => registry
+            .with(tracing_subscriber::fmt::layer().with_filter(filter))
+            .init(),
+        LogFormat::Json => registry
+            .with(
+                tracing_subscriber::fmt::layer()
+                    .json()
+                    .flatten_event(true)
+                    .with_current_span(true)
+                    .with_span_list(false)
+                    .with_filter(filter),
+            )
+            .init(),
+    }
+}
+
 fn main() {
     let args = get_args();
-    if args.tokio_console {
-        console_subscriber::init();
-    }
+    init_logging(&args);
 
-    env_logger::Builder::from_env(
-        env_logger::Env::default()
-            .default_filter_or(&args.log_level)
-            .default_write_style_or(&args.log_level),
-    )
-    .init();
+    tracing::info!("args: {:?}", args);
 
-    log::info!("args: {:?}", args);
+    if let Some(Command::Replay { file }) = args.command.clone() {
+        match sink_runtime(1).block_on(replay(args, file)) {
+            Ok(rows) => tracing::info!(rows, "replay finished"),
+            Err(e) => {
+                tracing::error!("replay failed: {e:?}");
+                std::process::exit(1)
+            }
+        }
+        return;
+    }
 
     tokio::runtime::Builder::new_current_thread()
         .enable_all()
@@ -143,17 +246,40 @@ fn main() {
         .block_on(run_server(args));
 }
 
+async fn replay(mut args: Options, file: PathBuf) -> Result<u64, SinkError> {
+    let Some(connection_string) = args.connection_string.clone() else {
+        return Err(SinkError::StringError(StringError {
+            message: "replay needs a --connection-string".to_string(),
+        }));
+    };
+    // A running server may own the write ahead log; replaying doesn't need one
+    args.wal_path = None;
+    let (_send_queue, receive_queue) = MetricsSendQueue::new();
+    let sender = PostgresSender::new_connection(&connection_string, receive_queue, args).await?;
+    sender.replay_from_file(file).await
+}
+
 async fn run_server(args: Options) {
     let mut handlers = Vec::new();
     let args_shared = args;
     let (send_queue, receive_queue) = MetricsSendQueue::new();
+    // The primary receiver counts queue depth and answers flushes, so some consumer has to
+    // read it: postgres when there is one, otherwise whichever other sink comes first.
+    let mut primary_queue = Some(receive_queue);
+    let batch_sizes = Arc::new(BatchSizeHistograms::default());
+    let (shutdown_trigger, shutdown) = shutdown_token();
+
+    let readiness = Readiness::new(args_shared.connection_string.is_some());
 
     for i in 0..min(args_shared.max_threads, num_cpus::get()) {
         let threadlocal_args = args_shared.clone();
         let thread_send_queue = send_queue.clone();
+        let thread_batch_sizes = batch_sizes.clone();
+        let thread_readiness = readiness.clone();
+        let thread_shutdown = shutdown.clone();
 
         let h = std::thread::spawn(move || {
-            log::info!(
+            tracing::info!(
                 "starting server thread {} listening on {}",
                 i,
                 &threadlocal_args.listen_socket_address
@@ -163,25 +289,174 @@ async fn run_server(args: Options) {
                 .enable_all()
                 .build()
                 .expect("runtime can be made")
-                .block_on(serve(threadlocal_args, thread_send_queue))
+                .block_on(serve(
+                    threadlocal_args,
+                    thread_send_queue,
+                    thread_batch_sizes,
+                    thread_readiness,
+                    thread_shutdown,
+                ))
                 .expect("server completes");
         });
         handlers.push(h);
     }
 
-    if let Some(connection_string_arg) = &args_shared.connection_string {
+    if let Some(statsd_address_arg) = &args_shared.statsd.listen_socket_address {
+        match statsd_address_arg.parse::<SocketAddr>() {
+            Ok(statsd_address) => {
+                let statsd_send_queue = send_queue.clone();
+                let flush_interval = args_shared.statsd.flush_interval;
+                let statsd_shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_statsd(
+                        statsd_address,
+                        flush_interval,
+                        statsd_send_queue,
+                        statsd_shutdown,
+                    )
+                    .await
+                    {
+                        tracing::error!("statsd server failed: {e:?}");
+                    }
+                });
+            }
+            Err(e) => tracing::error!("not serving statsd, bad address: {e:?}"),
+        }
+    }
+
+    if let Some(influx_address_arg) = &args_shared.influx.udp_listen_socket_address {
+        match influx_address_arg.parse::<SocketAddr>() {
+            Ok(influx_address) => {
+                let influx_send_queue = send_queue.clone();
+                let influx_shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    if let Err(e) =
+                        serve_influx_udp(influx_address, influx_send_queue, influx_shutdown).await
+                    {
+                        tracing::error!("influx udp server failed: {e:?}");
+                    }
+                });
+            }
+            Err(e) => tracing::error!("not serving influx over udp, bad address: {e:?}"),
+        }
+    }
+    if let Some(influx_address_arg) = &args_shared.influx.http_listen_socket_address {
+        match influx_address_arg.parse::<SocketAddr>() {
+            Ok(influx_address) => {
+                let influx_send_queue = send_queue.clone();
+                let influx_shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    if let Err(e) =
+                        serve_influx_http(influx_address, influx_send_queue, influx_shutdown).await
+                    {
+                        tracing::error!("influx http server failed: {e:?}");
+                    }
+                });
+            }
+            Err(e) => tracing::error!("not serving influx over http, bad address: {e:?}"),
+        }
+    }
+    if let Some(graphite_address_arg) = &args_shared.graphite.listen_socket_address {
+        match graphite_address_arg.parse::<SocketAddr>() {
+            Ok(graphite_address) => {
+                let graphite_send_queue = send_queue.clone();
+                let graphite_shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_graphite_plaintext(
+                        graphite_address,
+                        graphite_send_queue,
+                        graphite_shutdown,
+                    )
+                    .await
+                    {
+                        tracing::error!("graphite plaintext server failed: {e:?}");
+                    }
+                });
+            }
+            Err(e) => tracing::error!("not serving graphite plaintext, bad address: {e:?}"),
+        }
+    }
+    if let Some(graphite_address_arg) = &args_shared.graphite.pickle_listen_socket_address {
+        match graphite_address_arg.parse::<SocketAddr>() {
+            Ok(graphite_address) => {
+                let graphite_send_queue = send_queue.clone();
+                let graphite_shutdown = shutdown.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_graphite_pickle(
+                        graphite_address,
+                        graphite_send_queue,
+                        graphite_shutdown,
+                    )
+                    .await
+                    {
+                        tracing::error!("graphite pickle server failed: {e:?}");
+                    }
+                });
+            }
+            Err(e) => tracing::error!("not serving graphite pickle, bad address: {e:?}"),
+        }
+    }
+
+    // Probes failing is no reason to stop taking metrics, so this only logs
+    match args_shared
+        .health_listen_socket_address
+        .parse::<SocketAddr>()
+    {
+        Ok(health_address) => {
+            let health_readiness = readiness.clone();
+            let health_send_queue = send_queue.clone();
+            let health_shutdown = shutdown.clone();
+            tokio::spawn(async move {
+                if let Err(e) = serve_health(
+                    health_address,
+                    health_readiness,
+                    health_send_queue,
+                    batch_sizes,
+                    health_shutdown,
+                )
+                .await
+                {
+                    tracing::error!("health server failed: {e:?}");
+                }
+            });
+        }
+        Err(e) => tracing::error!("not serving health, bad address: {e:?}"),
+    }
+
+    if let Some(tenant_dimension) = args_shared.tenant_dimension.clone() {
+        let receive_queue = primary_queue.take().expect("primary queue is unclaimed");
+        let threadlocal_args = args_shared.clone();
+        let postgres_readiness = readiness.clone();
+        let postgres_shutdown = shutdown.clone();
+        let sink_runtime_threads = args_shared.sink_runtime_threads;
+        let bg_handle = std::thread::spawn(move || {
+            sink_runtime(sink_runtime_threads)
+                .block_on(consume_multitenant(
+                    tenant_dimension,
+                    receive_queue,
+                    threadlocal_args,
+                    postgres_readiness,
+                    postgres_shutdown,
+                ))
+                .expect("multi-tenant router completes");
+        });
+        handlers.push(bg_handle);
+    } else if let Some(connection_string_arg) = &args_shared.connection_string {
+        let receive_queue = primary_queue.take().expect("primary queue is unclaimed");
         let connection_string = connection_string_arg.clone();
         let threadlocal_args = args_shared.clone();
+        let postgres_readiness = readiness.clone();
+        let postgres_shutdown = shutdown.clone();
+        let sink_runtime_threads = args_shared.sink_runtime_threads;
         let bg_handle = std::thread::spawn(move || {
             // Consume stuff on a background task
-            tokio::runtime::Builder::new_current_thread()
-                .enable_all()
-                .build()
-                .expect("runtime can be made")
+            sink_runtime(sink_runtime_threads)
                 .block_on(consume_postgres(
                     connection_string,
                     receive_queue,
                     threadlocal_args,
+                    postgres_readiness,
+                    postgres_shutdown,
                 ))
                 .expect("postgres sender completes");
         });
@@ -190,9 +465,9 @@ async fn run_server(args: Options) {
 
     let insecure_otlp = args_shared.otlp_insecure;
     if let Some(otlp_remote_arg) = &args_shared.otlp_remote {
-        let cloned_queue = MetricsReceiveQueue {
-            rx: send_queue.tx.subscribe(),
-        };
+        let cloned_queue = primary_queue
+            .take()
+            .unwrap_or_else(|| send_queue.subscribe());
         let otlp_remote = otlp_remote_arg.clone();
         let bg_handle = std::thread::spawn(move || {
             // Consume stuff on a background task
@@ -206,8 +481,76 @@ async fn run_server(args: Options) {
         handlers.push(bg_handle);
     }
 
-    for h in handlers {
-        h.join().expect("all handles join gracefully");
+    if let Some(bootstrap_servers_arg) = &args_shared.kafka.bootstrap_servers {
+        let cloned_queue = primary_queue
+            .take()
+            .unwrap_or_else(|| send_queue.subscribe());
+        let bootstrap_servers = bootstrap_servers_arg.clone();
+        let kafka_options = args_shared.kafka.clone();
+        let bg_handle = std::thread::spawn(move || {
+            // Consume stuff on a background task
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .expect("runtime can be made")
+                .block_on(consume_kafka(
+                    bootstrap_servers,
+                    cloned_queue,
+                    kafka_options,
+                ))
+                .expect("kafka sender completes");
+        });
+        handlers.push(bg_handle);
+    }
+
+    // With no sinks at all, nothing waits on a receiver nobody reads
+    drop(primary_queue);
+
+    let mut all_joined = tokio::task::spawn_blocking(move || {
+        for h in handlers {
+            h.join().expect("all handles join gracefully");
+        }
+    });
+    tokio::select! {
+        _ = wait_for_signal() => {}
+        _ = &mut all_joined => return,
+    }
+
+    // Servers stop accepting and finish their in-flight requests. Once the last of them
+    // lets go of the send queue it closes, and the consumers flush what is left and return.
+    shutdown_trigger.trigger();
+    drop(send_queue);
+    match tokio::time::timeout(args_shared.shutdown_drain_window, all_joined).await {
+        Ok(_) => tracing::info!("shut down cleanly"),
+        Err(_) => {
+            tracing::error!(
+                "still draining after {:?}, exiting anyway",
+                args_shared.shutdown_drain_window
+            );
+            std::process::exit(1)
+        }
+    }
+}
+
+// The postgres sink's own runtime. Its consumer loop stays on the thread that calls block_on,
+// and with more than 1 thread each table's send can run on another.
+fn sink_runtime(threads: usize) -> tokio::runtime::Runtime {
+    let mut builder = if 1 < threads {
+        let mut builder = tokio::runtime::Builder::new_multi_thread();
+        builder.worker_threads(threads);
+        builder
+    } else {
+        tokio::runtime::Builder::new_current_thread()
+    };
+    builder.enable_all().build().expect("runtime can be made")
+}
+
+async fn wait_for_signal() {
+    let mut terminate = signal(SignalKind::terminate()).expect("can listen for SIGTERM");
+    let mut interrupt = signal(SignalKind::interrupt()).expect("can listen for SIGINT");
+    tokio::select! {
+        _ = terminate.recv() => tracing::info!("received SIGTERM, shutting down"),
+        _ = interrupt.recv() => tracing::info!("received SIGINT, shutting down"),
     }
 }
 
@@ -215,19 +558,48 @@ async fn consume_postgres(
     connection_string: String,
     receive_queue: MetricsReceiveQueue,
     options: Options,
+    readiness: Readiness,
+    shutdown: ShutdownToken,
 ) -> Result<(), SinkError> {
     let sender =
         match PostgresSender::new_connection(&connection_string, receive_queue, options).await {
             Ok(sender) => sender,
             Err(e) => {
-                log::error!("failed to start postgres sender: {:?}", e);
+                tracing::error!("failed to start postgres sender: {:?}", e);
                 std::process::exit(3)
             }
         };
-    sender.consume_stuff().await?;
+    readiness.set_postgres(sender.connector());
+    // Once shutdown is triggered the sender drains the queue as it closes
+    sender.consume_stuff(shutdown).await?;
     Ok(())
 }
 
+async fn consume_multitenant(
+    tenant_dimension: String,
+    receive_queue: MetricsReceiveQueue,
+    options: Options,
+    readiness: Readiness,
+    shutdown: ShutdownToken,
+) -> Result<(), SinkError> {
+    let router = match MultiTenantRouter::new(
+        receive_queue,
+        options,
+        tenant_dimension,
+        readiness,
+        shutdown,
+    )
+    .await
+    {
+        Ok(router) => router,
+        Err(e) => {
+            tracing::error!("failed to start multi-tenant router: {:?}", e);
+            std::process::exit(3)
+        }
+    };
+    router.route().await
+}
+
 async fn consume_otel(
     opentelemetry_endpoint: String,
     receive_queue: MetricsReceiveQueue,
@@ -237,10 +609,193 @@ async fn consume_otel(
         match OtelSender::new_connection(&opentelemetry_endpoint, receive_queue, insecure).await {
             Ok(sender) => sender,
             Err(e) => {
-                log::error!("failed to start otel sender: {:?}", e);
+                tracing::error!("failed to start otel sender: {:?}", e);
                 std::process::exit(3)
             }
         };
     sender.consume_stuff().await?;
     Ok(())
 }
+
+async fn consume_kafka(
+    bootstrap_servers: String,
+    receive_queue: MetricsReceiveQueue,
+    options: KafkaOptions,
+) -> Result<(), SinkError> {
+    let sender = match KafkaSender::new_connection(&bootstrap_servers, receive_queue, &options) {
+        Ok(sender) => sender,
+        Err(e) => {
+            tracing::error!("failed to start kafka sender: {:?}", e);
+            std::process::exit(3)
+        }
+    };
+    sender.consume_stuff().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashSet, sync::Arc};
+
+    use clap::Parser;
+    use communication::{get_channel, proto::goodmetrics::Datum, ChannelType};
+    use tokio::runtime::RuntimeFlavor;
+    use tonic_reflection::pb::{
+        server_reflection_client::ServerReflectionClient,
+        server_reflection_request::MessageRequest, server_reflection_response::MessageResponse,
+        ServerReflectionRequest,
+    };
+
+    use super::{serve, sink_runtime};
+    use crate::{
+        config::options::Options,
+        servers::{batch_size_histograms::BatchSizeHistograms, health::Readiness},
+        shutdown::shutdown_token,
+        sink::{metricssendqueue::MetricsSendQueue, MetricsSink},
+    };
+
+    async fn reflect(
+        client: &mut ServerReflectionClient<ChannelType>,
+        request: MessageRequest,
+    ) -> Result<MessageResponse, tonic::Status> {
+        let request = ServerReflectionRequest {
+            host: String::new(),
+            message_request: Some(request),
+        };
+        let response = client
+            .server_reflection_info(tokio_stream::iter([request]))
+            .await?
+            .into_inner()
+            .message()
+            .await?
+            .expect("a response");
+        Ok(response.message_response.expect("a message response"))
+    }
+
+    // What grpcurl does: list the services, then ask where a method is described
+    #[tokio::test]
+    async fn reflection_lists_the_metrics_service() {
+        let address = std::net::TcpListener::bind("127.0.0.1:0")
+            .and_then(|listener| listener.local_addr())
+            .expect("a free port");
+        let args = Options::parse_from([
+            "goodmetricsd",
+            "--listen-socket-address",
+            &address.to_string(),
+            "--connection-string",
+            "host=test",
+        ]);
+        let (send_queue, _receive_queue) = MetricsSendQueue::new();
+        let (trigger, shutdown) = shutdown_token();
+        let server = serve(
+            args,
+            send_queue,
+            Arc::new(BatchSizeHistograms::default()),
+            Readiness::new(false),
+            shutdown,
+        );
+
+        let client = async {
+            let channel = get_channel(&format!("https://{address}"), true)
+                .await
+                .expect("a channel");
+            let mut client = ServerReflectionClient::new(channel);
+
+            let services = match reflect(&mut client, MessageRequest::ListServices(String::new()))
+                .await
+                .expect("services are listed")
+            {
+                MessageResponse::ListServicesResponse(list) => list
+                    .service
+                    .into_iter()
+                    .map(|service| service.name)
+                    .collect::<HashSet<_>>(),
+                other => panic!("not a service list: {other:?}"),
+            };
+            let send_metrics = reflect(
+                &mut client,
+                MessageRequest::FileContainingSymbol("goodmetrics.Metrics.SendMetrics".to_string()),
+            )
+            .await
+            .expect("SendMetrics is found");
+            let unknown = reflect(
+                &mut client,
+                MessageRequest::FileContainingSymbol("goodmetrics.Metrics.Nope".to_string()),
+            )
+            .await;
+            trigger.trigger();
+            (services, send_metrics, unknown)
+        };
+
+        let (served, (services, send_metrics, unknown)) = tokio::join!(server, client);
+        served.expect("server stops cleanly");
+
+        assert!(services.contains("goodmetrics.Metrics"), "{services:?}");
+        match send_metrics {
+            MessageResponse::FileDescriptorResponse(file) => {
+                assert!(!file.file_descriptor_proto.is_empty())
+            }
+            other => panic!("SendMetrics is not described: {other:?}"),
+        }
+        assert_eq!(tonic::Code::NotFound, unknown.unwrap_err().code());
+    }
+
+    #[test]
+    fn one_sink_thread_is_a_current_thread_runtime() {
+        let flavor =
+            sink_runtime(1).block_on(async { tokio::runtime::Handle::current().runtime_flavor() });
+        assert_eq!(RuntimeFlavor::CurrentThread, flavor);
+    }
+
+    // Like goodmetricsd with --sink-runtime-threads: the servers' runtime sends and a
+    // multi-thread sink runtime on its own thread receives.
+    #[test]
+    fn multi_thread_sink_runtime_receives_every_datum() {
+        const BATCHES: u64 = 1000;
+        const BATCH_SIZE: u64 = 10;
+        let (send_queue, mut receive_queue) = MetricsSendQueue::new();
+
+        let receiver = std::thread::spawn(move || {
+            let runtime = sink_runtime(4);
+            assert_eq!(
+                RuntimeFlavor::MultiThread,
+                runtime.handle().runtime_flavor()
+            );
+            runtime.block_on(async move {
+                // Received on a worker thread, like the sinks' spawned senders
+                tokio::spawn(async move {
+                    let mut received = HashSet::new();
+                    while let Some(datums) = receive_queue.recv().await {
+                        received.extend(datums.into_iter().map(|datum| datum.unix_nanos));
+                        receive_queue.batch_done();
+                    }
+                    received
+                })
+                .await
+                .expect("receiver completes")
+            })
+        });
+
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .expect("runtime can be made")
+            .block_on(async move {
+                for batch in 0..BATCHES {
+                    let datums = (0..BATCH_SIZE)
+                        .map(|i| Datum {
+                            metric: "requests".to_string(),
+                            unix_nanos: batch * BATCH_SIZE + i,
+                            ..Default::default()
+                        })
+                        .collect();
+                    send_queue.drain(datums).expect("queue has room");
+                    tokio::task::yield_now().await;
+                }
+                // Dropping the sender ends the receiver once it has everything
+            });
+
+        let received = receiver.join().expect("receiver thread completes");
+        assert_eq!((0..BATCHES * BATCH_SIZE).collect::<HashSet<_>>(), received);
+    }
+}
@@ -0,0 +1,24 @@
+/// Where new postgres connections get their connection string.
+/// Lets credentials that rotate underneath goodmetricsd be picked up without a restart.
+#[tonic::async_trait]
+pub trait ConnectionStringProvider: Send + Sync + 'static {
+    async fn get(&self) -> String;
+}
+
+/// The connection string goodmetricsd was started with, forever.
+pub struct StaticProvider {
+    connection_string: String,
+}
+
+impl StaticProvider {
+    pub fn new(connection_string: String) -> Self {
+        Self { connection_string }
+    }
+}
+
+#[tonic::async_trait]
+impl ConnectionStringProvider for StaticProvider {
+    async fn get(&self) -> String {
+        self.connection_string.clone()
+    }
+}
@@ -0,0 +1,101 @@
+use std::borrow::Cow;
+
+use crate::config::options::CopyFormat;
+
+/// Builds the body of a `copy ... from stdin` in either of postgres' textual formats.
+pub struct CopyRowWriter {
+    format: CopyFormat,
+    buffer: Vec<u8>,
+    start_of_row: bool,
+}
+
+impl CopyRowWriter {
+    pub fn new(format: CopyFormat) -> Self {
+        Self {
+            format,
+            buffer: Vec::with_capacity(4 * (1 << 10)),
+            start_of_row: true,
+        }
+    }
+
+    /// The `with (...)` options for a copy statement that reads what this writes
+    pub fn copy_options(format: CopyFormat) -> &'static str {
+        match format {
+            CopyFormat::Csv => "format csv, header false",
+            CopyFormat::Text => "format text",
+        }
+    }
+
+    pub fn write_field(&mut self, field: &str) {
+        self.delimit();
+        let field = match self.format {
+            CopyFormat::Csv => pg_csv_quote(field),
+            CopyFormat::Text => pg_text_escape(field),
+        };
+        self.buffer.extend_from_slice(field.as_bytes());
+    }
+
+    pub fn write_null(&mut self) {
+        self.delimit();
+        match self.format {
+            // An unquoted empty field is null in postgres csv
+            CopyFormat::Csv => {}
+            CopyFormat::Text => self.buffer.extend_from_slice(b"\\N"),
+        }
+    }
+
+    pub fn end_record(&mut self) {
+        self.buffer.push(b'\n');
+        self.start_of_row = true;
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    fn delimit(&mut self) {
+        if !self.start_of_row {
+            self.buffer.push(match self.format {
+                CopyFormat::Csv => b',',
+                CopyFormat::Text => b'\t',
+            });
+        }
+        self.start_of_row = false;
+    }
+}
+
+/// Quotes a value for `copy ... with (format csv)` when it needs it. An empty string has to be
+/// quoted too, since postgres reads an unquoted empty field as null; so does `\.`, which would
+/// otherwise end the data.
+pub fn pg_csv_quote(s: &str) -> Cow<'_, str> {
+    if !s.is_empty() && s != "\\." && !s.contains([',', '"', '\n', '\r']) {
+        return Cow::Borrowed(s);
+    }
+    Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+}
+
+/// Escapes a value for `copy ... with (format text)`. Only the delimiter, row separators
+/// and backslash are special there; everything else, including any unicode, passes through.
+/// Most fields have nothing to escape, and those are borrowed rather than copied.
+pub fn pg_text_escape(s: &str) -> Cow<'_, str> {
+    if !s
+        .chars()
+        .any(|c| matches!(c, '\\' | '\t' | '\n' | '\r' | '\u{8}' | '\u{c}' | '\u{b}'))
+    {
+        return Cow::Borrowed(s);
+    }
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\u{8}' => escaped.push_str("\\b"),
+            '\u{c}' => escaped.push_str("\\f"),
+            '\u{b}' => escaped.push_str("\\v"),
+            _ => escaped.push(c),
+        }
+    }
+    Cow::Owned(escaped)
+}
@@ -1,22 +1,49 @@
-use std::time::Duration;
+use std::{collections::BTreeMap, hash::Hasher, time::Duration};
 
 use lazy_static::lazy_static;
 use regex::Regex;
-use tokio_postgres::Client;
+use tokio::sync::OnceCell;
+use tokio_postgres::{error::SqlState, Client};
+
+use crate::{
+    config::options::{IdentifierMode, TimeConstraint, TimescaleMode},
+    fnv::Fnv1a,
+    sink::sink_error::SinkError,
+};
+
+/// Where the nanoseconds timestamptz can't hold go, with --timestamp-precision nanoseconds-in-separate-column
+pub const TIME_NS_REMAINDER_COLUMN: &str = "time_ns_remainder";
+
+/// Every table's jsonb column for datums' tags
+pub const TAGS_COLUMN: &str = "tags";
+
+/// Where goodmetricsd keeps tables of its own, apart from metrics tables
+pub const META_SCHEMA: &str = "goodmetrics_meta";
+
+// Postgres silently truncates longer identifiers
+const MAX_IDENTIFIER_BYTES: usize = 63;
 
 lazy_static! {
-    static ref NOT_WHITESPACE: Regex = Regex::new(r"[^\w]+").expect("regex compiles");
+    // ascii only: \w would let through unicode letters and digits, which need quoting
+    static ref NOT_IDENTIFIER: Regex = Regex::new(r"[^a-z0-9_]+").expect("regex compiles");
 }
 
+/// Rows already in the table get the default, like `'{}'::histogram`, or null without one.
+/// Postgres 11+ stores a constant default without rewriting the table.
 pub async fn add_column(
     client: &Client,
     table_name: &str,
     column_name: &str,
     data_type: &str,
+    default_expression: Option<&str>,
 ) -> Result<(), tokio_postgres::Error> {
+    let default = match default_expression {
+        Some(default_expression) => format!(" default {default_expression}"),
+        None => String::new(),
+    };
     client
         .batch_execute(&format!(
-            "alter table {table} add column {column} {data_type}",
+            "alter table {table} add column if not exists {column} {data_type}{default}",
             table = table_name,
             column = column_name,
             data_type = data_type,
@@ -24,18 +51,340 @@ pub async fn add_column(
         .await
 }
 
+/// A table or column that a batch needs before its COPY
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaChange {
+    AddTable(String),
+    AddColumn {
+        table: String,
+        column: String,
+        data_type: String,
+    },
+}
+
+/// Makes the tables, then adds all of the columns in 1 round trip. The columns' alter tables,
+/// 1 per table, go out as 1 multi-statement query, which postgres runs as 1 implicit
+/// transaction: all of them or none. Tables are made 1 at a time first, by create_table,
+/// because a missing timescale extension mustn't roll the table back.
+pub async fn apply_schema_changes(
+    client: &Client,
+    changes: Vec<SchemaChange>,
+    options: &CreateTableOptions,
+    time_constraint: &TimeConstraint,
+    timescale: &TimescaleMode,
+) -> Result<(), SinkError> {
+    let mut columns_by_table: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    for change in changes {
+        match change {
+            SchemaChange::AddTable(table) => {
+                match create_table(client, &table, options, time_constraint, timescale).await {
+                    // Another goodmetricsd got there first
+                    Err(e) if e.code() == Some(&SqlState::DUPLICATE_TABLE) => {}
+                    result => result?,
+                }
+            }
+            SchemaChange::AddColumn {
+                table,
+                column,
+                data_type,
+            } => columns_by_table
+                .entry(table)
+                .or_default()
+                .push(format!("add column if not exists {column} {data_type}")),
+        }
+    }
+    if columns_by_table.is_empty() {
+        return Ok(());
+    }
+    let statements: Vec<String> = columns_by_table
+        .into_iter()
+        .map(|(table, columns)| format!("alter table {table} {}", columns.join(", ")))
+        .collect();
+    client.batch_execute(&statements.join(";\n")).await?;
+    Ok(())
+}
+
+/// A schema change the postgres sender made, for goodmetrics_meta.goodmetrics_ddl_audit
+#[derive(Debug, Clone)]
+pub struct DdlAuditEvent {
+    /// Like DDL_OPERATIONS' kinds: create_table, add_column or alter_column
+    pub operation: &'static str,
+    pub table_name: String,
+    pub column_name: Option<String>,
+    pub data_type: Option<String>,
+    /// The metric whose datums needed the change
+    pub triggered_by: String,
+}
+
+impl DdlAuditEvent {
+    pub fn of_change(change: &SchemaChange, metric: &str) -> Self {
+        match change {
+            SchemaChange::AddTable(table) => Self {
+                operation: "create_table",
+                table_name: table.clone(),
+                column_name: None,
+                data_type: None,
+                triggered_by: metric.to_string(),
+            },
+            SchemaChange::AddColumn {
+                table,
+                column,
+                data_type,
+            } => Self {
+                operation: "add_column",
+                table_name: table.clone(),
+                column_name: Some(column.clone()),
+                data_type: Some(data_type.clone()),
+                triggered_by: metric.to_string(),
+            },
+        }
+    }
+}
+
+pub async fn record_audit_event(
+    client: &Client,
+    event: &DdlAuditEvent,
+) -> Result<(), tokio_postgres::Error> {
+    client
+        .execute(
+            &format!(
+                "insert into {META_SCHEMA}.goodmetrics_ddl_audit
+                    (event_time, operation, table_name, column_name, data_type, triggered_by)
+                values (now(), $1, $2, $3, $4, $5)"
+            ),
+            &[
+                &event.operation,
+                &event.table_name,
+                &event.column_name,
+                &event.data_type,
+                &event.triggered_by,
+            ],
+        )
+        .await?;
+    Ok(())
+}
+
+async fn create_audit_table(client: &Client) -> Result<(), tokio_postgres::Error> {
+    client
+        .batch_execute(&format!(
+            "create schema if not exists {META_SCHEMA};
+            create table if not exists {META_SCHEMA}.goodmetrics_ddl_audit (
+                event_time timestamptz not null,
+                operation text not null,
+                table_name text not null,
+                column_name text,
+                data_type text,
+                triggered_by text
+            )"
+        ))
+        .await
+}
+
+/// Records the schema changes a sender makes, with --enable-ddl-audit. The audit table is made
+/// the first time there's something to record. Failing to record is logged rather than
+/// failing the write that needed the change.
+#[derive(Default)]
+pub struct DdlAudit {
+    audit_table: OnceCell<()>,
+}
+
+impl DdlAudit {
+    pub async fn record(&self, client: &Client, events: &[DdlAuditEvent]) {
+        if events.is_empty() {
+            return;
+        }
+        if let Err(e) = self
+            .audit_table
+            .get_or_try_init(|| create_audit_table(client))
+            .await
+        {
+            tracing::warn!("failed to create the ddl audit table: {e:?}");
+            return;
+        }
+        for event in events {
+            if let Err(e) = record_audit_event(client, event).await {
+                tracing::warn!(?event, "failed to record a ddl audit event: {e:?}");
+            }
+        }
+    }
+}
+
+pub async fn drop_column(
+    client: &Client,
+    table_name: &str,
+    column_name: &str,
+) -> Result<(), tokio_postgres::Error> {
+    client
+        .batch_execute(&format!(
+            "alter table {table_name} drop column if exists {column_name}"
+        ))
+        .await
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IndexMethod {
+    /// For dimensions, filtered on over a time range
+    Btree,
+    /// For the tags column's `tags @> '{"trace_id": "..."}'` lookups
+    Gin,
+}
+
+/// Indexes a column without holding up writes to the table. Hypertables can't build indexes
+/// concurrently, so they build one chunk per transaction instead.
+pub async fn create_index(
+    client: &Client,
+    table_name: &str,
+    column_name: &str,
+    method: IndexMethod,
+    timescale: &TimescaleMode,
+) -> Result<(), tokio_postgres::Error> {
+    // Indexes always live in their table's schema, so they're named without it
+    let unqualified_table = unqualified_table_name(table_name);
+    let index_name = derived_id(&format!("{unqualified_table}_{column_name}_idx"));
+    let statement = match (method, timescale.enabled) {
+        (IndexMethod::Btree, true) => format!("CREATE INDEX IF NOT EXISTS {index_name} ON {table_name} ({column_name}, time DESC) WITH (timescaledb.transaction_per_chunk)"),
+        (IndexMethod::Btree, false) => format!(
+            "CREATE INDEX CONCURRENTLY IF NOT EXISTS {index_name} ON {table_name} ({column_name})"
+        ),
+        (IndexMethod::Gin, true) => format!("CREATE INDEX IF NOT EXISTS {index_name} ON {table_name} USING gin ({column_name}) WITH (timescaledb.transaction_per_chunk)"),
+        (IndexMethod::Gin, false) => format!(
+            "CREATE INDEX CONCURRENTLY IF NOT EXISTS {index_name} ON {table_name} USING gin ({column_name})"
+        ),
+    };
+    client.batch_execute(&statement).await
+}
+
+/// Column names and type oids, in order. Empty when there's no such table: metrics tables
+/// always have a time column.
+pub async fn table_columns(
+    client: &Client,
+    table_name: &str,
+) -> Result<Vec<(String, u32)>, tokio_postgres::Error> {
+    let rows = client
+        .query(
+            "select a.attname::text, a.atttypid from pg_attribute a where a.attrelid = to_regclass($1) and a.attnum > 0 and not a.attisdropped order by a.attnum",
+            &[&table_name],
+        )
+        .await?;
+    Ok(rows.iter().map(|row| (row.get(0), row.get(1))).collect())
+}
+
+/// Turns a statistic_set column into a histogram column, approximating each existing
+/// row as a 3-bucket histogram: 1 at the minimum, 1 at the maximum and the rest at the mean.
+pub async fn upgrade_statistic_set_to_histogram(
+    client: &Client,
+    table_name: &str,
+    column_name: &str,
+) -> Result<(), tokio_postgres::Error> {
+    client
+        .batch_execute(&format!(
+            r#"
+CREATE OR REPLACE FUNCTION statistic_set_to_histogram(ss statistic_set) RETURNS jsonb
+AS $$
+    SELECT jsonb_object_agg(bucket, bucket_count)
+    FROM (
+        SELECT round(value)::int8::text AS bucket, sum(approximate_count)::int8 AS bucket_count
+        FROM (VALUES
+            (ss.minimum, CASE WHEN ss.samplecount >= 2 THEN 1 ELSE 0 END),
+            (ss.samplesum / nullif(ss.samplecount, 0), CASE WHEN ss.samplecount >= 2 THEN ss.samplecount - 2 ELSE ss.samplecount END),
+            (ss.maximum, CASE WHEN ss.samplecount >= 2 THEN 1 ELSE 0 END)
+        ) AS approximation(value, approximate_count)
+        WHERE approximate_count > 0
+        GROUP BY 1
+    ) buckets
+$$ LANGUAGE sql IMMUTABLE STRICT;
+
+ALTER TABLE {table_name} ALTER COLUMN {column_name} TYPE histogram USING statistic_set_to_histogram({column_name});
+            "#
+        ))
+        .await
+}
+
+/// How new tables are made, beyond their columns
+#[derive(Debug, Clone)]
+pub struct CreateTableOptions {
+    /// `PRIMARY KEY (time)`. Only for plain postgres tables.
+    pub primary_key_time: bool,
+    /// A unique index on time, made after the table is a hypertable
+    pub unique_time_index: bool,
+    pub include_retention_policy: Option<Duration>,
+    pub compress: bool,
+    /// A GIN index on the tags column
+    pub index_tags: bool,
+}
+
 pub async fn create_table(
     transaction: &Client,
     table_name: &str,
-    retention: &Duration,
-    compress: bool,
+    options: &CreateTableOptions,
+    time_constraint: &TimeConstraint,
+    timescale: &TimescaleMode,
+) -> Result<(), tokio_postgres::Error> {
+    let time_check = time_check(time_constraint);
+    let primary_key = if options.primary_key_time {
+        " PRIMARY KEY"
+    } else {
+        ""
+    };
+    transaction
+        .batch_execute(&format!(
+            "CREATE TABLE {table_name} (time timestamptz{primary_key}{time_check}, {TAGS_COLUMN} jsonb)"
+        ))
+        .await?;
+
+    if timescale.enabled {
+        // Separate from the create so that a missing extension doesn't roll back the table
+        match create_hypertable(transaction, table_name, options, timescale).await {
+            Err(e) if e.code() == Some(&SqlState::UNDEFINED_FUNCTION) => {
+                tracing::warn!(
+                    "TimescaleDB does not appear to be installed, {table_name} is a plain table: {e:?}"
+                );
+            }
+            result => result?,
+        }
+    }
+    if options.unique_time_index {
+        // Indexes always live in their table's schema, so they're named without it
+        let unqualified_table = unqualified_table_name(table_name);
+        let index_name = derived_id(&format!("{unqualified_table}_time_key"));
+        transaction
+            .batch_execute(&format!(
+                "CREATE UNIQUE INDEX IF NOT EXISTS {index_name} ON {table_name} (time)"
+            ))
+            .await?;
+    }
+    if options.index_tags {
+        // The table is empty, so there's nothing to wait for
+        let unqualified_table = unqualified_table_name(table_name);
+        let index_name = derived_id(&format!("{unqualified_table}_{TAGS_COLUMN}_idx"));
+        transaction
+            .batch_execute(&format!(
+                "CREATE INDEX IF NOT EXISTS {index_name} ON {table_name} USING gin ({TAGS_COLUMN})"
+            ))
+            .await?;
+    }
+    Ok(())
+}
+
+async fn create_hypertable(
+    transaction: &Client,
+    table_name: &str,
+    options: &CreateTableOptions,
+    timescale: &TimescaleMode,
 ) -> Result<(), tokio_postgres::Error> {
-    let chunk = "4h";
-    let compression_statement = if compress {
+    let chunk_seconds = timescale.chunk_time_interval.as_secs();
+    let retention_statement = match options.include_retention_policy {
+        Some(retention) => format!(
+            "SELECT add_retention_policy('{table_name}', INTERVAL '{} seconds');",
+            retention.as_secs()
+        ),
+        None => "".to_string(),
+    };
+    let compression_statement = if options.compress {
         format!(
             r#"
             ALTER TABLE {table_name} SET (timescaledb.compress, timescaledb.compress_orderby = 'time DESC', timescaledb.compress_chunk_time_interval = '24 hours');
-            SELECT add_compression_policy('{table_name}', INTERVAL '{chunk}');
+            SELECT add_compression_policy('{table_name}', INTERVAL '{chunk_seconds} seconds');
             "#
         )
     } else {
@@ -43,18 +392,236 @@ pub async fn create_table(
     };
     transaction.batch_execute(
     &format!(
-            r#"CREATE TABLE {table_name} (time timestamptz);
-            SELECT * from create_hypertable('{table_name}', 'time', chunk_time_interval => INTERVAL '{chunk}' );
-            SELECT add_retention_policy('{table_name}', INTERVAL '{retention_seconds} seconds');
+            r#"SELECT * from create_hypertable('{table_name}', 'time', chunk_time_interval => INTERVAL '{chunk_seconds} seconds', if_not_exists => TRUE);
+            {retention_statement}
             {compression_statement}
             "#,
-            retention_seconds=retention.as_secs(),
         )
     ).await
 }
 
+fn time_check(time_constraint: &TimeConstraint) -> String {
+    let mut checks: Vec<String> = Vec::new();
+    if time_constraint.not_null {
+        checks.push("time IS NOT NULL".to_string());
+    }
+    if let Some(min_year) = time_constraint.min_year {
+        checks.push(format!("time >= '{min_year:04}-01-01'::timestamptz"));
+    }
+    if let Some(max_year) = time_constraint.max_year {
+        checks.push(format!("time <= '{max_year:04}-01-01'::timestamptz"));
+    }
+
+    if checks.is_empty() {
+        "".to_string()
+    } else {
+        format!(" CHECK ({})", checks.join(" AND "))
+    }
+}
+
+/// `schema.table` when there's a schema, so the table lands there instead of in the search_path
+pub fn qualified_table_name(schema_name: Option<&str>, table_name: &str) -> String {
+    match schema_name {
+        Some(schema_name) => format!("{}.{table_name}", clean_id(schema_name)),
+        None => table_name.to_string(),
+    }
+}
+
+/// The table name without its schema. Schemas are clean_ids, so they never have a . of their own
+pub fn unqualified_table_name(table_name: &str) -> &str {
+    match table_name.split_once('.') {
+        Some((schema, table)) if !schema.starts_with('"') => table,
+        _ => table_name,
+    }
+}
+
+/// The table a metric is written to. The prefix is cleaned along with the metric, so the
+/// whole name still fits in 63 bytes.
+pub fn metric_table_name(
+    identifier_mode: IdentifierMode,
+    table_prefix: &str,
+    metric: &str,
+) -> String {
+    identifier(identifier_mode, &format!("{table_prefix}{metric}"))
+}
+
+/// The table or column name for a metric, dimension or measurement name
+pub fn identifier(identifier_mode: IdentifierMode, name: &str) -> String {
+    match identifier_mode {
+        IdentifierMode::LowercaseUnquoted => clean_id(name),
+        IdentifierMode::PreserveCaseQuoted => quote_id(name),
+    }
+}
+
+/// A name the way postgres reports it, in its catalogs and error messages, written the way
+/// `identifier` would write it
+pub fn catalog_identifier(identifier_mode: IdentifierMode, name: &str) -> String {
+    match identifier_mode {
+        IdentifierMode::LowercaseUnquoted => name.to_string(),
+        IdentifierMode::PreserveCaseQuoted => quote_id(name),
+    }
+}
+
+/// A name made from other names, like an index's from its table's and column's. It's quoted
+/// when any of them are, so indexes of tables that only differ in case don't collide.
+pub fn derived_id(name: &str) -> String {
+    if name.contains('"') {
+        quote_id(&unquote_id(name))
+    } else {
+        clean_id(name)
+    }
+}
+
+/// Lowercase ascii letters, digits and underscores, not starting with a digit, and at most 63
+/// bytes; safe to interpolate into sql unquoted. Everything else is replaced with `_`.
+/// Longer ids are cut short and suffixed with a hash of the original, so ids that only
+/// differ past the limit don't end up naming the same table or column.
 pub fn clean_id(s: &str) -> String {
     let l = s.to_lowercase();
-    let a = NOT_WHITESPACE.replace_all(&l, "_");
-    a.to_string()
+    let mut a = NOT_IDENTIFIER.replace_all(&l, "_").into_owned();
+    if a.is_empty() || a.starts_with(|c: char| c.is_ascii_digit()) {
+        a.insert(0, '_');
+    }
+    if a.len() <= MAX_IDENTIFIER_BYTES {
+        return a;
+    }
+
+    // All ascii by now, so any byte is a char boundary
+    let suffix = truncation_suffix(s);
+    a.truncate(MAX_IDENTIFIER_BYTES - suffix.len());
+    a + &suffix
+}
+
+/// The name as it is, double quoted, so case and punctuation survive. Cut short like clean_id
+/// when it's more than 63 bytes, not counting the quotes.
+fn quote_id(s: &str) -> String {
+    // The one character postgres can't take, even quoted
+    let mut a = s.replace('\0', "_");
+    if MAX_IDENTIFIER_BYTES < a.len() {
+        let suffix = truncation_suffix(s);
+        let mut end = MAX_IDENTIFIER_BYTES - suffix.len();
+        while !a.is_char_boundary(end) {
+            end -= 1;
+        }
+        a.truncate(end);
+        a.push_str(&suffix);
+    }
+    format!("\"{}\"", a.replace('"', "\"\""))
+}
+
+// How postgres reads a name: quoted parts as they are, with "" for a quote. The unquoted parts
+// are clean_ids or goodmetrics' own lowercase suffixes, so there's no case to fold.
+fn unquote_id(name: &str) -> String {
+    let mut unquoted = String::with_capacity(name.len());
+    let mut chars = name.chars().peekable();
+    let mut quoted = false;
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                unquoted.push('"');
+            }
+            '"' => quoted = !quoted,
+            c => unquoted.push(c),
+        }
+    }
+    unquoted
+}
+
+fn truncation_suffix(s: &str) -> String {
+    let mut hasher = Fnv1a::default();
+    hasher.write(s.as_bytes());
+    format!("_{:04x}", hasher.finish() & 0xffff)
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{clean_id, metric_table_name, quote_id, MAX_IDENTIFIER_BYTES};
+    use crate::config::options::IdentifierMode;
+
+    #[test]
+    fn short_ids_are_unchanged() {
+        for id in ["requests", "latency_ms", "_private", "a", "p99_2"] {
+            assert_eq!(clean_id(id), id);
+        }
+        let longest = "x".repeat(MAX_IDENTIFIER_BYTES);
+        assert_eq!(clean_id(&longest), longest);
+    }
+
+    #[test]
+    fn long_ids_sharing_a_prefix_stay_distinct() {
+        let prefix = "service_request_latency_by_region_and_availability_zone_and_";
+        let east = clean_id(&format!("{prefix}host_in_us_east_1"));
+        let west = clean_id(&format!("{prefix}host_in_us_west_2"));
+        assert_ne!(east, west);
+        for id in [&east, &west] {
+            assert_eq!(id.len(), MAX_IDENTIFIER_BYTES);
+            assert!(id.starts_with(&prefix[..50]));
+        }
+        // The same name always gets the same id
+        assert_eq!(east, clean_id(&format!("{prefix}host_in_us_east_1")));
+    }
+
+    #[test]
+    fn multibyte_names_are_cut_on_char_boundaries() {
+        let cleaned = clean_id(&"hé".repeat(40));
+        assert!(cleaned.is_ascii());
+        assert_eq!(cleaned.len(), MAX_IDENTIFIER_BYTES);
+        assert!(cleaned.starts_with("h_h_h_"));
+
+        // Quoted ids keep the characters, so the cut has to land between them
+        let name = format!("{}ééééé", "a".repeat(MAX_IDENTIFIER_BYTES - 2));
+        let quoted = quote_id(&name);
+        let unquoted = &quoted[1..quoted.len() - 1];
+        assert!(unquoted.len() <= MAX_IDENTIFIER_BYTES);
+        assert!(unquoted.starts_with(&"a".repeat(MAX_IDENTIFIER_BYTES - 8)));
+    }
+
+    #[test]
+    fn quoted_table_names_keep_case() {
+        let table = |metric| metric_table_name(IdentifierMode::PreserveCaseQuoted, "", metric);
+        assert_eq!("\"CpuUsage\"", table("CpuUsage"));
+        assert_ne!(table("CpuUsage"), table("cpuusage"));
+        assert_ne!(table("CpuUsage"), table("CPUUSAGE"));
+
+        // Unquoted, they're the same table
+        let table = |metric| metric_table_name(IdentifierMode::LowercaseUnquoted, "", metric);
+        assert_eq!(table("CpuUsage"), table("cpuusage"));
+    }
+
+    #[test]
+    fn table_prefix_is_prepended() {
+        for identifier_mode in [
+            IdentifierMode::LowercaseUnquoted,
+            IdentifierMode::PreserveCaseQuoted,
+        ] {
+            let table = metric_table_name(identifier_mode, "gm_", "cpu_usage");
+            assert_eq!("gm_cpu_usage", table.trim_matches('"'));
+        }
+        // Cleaned together, so a long prefix still leaves a name that fits
+        let table = metric_table_name(
+            IdentifierMode::LowercaseUnquoted,
+            "gm_",
+            &"x".repeat(MAX_IDENTIFIER_BYTES),
+        );
+        assert!(table.starts_with("gm_x"));
+        assert_eq!(MAX_IDENTIFIER_BYTES, table.len());
+    }
+
+    proptest! {
+        #[test]
+        fn clean_ids_are_plain_postgres_identifiers(name in any::<String>()) {
+            let cleaned = clean_id(&name);
+            prop_assert!(cleaned.len() <= MAX_IDENTIFIER_BYTES);
+            prop_assert!(regex::Regex::new("^[a-z_][a-z0-9_]*$").unwrap().is_match(&cleaned), "{cleaned}");
+        }
+
+        #[test]
+        fn clean_ids_are_stable(name in "[A-Za-z0-9_ .é-]{0,100}") {
+            let cleaned = clean_id(&name);
+            prop_assert_eq!(clean_id(&cleaned), cleaned);
+        }
+    }
 }
@@ -0,0 +1,55 @@
+use std::{error::Error, fmt::Display};
+
+use bytes::BytesMut;
+use communication::proto::goodmetrics;
+use postgres_types::{to_sql_checked, IsNull, ToSql, Type};
+use serde_json::json;
+
+/// Exponential histograms are kept as jsonb, like
+/// `{"scale": 3, "zero_count": 0, "positive_offset": 8, "positive": [1, 4], "negative_offset": 0, "negative": []}`.
+/// `ExponentialHistogram::to_linear_histogram` turns one into a regular histogram.
+#[derive(Debug)]
+pub struct SqlExponentialHistogram {
+    histogram: goodmetrics::ExponentialHistogram,
+}
+
+impl SqlExponentialHistogram {
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "scale": self.histogram.scale,
+            "zero_count": self.histogram.zero_count,
+            "positive_offset": self.histogram.positive_offset,
+            "positive": self.histogram.positive,
+            "negative_offset": self.histogram.negative_offset,
+            "negative": self.histogram.negative,
+        })
+    }
+}
+
+impl Display for SqlExponentialHistogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.to_json())
+    }
+}
+
+impl From<goodmetrics::ExponentialHistogram> for SqlExponentialHistogram {
+    fn from(histogram: goodmetrics::ExponentialHistogram) -> Self {
+        Self { histogram }
+    }
+}
+
+impl ToSql for SqlExponentialHistogram {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn Error + Sync + Send>> {
+        self.to_json().to_sql(ty, out)
+    }
+
+    fn accepts(ty: &Type) -> bool {
+        <serde_json::Value as ToSql>::accepts(ty)
+    }
+
+    to_sql_checked!();
+}
@@ -1,8 +1,10 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+};
 
 use communication::proto::goodmetrics;
 use postgres_types::Type;
-use serde_json::json;
 use tokio_postgres::{error::SqlState, Client, GenericClient};
 
 use crate::sink::sink_error::SinkError;
@@ -19,7 +21,7 @@ pub async fn get_or_create_histogram_type(
             if let Some(dbe) = e.as_db_error() {
                 match *dbe.code() {
                     SqlState::UNDEFINED_OBJECT => {
-                        log::info!(
+                        tracing::info!(
                             "Probably missing histogram type. Going to try to make it: {:?}",
                             dbe
                         );
@@ -31,7 +33,7 @@ pub async fn get_or_create_histogram_type(
                         Ok(t)
                     }
                     _ => {
-                        log::info!("Can't find the histogram type, so I can't run: {:?}", dbe);
+                        tracing::info!("Can't find the histogram type, so I can't run: {:?}", dbe);
 
                         Err(SinkError::Postgres(e))
                     }
@@ -63,13 +65,65 @@ async fn get_special_types(client: &Client) -> Result<SpecialTypes, tokio_postgr
     }
 }
 
-pub fn to_jsonmap(histogram: &goodmetrics::Histogram) -> serde_json::Value {
-    let a: HashMap<String, u64> = histogram
-        .buckets
+/// Appends the histogram as a jsonb object of bucket to count. The keys are integers, so there's
+/// nothing to escape and no need for an intermediate serde_json::Value.
+pub fn write_jsonmap(histogram: &goodmetrics::Histogram, out: &mut String) {
+    out.push('{');
+    for (i, (bucket, count)) in histogram.buckets.iter().enumerate() {
+        if 0 < i {
+            out.push(',');
+        }
+        // Writing to a String can't fail
+        let _ = write!(out, "\"{bucket}\":{count}");
+    }
+    out.push('}');
+}
+
+/// Adds the counts of buckets in both, and keeps the buckets that are only in one
+pub fn merge_histograms(
+    a: &goodmetrics::Histogram,
+    b: &goodmetrics::Histogram,
+) -> goodmetrics::Histogram {
+    let mut buckets = a.buckets.clone();
+    for (bucket, count) in &b.buckets {
+        *buckets.entry(*bucket).or_default() += count;
+    }
+    goodmetrics::Histogram { buckets }
+}
+
+/// Merges low-count buckets into the next bucket up until at most max_buckets are left.
+/// The most populated buckets and the highest bucket are kept, so the total count and the
+/// maximum are preserved; merged samples are only ever reported in a larger bucket.
+pub fn compress(histogram: &goodmetrics::Histogram, max_buckets: usize) -> goodmetrics::Histogram {
+    let max_buckets = max_buckets.max(1);
+    if histogram.buckets.len() <= max_buckets {
+        return histogram.clone();
+    }
+    let sorted: BTreeMap<i64, u64> = histogram.buckets.iter().map(|(k, v)| (*k, *v)).collect();
+    let highest = *sorted
+        .keys()
+        .next_back()
+        .expect("more buckets than max_buckets");
+
+    let mut kept: Vec<(i64, u64)> = sorted
         .iter()
-        .map(|(k, v)| (k.to_string(), *v))
+        .filter(|(bucket, _)| **bucket != highest)
+        .map(|(bucket, count)| (*bucket, *count))
         .collect();
-    json!(a)
+    kept.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    kept.truncate(max_buckets - 1);
+    let kept: BTreeMap<i64, u64> = kept.into_iter().collect();
+
+    let mut buckets = HashMap::with_capacity(max_buckets);
+    let mut carried = 0;
+    for (bucket, count) in sorted {
+        carried += count;
+        if bucket == highest || kept.contains_key(&bucket) {
+            buckets.insert(bucket, carried);
+            carried = 0;
+        }
+    }
+    goodmetrics::Histogram { buckets }
 }
 
 async fn create_histogram_type(client: &Client) -> Result<SpecialTypes, tokio_postgres::Error> {
@@ -175,3 +229,75 @@ $$ LANGUAGE SQL STRICT IMMUTABLE PARALLEL SAFE;
         Err(e) => Err(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use communication::proto::goodmetrics::Histogram;
+
+    use super::{compress, merge_histograms};
+
+    fn histogram(buckets: &[(i64, u64)]) -> Histogram {
+        Histogram {
+            buckets: buckets.iter().copied().collect(),
+        }
+    }
+
+    fn total_count(histogram: &Histogram) -> u64 {
+        histogram.buckets.values().sum()
+    }
+
+    #[test]
+    fn disjoint_buckets_are_all_kept() {
+        let merged = merge_histograms(&histogram(&[(1, 2), (5, 3)]), &histogram(&[(10, 4)]));
+        assert_eq!(HashMap::from([(1, 2), (5, 3), (10, 4)]), merged.buckets);
+    }
+
+    #[test]
+    fn overlapping_buckets_are_added() {
+        let merged = merge_histograms(
+            &histogram(&[(1, 2), (5, 3)]),
+            &histogram(&[(5, 4), (10, 1)]),
+        );
+        assert_eq!(HashMap::from([(1, 2), (5, 7), (10, 1)]), merged.buckets);
+    }
+
+    #[test]
+    fn empty_histograms_change_nothing() {
+        let some = histogram(&[(1, 2), (5, 3)]);
+        assert_eq!(some, merge_histograms(&some, &Histogram::default()));
+        assert_eq!(some, merge_histograms(&Histogram::default(), &some));
+        assert_eq!(
+            Histogram::default(),
+            merge_histograms(&Histogram::default(), &Histogram::default())
+        );
+    }
+
+    #[test]
+    fn merging_keeps_the_total_count() {
+        let a = histogram(&[(1, 2), (5, 3), (100, 7)]);
+        let b = histogram(&[(5, 4), (10, 1), (1000, 9)]);
+        let merged = merge_histograms(&a, &b);
+        assert_eq!(total_count(&a) + total_count(&b), total_count(&merged));
+    }
+
+    #[test]
+    fn compressing_a_sparse_histogram_keeps_the_total_and_maximum() {
+        // Buckets spread over many orders of magnitude, mostly with small counts
+        let sparse = histogram(
+            &(0..200)
+                .map(|i: i64| ((1 << (i % 60)) + i, (i as u64 * 7919) % 13 + 1))
+                .collect::<Vec<_>>(),
+        );
+        let highest = *sparse.buckets.keys().max().unwrap();
+        for max_buckets in [1, 2, 10, 64, 199] {
+            let compressed = compress(&sparse, max_buckets);
+            assert!(compressed.buckets.len() <= max_buckets);
+            assert_eq!(total_count(&sparse), total_count(&compressed));
+            assert!(compressed.buckets.contains_key(&highest));
+        }
+        // Small enough already
+        assert_eq!(sparse, compress(&sparse, 200));
+    }
+}
@@ -0,0 +1,28 @@
+/// A correlated subquery for the approximate `p` quantile, 0 to 1, of a histogram column.
+/// Buckets are keyed by their upper bound, so this is the first bucket whose cumulative
+/// count reaches `p` of the total. Null when the histogram is empty or null.
+///
+/// `select time, (SELECT ...) as p99 from requests` with the fragment from `p99_sql("latency")`.
+pub fn histogram_percentile_sql(column_name: &str, p: f64) -> String {
+    let p = p.clamp(0.0, 1.0);
+    format!(
+        "(SELECT bucket FROM (\
+SELECT b.key::float8 AS bucket, \
+sum(b.value::int8) OVER (ORDER BY b.key::float8) AS cumulative, \
+sum(b.value::int8) OVER () AS total \
+FROM jsonb_each_text({column_name}) AS b\
+) buckets WHERE cumulative >= {p} * total ORDER BY bucket LIMIT 1)"
+    )
+}
+
+pub fn p50_sql(column_name: &str) -> String {
+    histogram_percentile_sql(column_name, 0.5)
+}
+
+pub fn p90_sql(column_name: &str) -> String {
+    histogram_percentile_sql(column_name, 0.9)
+}
+
+pub fn p99_sql(column_name: &str) -> String {
+    histogram_percentile_sql(column_name, 0.99)
+}
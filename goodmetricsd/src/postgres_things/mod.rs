@@ -1,6 +1,15 @@
+pub mod connection_string_provider;
+pub mod copy_writer;
 pub mod ddl;
+pub mod exponential_histogram;
 pub mod histogram;
+// For writing dashboards' queries; goodmetricsd doesn't read histograms back itself
+#[allow(dead_code)]
+pub mod histogram_queries;
 pub mod postgres_connector;
+pub mod ratio;
+pub mod schema_cache;
 pub mod statistic_set;
 pub mod tdigest;
+pub mod tls;
 pub mod type_conversion;
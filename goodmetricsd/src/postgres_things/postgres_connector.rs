@@ -1,22 +1,159 @@
-use bb8::Pool;
+use std::{
+    fmt::Display,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use bb8::{ManageConnection, Pool};
 use bb8_postgres::PostgresConnectionManager;
-use tokio_postgres::NoTls;
+use tokio::{
+    task::JoinHandle,
+    time::{Instant, MissedTickBehavior},
+};
+use tokio_postgres::{CancelToken, Client};
+use tokio_postgres_rustls::MakeRustlsConnect;
 
 use crate::sink::sink_error::{SinkError, StringError};
 
+use super::{
+    connection_string_provider::ConnectionStringProvider,
+    tls::{parse_connection_string, ConnectError},
+};
+
+#[derive(Clone)]
 pub struct PostgresConnector {
-    pool: Pool<PostgresConnectionManager<NoTls>>,
+    pool: Pool<RotatingConnectionManager>,
+    max_conns: usize,
+    current: Arc<Mutex<Option<CurrentManager>>>,
+    // bb8 doesn't count who's waiting for a connection
+    pending: Arc<AtomicU32>,
+    // Stops the idle connection health check when the last clone is dropped
+    _health_check: Option<Arc<HealthCheck>>,
+}
+
+/// How the connection pool is doing, for seeing when it's close to running out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub total: u32,
+    pub idle: u32,
+    pub in_use: u32,
+    /// Waiting for a connection to come free
+    pub pending: u32,
+    pub max_size: u32,
+}
+
+impl Display for PoolStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "total={} idle={} in_use={} pending={} max_size={}",
+            self.total, self.idle, self.in_use, self.pending, self.max_size
+        )
+    }
+}
+
+// Uncounts a wait for a connection however it ends, including the caller giving up on it
+struct Waiting<'a>(&'a AtomicU32);
+
+impl<'a> Waiting<'a> {
+    fn new(pending: &'a AtomicU32) -> Self {
+        pending.fetch_add(1, Ordering::Relaxed);
+        Self(pending)
+    }
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+struct HealthCheck(JoinHandle<()>);
+
+impl Drop for HealthCheck {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+#[derive(Clone)]
+struct CurrentManager {
+    fetched_at: Instant,
+    manager: PostgresConnectionManager<MakeRustlsConnect>,
+    // Cancel requests go out on their own connection, encrypted like the rest
+    tls: MakeRustlsConnect,
+}
+
+/// Makes the pool's connections with whatever the provider last said the connection string is.
+/// The provider is asked again at most once per refresh interval, so secret stores aren't hammered.
+/// The connection string's sslmode and sslrootcert pick the tls, per `tls::parse_connection_string`.
+pub struct RotatingConnectionManager {
+    provider: Box<dyn ConnectionStringProvider>,
+    refresh_interval: Duration,
+    current: Arc<Mutex<Option<CurrentManager>>>,
+}
+
+impl RotatingConnectionManager {
+    async fn manager(&self) -> Result<PostgresConnectionManager<MakeRustlsConnect>, ConnectError> {
+        let cached = self
+            .current
+            .lock()
+            .expect("connection manager lock")
+            .as_ref()
+            .filter(|current| current.fetched_at.elapsed() < self.refresh_interval)
+            .map(|current| current.manager.clone());
+        if let Some(manager) = cached {
+            return Ok(manager);
+        }
+
+        let (config, tls) = parse_connection_string(&self.provider.get().await)?;
+        let manager = PostgresConnectionManager::new(config, tls.clone());
+        *self.current.lock().expect("connection manager lock") = Some(CurrentManager {
+            fetched_at: Instant::now(),
+            manager: manager.clone(),
+            tls,
+        });
+        Ok(manager)
+    }
+}
+
+#[tonic::async_trait]
+impl ManageConnection for RotatingConnectionManager {
+    type Connection = Client;
+    type Error = ConnectError;
+
+    async fn connect(&self) -> Result<Client, ConnectError> {
+        Ok(self.manager().await?.connect().await?)
+    }
+
+    async fn is_valid(&self, connection: &mut Client) -> Result<(), ConnectError> {
+        connection.simple_query("").await?;
+        Ok(())
+    }
+
+    fn has_broken(&self, connection: &mut Client) -> bool {
+        connection.is_closed()
+    }
 }
 
 impl PostgresConnector {
     pub async fn new(
-        connection_string: String,
+        provider: Box<dyn ConnectionStringProvider>,
+        refresh_interval: Duration,
         max_conns: usize,
+        health_check_interval: Duration,
     ) -> Result<PostgresConnector, SinkError> {
-        let pg_manager = PostgresConnectionManager::new_from_stringlike(
-            connection_string,
-            tokio_postgres::NoTls,
-        )?;
+        let current = Arc::new(Mutex::new(None));
+        let pg_manager = RotatingConnectionManager {
+            provider,
+            refresh_interval,
+            current: current.clone(),
+        };
+        // A connection string that doesn't parse should stop startup, not every connection
+        pg_manager.manager().await?;
         let pool = match Pool::builder()
             .max_size(max_conns as u32)
             .build(pg_manager)
@@ -26,21 +163,135 @@ impl PostgresConnector {
             Err(e) => panic!("bb8 error {}", e),
         };
 
-        Ok(PostgresConnector { pool })
+        let health_check = if health_check_interval.is_zero() {
+            None
+        } else {
+            Some(Arc::new(HealthCheck(tokio::spawn(check_idle_connections(
+                pool.clone(),
+                health_check_interval,
+            )))))
+        };
+
+        Ok(PostgresConnector {
+            pool,
+            max_conns,
+            current,
+            pending: Arc::new(AtomicU32::new(0)),
+            _health_check: health_check,
+        })
+    }
+
+    /// Logs how big each server's pool can be without the servers exhausting postgres together.
+    pub async fn recommend_pool_size(&self, num_instances: u32) -> Result<(), SinkError> {
+        let connection = self.use_connection().await?;
+        let row = connection
+            .query_one(
+                "select
+                    (select setting::int from pg_settings where name = 'max_connections'),
+                    (select setting::int from pg_settings where name = 'superuser_reserved_connections')",
+                &[],
+            )
+            .await?;
+        let max_connections: i32 = row.get(0);
+        let reserved_connections: i32 = row.get(1);
+
+        let available = (max_connections - reserved_connections).max(0) as u32;
+        let optimal = available / num_instances.max(1);
+        tracing::info!(
+            "Recommended pool size for this Postgres instance: {optimal} (current: {current})",
+            current = self.max_conns,
+        );
+        if self.max_conns as u32 > optimal {
+            tracing::warn!(
+                pool_size = self.max_conns,
+                recommended_pool_size = optimal,
+                max_connections,
+                superuser_reserved_connections = reserved_connections,
+                num_instances,
+                "pool size exceeds recommendation"
+            );
+        }
+        Ok(())
+    }
+
+    /// Asks postgres to stop what a connection is doing, over the same tls as the pool's
+    pub async fn cancel_query(&self, cancel_token: CancelToken) -> Result<(), ConnectError> {
+        let tls = self
+            .current
+            .lock()
+            .expect("connection manager lock")
+            .as_ref()
+            .map(|current| current.tls.clone());
+        match tls {
+            Some(tls) => Ok(cancel_token.cancel_query(tls).await?),
+            // new() makes a manager before anything can be connected, let alone cancelled
+            None => Ok(()),
+        }
+    }
+
+    pub fn pool_stats(&self) -> PoolStats {
+        let state = self.pool.state();
+        PoolStats {
+            total: state.connections,
+            idle: state.idle_connections,
+            in_use: state.connections.saturating_sub(state.idle_connections),
+            pending: self.pending.load(Ordering::Relaxed),
+            max_size: self.max_conns as u32,
+        }
     }
 
     pub async fn use_connection(
         &self,
-    ) -> Result<bb8::PooledConnection<'_, PostgresConnectionManager<NoTls>>, SinkError> {
+    ) -> Result<bb8::PooledConnection<'_, RotatingConnectionManager>, SinkError> {
         // need to get the connection via the method that ensures it's connected
+        let waiting = Waiting::new(&self.pending);
         let poolconn = match self.pool.get().await {
             Ok(client) => client,
             Err(err) => {
+                drop(waiting);
                 return Err(SinkError::StringError(StringError {
-                    message: format!("failed to get connection: {:?}", err),
+                    message: format!(
+                        "failed to get connection: {:?} (pool: {})",
+                        err,
+                        self.pool_stats()
+                    ),
                 }));
             }
         };
         Ok(poolconn)
     }
 }
+
+/// Checks out every idle connection once per interval and runs `select 1` on it, so a connection
+/// a firewall quietly dropped while idle is found here rather than by the next batch.
+/// The pool tests connections as they're checked out and evicts those that fail.
+async fn check_idle_connections(pool: Pool<RotatingConnectionManager>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    // The first tick is immediate, and the pool was just made
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let idle = pool.state().idle_connections;
+        // Held until the end of the check, so each get is a different connection
+        let mut checked = Vec::with_capacity(idle as usize);
+        for _ in 0..idle {
+            match pool.get().await {
+                Ok(connection) => {
+                    if let Err(e) = connection.simple_query("select 1").await {
+                        tracing::warn!("idle postgres connection failed its health check: {e:?}");
+                    }
+                    checked.push(connection);
+                }
+                Err(e) => {
+                    tracing::warn!("could not check out a connection to health check: {e:?}");
+                    break;
+                }
+            }
+        }
+        tracing::debug!(
+            connections = checked.len(),
+            "health checked idle connections"
+        );
+    }
+}
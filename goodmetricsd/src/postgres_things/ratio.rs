@@ -0,0 +1,123 @@
+use std::fmt::Display;
+
+use communication::proto::goodmetrics;
+use postgres_types::{FromSql, ToSql, Type};
+use tokio_postgres::{error::SqlState, Client, GenericClient};
+
+use crate::sink::sink_error::SinkError;
+
+use super::postgres_connector::PostgresConnector;
+
+pub async fn get_or_create_ratio_type(
+    connector: &mut PostgresConnector,
+) -> Result<Type, SinkError> {
+    let connection = connector.use_connection().await?;
+    match get_ratio_type(connection.client()).await {
+        Ok(def) => Ok(def),
+        Err(e) => {
+            if let Some(dbe) = e.as_db_error() {
+                match *dbe.code() {
+                    SqlState::UNDEFINED_OBJECT => {
+                        tracing::info!(
+                            "Probably missing ratio_t type. Going to try to make it: {:?}",
+                            dbe
+                        );
+                        drop(connection);
+
+                        let connection = connector.use_connection().await?;
+                        let t = create_ratio_type(connection.client()).await?;
+
+                        Ok(t)
+                    }
+                    _ => {
+                        tracing::info!("Can't find the ratio_t type, so I can't run: {:?}", dbe);
+
+                        Err(SinkError::Postgres(e))
+                    }
+                }
+            } else {
+                Err(SinkError::Postgres(e))
+            }
+        }
+    }
+}
+
+async fn get_ratio_type(client: &Client) -> Result<Type, tokio_postgres::Error> {
+    let statement = client.prepare("SELECT $1::ratio_t").await?;
+    Ok(statement.params()[0].clone())
+}
+
+#[derive(ToSql, FromSql, Debug)]
+#[postgres(name = "ratio_t")]
+pub struct SqlRatio {
+    numerator: i64,
+    denominator: i64,
+}
+
+impl Display for SqlRatio {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("({},{})", self.numerator, self.denominator))
+    }
+}
+
+impl From<goodmetrics::Ratio> for SqlRatio {
+    fn from(value: goodmetrics::Ratio) -> Self {
+        Self {
+            numerator: value.numerator,
+            denominator: value.denominator,
+        }
+    }
+}
+
+async fn create_ratio_type(client: &Client) -> Result<Type, tokio_postgres::Error> {
+    client
+        .batch_execute(
+            r#"
+CREATE TYPE ratio_t AS (
+  numerator   bigint,
+  denominator bigint
+);
+
+CREATE OR REPLACE FUNCTION ratio_accum(internal_state ratio_t, next_row ratio_t) RETURNS ratio_t
+AS $$
+DECLARE
+BEGIN
+    internal_state.numerator := internal_state.numerator + next_row.numerator;
+    internal_state.denominator := internal_state.denominator + next_row.denominator;
+    return internal_state;
+END;
+$$ LANGUAGE plpgsql IMMUTABLE STRICT;
+
+-- The ratio as a number, or null when nothing was counted
+CREATE OR REPLACE FUNCTION ratio_value(value ratio_t) RETURNS double precision
+AS $$
+DECLARE BEGIN
+    IF value.denominator = 0 THEN return NULL; END IF;
+    return value.numerator::double precision / value.denominator;
+END;
+$$ LANGUAGE plpgsql IMMUTABLE STRICT;
+
+-- avg(column) is sum((column).numerator) / sum((column).denominator), not an average of averages
+CREATE AGGREGATE avg (ratio_t)
+(
+    sfunc = ratio_accum,
+    stype = ratio_t,
+    finalfunc = ratio_value,
+    initcond = '(0,0)',
+    combinefunc = ratio_accum,
+    PARALLEL = SAFE
+);
+
+CREATE AGGREGATE accumulate(ratio_t)
+(
+    sfunc = ratio_accum,
+    stype = ratio_t,
+    initcond = '(0,0)',
+    combinefunc = ratio_accum,
+    PARALLEL = SAFE
+);
+    "#,
+        )
+        .await?;
+    get_ratio_type(client).await
+}
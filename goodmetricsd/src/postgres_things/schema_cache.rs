@@ -0,0 +1,124 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    sync::Mutex,
+};
+
+use postgres_types::Type;
+
+/// What the sender has learned about the database's tables from successful COPYs and DDL.
+/// Knowing a table's columns lets new columns be added before COPY instead of after a failed one.
+#[derive(Default)]
+pub struct SchemaCache {
+    tables: Mutex<HashMap<String, BTreeMap<String, Type>>>,
+    // Rows written to each table since it was last analyzed
+    rows_since_analyze: Mutex<HashMap<String, u64>>,
+}
+
+impl SchemaCache {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// None if the table has not been seen yet
+    pub fn known_columns(&self, table: &str) -> Option<BTreeSet<String>> {
+        self.tables
+            .lock()
+            .expect("schema cache lock")
+            .get(table)
+            .map(|columns| columns.keys().cloned().collect())
+    }
+
+    /// Record that a table exists, without learning anything about its columns.
+    pub fn remember_table(&self, table: &str) {
+        self.tables
+            .lock()
+            .expect("schema cache lock")
+            .entry(table.to_string())
+            .or_default();
+    }
+
+    pub fn remember_columns(&self, table: &str, columns: impl IntoIterator<Item = (String, Type)>) {
+        self.tables
+            .lock()
+            .expect("schema cache lock")
+            .entry(table.to_string())
+            .or_default()
+            .extend(columns);
+    }
+
+    /// For when the table turns out to be gone, like after someone drops it.
+    pub fn forget_table(&self, table: &str) {
+        self.tables.lock().expect("schema cache lock").remove(table);
+        self.rows_since_analyze
+            .lock()
+            .expect("schema cache lock")
+            .remove(table);
+    }
+
+    /// Count rows written to a table. True when that takes it to `threshold` rows since the
+    /// last time this said so, meaning its statistics are due an ANALYZE. A threshold of 0 never is.
+    pub fn count_rows_for_analyze(&self, table: &str, rows: u64, threshold: u64) -> bool {
+        if threshold == 0 {
+            return false;
+        }
+        let mut rows_since_analyze = self.rows_since_analyze.lock().expect("schema cache lock");
+        let count = rows_since_analyze.entry(table.to_string()).or_default();
+        crosses_analyze_threshold(count, rows, threshold)
+    }
+}
+
+// Adds the rows to the count, starting it over when it reaches the threshold
+fn crosses_analyze_threshold(count: &mut u64, rows: u64, threshold: u64) -> bool {
+    *count += rows;
+    if *count < threshold {
+        return false;
+    }
+    *count = 0;
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crosses_analyze_threshold, SchemaCache};
+
+    #[test]
+    fn threshold_is_crossed_then_the_count_starts_over() {
+        let mut count = 0;
+        assert!(!crosses_analyze_threshold(&mut count, 40, 100));
+        assert!(!crosses_analyze_threshold(&mut count, 59, 100));
+        assert_eq!(99, count);
+        assert!(crosses_analyze_threshold(&mut count, 1, 100));
+        assert_eq!(0, count);
+
+        // Overshooting doesn't carry over into the next round
+        assert!(crosses_analyze_threshold(&mut count, 250, 100));
+        assert_eq!(0, count);
+        assert!(!crosses_analyze_threshold(&mut count, 99, 100));
+    }
+
+    #[test]
+    fn tables_are_counted_separately() {
+        let cache = SchemaCache::new();
+        assert!(!cache.count_rows_for_analyze("a", 60, 100));
+        assert!(!cache.count_rows_for_analyze("b", 60, 100));
+        assert!(cache.count_rows_for_analyze("a", 40, 100));
+        assert!(!cache.count_rows_for_analyze("a", 40, 100));
+        assert!(cache.count_rows_for_analyze("b", 40, 100));
+    }
+
+    #[test]
+    fn forgetting_a_table_resets_its_count() {
+        let cache = SchemaCache::new();
+        assert!(!cache.count_rows_for_analyze("a", 90, 100));
+        cache.forget_table("a");
+        assert!(!cache.count_rows_for_analyze("a", 90, 100));
+        assert!(cache.count_rows_for_analyze("a", 10, 100));
+    }
+
+    #[test]
+    fn zero_threshold_never_analyzes() {
+        let cache = SchemaCache::new();
+        assert!(!cache.count_rows_for_analyze("a", u64::MAX / 2, 0));
+        assert!(!cache.count_rows_for_analyze("a", u64::MAX / 2, 0));
+    }
+}
@@ -18,7 +18,7 @@ pub async fn get_or_create_statistic_set_type(
             if let Some(dbe) = e.as_db_error() {
                 match *dbe.code() {
                     SqlState::UNDEFINED_OBJECT => {
-                        log::info!(
+                        tracing::info!(
                             "Probably missing statistic_set type. Going to try to make it: {:?}",
                             dbe
                         );
@@ -30,7 +30,7 @@ pub async fn get_or_create_statistic_set_type(
                         Ok(t)
                     }
                     _ => {
-                        log::info!(
+                        tracing::info!(
                             "Can't find the statistic_set type, so I can't run: {:?}",
                             dbe
                         );
@@ -56,6 +56,10 @@ async fn get_statistic_set_type(client: &Client) -> Result<Type, tokio_postgres:
     }
 }
 
+/// COPY and INSERT write it as its text form, `(minimum,maximum,samplesum,samplecount)`.
+/// The derived ToSql is only for query parameters: it writes postgres' binary record format,
+/// an i32 field count then each field's type oid, i32 length and value. Fields are matched to
+/// the type's attributes by name, so the struct's order doesn't have to follow the CREATE TYPE.
 #[derive(ToSql, FromSql, Debug)]
 #[postgres(name = "statistic_set")]
 pub struct SqlStatisticSet {
@@ -85,6 +89,18 @@ impl From<goodmetrics::StatisticSet> for SqlStatisticSet {
     }
 }
 
+pub fn merge_statistic_sets(
+    a: &goodmetrics::StatisticSet,
+    b: &goodmetrics::StatisticSet,
+) -> goodmetrics::StatisticSet {
+    goodmetrics::StatisticSet {
+        minimum: a.minimum.min(b.minimum),
+        maximum: a.maximum.max(b.maximum),
+        samplesum: a.samplesum + b.samplesum,
+        samplecount: a.samplecount + b.samplecount,
+    }
+}
+
 async fn create_statistic_set_type(client: &Client) -> Result<Type, tokio_postgres::Error> {
     match client.batch_execute(r#"
 -- Here is a data type I find highly useful when recording high frequency events:
@@ -243,3 +259,43 @@ CREATE AGGREGATE accumulate(double precision)
         Err(e) => Err(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use communication::proto::goodmetrics::StatisticSet;
+
+    use super::SqlStatisticSet;
+
+    fn literal(minimum: f64, maximum: f64, samplesum: f64, samplecount: u64) -> String {
+        SqlStatisticSet::from(StatisticSet {
+            minimum,
+            maximum,
+            samplesum,
+            samplecount,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn record_literal() {
+        assert_eq!("(0.5,2,7.25,4)", literal(0.5, 2.0, 7.25, 4));
+        assert_eq!("(-3,0,-3,1)", literal(-3.0, 0.0, -3.0, 1));
+    }
+
+    // Postgres' float8 input takes all of these
+    #[test]
+    fn non_finite_values_are_spelled_like_postgres_reads_them() {
+        assert_eq!(
+            "(-inf,inf,NaN,2)",
+            literal(f64::NEG_INFINITY, f64::INFINITY, f64::NAN, 2)
+        );
+    }
+
+    #[test]
+    fn no_exponents() {
+        assert_eq!(
+            "(0.0000001,100000000000000000000,1,1)",
+            literal(1e-7, 1e20, 1.0, 1)
+        );
+    }
+}
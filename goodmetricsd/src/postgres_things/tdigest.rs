@@ -23,7 +23,8 @@ pub struct SqlCentroid {
 impl Display for SqlTdigest {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!(
-            "(version:1,max_buckets:{},count:{},sum:{},min:{},max:{},centroids:[{}])",
+            "(version:{},max_buckets:{},count:{},sum:{},min:{},max:{},centroids:[{}])",
+            self.version,
             self.max_buckets,
             self.count,
             self.sum,
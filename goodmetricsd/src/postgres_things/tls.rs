@@ -0,0 +1,359 @@
+use std::{fs::File, io::BufReader, path::PathBuf, str::FromStr, sync::Arc, time::SystemTime};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use thiserror::Error;
+use tokio_postgres::config::SslMode;
+use tokio_postgres_rustls::MakeRustlsConnect;
+use tokio_rustls::rustls::{
+    client::{ServerCertVerified, ServerCertVerifier},
+    Certificate, ClientConfig, RootCertStore, ServerName,
+};
+
+use crate::sink::sink_error::{SinkError, StringError};
+
+lazy_static! {
+    // key = value connection strings, with the value optionally in single quotes
+    static ref KEY_VALUE_TLS_PARAMETER: Regex =
+        Regex::new(r"(?:^|\s)(?P<key>sslmode|sslrootcert)\s*=\s*(?P<value>'(?:[^'\\]|\\.)*'|\S*)")
+            .expect("regex compiles");
+}
+
+/// How connections to postgres are encrypted, from the connection string's sslmode and
+/// sslrootcert.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PgTlsConfig {
+    /// disable, allow or no sslmode: plaintext, the way goodmetricsd always connected
+    NoTls,
+    /// prefer: encrypted if the server supports it, without checking its certificate
+    Prefer,
+    /// require: encrypted, but like libpq without checking the server's certificate
+    Require,
+    /// verify-full: the server's certificate has to chain to ca_cert and name the host
+    VerifyFull { ca_cert: PathBuf },
+}
+
+#[derive(Debug, Error)]
+pub enum ConnectError {
+    #[error("postgres connection error")]
+    Postgres(#[from] tokio_postgres::Error),
+
+    #[error("postgres tls error: {0}")]
+    Tls(String),
+}
+
+impl From<ConnectError> for SinkError {
+    fn from(e: ConnectError) -> Self {
+        match e {
+            ConnectError::Postgres(e) => SinkError::Postgres(e),
+            ConnectError::Tls(message) => SinkError::StringError(StringError { message }),
+        }
+    }
+}
+
+/// Reads the tls settings out of a connection string, in either url or key=value form.
+/// tokio-postgres only knows sslmode disable, prefer and require, and no sslrootcert, so the
+/// returned config is parsed from what's left with the mode it can handle.
+pub fn parse_connection_string(
+    connection_string: &str,
+) -> Result<(tokio_postgres::Config, MakeRustlsConnect), ConnectError> {
+    let mut sslmode = None;
+    let mut sslrootcert = None;
+    let remaining = if connection_string.starts_with("postgres://")
+        || connection_string.starts_with("postgresql://")
+    {
+        take_url_parameters(connection_string, &mut sslmode, &mut sslrootcert)
+    } else {
+        take_key_value_parameters(connection_string, &mut sslmode, &mut sslrootcert)
+    };
+
+    let tls_config = match sslmode.as_deref() {
+        None | Some("disable" | "allow") => PgTlsConfig::NoTls,
+        Some("prefer") => PgTlsConfig::Prefer,
+        Some("require") => PgTlsConfig::Require,
+        Some("verify-full") => match sslrootcert {
+            Some(ca_cert) => PgTlsConfig::VerifyFull {
+                ca_cert: PathBuf::from(ca_cert),
+            },
+            None => {
+                return Err(ConnectError::Tls(
+                    "sslmode=verify-full needs an sslrootcert".to_string(),
+                ))
+            }
+        },
+        Some(other) => {
+            return Err(ConnectError::Tls(format!(
+                "unsupported sslmode {other}; use disable, prefer, require or verify-full"
+            )))
+        }
+    };
+
+    let mut config = tokio_postgres::Config::from_str(&remaining)?;
+    config.ssl_mode(match tls_config {
+        PgTlsConfig::NoTls => SslMode::Disable,
+        PgTlsConfig::Prefer => SslMode::Prefer,
+        PgTlsConfig::Require | PgTlsConfig::VerifyFull { .. } => SslMode::Require,
+    });
+    Ok((config, tls_config.make_tls_connect()?))
+}
+
+impl PgTlsConfig {
+    pub fn make_tls_connect(&self) -> Result<MakeRustlsConnect, ConnectError> {
+        let builder = ClientConfig::builder().with_safe_defaults();
+        let config = match self {
+            // With ssl_mode disabled, NoTls never gets as far as using this
+            PgTlsConfig::NoTls | PgTlsConfig::Prefer | PgTlsConfig::Require => {
+                let mut config = builder
+                    .with_root_certificates(RootCertStore::empty())
+                    .with_no_client_auth();
+                config
+                    .dangerous()
+                    .set_certificate_verifier(Arc::new(AnyCertificate {}));
+                config
+            }
+            PgTlsConfig::VerifyFull { ca_cert } => builder
+                .with_root_certificates(read_root_certificates(ca_cert)?)
+                .with_no_client_auth(),
+        };
+        Ok(MakeRustlsConnect::new(config))
+    }
+}
+
+fn read_root_certificates(path: &PathBuf) -> Result<RootCertStore, ConnectError> {
+    let file = File::open(path)
+        .map_err(|e| ConnectError::Tls(format!("could not open sslrootcert {path:?}: {e}")))?;
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(file))
+        .map_err(|e| ConnectError::Tls(format!("could not read sslrootcert {path:?}: {e}")))?;
+    let mut roots = RootCertStore::empty();
+    let (added, ignored) = roots.add_parsable_certificates(&certificates);
+    if ignored != 0 {
+        tracing::warn!(path = ?path, ignored, "skipped unparseable certificates in sslrootcert");
+    }
+    if added == 0 {
+        return Err(ConnectError::Tls(format!(
+            "sslrootcert {path:?} has no certificates"
+        )));
+    }
+    Ok(roots)
+}
+
+fn take_url_parameters(
+    connection_string: &str,
+    sslmode: &mut Option<String>,
+    sslrootcert: &mut Option<String>,
+) -> String {
+    let Some((base, query)) = connection_string.split_once('?') else {
+        return connection_string.to_string();
+    };
+    let kept: Vec<&str> = query
+        .split('&')
+        .filter(|parameter| match parameter.split_once('=') {
+            Some(("sslmode", value)) => {
+                *sslmode = Some(percent_decode(value));
+                false
+            }
+            Some(("sslrootcert", value)) => {
+                *sslrootcert = Some(percent_decode(value));
+                false
+            }
+            _ => true,
+        })
+        .collect();
+    if kept.is_empty() {
+        base.to_string()
+    } else {
+        format!("{base}?{}", kept.join("&"))
+    }
+}
+
+fn take_key_value_parameters(
+    connection_string: &str,
+    sslmode: &mut Option<String>,
+    sslrootcert: &mut Option<String>,
+) -> String {
+    for captures in KEY_VALUE_TLS_PARAMETER.captures_iter(connection_string) {
+        let value = unquote(&captures["value"]);
+        match &captures["key"] {
+            "sslmode" => *sslmode = Some(value),
+            _ => *sslrootcert = Some(value),
+        }
+    }
+    KEY_VALUE_TLS_PARAMETER
+        .replace_all(connection_string, "")
+        .into_owned()
+}
+
+fn unquote(value: &str) -> String {
+    match value
+        .strip_prefix('\'')
+        .and_then(|value| value.strip_suffix('\''))
+    {
+        Some(quoted) => {
+            let mut unquoted = String::with_capacity(quoted.len());
+            let mut chars = quoted.chars();
+            while let Some(c) = chars.next() {
+                match c {
+                    '\\' => unquoted.extend(chars.next()),
+                    c => unquoted.push(c),
+                }
+            }
+            unquoted
+        }
+        None => value.to_string(),
+    }
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| value.get(i + 1..i + 3))
+            .flatten()
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// sslmode=prefer and require encrypt without checking who's on the other end
+struct AnyCertificate {}
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_postgres::config::SslMode;
+
+    use super::parse_connection_string;
+
+    // Tests against a real postgres are ignored unless run with its connection string, and for
+    // verify-full the ca that signed its certificate, e.g.
+    // GOODMETRICS_TEST_POSTGRES="host=localhost user=postgres" GOODMETRICS_TEST_POSTGRES_CA=ca.crt
+    const TEST_POSTGRES: &str = "GOODMETRICS_TEST_POSTGRES";
+    const TEST_POSTGRES_CA: &str = "GOODMETRICS_TEST_POSTGRES_CA";
+
+    // Self-signed for localhost, and not what any test server serves
+    const UNRELATED_CA: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIDJzCCAg+gAwIBAgIUJpAucbefGc6zBFxX95o1Eu+3nWwwDQYJKoZIhvcNAQEL
+BQAwFDESMBAGA1UEAwwJbG9jYWxob3N0MCAXDTI2MTAxNTA0MzQxNVoYDzIxMjYw
+OTIxMDQzNDE1WjAUMRIwEAYDVQQDDAlsb2NhbGhvc3QwggEiMA0GCSqGSIb3DQEB
+AQUAA4IBDwAwggEKAoIBAQC6LVTJeZDPZ5hEJ0KSisL+/ZlOzKnXpKTA7sEmt/qQ
+byko+9R/IS5jynAZcG3eITdpw9FopX335ladnHHlL7b7m4w4n0khqprPmJeMv3eE
+fXsF9xjFL5rMgFw7Nkptp6I63ZWZ3xIEeYK2YFhrN1qUP4gmACnmhdOT/bZsF3YA
+/WZhR9tRqaWOQrvplWJT5RI1w6fcMOJ5xjihW/gSSgthffhD0bJMWAVAkFBJgbaP
+P0NhXTvmlkaYld5/wixB4r8NLjz+bdDT1QYcDfqlsXT33lTanXWcBJRxVm/Pd7/Q
+JrYwOjYSGPdbhbVCPeqByYCg5tLcJS08YQK361R+oH5vAgMBAAGjbzBtMB0GA1Ud
+DgQWBBQMfnkWTZO5CCigyWPzvJVpc87GXjAfBgNVHSMEGDAWgBQMfnkWTZO5CCig
+yWPzvJVpc87GXjAPBgNVHRMBAf8EBTADAQH/MBoGA1UdEQQTMBGCCWxvY2FsaG9z
+dIcEfwAAATANBgkqhkiG9w0BAQsFAAOCAQEAcKLhm+WYQ8ofhgzWgRh2x9ZKk6Z7
+40sx0yvIH6MKS8qQHxHbQsTrikmgz5NLev0HJPU5STTMAggJ5nhFD4TaUFDu5IQh
+scnXvm1p/gOEV7/oO8203FskISnnpsSCIMwg9aWJTJpXKd6+Qu0ca+Dn6EDVfJ9L
+XAnk5qtVDpxsW/kFCrzULQtdvylSXZT+0TxiMTvImNoFdmDg04SuKEcp2yFJnHBl
+GUZhtB28DqdqlD+gVu2QIwjRH7kfeRxK1auEYCthq0G+zmRpRZgapNK6pfHM5QGw
+guzX2rUb7oOB1TzjVMgQhmIfpDJihq8WDTcJtTdlnYpEJreCuPBeLvxjLQ==
+-----END CERTIFICATE-----";
+
+    fn test_postgres(env: &str) -> String {
+        std::env::var(env).unwrap_or_else(|_| panic!("{env} is set for the test postgres"))
+    }
+
+    async fn connect(connection_string: &str) -> Result<tokio_postgres::Client, String> {
+        let (config, tls) =
+            parse_connection_string(connection_string).map_err(|e| format!("{e:?}"))?;
+        let (client, connection) = config.connect(tls).await.map_err(|e| format!("{e:?}"))?;
+        tokio::spawn(connection);
+        Ok(client)
+    }
+
+    async fn is_encrypted(client: &tokio_postgres::Client) -> bool {
+        client
+            .query_one(
+                "select ssl from pg_stat_ssl where pid = pg_backend_pid()",
+                &[],
+            )
+            .await
+            .expect("pg_stat_ssl can be read")
+            .get(0)
+    }
+
+    #[test]
+    fn sslmodes_map_to_tokio_postgres() {
+        let ssl_mode = |connection_string| {
+            parse_connection_string(connection_string)
+                .expect("connection string parses")
+                .0
+                .get_ssl_mode()
+        };
+        assert_eq!(SslMode::Disable, ssl_mode("host=db"));
+        assert_eq!(SslMode::Disable, ssl_mode("host=db sslmode=disable"));
+        assert_eq!(SslMode::Disable, ssl_mode("host=db sslmode=allow"));
+        assert_eq!(SslMode::Prefer, ssl_mode("host=db sslmode=prefer"));
+        assert_eq!(
+            SslMode::Require,
+            ssl_mode("postgres://db/metrics?sslmode=require")
+        );
+        assert!(parse_connection_string("host=db sslmode=verify-full").is_err());
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres with ssl at GOODMETRICS_TEST_POSTGRES"]
+    async fn prefer_encrypts_when_the_server_can() {
+        let client = connect(&format!("{} sslmode=prefer", test_postgres(TEST_POSTGRES)))
+            .await
+            .expect("prefer connects");
+        assert!(is_encrypted(&client).await);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres with ssl at GOODMETRICS_TEST_POSTGRES"]
+    async fn verify_full_accepts_only_the_servers_ca() {
+        let connection_string = test_postgres(TEST_POSTGRES);
+        let server_ca = test_postgres(TEST_POSTGRES_CA);
+        let client = connect(&format!(
+            "{connection_string} sslmode=verify-full sslrootcert={server_ca}"
+        ))
+        .await
+        .expect("the server's own certificate is accepted");
+        assert!(is_encrypted(&client).await);
+
+        let unrelated_ca = std::env::temp_dir().join(format!(
+            "goodmetrics-unrelated-ca-{}.crt",
+            std::process::id()
+        ));
+        std::fs::write(&unrelated_ca, UNRELATED_CA).expect("temp file can be written");
+        let rejected = connect(&format!(
+            "{connection_string} sslmode=verify-full sslrootcert={}",
+            unrelated_ca.display()
+        ))
+        .await;
+        std::fs::remove_file(&unrelated_ca).expect("temp file can be removed");
+        assert!(
+            rejected.is_err(),
+            "a certificate from another ca is accepted"
+        );
+    }
+}
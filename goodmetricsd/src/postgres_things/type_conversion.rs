@@ -4,11 +4,23 @@ use postgres_types::Type;
 
 use communication::proto::goodmetrics::{dimension, measurement, Datum, Dimension, Measurement};
 
+use crate::config::options::DimensionTypeConflict;
+
+/// A column that datums in the same batch send as 2 different types
+#[derive(Debug, Clone, PartialEq)]
+pub struct TypeConflict {
+    pub column: String,
+    pub first_type: Type,
+    pub conflicting_type: Type,
+}
+
 #[derive(Clone)]
 pub struct TypeConverter {
     pub statistic_set_type: Type,
     pub histogram_type: Type,
     pub tdigest_type: Type,
+    pub ratio_type: Type,
+    pub dimension_type_conflict: DimensionTypeConflict,
 }
 
 impl TypeConverter {
@@ -21,6 +33,8 @@ impl TypeConverter {
             measurement::Value::StatisticSet(_) => self.statistic_set_type.clone(),
             measurement::Value::Histogram(_) => Type::JSONB,
             measurement::Value::Tdigest(_) => self.tdigest_type.clone(),
+            measurement::Value::Ratio(_) => self.ratio_type.clone(),
+            measurement::Value::ExponentialHistogram(_) => Type::JSONB,
         })
     }
 
@@ -32,25 +46,126 @@ impl TypeConverter {
         })
     }
 
+    /// The type of an existing column, including goodmetrics' own types
+    pub fn type_from_oid(&self, oid: u32) -> Type {
+        [
+            &self.statistic_set_type,
+            &self.histogram_type,
+            &self.tdigest_type,
+            &self.ratio_type,
+        ]
+        .into_iter()
+        .find(|special_type| special_type.oid() == oid)
+        .cloned()
+        .or_else(|| Type::from_oid(oid))
+        .unwrap_or(Type::UNKNOWN)
+    }
+
+    /// Dimensions sent as different types in the same batch get 1 column type, picked by
+    /// dimension_type_conflict
     pub fn get_dimension_type_map(&self, datums: &[Datum]) -> BTreeMap<String, Type> {
-        datums
-            .iter()
-            .flat_map(|d| d.dimensions.iter())
-            .filter_map(|(dimension_name, dimension_value)| {
-                self.dimension_sql_type(dimension_value)
-                    .map(|sql_type| (dimension_name.clone(), sql_type))
-            })
-            .collect()
+        let mut dimension_types: BTreeMap<String, Type> = BTreeMap::new();
+        for (dimension_name, dimension_value) in datums.iter().flat_map(|d| d.dimensions.iter()) {
+            let Some(sql_type) = self.dimension_sql_type(dimension_value) else {
+                continue;
+            };
+            match dimension_types.get_mut(dimension_name) {
+                None => {
+                    dimension_types.insert(dimension_name.clone(), sql_type);
+                }
+                Some(existing) if *existing == sql_type => {}
+                Some(existing) => match self.dimension_type_conflict {
+                    DimensionTypeConflict::FirstWins => {}
+                    DimensionTypeConflict::LastWins => *existing = sql_type,
+                    DimensionTypeConflict::TextFallback => {
+                        *existing = merge_types(existing.clone(), sql_type)
+                    }
+                },
+            }
+        }
+        dimension_types
     }
 
+    /// Numbers sent as different types in the same batch get the widest of them. Anything else
+    /// keeps the last type seen, and check_type_conflicts splits those datums into their own COPY.
     pub fn get_measurement_type_map(&self, datums: &[Datum]) -> BTreeMap<String, Type> {
         datums
             .iter()
             .flat_map(|d| d.measurements.iter())
             .filter_map(|(measurement_name, measurement_value)| {
                 self.measurement_sql_type(measurement_value)
-                    .map(|sql_type| (measurement_name.clone(), sql_type))
+                    .map(|sql_type| (measurement_name, sql_type))
             })
-            .collect()
+            .fold(
+                BTreeMap::new(),
+                |mut measurement_types, (name, sql_type)| {
+                    match measurement_types.get_mut(name) {
+                        Some(existing) => *existing = merge_types(existing.clone(), sql_type),
+                        None => {
+                            measurement_types.insert(name.clone(), sql_type);
+                        }
+                    }
+                    measurement_types
+                },
+            )
+    }
+
+    /// Every measurement column whose type changes across the datums, once per pair of types.
+    /// Numbers aren't conflicts: COPY reads any of them into the widest one's column.
+    /// Dimensions aren't either: get_dimension_type_map settles on 1 type for them.
+    pub fn check_type_conflicts(&self, datums: &[Datum]) -> Vec<TypeConflict> {
+        let mut first_types: BTreeMap<&str, Type> = BTreeMap::new();
+        let mut conflicts: Vec<TypeConflict> = Vec::new();
+        let columns = datums.iter().flat_map(|datum| {
+            datum.measurements.iter().filter_map(|(name, measurement)| {
+                self.measurement_sql_type(measurement).map(|t| (name, t))
+            })
+        });
+        for (column, sql_type) in columns {
+            let first_type = first_types
+                .entry(column.as_str())
+                .or_insert_with(|| sql_type.clone());
+            if compatible_types(first_type, &sql_type) {
+                continue;
+            }
+            let conflict = TypeConflict {
+                column: column.clone(),
+                first_type: first_type.clone(),
+                conflicting_type: sql_type,
+            };
+            if !conflicts.contains(&conflict) {
+                conflicts.push(conflict);
+            }
+        }
+        conflicts
+    }
+}
+
+/// Whether COPY can write values of both types to one column
+pub fn compatible_types(a: &Type, b: &Type) -> bool {
+    a == b || (is_number(a) && is_number(b))
+}
+
+fn is_number(sql_type: &Type) -> bool {
+    [Type::INT4, Type::INT8, Type::FLOAT4, Type::FLOAT8].contains(sql_type)
+}
+
+/// The column type that can hold values of both types. Numbers widen: INT4 < INT8 and
+/// FLOAT4 < FLOAT8, and integers mixed with floats need FLOAT8. Dimensions of different types
+/// fall back to TEXT. Anything else has no common type, and b wins.
+pub fn merge_types(a: Type, b: Type) -> Type {
+    if a == b {
+        return a;
+    }
+    let integers = [Type::INT4, Type::INT8];
+    let dimensions = [Type::TEXT, Type::INT8, Type::BOOL];
+    if integers.contains(&a) && integers.contains(&b) {
+        Type::INT8
+    } else if is_number(&a) && is_number(&b) {
+        Type::FLOAT8
+    } else if dimensions.contains(&a) && dimensions.contains(&b) {
+        Type::TEXT
+    } else {
+        b
     }
 }
@@ -0,0 +1,162 @@
+use communication::proto::goodmetrics::Datum;
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq)]
+pub enum ValidationError {
+    #[error("metric name is empty")]
+    EmptyMetric,
+
+    #[error("metric {metric} has no timestamp (unix_nanos is 0)")]
+    MissingTimestamp { metric: String },
+
+    #[error("metric {metric} has no measurements")]
+    NoMeasurements { metric: String },
+
+    #[error("measurement {measurement} of metric {metric} has no value")]
+    UnsetMeasurement { metric: String, measurement: String },
+
+    #[error("metric {metric} has sample_rate {sample_rate}, which is not between 0 and 1")]
+    BadSampleRate { metric: String, sample_rate: f32 },
+}
+
+/// Rejects datums that could never be written, before they get to a sink.
+/// Datums without dimensions are fine: they're still rows of measurements.
+pub fn validate_datum(datum: &Datum) -> Result<(), ValidationError> {
+    if datum.metric.is_empty() {
+        return Err(ValidationError::EmptyMetric);
+    }
+    if datum.unix_nanos == 0 {
+        return Err(ValidationError::MissingTimestamp {
+            metric: datum.metric.clone(),
+        });
+    }
+    if datum.measurements.is_empty() {
+        return Err(ValidationError::NoMeasurements {
+            metric: datum.metric.clone(),
+        });
+    }
+    if let Some((measurement, _)) = datum
+        .measurements
+        .iter()
+        .find(|(_name, measurement)| measurement.value.is_none())
+    {
+        return Err(ValidationError::UnsetMeasurement {
+            metric: datum.metric.clone(),
+            measurement: measurement.clone(),
+        });
+    }
+    if !(0.0..=1.0).contains(&datum.sample_rate) {
+        return Err(ValidationError::BadSampleRate {
+            metric: datum.metric.clone(),
+            sample_rate: datum.sample_rate,
+        });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use communication::proto::goodmetrics::{measurement, Datum, Measurement};
+
+    use super::{validate_datum, ValidationError};
+    use crate::postgres_things::ddl::clean_id;
+
+    fn datum() -> Datum {
+        Datum {
+            metric: "requests".to_string(),
+            unix_nanos: 1_700_000_000_000_000_000,
+            measurements: [(
+                "latency".to_string(),
+                Measurement {
+                    value: Some(measurement::Value::I64(12)),
+                },
+            )]
+            .into(),
+            sample_rate: 1.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn metric_name() {
+        assert_eq!(validate_datum(&datum()), Ok(()));
+
+        let mut empty = datum();
+        empty.metric.clear();
+        assert_eq!(validate_datum(&empty), Err(ValidationError::EmptyMetric));
+    }
+
+    #[test]
+    fn long_metric_names_are_truncated_not_rejected() {
+        // clean_id gives long names a hashed suffix that keeps them within postgres' 63 bytes
+        let mut long = datum();
+        long.metric = "a_very_long_metric_name_".repeat(10);
+        assert_eq!(validate_datum(&long), Ok(()));
+        assert!(clean_id(&long.metric).len() <= 63);
+    }
+
+    #[test]
+    fn timestamp() {
+        let mut earliest = datum();
+        earliest.unix_nanos = 1;
+        assert_eq!(validate_datum(&earliest), Ok(()));
+
+        let mut missing = datum();
+        missing.unix_nanos = 0;
+        assert_eq!(
+            validate_datum(&missing),
+            Err(ValidationError::MissingTimestamp {
+                metric: "requests".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn empty_maps() {
+        // Unlabelled prometheus samples have no dimensions and are still rows
+        let no_dimensions = datum();
+        assert!(no_dimensions.dimensions.is_empty());
+        assert_eq!(validate_datum(&no_dimensions), Ok(()));
+
+        let mut no_measurements = datum();
+        no_measurements.measurements.clear();
+        assert_eq!(
+            validate_datum(&no_measurements),
+            Err(ValidationError::NoMeasurements {
+                metric: "requests".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn unset_measurement() {
+        let mut unset = datum();
+        unset
+            .measurements
+            .insert("errors".to_string(), Measurement { value: None });
+        assert_eq!(
+            validate_datum(&unset),
+            Err(ValidationError::UnsetMeasurement {
+                metric: "requests".to_string(),
+                measurement: "errors".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn sample_rate() {
+        for sample_rate in [0.0, 0.25, 1.0] {
+            let mut sampled = datum();
+            sampled.sample_rate = sample_rate;
+            assert_eq!(validate_datum(&sampled), Ok(()));
+        }
+        for sample_rate in [-0.5, 1.5, f32::NAN] {
+            let mut sampled = datum();
+            sampled.sample_rate = sample_rate;
+            assert!(matches!(
+                validate_datum(&sampled),
+                Err(ValidationError::BadSampleRate { .. })
+            ));
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, TextEncoder,
+};
+
+// goodmetricsd's own health, served on the health port's /metrics
+lazy_static! {
+    pub static ref QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "goodmetrics_queue_depth",
+        "Metrics requests waiting for the postgres sender"
+    )
+    .expect("metric can be registered");
+    pub static ref QUEUE_DEPTH_DATUMS: IntGauge = register_int_gauge!(
+        "goodmetrics_queue_depth_datums",
+        "Datums waiting for the postgres sender"
+    )
+    .expect("metric can be registered");
+    pub static ref BATCHES_PROCESSED: IntCounter = register_int_counter!(
+        "goodmetrics_batches_processed_total",
+        "Batches the postgres sender has collected and sent"
+    )
+    .expect("metric can be registered");
+    pub static ref ROWS_WRITTEN: IntCounter = register_int_counter!(
+        "goodmetrics_rows_written_total",
+        "Rows copied into postgres"
+    )
+    .expect("metric can be registered");
+    pub static ref DDL_OPERATIONS: IntCounterVec = register_int_counter_vec!(
+        "goodmetrics_ddl_operations_total",
+        "Schema changes made in postgres",
+        &["kind"]
+    )
+    .expect("metric can be registered");
+    pub static ref ANALYZE_OPERATIONS: IntCounter = register_int_counter!(
+        "goodmetrics_analyze_operations_total",
+        "ANALYZEs started on tables after --vacuum-analyze-threshold rows were written to them"
+    )
+    .expect("metric can be registered");
+    pub static ref COPY_DURATION: HistogramVec = register_histogram_vec!(
+        "goodmetrics_copy_duration_seconds",
+        "How long writing a batch's rows for 1 metric took, timeouts and failures included. The metric label is \"all\" with --disable-per-metric-latency",
+        &["metric"],
+        vec![0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 30.0]
+    )
+    .expect("metric can be registered");
+    pub static ref COPY_TIMEOUTS: IntCounter = register_int_counter!(
+        "goodmetrics_copy_timeouts_total",
+        "Postgres COPYs into a table that took too long and were abandoned"
+    )
+    .expect("metric can be registered");
+    pub static ref CARDINALITY_TRUNCATED: IntCounter = register_int_counter!(
+        "goodmetrics_cardinality_truncated_total",
+        "Dimension values replaced because their column had too many distinct values"
+    )
+    .expect("metric can be registered");
+    pub static ref DEDUPLICATED_DATUMS: IntCounter = register_int_counter!(
+        "goodmetrics_deduplicated_datums_total",
+        "Exact duplicate datums dropped from a batch or the dedup window, usually from client retries"
+    )
+    .expect("metric can be registered");
+    pub static ref ANOMALIES_DETECTED: IntCounter = register_int_counter!(
+        "goodmetrics_datums_anomaly_detected_total",
+        "Datums with a measurement more than --anomaly-sigma-threshold standard deviations from its mean, dropped or not"
+    )
+    .expect("metric can be registered");
+    pub static ref PG_POOL_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "goodmetrics_pg_pool_connections",
+        "The postgres connection pool's connections by state: idle, in_use, or pending for callers waiting on one",
+        &["state"]
+    )
+    .expect("metric can be registered");
+    pub static ref PG_POOL_MAX_SIZE: IntGauge = register_int_gauge!(
+        "goodmetrics_pg_pool_max_size",
+        "How many connections the postgres connection pool can open"
+    )
+    .expect("metric can be registered");
+    pub static ref CIRCUIT_BREAKER_STATE: IntGauge = register_int_gauge!(
+        "goodmetrics_circuit_breaker_state",
+        "Postgres connection circuit breaker: 0 closed, 1 open, 2 half open"
+    )
+    .expect("metric can be registered");
+    pub static ref CIRCUIT_BREAKER_TRANSITIONS: IntCounterVec = register_int_counter_vec!(
+        "goodmetrics_circuit_breaker_transitions_total",
+        "Postgres connection circuit breaker state changes, by the state changed to",
+        &["state"]
+    )
+    .expect("metric can be registered");
+    pub static ref SINK_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "goodmetrics_sink_errors_total",
+        "Errors while writing to postgres",
+        &["kind"]
+    )
+    .expect("metric can be registered");
+    pub static ref TENANT_DATUMS_REJECTED: IntCounter = register_int_counter!(
+        "goodmetrics_tenant_datums_rejected_total",
+        "Datums dropped because their tenant has no database"
+    )
+    .expect("metric can be registered");
+    pub static ref RATE_LIMITED: IntCounterVec = register_int_counter_vec!(
+        "goodmetrics_rate_limited_datums_total",
+        "Datums dropped by the rate limiter, by reason: metric_rate for --max-datums-per-metric-per-second, new_metrics for --max-new-metrics-per-second",
+        &["reason"]
+    )
+    .expect("metric can be registered");
+    pub static ref STATSD_MALFORMED_LINES: IntCounter = register_int_counter!(
+        "goodmetrics_statsd_malformed_lines_total",
+        "Statsd lines that couldn't be parsed and were dropped"
+    )
+    .expect("metric can be registered");
+    pub static ref INFLUX_MALFORMED_LINES: IntCounter = register_int_counter!(
+        "goodmetrics_influx_malformed_lines_total",
+        "InfluxDB line protocol lines that couldn't be parsed and were dropped"
+    )
+    .expect("metric can be registered");
+    pub static ref GRAPHITE_MALFORMED_LINES: IntCounter = register_int_counter!(
+        "goodmetrics_graphite_malformed_lines_total",
+        "Graphite plaintext lines, pickles and pickled points that couldn't be parsed and were dropped"
+    )
+    .expect("metric can be registered");
+}
+
+/// Prometheus text exposition of everything registered
+pub fn render_prometheus() -> String {
+    let mut buffer = Vec::new();
+    if let Err(e) = TextEncoder::new().encode(&prometheus::gather(), &mut buffer) {
+        tracing::error!("failed to encode self metrics: {e:?}");
+    }
+    String::from_utf8(buffer).unwrap_or_default()
+}
@@ -0,0 +1,157 @@
+use communication::proto::goodmetrics::admin::{
+    admin_server::Admin, AddColumnRequest, AdminReply, Column, DropColumnRequest,
+    ListTablesRequest, TableInfo,
+};
+use futures::stream;
+use itertools::Itertools;
+use tonic::{Request, Response, Status};
+
+use crate::{
+    config::options::IdentifierMode,
+    postgres_things::{
+        ddl::{self, clean_id, identifier, qualified_table_name, META_SCHEMA},
+        postgres_connector::PostgresConnector,
+    },
+    self_metrics::DDL_OPERATIONS,
+};
+
+use super::health::Readiness;
+
+// What goodmetrics itself creates columns as
+const COLUMN_TYPES: [&str; 10] = [
+    "text",
+    "int8",
+    "int4",
+    "float8",
+    "float4",
+    "boolean",
+    "statistic_set",
+    "histogram",
+    "tdigest",
+    "ratio_t",
+];
+
+/// Schema inspection and repair on the metrics tables, without restarting goodmetricsd.
+/// It uses the postgres sender's connections, so it's unavailable until that has connected.
+pub struct AdminServer {
+    pub readiness: Readiness,
+    pub schema_name: Option<String>,
+    pub identifier_mode: IdentifierMode,
+}
+
+// Status is what the grpc methods return, so boxing it here would only move the unboxing
+#[allow(clippy::result_large_err)]
+impl AdminServer {
+    fn connector(&self) -> Result<&PostgresConnector, Status> {
+        self.readiness
+            .postgres()
+            .ok_or_else(|| Status::unavailable("not connected to postgres"))
+    }
+
+    fn table_name(&self, table: &str) -> Result<String, Status> {
+        if table.is_empty() {
+            return Err(Status::invalid_argument("table is required"));
+        }
+        Ok(qualified_table_name(
+            self.schema_name.as_deref(),
+            &identifier(self.identifier_mode, table),
+        ))
+    }
+
+    fn column_name(&self, column: &str) -> Result<String, Status> {
+        if column.is_empty() {
+            return Err(Status::invalid_argument("column is required"));
+        }
+        let column = identifier(self.identifier_mode, column);
+        if column == identifier(self.identifier_mode, "time") {
+            return Err(Status::invalid_argument("the time column can't be changed"));
+        }
+        Ok(column)
+    }
+}
+
+fn internal(error: impl std::fmt::Debug) -> Status {
+    Status::internal(format!("{error:?}"))
+}
+
+#[tonic::async_trait]
+#[allow(clippy::result_large_err)]
+impl Admin for AdminServer {
+    type ListTablesStream = stream::Iter<std::vec::IntoIter<Result<TableInfo, Status>>>;
+
+    async fn list_tables(
+        &self,
+        _request: Request<ListTablesRequest>,
+    ) -> Result<Response<Self::ListTablesStream>, Status> {
+        let connection = self.connector()?.use_connection().await.map_err(internal)?;
+        let rows = connection
+            .query(
+                "select table_name::text, column_name::text, udt_name::text
+                from information_schema.columns
+                where table_schema = coalesce($1::text, current_schema())
+                    and table_schema <> $2
+                order by table_name, ordinal_position",
+                &[&self.schema_name.as_deref().map(clean_id), &META_SCHEMA],
+            )
+            .await
+            .map_err(internal)?;
+
+        let tables: Vec<Result<TableInfo, Status>> = rows
+            .iter()
+            .group_by(|row| row.get::<_, String>(0))
+            .into_iter()
+            .map(|(name, columns)| {
+                Ok(TableInfo {
+                    name,
+                    columns: columns
+                        .map(|row| Column {
+                            name: row.get(1),
+                            sql_type: row.get(2),
+                        })
+                        .collect(),
+                })
+            })
+            .collect();
+        Ok(Response::new(stream::iter(tables)))
+    }
+
+    async fn add_column(
+        &self,
+        request: Request<AddColumnRequest>,
+    ) -> Result<Response<AdminReply>, Status> {
+        let request = request.into_inner();
+        let table = self.table_name(&request.table)?;
+        let column = self.column_name(&request.column)?;
+        let Some(sql_type) = COLUMN_TYPES.iter().find(|t| **t == request.sql_type) else {
+            return Err(Status::invalid_argument(format!(
+                "sql_type must be one of {}",
+                COLUMN_TYPES.join(", ")
+            )));
+        };
+
+        let connection = self.connector()?.use_connection().await.map_err(internal)?;
+        tracing::info!(table = %table, column = %column, sql_type, "admin adding column");
+        DDL_OPERATIONS.with_label_values(&["add_column"]).inc();
+        ddl::add_column(&connection, &table, &column, sql_type, None)
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(AdminReply {}))
+    }
+
+    async fn drop_column(
+        &self,
+        request: Request<DropColumnRequest>,
+    ) -> Result<Response<AdminReply>, Status> {
+        let request = request.into_inner();
+        let table = self.table_name(&request.table)?;
+        let column = self.column_name(&request.column)?;
+
+        let connection = self.connector()?.use_connection().await.map_err(internal)?;
+        tracing::info!(table = %table, column = %column, "admin dropping column");
+        DDL_OPERATIONS.with_label_values(&["drop_column"]).inc();
+        ddl::drop_column(&connection, &table, &column)
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(AdminReply {}))
+    }
+}
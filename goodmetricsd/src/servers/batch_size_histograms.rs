@@ -0,0 +1,190 @@
+use std::{fmt::Write, net::IpAddr, sync::Mutex};
+
+use dashmap::DashMap;
+
+const BUCKETS: [u64; 5] = [1, 10, 100, 1000, 10000];
+const METRIC_NAME: &str = "goodmetrics_rpc_batch_size_datums";
+// Clients get their own series up to this many ips. The rest share client_ip="other", so a
+// fleet of short lived clients can't grow /metrics without bound.
+const MAX_CLIENTS: usize = 1000;
+
+/// How many datums clients send per SendMetrics call, overall and per client ip.
+/// Shared by every server thread.
+pub struct BatchSizeHistograms {
+    all_clients: Mutex<BatchSizeHistogram>,
+    per_client: DashMap<IpAddr, BatchSizeHistogram>,
+    other_clients: Mutex<BatchSizeHistogram>,
+}
+
+// Cumulative counts for each of BUCKETS, like prometheus reports them
+#[derive(Default)]
+struct BatchSizeHistogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: u64,
+}
+
+impl BatchSizeHistogram {
+    fn record(&mut self, datums: u64) {
+        for (bucket, count) in BUCKETS.iter().zip(&mut self.buckets) {
+            if datums <= *bucket {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += datums;
+    }
+
+    fn write_prometheus(&self, output: &mut String, labels: &str) {
+        for (bucket, count) in BUCKETS.iter().zip(self.buckets) {
+            let _ = writeln!(
+                output,
+                "{METRIC_NAME}_bucket{{{labels}le=\"{bucket}\"}} {count}"
+            );
+        }
+        let total = self.count;
+        let _ = writeln!(
+            output,
+            "{METRIC_NAME}_bucket{{{labels}le=\"+Inf\"}} {total}"
+        );
+        let labels = labels.trim_end_matches(',');
+        let _ = writeln!(output, "{METRIC_NAME}_sum{{{labels}}} {}", self.sum);
+        let _ = writeln!(output, "{METRIC_NAME}_count{{{labels}}} {total}");
+    }
+}
+
+impl Default for BatchSizeHistograms {
+    fn default() -> Self {
+        Self {
+            all_clients: Mutex::new(BatchSizeHistogram::default()),
+            per_client: DashMap::new(),
+            other_clients: Mutex::new(BatchSizeHistogram::default()),
+        }
+    }
+}
+
+impl BatchSizeHistograms {
+    pub fn record(&self, client: Option<IpAddr>, datums: usize) {
+        let datums = datums as u64;
+        self.all_clients
+            .lock()
+            .expect("batch size histogram lock is not poisoned")
+            .record(datums);
+        let Some(client) = client else {
+            return;
+        };
+        if let Some(mut histogram) = self.per_client.get_mut(&client) {
+            histogram.record(datums);
+        } else if self.per_client.len() < MAX_CLIENTS {
+            // Racing threads can each add 1 past the limit, which is fine for a bound
+            self.per_client.entry(client).or_default().record(datums);
+        } else {
+            self.other_clients
+                .lock()
+                .expect("batch size histogram lock is not poisoned")
+                .record(datums);
+        }
+    }
+
+    /// Prometheus text exposition of the histograms
+    pub fn render_prometheus(&self) -> String {
+        let mut output = String::new();
+        let _ = writeln!(
+            output,
+            "# HELP {METRIC_NAME} Datums received per SendMetrics call"
+        );
+        let _ = writeln!(output, "# TYPE {METRIC_NAME} histogram");
+        self.all_clients
+            .lock()
+            .expect("batch size histogram lock is not poisoned")
+            .write_prometheus(&mut output, "");
+        for entry in self.per_client.iter() {
+            entry.value().write_prometheus(
+                &mut output,
+                &format!("client_ip=\"{client}\",", client = entry.key()),
+            );
+        }
+        let other_clients = self
+            .other_clients
+            .lock()
+            .expect("batch size histogram lock is not poisoned");
+        if 0 < other_clients.count {
+            other_clients.write_prometheus(&mut output, "client_ip=\"other\",");
+        }
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{IpAddr, Ipv4Addr};
+
+    use super::{BatchSizeHistograms, MAX_CLIENTS, METRIC_NAME};
+
+    fn series(output: &str, series: &str) -> u64 {
+        output
+            .lines()
+            .find_map(|line| line.strip_prefix(series)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("no {series} in {output}"))
+            .parse()
+            .expect("a count")
+    }
+
+    #[test]
+    fn buckets_count_exactly_at_their_bounds() {
+        let histograms = BatchSizeHistograms::default();
+        for datums in [1, 10, 11, 100, 101, 9999, 10000, 10001, 1_000_000] {
+            histograms.record(None, datums);
+        }
+
+        let output = histograms.render_prometheus();
+        let bucket = |le: &str| series(&output, &format!("{METRIC_NAME}_bucket{{le=\"{le}\"}}"));
+        assert_eq!(1, bucket("1"));
+        assert_eq!(2, bucket("10"));
+        assert_eq!(4, bucket("100"));
+        assert_eq!(5, bucket("1000"));
+        assert_eq!(7, bucket("10000"));
+        assert_eq!(9, bucket("+Inf"));
+        assert_eq!(9, series(&output, &format!("{METRIC_NAME}_count{{}}")));
+        assert_eq!(
+            1_030_223,
+            series(&output, &format!("{METRIC_NAME}_sum{{}}"))
+        );
+    }
+
+    #[test]
+    fn clients_past_the_limit_share_a_series() {
+        let histograms = BatchSizeHistograms::default();
+        let client = |i: usize| Some(IpAddr::V4(Ipv4Addr::from(i as u32)));
+        for i in 0..MAX_CLIENTS + 5 {
+            histograms.record(client(i), 2);
+        }
+        // Clients that already have a series keep it
+        histograms.record(client(0), 2);
+
+        let output = histograms.render_prometheus();
+        let counts = output
+            .lines()
+            .filter(|line| line.starts_with(&format!("{METRIC_NAME}_count{{client_ip=")))
+            .count();
+        assert_eq!(MAX_CLIENTS + 1, counts);
+        assert_eq!(
+            2,
+            series(
+                &output,
+                &format!("{METRIC_NAME}_count{{client_ip=\"0.0.0.0\"}}")
+            )
+        );
+        assert_eq!(
+            5,
+            series(
+                &output,
+                &format!("{METRIC_NAME}_count{{client_ip=\"other\"}}")
+            )
+        );
+        assert_eq!(
+            MAX_CLIENTS as u64 + 6,
+            series(&output, &format!("{METRIC_NAME}_count{{}}"))
+        );
+    }
+}
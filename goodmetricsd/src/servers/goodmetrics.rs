@@ -1,22 +1,35 @@
+use std::sync::Arc;
+
 use tonic::Response;
 
+use super::batch_size_histograms::BatchSizeHistograms;
+use crate::proto::validation::validate_datum;
 use crate::sink::metricssendqueue::MetricsSendQueue;
 use crate::sink::MetricsSink;
 use communication::proto::goodmetrics::metrics_server::Metrics;
-use communication::proto::goodmetrics::{MetricsReply, MetricsRequest};
+use communication::proto::goodmetrics::{dimension, Dimension, MetricsReply, MetricsRequest};
+
+const SAMPLE_RATE_DIMENSION: &str = "sample_rate";
 
-#[derive(Debug)]
-pub struct GoodmetricsServer {
-    pub metrics_sink: MetricsSendQueue,
+pub struct GoodmetricsServer<Sink = MetricsSendQueue> {
+    pub metrics_sink: Sink,
+    pub batch_sizes: Arc<BatchSizeHistograms>,
 }
 
 #[tonic::async_trait]
-impl Metrics for GoodmetricsServer {
+impl<Sink> Metrics for GoodmetricsServer<Sink>
+where
+    Sink: MetricsSink + Sync + 'static,
+{
     async fn send_metrics(
         &self,
         request: tonic::Request<MetricsRequest>,
     ) -> Result<tonic::Response<MetricsReply>, tonic::Status> {
-        log::trace!("request: {:?}", request);
+        tracing::trace!("request: {:?}", request);
+        self.batch_sizes.record(
+            request.remote_addr().map(|address| address.ip()),
+            request.get_ref().metrics.len(),
+        );
 
         // We shared the dimensions across the wire, but here we'll keep it simple and just spew it all across each datum
         let mut request = request.into_inner();
@@ -24,11 +37,30 @@ impl Metrics for GoodmetricsServer {
             .metrics
             .iter_mut()
             .for_each(|datum| datum.dimensions.extend(request.shared_dimensions.clone()));
+        for (index, datum) in request.metrics.iter().enumerate() {
+            if let Err(e) = validate_datum(datum) {
+                tracing::debug!("rejecting request: datum {index}: {e}");
+                return Err(tonic::Status::invalid_argument(format!(
+                    "datum {index}: {e}"
+                )));
+            }
+        }
+        // So you can tell sampled rows, whose counts were scaled up by the client, from the rest
+        for datum in request.metrics.iter_mut() {
+            if 0.0 < datum.sample_rate && datum.sample_rate < 1.0 {
+                datum.dimensions.insert(
+                    SAMPLE_RATE_DIMENSION.to_string(),
+                    Dimension {
+                        value: Some(dimension::Value::String(datum.sample_rate.to_string())),
+                    },
+                );
+            }
+        }
         let queue_result = self.metrics_sink.drain(request.metrics);
 
         match queue_result {
             Ok(result) => {
-                log::debug!("result: {:?}", result);
+                tracing::debug!("result: {:?}", result);
 
                 Ok(Response::new(MetricsReply {}))
             }
@@ -40,3 +72,53 @@ impl Metrics for GoodmetricsServer {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Arc};
+
+    use communication::proto::goodmetrics::{
+        measurement, metrics_server::Metrics, Datum, Measurement, MetricsRequest,
+    };
+
+    use super::GoodmetricsServer;
+    use crate::{servers::batch_size_histograms::BatchSizeHistograms, sink::mock_sink::MockSink};
+
+    fn request() -> tonic::Request<MetricsRequest> {
+        tonic::Request::new(MetricsRequest {
+            shared_dimensions: HashMap::new(),
+            metrics: vec![Datum {
+                metric: "requests".to_string(),
+                unix_nanos: 1,
+                measurements: HashMap::from([(
+                    "count".to_string(),
+                    Measurement {
+                        value: Some(measurement::Value::I64(1)),
+                    },
+                )]),
+                ..Default::default()
+            }],
+        })
+    }
+
+    #[tokio::test]
+    async fn full_queue_is_resource_exhausted_until_it_drains() {
+        let (sink, handle) = MockSink::new();
+        sink.fail_next_n(2);
+        let server = GoodmetricsServer {
+            metrics_sink: sink,
+            batch_sizes: Arc::new(BatchSizeHistograms::default()),
+        };
+
+        for _ in 0..2 {
+            let status = server.send_metrics(request()).await.unwrap_err();
+            assert_eq!(tonic::Code::ResourceExhausted, status.code());
+        }
+        assert!(handle.received().is_empty());
+
+        server.send_metrics(request()).await.unwrap();
+        let received = handle.received();
+        assert_eq!(1, received.len());
+        assert_eq!("requests", received[0].metric);
+    }
+}
@@ -0,0 +1,448 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    io::ErrorKind,
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use bytes::BytesMut;
+use communication::proto::goodmetrics::{dimension, measurement, Datum, Dimension, Measurement};
+use serde_pickle::{DeOptions, Value};
+use tokio::{
+    io::AsyncReadExt,
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{
+    self_metrics::GRAPHITE_MALFORMED_LINES,
+    shutdown::ShutdownToken,
+    sink::{metricssendqueue::MetricsSendQueue, MetricsSink},
+};
+
+const READ_BUFFER_BYTES: usize = 64 * 1024;
+// A line longer than this is dropped rather than buffered forever
+const MAX_LINE_BYTES: usize = 64 * 1024;
+// carbon's own limit on a pickled message
+const MAX_PICKLE_BYTES: u32 = 1024 * 1024;
+
+#[derive(Debug, Clone, Copy)]
+enum Protocol {
+    Plaintext,
+    Pickle,
+}
+
+impl Display for Protocol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Protocol::Plaintext => write!(f, "plaintext"),
+            Protocol::Pickle => write!(f, "pickle"),
+        }
+    }
+}
+
+/// Listens for carbon's plaintext protocol, `metric.path value timestamp` lines over tcp.
+pub async fn serve_graphite_plaintext(
+    address: SocketAddr,
+    metrics_sink: MetricsSendQueue,
+    shutdown: ShutdownToken,
+) -> std::io::Result<()> {
+    serve(address, metrics_sink, shutdown, Protocol::Plaintext).await
+}
+
+/// Listens for carbon's pickle protocol: length prefixed pickled lists of
+/// `(metric.path, (timestamp, value))`, like carbon-relay sends.
+pub async fn serve_graphite_pickle(
+    address: SocketAddr,
+    metrics_sink: MetricsSendQueue,
+    shutdown: ShutdownToken,
+) -> std::io::Result<()> {
+    serve(address, metrics_sink, shutdown, Protocol::Pickle).await
+}
+
+async fn serve(
+    address: SocketAddr,
+    metrics_sink: MetricsSendQueue,
+    shutdown: ShutdownToken,
+    protocol: Protocol,
+) -> std::io::Result<()> {
+    let listener = TcpListener::bind(address).await?;
+    tracing::info!("listening for graphite {protocol} over tcp on {address}");
+    accept(listener, metrics_sink, shutdown, protocol).await
+}
+
+async fn accept(
+    listener: TcpListener,
+    metrics_sink: MetricsSendQueue,
+    shutdown: ShutdownToken,
+    protocol: Protocol,
+) -> std::io::Result<()> {
+    let stopped = shutdown.clone().wait();
+    tokio::pin!(stopped);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((stream, peer)) => {
+                    let metrics_sink = metrics_sink.clone();
+                    let shutdown = shutdown.clone();
+                    tokio::spawn(async move {
+                        let read = match protocol {
+                            Protocol::Plaintext => read_plaintext(stream, &metrics_sink, shutdown).await,
+                            Protocol::Pickle => read_pickle(stream, &metrics_sink, shutdown).await,
+                        };
+                        if let Err(e) = read {
+                            tracing::debug!(%peer, "graphite connection failed: {e:?}");
+                        }
+                    });
+                }
+                Err(e) => tracing::warn!("failed to accept graphite connection: {e:?}"),
+            },
+            _ = &mut stopped => {
+                tracing::info!("graphite {protocol} server stopped");
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Lines become datums as each read completes them, so a long lived connection isn't held back
+async fn read_plaintext(
+    mut stream: TcpStream,
+    metrics_sink: &MetricsSendQueue,
+    shutdown: ShutdownToken,
+) -> std::io::Result<()> {
+    let mut buffer = BytesMut::with_capacity(READ_BUFFER_BYTES);
+    let stopped = shutdown.wait();
+    tokio::pin!(stopped);
+    loop {
+        let read = tokio::select! {
+            read = stream.read_buf(&mut buffer) => read?,
+            _ = &mut stopped => return Ok(()),
+        };
+        // Once the client hangs up its last line counts, newline or not
+        let complete = if read == 0 {
+            buffer.len()
+        } else {
+            match buffer.iter().rposition(|b| *b == b'\n') {
+                Some(newline) => newline + 1,
+                None => {
+                    if MAX_LINE_BYTES < buffer.len() {
+                        GRAPHITE_MALFORMED_LINES.inc();
+                        tracing::debug!("dropping an overlong graphite line");
+                        buffer.clear();
+                    }
+                    continue;
+                }
+            }
+        };
+        let lines = buffer.split_to(complete);
+        let now_nanos = now_nanos();
+        let datums: Vec<Datum> = String::from_utf8_lossy(&lines)
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .filter_map(|line| match parse_line(line, now_nanos) {
+                Ok(datum) => Some(datum),
+                Err(e) => {
+                    GRAPHITE_MALFORMED_LINES.inc();
+                    tracing::debug!(line, "skipping malformed graphite line: {e}");
+                    None
+                }
+            })
+            .collect();
+        send(metrics_sink, datums);
+        if read == 0 {
+            return Ok(());
+        }
+    }
+}
+
+async fn read_pickle(
+    mut stream: TcpStream,
+    metrics_sink: &MetricsSendQueue,
+    shutdown: ShutdownToken,
+) -> std::io::Result<()> {
+    let stopped = shutdown.wait();
+    tokio::pin!(stopped);
+    loop {
+        let length = tokio::select! {
+            length = stream.read_u32() => match length {
+                Ok(length) => length,
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            },
+            _ = &mut stopped => return Ok(()),
+        };
+        if MAX_PICKLE_BYTES < length {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                format!("pickled message of {length} bytes is too big"),
+            ));
+        }
+        let mut payload = vec![0; length as usize];
+        stream.read_exact(&mut payload).await?;
+        match parse_pickle(&payload, now_nanos()) {
+            Ok(datums) => send(metrics_sink, datums),
+            Err(e) => {
+                GRAPHITE_MALFORMED_LINES.inc();
+                tracing::debug!("skipping malformed graphite pickle: {e}");
+            }
+        }
+    }
+}
+
+fn send(metrics_sink: &MetricsSendQueue, datums: Vec<Datum>) {
+    if datums.is_empty() {
+        return;
+    }
+    if let Err(e) = metrics_sink.drain(datums) {
+        tracing::warn!("dropping graphite datums: {e:?}");
+    }
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+// servers.web01.cpu.user 0.25 1700000000
+fn parse_line(line: &str, now_nanos: u64) -> Result<Datum, String> {
+    let mut parts = line.split_whitespace();
+    let path = parts.next().ok_or("missing metric path")?;
+    let value = parts.next().ok_or("missing value")?;
+    let value: f64 = value
+        .parse()
+        .map_err(|e| format!("bad value {value}: {e}"))?;
+    let timestamp = match parts.next() {
+        Some(timestamp) => Some(
+            timestamp
+                .parse::<f64>()
+                .map_err(|e| format!("bad timestamp {timestamp}: {e}"))?,
+        ),
+        None => None,
+    };
+    graphite_datum(path, value, timestamp, now_nanos)
+}
+
+// A list of (path, (timestamp, value)). Pickle is only decoded as data: anything that would
+// make python import or call something is an error.
+fn parse_pickle(payload: &[u8], now_nanos: u64) -> Result<Vec<Datum>, String> {
+    let value = serde_pickle::value_from_slice(payload, DeOptions::new().decode_strings())
+        .map_err(|e| format!("bad pickle: {e}"))?;
+    let points = match value {
+        Value::List(points) | Value::Tuple(points) => points,
+        _ => return Err("pickle is not a list of points".to_string()),
+    };
+    let mut datums = Vec::with_capacity(points.len());
+    for point in points {
+        match parse_pickled_point(point, now_nanos) {
+            Ok(datum) => datums.push(datum),
+            Err(e) => {
+                GRAPHITE_MALFORMED_LINES.inc();
+                tracing::debug!("skipping malformed pickled graphite point: {e}");
+            }
+        }
+    }
+    Ok(datums)
+}
+
+fn parse_pickled_point(point: Value, now_nanos: u64) -> Result<Datum, String> {
+    let (path, sample) = match point {
+        Value::Tuple(pair) | Value::List(pair) if pair.len() == 2 => {
+            let mut pair = pair.into_iter();
+            (pair.next(), pair.next())
+        }
+        point => return Err(format!("not a (path, (timestamp, value)) pair: {point:?}")),
+    };
+    let path = match path {
+        Some(Value::String(path)) => path,
+        Some(Value::Bytes(path)) => String::from_utf8_lossy(&path).into_owned(),
+        path => return Err(format!("bad path: {path:?}")),
+    };
+    let (timestamp, value) = match sample {
+        Some(Value::Tuple(sample) | Value::List(sample)) if sample.len() == 2 => {
+            (pickled_number(&sample[0]), pickled_number(&sample[1]))
+        }
+        sample => return Err(format!("bad sample for {path}: {sample:?}")),
+    };
+    let value = value.ok_or_else(|| format!("bad value for {path}"))?;
+    let timestamp = timestamp.ok_or_else(|| format!("bad timestamp for {path}"))?;
+    graphite_datum(&path, value, Some(timestamp), now_nanos)
+}
+
+fn pickled_number(value: &Value) -> Option<f64> {
+    match value {
+        Value::I64(i) => Some(*i as f64),
+        Value::F64(f) => Some(*f),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+/// The last component of the path is the metric and the ones before it are dimensions named
+/// by depth: `servers.web01.cpu.user` is metric `user` with `key_0=servers`, `key_1=web01` and
+/// `key_2=cpu`. Graphite 1.1 tags, as in `cpu.user;host=web01`, become dimensions by name.
+/// Timestamps are seconds; a missing or negative one means now, like carbon.
+fn graphite_datum(
+    path: &str,
+    value: f64,
+    timestamp: Option<f64>,
+    now_nanos: u64,
+) -> Result<Datum, String> {
+    if !value.is_finite() {
+        return Err(format!("{path} has no usable value: {value}"));
+    }
+    let mut tagged = path.split(';');
+    let path = tagged.next().unwrap_or_default();
+    let mut components: Vec<&str> = path.split('.').collect();
+    let metric = components.pop().unwrap_or_default();
+    if metric.is_empty() {
+        return Err(format!("missing metric name in {path}"));
+    }
+
+    let mut dimensions: HashMap<String, Dimension> = components
+        .into_iter()
+        .enumerate()
+        .map(|(depth, component)| (format!("key_{depth}"), string_dimension(component)))
+        .collect();
+    for tag in tagged {
+        let (name, tag_value) = tag
+            .split_once('=')
+            .ok_or_else(|| format!("bad tag {tag} in {path}"))?;
+        dimensions.insert(name.to_string(), string_dimension(tag_value));
+    }
+
+    let unix_nanos = match timestamp {
+        Some(seconds) if seconds.is_finite() && 0.0 <= seconds => (seconds * 1e9) as u64,
+        _ => now_nanos,
+    };
+
+    Ok(Datum {
+        metric: metric.to_string(),
+        unix_nanos,
+        dimensions,
+        measurements: HashMap::from([(
+            "value".to_string(),
+            Measurement {
+                value: Some(measurement::Value::F64(value)),
+            },
+        )]),
+        ..Default::default()
+    })
+}
+
+fn string_dimension(value: &str) -> Dimension {
+    Dimension {
+        value: Some(dimension::Value::String(value.to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use communication::proto::goodmetrics::{dimension, measurement, Datum};
+    use serde_pickle::{SerOptions, Value};
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::{accept, Protocol};
+    use crate::{shutdown::shutdown_token, sink::metricssendqueue::MetricsSendQueue};
+
+    // Sends the payload to a server on an ephemeral port and returns what it queued
+    async fn serve_once(protocol: Protocol, payload: &[u8]) -> Vec<Datum> {
+        let (send_queue, mut receive_queue) = MetricsSendQueue::new();
+        let (trigger, shutdown) = shutdown_token();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = tokio::spawn(accept(listener, send_queue, shutdown, protocol));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        stream.write_all(payload).await.unwrap();
+        stream.shutdown().await.unwrap();
+        let datums = receive_queue.recv().await.unwrap();
+
+        trigger.trigger();
+        server.await.unwrap().unwrap();
+        datums
+    }
+
+    fn dimension(datum: &Datum, name: &str) -> Option<dimension::Value> {
+        datum.dimensions.get(name).and_then(|d| d.value.clone())
+    }
+
+    fn value(datum: &Datum) -> Option<measurement::Value> {
+        datum
+            .measurements
+            .get("value")
+            .and_then(|m| m.value.clone())
+    }
+
+    #[tokio::test]
+    async fn plaintext_line_is_queued() {
+        let datums = serve_once(
+            Protocol::Plaintext,
+            b"servers.web01.cpu.user 0.25 1700000000\n",
+        )
+        .await;
+
+        assert_eq!(1, datums.len());
+        let datum = &datums[0];
+        assert_eq!("user", datum.metric);
+        assert_eq!(1_700_000_000_000_000_000, datum.unix_nanos);
+        assert_eq!(
+            Some(dimension::Value::String("servers".to_string())),
+            dimension(datum, "key_0")
+        );
+        assert_eq!(
+            Some(dimension::Value::String("web01".to_string())),
+            dimension(datum, "key_1")
+        );
+        assert_eq!(
+            Some(dimension::Value::String("cpu".to_string())),
+            dimension(datum, "key_2")
+        );
+        assert_eq!(Some(measurement::Value::F64(0.25)), value(datum));
+    }
+
+    #[tokio::test]
+    async fn pickle_is_queued() {
+        let points = Value::List(vec![
+            Value::Tuple(vec![
+                Value::String("servers.web01.load;dc=east".to_string()),
+                Value::Tuple(vec![Value::I64(1_700_000_000), Value::F64(1.5)]),
+            ]),
+            Value::Tuple(vec![
+                Value::String("servers.web02.load".to_string()),
+                Value::Tuple(vec![Value::I64(1_700_000_010), Value::I64(3)]),
+            ]),
+        ]);
+        let pickled = serde_pickle::value_to_vec(&points, SerOptions::new()).unwrap();
+        let mut payload = (pickled.len() as u32).to_be_bytes().to_vec();
+        payload.extend(pickled);
+
+        let datums = serve_once(Protocol::Pickle, &payload).await;
+
+        assert_eq!(2, datums.len());
+        assert_eq!("load", datums[0].metric);
+        assert_eq!(1_700_000_000_000_000_000, datums[0].unix_nanos);
+        assert_eq!(
+            Some(dimension::Value::String("web01".to_string())),
+            dimension(&datums[0], "key_1")
+        );
+        assert_eq!(
+            Some(dimension::Value::String("east".to_string())),
+            dimension(&datums[0], "dc")
+        );
+        assert_eq!(Some(measurement::Value::F64(1.5)), value(&datums[0]));
+
+        assert_eq!(1_700_000_010_000_000_000, datums[1].unix_nanos);
+        assert_eq!(
+            Some(dimension::Value::String("web02".to_string())),
+            dimension(&datums[1], "key_1")
+        );
+        assert_eq!(Some(measurement::Value::F64(3.0)), value(&datums[1]));
+    }
+}
@@ -0,0 +1,146 @@
+use std::{
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+};
+
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+
+use crate::{
+    postgres_things::postgres_connector::PostgresConnector,
+    self_metrics::{self, PG_POOL_CONNECTIONS, PG_POOL_MAX_SIZE, QUEUE_DEPTH_DATUMS},
+    shutdown::ShutdownToken,
+    sink::metricssendqueue::MetricsSendQueue,
+};
+
+use super::batch_size_histograms::BatchSizeHistograms;
+
+/// Whether goodmetricsd can do its job, for /readyz.
+/// The postgres sender hands over its connector once it's connected.
+#[derive(Clone)]
+pub struct Readiness {
+    expects_postgres: bool,
+    postgres: Arc<OnceLock<PostgresConnector>>,
+}
+
+impl Readiness {
+    pub fn new(expects_postgres: bool) -> Self {
+        Self {
+            expects_postgres,
+            postgres: Arc::new(OnceLock::new()),
+        }
+    }
+
+    pub fn set_postgres(&self, connector: PostgresConnector) {
+        if self.postgres.set(connector).is_err() {
+            tracing::warn!("postgres readiness was already set");
+        }
+    }
+
+    /// Once the postgres sender has connected
+    pub fn postgres(&self) -> Option<&PostgresConnector> {
+        self.postgres.get()
+    }
+
+    async fn is_ready(&self) -> bool {
+        match self.postgres.get() {
+            Some(connector) => match connector.use_connection().await {
+                Ok(connection) => connection.simple_query("select 1").await.is_ok(),
+                Err(e) => {
+                    tracing::warn!("not ready: {e:?}");
+                    false
+                }
+            },
+            None => !self.expects_postgres,
+        }
+    }
+}
+
+/// Plain http endpoints for the things that scrape, probe and test goodmetricsd.
+pub async fn serve_health(
+    address: SocketAddr,
+    readiness: Readiness,
+    send_queue: MetricsSendQueue,
+    batch_sizes: Arc<BatchSizeHistograms>,
+    shutdown: ShutdownToken,
+) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_connection| {
+        let readiness = readiness.clone();
+        let send_queue = send_queue.clone();
+        let batch_sizes = batch_sizes.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let readiness = readiness.clone();
+                let send_queue = send_queue.clone();
+                let batch_sizes = batch_sizes.clone();
+                async move {
+                    Ok::<_, Infallible>(route(request, &readiness, &send_queue, &batch_sizes).await)
+                }
+            }))
+        }
+    });
+
+    tracing::info!("serving health on {address}");
+    Server::bind(&address)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown.wait())
+        .await
+}
+
+async fn route(
+    request: Request<Body>,
+    readiness: &Readiness,
+    send_queue: &MetricsSendQueue,
+    batch_sizes: &BatchSizeHistograms,
+) -> Response<Body> {
+    match (request.method(), request.uri().path()) {
+        (&Method::GET, "/healthz") => status_response(StatusCode::OK),
+        (&Method::GET, "/readyz") => {
+            if readiness.is_ready().await {
+                status_response(StatusCode::OK)
+            } else {
+                status_response(StatusCode::SERVICE_UNAVAILABLE)
+            }
+        }
+        // For integration tests, instead of sleeping until their datums are written
+        (&Method::POST, "/flush") => {
+            send_queue.flush().await;
+            status_response(StatusCode::OK)
+        }
+        (&Method::GET, "/metrics") => {
+            QUEUE_DEPTH_DATUMS.set(send_queue.queued_datums() as i64);
+            if let Some(connector) = readiness.postgres() {
+                let stats = connector.pool_stats();
+                for (state, connections) in [
+                    ("idle", stats.idle),
+                    ("in_use", stats.in_use),
+                    ("pending", stats.pending),
+                ] {
+                    PG_POOL_CONNECTIONS
+                        .with_label_values(&[state])
+                        .set(connections as i64);
+                }
+                PG_POOL_MAX_SIZE.set(stats.max_size as i64);
+            }
+            Response::builder()
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .body(Body::from(
+                    self_metrics::render_prometheus() + &batch_sizes.render_prometheus(),
+                ))
+                .expect("static response parts are valid")
+        }
+        _ => status_response(StatusCode::NOT_FOUND),
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(
+            status.canonical_reason().unwrap_or_default().to_string(),
+        ))
+        .expect("static response parts are valid")
+}
@@ -0,0 +1,423 @@
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use communication::proto::goodmetrics::{dimension, measurement, Datum, Dimension, Measurement};
+use hyper::{
+    service::{make_service_fn, service_fn},
+    Body, Method, Request, Response, Server, StatusCode,
+};
+use tokio::net::UdpSocket;
+
+use crate::{
+    self_metrics::INFLUX_MALFORMED_LINES,
+    shutdown::ShutdownToken,
+    sink::{metricssendqueue::MetricsSendQueue, MetricsSink},
+};
+
+// Larger than any sane line protocol packet, which are sized to fit in one datagram
+const MAX_PACKET_BYTES: usize = 65535;
+
+/// What the line protocol's timestamps count
+#[derive(Debug, Clone, Copy)]
+enum Precision {
+    Nanoseconds,
+    Microseconds,
+    Milliseconds,
+    Seconds,
+}
+
+impl Precision {
+    // Both InfluxDB 1's and 2's spellings of the /write precision parameter
+    fn parse(precision: &str) -> Option<Self> {
+        match precision {
+            "n" | "ns" => Some(Precision::Nanoseconds),
+            "u" | "us" => Some(Precision::Microseconds),
+            "ms" => Some(Precision::Milliseconds),
+            "s" => Some(Precision::Seconds),
+            _ => None,
+        }
+    }
+
+    fn nanos_per_tick(self) -> u64 {
+        match self {
+            Precision::Nanoseconds => 1,
+            Precision::Microseconds => 1_000,
+            Precision::Milliseconds => 1_000_000,
+            Precision::Seconds => 1_000_000_000,
+        }
+    }
+}
+
+/// Listens for InfluxDB line protocol datagrams, 1 or more lines each with nanosecond timestamps.
+/// Each line becomes a datum as soon as it arrives.
+pub async fn serve_influx_udp(
+    address: SocketAddr,
+    metrics_sink: MetricsSendQueue,
+    shutdown: ShutdownToken,
+) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(address).await?;
+    tracing::info!("listening for influx line protocol over udp on {address}");
+    receive_packets(socket, metrics_sink, shutdown).await
+}
+
+async fn receive_packets(
+    socket: UdpSocket,
+    metrics_sink: MetricsSendQueue,
+    shutdown: ShutdownToken,
+) -> std::io::Result<()> {
+    let mut buffer = vec![0u8; MAX_PACKET_BYTES];
+    let shutdown = shutdown.wait();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buffer) => match received {
+                Ok((length, _)) => {
+                    let payload = String::from_utf8_lossy(&buffer[..length]);
+                    let (datums, _errors) = parse_payload(&payload, Precision::Nanoseconds);
+                    if !datums.is_empty() {
+                        if let Err(e) = metrics_sink.drain(datums) {
+                            tracing::warn!("dropping an influx packet: {e:?}");
+                        }
+                    }
+                }
+                Err(e) => tracing::warn!("failed to receive influx packet: {e:?}"),
+            },
+            _ = &mut shutdown => {
+                tracing::info!("influx udp server stopped");
+                return Ok(());
+            }
+        }
+    }
+}
+
+/// Takes line protocol POSTed to `/write`, like an InfluxDB 1 server, so telegraf and friends
+/// can point at goodmetricsd. `/ping` answers their health checks.
+pub async fn serve_influx_http(
+    address: SocketAddr,
+    metrics_sink: MetricsSendQueue,
+    shutdown: ShutdownToken,
+) -> Result<(), hyper::Error> {
+    let make_service = make_service_fn(move |_connection| {
+        let metrics_sink = metrics_sink.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let metrics_sink = metrics_sink.clone();
+                async move { Ok::<_, Infallible>(route(request, &metrics_sink).await) }
+            }))
+        }
+    });
+
+    tracing::info!("listening for influx line protocol over http on {address}");
+    Server::bind(&address)
+        .serve(make_service)
+        .with_graceful_shutdown(shutdown.wait())
+        .await
+}
+
+async fn route(request: Request<Body>, metrics_sink: &MetricsSendQueue) -> Response<Body> {
+    match (request.method(), request.uri().path()) {
+        (&Method::POST, "/write") => write(request, metrics_sink).await,
+        (&Method::GET | &Method::HEAD, "/ping") => text_response(StatusCode::NO_CONTENT, ""),
+        _ => text_response(StatusCode::NOT_FOUND, "not found"),
+    }
+}
+
+async fn write(request: Request<Body>, metrics_sink: &MetricsSendQueue) -> Response<Body> {
+    let precision = request.uri().query().and_then(|query| {
+        query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("precision="))
+    });
+    let precision = match precision {
+        Some(precision) => match Precision::parse(precision) {
+            Some(precision) => precision,
+            None => {
+                return text_response(
+                    StatusCode::BAD_REQUEST,
+                    &format!("unsupported precision: {precision}"),
+                )
+            }
+        },
+        None => Precision::Nanoseconds,
+    };
+    let body = match hyper::body::to_bytes(request.into_body()).await {
+        Ok(body) => body,
+        Err(e) => return text_response(StatusCode::BAD_REQUEST, &format!("bad body: {e}")),
+    };
+
+    let (datums, errors) = parse_payload(&String::from_utf8_lossy(&body), precision);
+    if !datums.is_empty() {
+        if let Err(e) = metrics_sink.drain(datums) {
+            tracing::debug!("rejecting an influx write: {e:?}");
+            return text_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "No space left in the send buffer",
+            );
+        }
+    }
+    // Like influx, the good lines are kept and the bad ones reported
+    match errors.first() {
+        Some(error) => text_response(
+            StatusCode::BAD_REQUEST,
+            &format!("partial write: {} bad lines, first: {error}", errors.len()),
+        ),
+        None => text_response(StatusCode::NO_CONTENT, ""),
+    }
+}
+
+fn text_response(status: StatusCode, text: &str) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(text.to_string()))
+        .expect("static response parts are valid")
+}
+
+fn parse_payload(payload: &str, precision: Precision) -> (Vec<Datum>, Vec<String>) {
+    let now_nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let mut datums = Vec::new();
+    let mut errors = Vec::new();
+    for line in payload
+        .lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+    {
+        match parse_line(line, precision, now_nanos) {
+            Ok(datum) => datums.push(datum),
+            Err(e) => {
+                INFLUX_MALFORMED_LINES.inc();
+                tracing::debug!(line, "skipping malformed influx line: {e}");
+                errors.push(e);
+            }
+        }
+    }
+    (datums, errors)
+}
+
+// measurement,tag=value,tag=value field=1i,field=2.5,field="text" 1700000000000000000
+// Tags become string dimensions and numeric fields measurements. Goodmetrics measurements are
+// only numbers, so string and boolean fields become dimensions too.
+fn parse_line(line: &str, precision: Precision, now_nanos: u64) -> Result<Datum, String> {
+    let (series, rest) = split_once_unescaped(line, b' ', false).ok_or("missing fields")?;
+    let (fields, timestamp) = match split_once_unescaped(rest, b' ', true) {
+        Some((fields, timestamp)) => (fields, Some(timestamp.trim())),
+        None => (rest, None),
+    };
+
+    let mut series = split_unescaped(series, b',', false).into_iter();
+    let metric = unescape(series.next().unwrap_or_default()).into_owned();
+    if metric.is_empty() {
+        return Err("missing measurement name".to_string());
+    }
+    let mut dimensions = HashMap::new();
+    for tag in series {
+        let (key, value) =
+            split_once_unescaped(tag, b'=', false).ok_or_else(|| format!("bad tag: {tag}"))?;
+        dimensions.insert(
+            unescape(key).into_owned(),
+            Dimension {
+                value: Some(dimension::Value::String(unescape(value).into_owned())),
+            },
+        );
+    }
+
+    let mut measurements = HashMap::new();
+    for field in split_unescaped(fields, b',', true) {
+        let (key, value) = split_once_unescaped(field, b'=', false)
+            .ok_or_else(|| format!("bad field: {field}"))?;
+        let key = unescape(key).into_owned();
+        match parse_field_value(value)? {
+            FieldValue::Measurement(value) => {
+                measurements.insert(key, Measurement { value: Some(value) });
+            }
+            FieldValue::Dimension(value) => {
+                dimensions.insert(key, Dimension { value: Some(value) });
+            }
+        }
+    }
+    if measurements.is_empty() {
+        return Err("no numeric fields".to_string());
+    }
+
+    let unix_nanos = match timestamp.filter(|t| !t.is_empty()) {
+        Some(timestamp) => {
+            let ticks: u64 = timestamp
+                .parse()
+                .map_err(|e| format!("bad timestamp {timestamp}: {e}"))?;
+            ticks
+                .checked_mul(precision.nanos_per_tick())
+                .ok_or_else(|| format!("timestamp out of range: {timestamp}"))?
+        }
+        None => now_nanos,
+    };
+
+    Ok(Datum {
+        metric,
+        unix_nanos,
+        dimensions,
+        measurements,
+        ..Default::default()
+    })
+}
+
+enum FieldValue {
+    Measurement(measurement::Value),
+    Dimension(dimension::Value),
+}
+
+fn parse_field_value(value: &str) -> Result<FieldValue, String> {
+    if let Some(quoted) = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+    {
+        return Ok(FieldValue::Dimension(dimension::Value::String(
+            unescape(quoted).into_owned(),
+        )));
+    }
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => {
+            return Ok(FieldValue::Dimension(dimension::Value::Boolean(true)))
+        }
+        "f" | "F" | "false" | "False" | "FALSE" => {
+            return Ok(FieldValue::Dimension(dimension::Value::Boolean(false)))
+        }
+        _ => {}
+    }
+    if let Some(integer) = value.strip_suffix('i') {
+        return integer
+            .parse()
+            .map(|i| FieldValue::Measurement(measurement::Value::I64(i)))
+            .map_err(|e| format!("bad integer {value}: {e}"));
+    }
+    if let Some(unsigned) = value.strip_suffix('u') {
+        let unsigned: u64 = unsigned
+            .parse()
+            .map_err(|e| format!("bad unsigned integer {value}: {e}"))?;
+        return i64::try_from(unsigned)
+            .map(|i| FieldValue::Measurement(measurement::Value::I64(i)))
+            .map_err(|_| format!("unsigned integer too big for an i64: {value}"));
+    }
+    value
+        .parse()
+        .map(|f| FieldValue::Measurement(measurement::Value::F64(f)))
+        .map_err(|e| format!("bad float {value}: {e}"))
+}
+
+// The first `delimiter` that isn't escaped with a backslash, nor inside double quotes when
+// `quoted` says there can be quoted strings. The delimiters are all ascii, so byte offsets are
+// always char boundaries.
+fn find_unescaped(s: &str, delimiter: u8, quoted: bool) -> Option<usize> {
+    let bytes = s.as_bytes();
+    let mut in_quotes = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 1,
+            b'"' if quoted => in_quotes = !in_quotes,
+            b if b == delimiter && !in_quotes => return Some(i),
+            _ => {}
+        }
+        i += 1;
+    }
+    None
+}
+
+fn split_once_unescaped(s: &str, delimiter: u8, quoted: bool) -> Option<(&str, &str)> {
+    find_unescaped(s, delimiter, quoted).map(|i| (&s[..i], &s[i + 1..]))
+}
+
+fn split_unescaped(mut s: &str, delimiter: u8, quoted: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    while let Some((part, rest)) = split_once_unescaped(s, delimiter, quoted) {
+        parts.push(part);
+        s = rest;
+    }
+    parts.push(s);
+    parts
+}
+
+// Backslashes only escape the line protocol's special characters; anywhere else they're literal
+fn unescape(s: &str) -> Cow<'_, str> {
+    if !s.contains('\\') {
+        return Cow::Borrowed(s);
+    }
+    let mut unescaped = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(escaped @ (',' | '=' | ' ' | '"' | '\\')) => unescaped.push(escaped),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    Cow::Owned(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use communication::proto::goodmetrics::{dimension, measurement};
+    use tokio::net::UdpSocket;
+
+    use super::receive_packets;
+    use crate::{shutdown::shutdown_token, sink::metricssendqueue::MetricsSendQueue};
+
+    #[tokio::test]
+    async fn udp_packet_is_queued() {
+        let (send_queue, mut receive_queue) = MetricsSendQueue::new();
+        let (trigger, shutdown) = shutdown_token();
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let server = tokio::spawn(receive_packets(socket, send_queue, shutdown));
+
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client
+            .send_to(
+                b"cpu,host=web01 user=0.25,procs=12i 1700000000000000000\nmemory free=1024i 1700000001000000000",
+                address,
+            )
+            .await
+            .unwrap();
+        let datums = receive_queue.recv().await.unwrap();
+        trigger.trigger();
+        server.await.unwrap().unwrap();
+
+        assert_eq!(2, datums.len());
+        let cpu = &datums[0];
+        assert_eq!("cpu", cpu.metric);
+        assert_eq!(1_700_000_000_000_000_000, cpu.unix_nanos);
+        assert_eq!(
+            Some(dimension::Value::String("web01".to_string())),
+            cpu.dimensions["host"].value
+        );
+        assert_eq!(
+            Some(measurement::Value::F64(0.25)),
+            cpu.measurements["user"].value
+        );
+        assert_eq!(
+            Some(measurement::Value::I64(12)),
+            cpu.measurements["procs"].value
+        );
+
+        let memory = &datums[1];
+        assert_eq!("memory", memory.metric);
+        assert_eq!(1_700_000_001_000_000_000, memory.unix_nanos);
+        assert_eq!(
+            Some(measurement::Value::I64(1024)),
+            memory.measurements["free"].value
+        );
+    }
+}
@@ -1 +1,8 @@
+pub mod admin;
+pub mod batch_size_histograms;
 pub mod goodmetrics;
+pub mod graphite_server;
+pub mod health;
+pub mod influxdb_server;
+pub mod otlp_server;
+pub mod statsd_server;
@@ -0,0 +1,219 @@
+use std::{collections::HashMap, sync::Arc};
+
+use communication::proto::{
+    goodmetrics::{dimension, measurement, Datum, Dimension, Histogram, Measurement},
+    opentelemetry::{
+        collector::metrics::v1::{
+            metrics_service_server::MetricsService, ExportMetricsServiceRequest,
+            ExportMetricsServiceResponse,
+        },
+        common::v1::{any_value, KeyValue},
+        metrics::v1::{
+            exponential_histogram_data_point::Buckets, metric, number_data_point,
+            ExponentialHistogramDataPoint, HistogramDataPoint, NumberDataPoint,
+        },
+    },
+};
+use tonic::Response;
+
+use super::batch_size_histograms::BatchSizeHistograms;
+use crate::proto::validation::validate_datum;
+use crate::sink::{metricssendqueue::MetricsSendQueue, MetricsSink};
+
+// DataPointFlags.FLAG_NO_RECORDED_VALUE: the point is a placeholder, not a measurement
+const NO_RECORDED_VALUE: u32 = 1;
+
+/// Takes OTLP metrics exports, so OpenTelemetry sdks can send here without a collector.
+/// Each data point becomes a datum with its value in the `value` column.
+pub struct OtlpServer {
+    pub metrics_sink: MetricsSendQueue,
+    pub batch_sizes: Arc<BatchSizeHistograms>,
+}
+
+#[tonic::async_trait]
+impl MetricsService for OtlpServer {
+    async fn export(
+        &self,
+        request: tonic::Request<ExportMetricsServiceRequest>,
+    ) -> Result<tonic::Response<ExportMetricsServiceResponse>, tonic::Status> {
+        let remote_address = request.remote_addr().map(|address| address.ip());
+        let datums = to_datums(request.into_inner());
+        self.batch_sizes.record(remote_address, datums.len());
+
+        for (index, datum) in datums.iter().enumerate() {
+            if let Err(e) = validate_datum(datum) {
+                tracing::debug!("rejecting otlp export: data point {index}: {e}");
+                return Err(tonic::Status::invalid_argument(format!(
+                    "data point {index}: {e}"
+                )));
+            }
+        }
+        if datums.is_empty() {
+            return Ok(Response::new(ExportMetricsServiceResponse {}));
+        }
+
+        match self.metrics_sink.drain(datums) {
+            Ok(result) => {
+                tracing::debug!("result: {:?}", result);
+                Ok(Response::new(ExportMetricsServiceResponse {}))
+            }
+            Err(e) => match e {
+                crate::sink::ErrorCode::QueueFull => Err(tonic::Status::resource_exhausted(
+                    "No space left in the send buffer",
+                )),
+            },
+        }
+    }
+}
+
+fn to_datums(request: ExportMetricsServiceRequest) -> Vec<Datum> {
+    let mut datums = Vec::new();
+    for resource_metrics in request.resource_metrics {
+        let resource_dimensions = resource_metrics
+            .resource
+            .map(|resource| to_dimensions(&resource.attributes))
+            .unwrap_or_default();
+        // This version of OTLP has instrumentation libraries rather than scopes, without attributes
+        for library_metrics in resource_metrics.instrumentation_library_metrics {
+            for metric in library_metrics.metrics {
+                let Some(data) = metric.data else {
+                    continue;
+                };
+                let points: Vec<(&[KeyValue], u64, measurement::Value)> = match &data {
+                    metric::Data::Gauge(gauge) => gauge
+                        .data_points
+                        .iter()
+                        .filter(|point| point.flags & NO_RECORDED_VALUE == 0)
+                        .filter_map(|point| {
+                            number(point)
+                                .map(|value| (&point.attributes[..], point.time_unix_nano, value))
+                        })
+                        .collect(),
+                    metric::Data::Sum(sum) => sum
+                        .data_points
+                        .iter()
+                        .filter(|point| point.flags & NO_RECORDED_VALUE == 0)
+                        .filter_map(|point| {
+                            number(point)
+                                .map(|value| (&point.attributes[..], point.time_unix_nano, value))
+                        })
+                        .collect(),
+                    metric::Data::Histogram(histogram) => histogram
+                        .data_points
+                        .iter()
+                        .filter(|point| point.flags & NO_RECORDED_VALUE == 0)
+                        .map(|point| {
+                            (
+                                &point.attributes[..],
+                                point.time_unix_nano,
+                                explicit_histogram(point),
+                            )
+                        })
+                        .collect(),
+                    metric::Data::ExponentialHistogram(histogram) => histogram
+                        .data_points
+                        .iter()
+                        .filter(|point| point.flags & NO_RECORDED_VALUE == 0)
+                        .map(|point| {
+                            (
+                                &point.attributes[..],
+                                point.time_unix_nano,
+                                exponential_histogram(point),
+                            )
+                        })
+                        .collect(),
+                    metric::Data::Summary(_) => {
+                        tracing::debug!(metric = %metric.name, "skipping unsupported otlp summary");
+                        continue;
+                    }
+                };
+
+                for (attributes, unix_nanos, value) in points {
+                    let mut dimensions = resource_dimensions.clone();
+                    dimensions.extend(to_dimensions(attributes));
+                    datums.push(Datum {
+                        metric: metric.name.clone(),
+                        unix_nanos,
+                        dimensions,
+                        measurements: HashMap::from([(
+                            "value".to_string(),
+                            Measurement { value: Some(value) },
+                        )]),
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+    }
+    datums
+}
+
+fn to_dimensions(attributes: &[KeyValue]) -> HashMap<String, Dimension> {
+    attributes
+        .iter()
+        .filter_map(|attribute| {
+            let value = match attribute.value.as_ref()?.value.as_ref()? {
+                any_value::Value::StringValue(s) => dimension::Value::String(s.clone()),
+                any_value::Value::BoolValue(b) => dimension::Value::Boolean(*b),
+                any_value::Value::IntValue(i) => match u64::try_from(*i) {
+                    Ok(number) => dimension::Value::Number(number),
+                    Err(_) => dimension::Value::String(i.to_string()),
+                },
+                any_value::Value::DoubleValue(f) => dimension::Value::String(f.to_string()),
+                // Arrays, maps and bytes don't make sensible columns
+                _ => return None,
+            };
+            Some((attribute.key.clone(), Dimension { value: Some(value) }))
+        })
+        .collect()
+}
+
+fn number(point: &NumberDataPoint) -> Option<measurement::Value> {
+    point.value.as_ref().map(|value| match value {
+        number_data_point::Value::AsDouble(f) => measurement::Value::F64(*f),
+        number_data_point::Value::AsInt(i) => measurement::Value::I64(*i),
+    })
+}
+
+// Buckets are keyed by their upper bound, rounded up like prometheus histograms are.
+// The overflow bucket has no upper bound, so it ends up at i64::MAX.
+fn explicit_histogram(point: &HistogramDataPoint) -> measurement::Value {
+    let mut buckets: HashMap<i64, u64> = HashMap::new();
+    for (index, count) in point.bucket_counts.iter().enumerate() {
+        if *count == 0 {
+            continue;
+        }
+        let upper_bound = point
+            .explicit_bounds
+            .get(index)
+            .copied()
+            .unwrap_or(f64::INFINITY);
+        *buckets.entry(upper_bound.ceil() as i64).or_default() += count;
+    }
+    measurement::Value::Histogram(Histogram { buckets })
+}
+
+// Bucket i covers (base^(offset+i), base^(offset+i+1)], with base = 2^(2^-scale).
+// Negative buckets mirror the positive ones.
+fn exponential_histogram(point: &ExponentialHistogramDataPoint) -> measurement::Value {
+    let base = 2f64.powf(2f64.powi(-point.scale));
+    let mut buckets: HashMap<i64, u64> = HashMap::new();
+    if point.zero_count > 0 {
+        *buckets.entry(0).or_default() += point.zero_count;
+    }
+    let mut add = |side: &Option<Buckets>, upper_bound: &dyn Fn(i32) -> f64| {
+        let Some(side) = side else {
+            return;
+        };
+        for (index, count) in side.bucket_counts.iter().enumerate() {
+            if *count == 0 {
+                continue;
+            }
+            let bucket = upper_bound(side.offset + index as i32).ceil() as i64;
+            *buckets.entry(bucket).or_default() += count;
+        }
+    };
+    add(&point.positive, &|index| base.powi(index + 1));
+    add(&point.negative, &|index| -base.powi(index));
+    measurement::Value::Histogram(Histogram { buckets })
+}
@@ -0,0 +1,239 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use communication::proto::goodmetrics::{
+    dimension, measurement, Datum, Dimension, Histogram, Measurement, StatisticSet,
+};
+use tokio::net::UdpSocket;
+
+use crate::{
+    self_metrics::STATSD_MALFORMED_LINES,
+    shutdown::ShutdownToken,
+    sink::{metricssendqueue::MetricsSendQueue, MetricsSink},
+};
+
+// Larger than any sane statsd packet, which are sized to fit in one datagram
+const MAX_PACKET_BYTES: usize = 65535;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum SampleKind {
+    Gauge,
+    Counter,
+    Timer,
+    Histogram,
+}
+
+#[derive(Debug)]
+struct Sample<'a> {
+    name: &'a str,
+    value: f64,
+    kind: SampleKind,
+    sample_rate: f64,
+    tags: Vec<(&'a str, &'a str)>,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+struct AggregationKey {
+    metric: String,
+    kind: SampleKind,
+    tags: Vec<(String, String)>,
+}
+
+enum Aggregate {
+    Gauge(f64),
+    Counter(f64),
+    Timer(StatisticSet),
+    Histogram(HashMap<i64, u64>),
+}
+
+/// Listens for statsd and DogStatsD packets, and sends what arrived once per flush interval.
+/// Each metric and tag set becomes 1 datum per flush, with its aggregate in the `value` column.
+pub async fn serve_statsd(
+    address: SocketAddr,
+    flush_interval: Duration,
+    metrics_sink: MetricsSendQueue,
+    shutdown: ShutdownToken,
+) -> std::io::Result<()> {
+    let socket = UdpSocket::bind(address).await?;
+    tracing::info!("listening for statsd on {address}");
+
+    let mut aggregates: HashMap<AggregationKey, Aggregate> = HashMap::new();
+    let mut flush = tokio::time::interval(flush_interval);
+    let mut buffer = vec![0u8; MAX_PACKET_BYTES];
+    let shutdown = shutdown.wait();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buffer) => match received {
+                Ok((length, _)) => record_packet(&mut aggregates, &buffer[..length]),
+                Err(e) => tracing::warn!("failed to receive statsd packet: {e:?}"),
+            },
+            _ = flush.tick() => flush_aggregates(&mut aggregates, &metrics_sink),
+            _ = &mut shutdown => {
+                flush_aggregates(&mut aggregates, &metrics_sink);
+                tracing::info!("statsd server stopped");
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn record_packet(aggregates: &mut HashMap<AggregationKey, Aggregate>, packet: &[u8]) {
+    let packet = String::from_utf8_lossy(packet);
+    for line in packet.lines().map(str::trim).filter(|l| !l.is_empty()) {
+        match parse_line(line) {
+            Ok(Some(sample)) => record_sample(aggregates, sample),
+            Ok(None) => tracing::debug!(line, "skipping unsupported statsd type"),
+            Err(e) => {
+                STATSD_MALFORMED_LINES.inc();
+                tracing::debug!(line, "skipping malformed statsd line: {e}");
+            }
+        }
+    }
+}
+
+// metric.name:value|type|@sample_rate|#tag:value,tag
+fn parse_line(line: &str) -> Result<Option<Sample<'_>>, String> {
+    let (name, rest) = line.split_once(':').ok_or("missing ':'")?;
+    if name.is_empty() {
+        return Err("missing metric name".to_string());
+    }
+    let mut fields = rest.split('|');
+    let value: f64 = fields
+        .next()
+        .unwrap_or_default()
+        .parse()
+        .map_err(|e| format!("bad value: {e}"))?;
+    let kind = match fields.next().ok_or("missing type")? {
+        "g" => SampleKind::Gauge,
+        "c" => SampleKind::Counter,
+        "ms" => SampleKind::Timer,
+        "h" | "d" => SampleKind::Histogram,
+        // Sets and anything newer
+        _ => return Ok(None),
+    };
+
+    let mut sample_rate = 1.0;
+    let mut tags = Vec::new();
+    for field in fields {
+        if let Some(rate) = field.strip_prefix('@') {
+            sample_rate = rate.parse().map_err(|e| format!("bad sample rate: {e}"))?;
+            if !(sample_rate > 0.0 && sample_rate <= 1.0) {
+                return Err(format!("sample rate out of range: {sample_rate}"));
+            }
+        } else if let Some(tag_list) = field.strip_prefix('#') {
+            tags.extend(
+                tag_list
+                    .split(',')
+                    .filter(|tag| !tag.is_empty())
+                    .map(|tag| tag.split_once(':').unwrap_or((tag, ""))),
+            );
+        }
+    }
+
+    Ok(Some(Sample {
+        name,
+        value,
+        kind,
+        sample_rate,
+        tags,
+    }))
+}
+
+fn record_sample(aggregates: &mut HashMap<AggregationKey, Aggregate>, sample: Sample) {
+    let mut tags: Vec<(String, String)> = sample
+        .tags
+        .iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect();
+    tags.sort();
+    let key = AggregationKey {
+        metric: sample.name.replace('.', "_"),
+        kind: sample.kind,
+        tags,
+    };
+    // A sample sent at 1/10th the rate stands for 10 samples
+    let weight = 1.0 / sample.sample_rate;
+    let value = sample.value;
+
+    match aggregates.entry(key).or_insert_with(|| match sample.kind {
+        SampleKind::Gauge => Aggregate::Gauge(value),
+        SampleKind::Counter => Aggregate::Counter(0.0),
+        SampleKind::Timer => Aggregate::Timer(StatisticSet {
+            minimum: value,
+            maximum: value,
+            samplesum: 0.0,
+            samplecount: 0,
+        }),
+        SampleKind::Histogram => Aggregate::Histogram(HashMap::new()),
+    }) {
+        Aggregate::Gauge(gauge) => *gauge = value,
+        Aggregate::Counter(count) => *count += value * weight,
+        Aggregate::Timer(statistic_set) => {
+            let count = weight.round().max(1.0);
+            statistic_set.minimum = statistic_set.minimum.min(value);
+            statistic_set.maximum = statistic_set.maximum.max(value);
+            statistic_set.samplesum += value * count;
+            statistic_set.samplecount += count as u64;
+        }
+        Aggregate::Histogram(buckets) => {
+            *buckets.entry(value.ceil() as i64).or_default() += weight.round().max(1.0) as u64;
+        }
+    }
+}
+
+fn flush_aggregates(
+    aggregates: &mut HashMap<AggregationKey, Aggregate>,
+    metrics_sink: &MetricsSendQueue,
+) {
+    if aggregates.is_empty() {
+        return;
+    }
+    let unix_nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let datums: Vec<Datum> = aggregates
+        .drain()
+        .map(|(key, aggregate)| Datum {
+            metric: key.metric,
+            unix_nanos,
+            dimensions: key
+                .tags
+                .into_iter()
+                .map(|(name, value)| {
+                    (
+                        name,
+                        Dimension {
+                            value: Some(dimension::Value::String(value)),
+                        },
+                    )
+                })
+                .collect(),
+            measurements: HashMap::from([(
+                "value".to_string(),
+                Measurement {
+                    value: Some(match aggregate {
+                        Aggregate::Gauge(gauge) => measurement::Value::F64(gauge),
+                        Aggregate::Counter(count) => measurement::Value::I64(count.round() as i64),
+                        Aggregate::Timer(statistic_set) => {
+                            measurement::Value::StatisticSet(statistic_set)
+                        }
+                        Aggregate::Histogram(buckets) => {
+                            measurement::Value::Histogram(Histogram { buckets })
+                        }
+                    }),
+                },
+            )]),
+            ..Default::default()
+        })
+        .collect();
+
+    tracing::debug!(datums = datums.len(), "flushing statsd");
+    if let Err(e) = metrics_sink.drain(datums) {
+        tracing::warn!("dropping a statsd flush: {e:?}");
+    }
+}
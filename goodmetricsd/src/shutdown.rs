@@ -0,0 +1,33 @@
+use tokio::sync::watch;
+
+/// Lets a server thread find out that goodmetricsd is stopping.
+#[derive(Clone)]
+pub struct ShutdownToken {
+    rx: watch::Receiver<bool>,
+}
+
+pub struct ShutdownTrigger {
+    tx: watch::Sender<bool>,
+}
+
+pub fn shutdown_token() -> (ShutdownTrigger, ShutdownToken) {
+    let (tx, rx) = watch::channel(false);
+    (ShutdownTrigger { tx }, ShutdownToken { rx })
+}
+
+impl ShutdownTrigger {
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+}
+
+impl ShutdownToken {
+    /// Completes once shutdown is triggered, or once nothing can trigger it anymore.
+    pub async fn wait(mut self) {
+        while !*self.rx.borrow_and_update() {
+            if self.rx.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
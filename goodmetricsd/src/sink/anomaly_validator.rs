@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+
+use communication::proto::goodmetrics::{measurement, Datum};
+
+use crate::self_metrics::ANOMALIES_DETECTED;
+
+// Too few samples make for a meaningless standard deviation
+const MINIMUM_HISTORY: u64 = 30;
+// Past this many samples, older ones fade out so the history follows lasting changes
+const DECAY_WINDOW: u64 = 1000;
+
+/// Flags numeric measurements that land absurdly far from what a (metric, measurement) has
+/// reported recently - think integer overflows reporting a trillion nanoseconds of latency.
+/// History is an exponentially weighted mean and variance, so it's a few numbers per column.
+pub struct AnomalyValidator {
+    sigma_threshold: f64,
+    drop_anomalies: bool,
+    history: HashMap<(String, String), RunningStats>,
+}
+
+#[derive(Default)]
+struct RunningStats {
+    count: u64,
+    mean: f64,
+    variance: f64,
+}
+
+impl RunningStats {
+    // Weighted 1/n, this is Welford's algorithm until the window fills
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        let weight = 1.0 / self.count.min(DECAY_WINDOW) as f64;
+        let delta = value - self.mean;
+        self.mean += weight * delta;
+        self.variance = (1.0 - weight) * (self.variance + weight * delta * delta);
+    }
+
+    fn standard_deviation(&self) -> f64 {
+        self.variance.sqrt()
+    }
+}
+
+impl AnomalyValidator {
+    pub fn new(sigma_threshold: f64, drop_anomalies: bool) -> Self {
+        Self {
+            sigma_threshold,
+            drop_anomalies,
+            history: HashMap::new(),
+        }
+    }
+
+    /// Warns about anomalous datums, and drops them if so configured.
+    pub fn filter(&mut self, batch: Vec<Datum>) -> Vec<Datum> {
+        batch
+            .into_iter()
+            .filter(|datum| !self.is_anomalous(datum) || !self.drop_anomalies)
+            .collect()
+    }
+
+    fn is_anomalous(&mut self, datum: &Datum) -> bool {
+        let mut anomalous = false;
+        for (measurement_name, measurement) in &datum.measurements {
+            let value = match &measurement.value {
+                Some(measurement::Value::I64(i)) => *i as f64,
+                Some(measurement::Value::I32(i)) => *i as f64,
+                Some(measurement::Value::F64(f)) => *f,
+                Some(measurement::Value::F32(f)) => *f as f64,
+                // Distributions already describe their own spread
+                _ => continue,
+            };
+            if !value.is_finite() {
+                continue;
+            }
+
+            let stats = self
+                .history
+                .entry((datum.metric.clone(), measurement_name.clone()))
+                .or_default();
+            if stats.count >= MINIMUM_HISTORY {
+                let standard_deviation = stats.standard_deviation();
+                let distance = (value - stats.mean).abs();
+                if standard_deviation > 0.0 && distance > self.sigma_threshold * standard_deviation
+                {
+                    tracing::warn!(
+                        "anomalous measurement {}.{}: {} is {:.1} standard deviations from the mean {}",
+                        datum.metric,
+                        measurement_name,
+                        value,
+                        distance / standard_deviation,
+                        stats.mean,
+                    );
+                    anomalous = true;
+                    // Clamped to the threshold: a lone spike barely moves the history, but a
+                    // level shift keeps pulling it along until the new level is normal.
+                    let clamped = self.sigma_threshold * standard_deviation;
+                    stats.add(stats.mean + clamped.copysign(value - stats.mean));
+                    continue;
+                }
+            }
+            stats.add(value);
+        }
+        if anomalous {
+            ANOMALIES_DETECTED.inc();
+        }
+        anomalous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use communication::proto::goodmetrics::{measurement, Datum, Measurement};
+
+    use super::{AnomalyValidator, MINIMUM_HISTORY};
+
+    fn latency(value: f64) -> Datum {
+        Datum {
+            metric: "requests".to_string(),
+            measurements: [(
+                "latency".to_string(),
+                Measurement {
+                    value: Some(measurement::Value::F64(value)),
+                },
+            )]
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    // Alternating 99 and 101: a mean of 100 and a standard deviation of about 1
+    fn steady_history(validator: &mut AnomalyValidator, samples: u64) {
+        for i in 0..samples {
+            let value = if i % 2 == 0 { 99.0 } else { 101.0 };
+            assert_eq!(1, validator.filter(vec![latency(value)]).len());
+        }
+    }
+
+    #[test]
+    fn drops_outliers_once_there_is_history() {
+        let mut validator = AnomalyValidator::new(3.0, true);
+        // Nothing is judged before there's enough history
+        assert_eq!(1, validator.filter(vec![latency(1e12)]).len());
+
+        let mut validator = AnomalyValidator::new(3.0, true);
+        steady_history(&mut validator, MINIMUM_HISTORY * 2);
+        assert!(validator.filter(vec![latency(1e12)]).is_empty());
+        assert!(validator.filter(vec![latency(-1e12)]).is_empty());
+        assert_eq!(1, validator.filter(vec![latency(102.0)]).len());
+    }
+
+    #[test]
+    fn only_warns_without_drop_anomalies() {
+        let mut validator = AnomalyValidator::new(3.0, false);
+        steady_history(&mut validator, MINIMUM_HISTORY * 2);
+        assert_eq!(1, validator.filter(vec![latency(1e12)]).len());
+    }
+
+    #[test]
+    fn lone_spike_leaves_the_history_alone() {
+        let mut validator = AnomalyValidator::new(3.0, true);
+        steady_history(&mut validator, 500);
+        assert!(validator.filter(vec![latency(1e12)]).is_empty());
+        // Still judged against the old level
+        assert!(validator.filter(vec![latency(120.0)]).is_empty());
+        assert_eq!(1, validator.filter(vec![latency(100.0)]).len());
+    }
+
+    #[test]
+    fn level_shift_is_learned() {
+        let mut validator = AnomalyValidator::new(3.0, true);
+        steady_history(&mut validator, 5000);
+
+        let dropped = (0..5000)
+            .take_while(|_| validator.filter(vec![latency(1000.0)]).is_empty())
+            .count();
+        assert!(0 < dropped, "the shift starts out anomalous");
+        assert!(dropped < 5000, "the new level is never accepted");
+        // And the new level stays accepted
+        for _ in 0..100 {
+            assert_eq!(1, validator.filter(vec![latency(1000.0)]).len());
+        }
+    }
+}
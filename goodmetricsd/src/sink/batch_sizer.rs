@@ -0,0 +1,154 @@
+use std::{collections::VecDeque, sync::Mutex, time::Duration};
+
+const MIN_BATCH_DATUMS: usize = 1_000;
+const MAX_BATCH_DATUMS: usize = 1_000_000;
+const INITIAL_BATCH_DATUMS: usize = 100_000;
+// COPYs the p95 is taken over
+const DURATION_WINDOW: usize = 64;
+
+/// Picks how many datums the next batch may collect, so COPYs stay under a target duration.
+/// The limit halves when the rolling p95 COPY duration is over the target, and grows by a
+/// quarter while it's under half of the target.
+pub struct BatchSizer {
+    target_copy_duration: Duration,
+    state: Mutex<SizerState>,
+}
+
+struct SizerState {
+    copy_durations: VecDeque<Duration>,
+    limit: usize,
+}
+
+impl BatchSizer {
+    pub fn new(target_copy_duration: Duration) -> Self {
+        Self {
+            target_copy_duration,
+            state: Mutex::new(SizerState {
+                copy_durations: VecDeque::with_capacity(DURATION_WINDOW),
+                limit: INITIAL_BATCH_DATUMS,
+            }),
+        }
+    }
+
+    /// Most datums the next batch should collect
+    pub fn limit(&self) -> usize {
+        self.state.lock().expect("batch sizer lock").limit
+    }
+
+    pub fn record_copy(&self, duration: Duration) {
+        let mut state = self.state.lock().expect("batch sizer lock");
+        if state.copy_durations.len() == DURATION_WINDOW {
+            state.copy_durations.pop_front();
+        }
+        state.copy_durations.push_back(duration);
+    }
+
+    /// Called between batches, with the COPYs of the last batch recorded
+    pub fn adjust(&self) {
+        let mut state = self.state.lock().expect("batch sizer lock");
+        let mut durations: Vec<Duration> = state.copy_durations.iter().copied().collect();
+        if durations.is_empty() {
+            return;
+        }
+        durations.sort_unstable();
+        let p95 = durations[(durations.len() - 1) * 95 / 100];
+
+        let limit = if self.target_copy_duration < p95 {
+            (state.limit / 2).max(MIN_BATCH_DATUMS)
+        } else if p95 < self.target_copy_duration / 2 {
+            (state.limit + state.limit / 4).min(MAX_BATCH_DATUMS)
+        } else {
+            state.limit
+        };
+        if limit != state.limit {
+            tracing::info!(
+                "batch limit {} -> {limit}: p95 copy duration {p95:?}, target {:?}",
+                state.limit,
+                self.target_copy_duration
+            );
+            // The old durations were for a different batch size
+            state.copy_durations.clear();
+            state.limit = limit;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{BatchSizer, INITIAL_BATCH_DATUMS, MAX_BATCH_DATUMS, MIN_BATCH_DATUMS};
+
+    // Runs batches through a sink whose COPYs take `per_datum` for each datum, a few COPYs
+    // per batch like the sender does, and returns the limit after each batch.
+    fn simulate(sizer: &BatchSizer, per_datum: Duration, batches: usize) -> Vec<usize> {
+        (0..batches)
+            .map(|_| {
+                let copy_duration = per_datum * (sizer.limit() / 4) as u32;
+                for _ in 0..4 {
+                    sizer.record_copy(copy_duration);
+                }
+                sizer.adjust();
+                sizer.limit()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn slow_sink_shrinks_batches_until_copies_fit() {
+        let target = Duration::from_secs(1);
+        let sizer = BatchSizer::new(target);
+        // 25k datum COPYs take 2.5s
+        let per_datum = Duration::from_micros(100);
+
+        let limits = simulate(&sizer, per_datum, 20);
+
+        assert_eq!(INITIAL_BATCH_DATUMS / 2, limits[0]);
+        assert_eq!(INITIAL_BATCH_DATUMS / 4, limits[1]);
+        let settled = *limits.last().unwrap();
+        let copy_duration = per_datum * (settled / 4) as u32;
+        assert!(copy_duration <= target, "{copy_duration:?} at {settled}");
+        assert!(target / 4 < copy_duration, "{copy_duration:?} at {settled}");
+        // It stays put once the COPYs fit
+        assert_eq!(settled, limits[limits.len() - 5]);
+    }
+
+    #[test]
+    fn hopeless_sink_bottoms_out() {
+        let sizer = BatchSizer::new(Duration::from_millis(10));
+        let limits = simulate(&sizer, Duration::from_millis(1), 20);
+        assert_eq!(MIN_BATCH_DATUMS, *limits.last().unwrap());
+    }
+
+    #[test]
+    fn fast_sink_grows_batches_up_to_the_maximum() {
+        let sizer = BatchSizer::new(Duration::from_secs(1));
+        // 25k datum COPYs take 25ms
+        let limits = simulate(&sizer, Duration::from_micros(1), 40);
+
+        assert_eq!(INITIAL_BATCH_DATUMS + INITIAL_BATCH_DATUMS / 4, limits[0]);
+        assert!(limits.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert_eq!(MAX_BATCH_DATUMS, *limits.last().unwrap());
+    }
+
+    #[test]
+    fn a_few_slow_copies_dont_shrink_batches() {
+        let sizer = BatchSizer::new(Duration::from_secs(1));
+        // Well inside the window's 5%
+        for _ in 0..2 {
+            sizer.record_copy(Duration::from_secs(5));
+        }
+        for _ in 0..62 {
+            sizer.record_copy(Duration::from_millis(700));
+        }
+        sizer.adjust();
+        assert_eq!(INITIAL_BATCH_DATUMS, sizer.limit());
+
+        // Once they're more than that, they count. The first 2 have left the window by now.
+        for _ in 0..6 {
+            sizer.record_copy(Duration::from_secs(5));
+        }
+        sizer.adjust();
+        assert_eq!(INITIAL_BATCH_DATUMS / 2, sizer.limit());
+    }
+}
@@ -0,0 +1,57 @@
+use std::{collections::HashSet, num::NonZeroUsize};
+
+use communication::proto::goodmetrics::{dimension, Datum};
+use lru::LruCache;
+
+use crate::self_metrics::CARDINALITY_TRUNCATED;
+
+pub const CARDINALITY_EXCEEDED: &str = "__cardinality_exceeded__";
+
+// Columns that haven't been written in a while are forgotten, and start counting again
+const TRACKED_COLUMNS: usize = 1024;
+
+/// Keeps things like request ids out of string dimension columns.
+/// Once a (table, column) has seen the limit of distinct values, new values are written
+/// as `__cardinality_exceeded__` instead.
+pub struct CardinalityGuard {
+    max_distinct_values: usize,
+    // (table, column) -> distinct values seen
+    observed: LruCache<(String, String), HashSet<String>>,
+}
+
+impl CardinalityGuard {
+    pub fn new(max_distinct_values: usize) -> Self {
+        Self {
+            max_distinct_values,
+            observed: LruCache::new(NonZeroUsize::new(TRACKED_COLUMNS).expect("nonzero")),
+        }
+    }
+
+    pub fn enforce(&mut self, table: &str, datums: &mut [Datum]) {
+        for datum in datums.iter_mut() {
+            for (column, dimension) in datum.dimensions.iter_mut() {
+                let Some(dimension::Value::String(value)) = &mut dimension.value else {
+                    continue;
+                };
+                let key = (table.to_string(), column.clone());
+                let seen = self.observed.get_or_insert_mut(key, HashSet::new);
+                if seen.contains(value.as_str()) {
+                    continue;
+                }
+                if seen.len() < self.max_distinct_values {
+                    seen.insert(value.clone());
+                    continue;
+                }
+                tracing::warn!(
+                    table,
+                    column = %column,
+                    max_distinct_values = self.max_distinct_values,
+                    value = %value,
+                    "too many distinct values, replacing the value"
+                );
+                CARDINALITY_TRUNCATED.inc();
+                *value = CARDINALITY_EXCEEDED.to_string();
+            }
+        }
+    }
+}
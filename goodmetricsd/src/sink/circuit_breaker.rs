@@ -0,0 +1,123 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::Instant;
+
+use crate::self_metrics::{CIRCUIT_BREAKER_STATE, CIRCUIT_BREAKER_TRANSITIONS};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum BreakerState {
+    Closed,
+    Open(Instant),
+    HalfOpen,
+}
+
+impl BreakerState {
+    fn name(&self) -> &'static str {
+        match self {
+            BreakerState::Closed => "closed",
+            BreakerState::Open(_) => "open",
+            BreakerState::HalfOpen => "half_open",
+        }
+    }
+
+    // The value of goodmetrics_circuit_breaker_state
+    fn gauge_value(&self) -> i64 {
+        match self {
+            BreakerState::Closed => 0,
+            BreakerState::Open(_) => 1,
+            BreakerState::HalfOpen => 2,
+        }
+    }
+}
+
+struct Breaker {
+    state: BreakerState,
+    consecutive_failures: u32,
+    probe_in_flight: bool,
+}
+
+/// Stops sends from queueing up on a postgres connection pool that can't connect.
+/// It opens after enough consecutive connection failures, and once the back-off passes
+/// lets a single send probe the pool before closing again.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_for: Duration,
+    breaker: Mutex<Breaker>,
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_for: Duration) -> Self {
+        CIRCUIT_BREAKER_STATE.set(BreakerState::Closed.gauge_value());
+        Self {
+            failure_threshold,
+            open_for,
+            breaker: Mutex::new(Breaker {
+                state: BreakerState::Closed,
+                consecutive_failures: 0,
+                probe_in_flight: false,
+            }),
+        }
+    }
+
+    /// Whether a send may try to get a connection now
+    pub fn allow(&self) -> bool {
+        let mut breaker = self.breaker.lock().expect("circuit breaker lock");
+        match breaker.state {
+            BreakerState::Closed => true,
+            BreakerState::Open(until) => {
+                if Instant::now() < until {
+                    return false;
+                }
+                transition(&mut breaker, BreakerState::HalfOpen);
+                breaker.probe_in_flight = true;
+                true
+            }
+            BreakerState::HalfOpen => {
+                if breaker.probe_in_flight {
+                    return false;
+                }
+                breaker.probe_in_flight = true;
+                true
+            }
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut breaker = self.breaker.lock().expect("circuit breaker lock");
+        breaker.consecutive_failures = 0;
+        breaker.probe_in_flight = false;
+        if breaker.state != BreakerState::Closed {
+            transition(&mut breaker, BreakerState::Closed);
+        }
+    }
+
+    pub fn record_failure(&self) {
+        let mut breaker = self.breaker.lock().expect("circuit breaker lock");
+        breaker.consecutive_failures += 1;
+        breaker.probe_in_flight = false;
+        let should_open = match breaker.state {
+            BreakerState::Closed => self.failure_threshold <= breaker.consecutive_failures,
+            BreakerState::HalfOpen => true,
+            BreakerState::Open(_) => false,
+        };
+        if should_open {
+            transition(
+                &mut breaker,
+                BreakerState::Open(Instant::now() + self.open_for),
+            );
+        }
+    }
+}
+
+fn transition(breaker: &mut Breaker, to: BreakerState) {
+    tracing::info!(
+        "postgres circuit breaker {} -> {}",
+        breaker.state.name(),
+        to.name()
+    );
+    CIRCUIT_BREAKER_TRANSITIONS
+        .with_label_values(&[to.name()])
+        .inc();
+    CIRCUIT_BREAKER_STATE.set(to.gauge_value());
+    breaker.state = to;
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+
+use communication::proto::goodmetrics::Datum;
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_postgres::types::WrongType;
+
+use super::{file_sink::FileFallbackSink, sink_error::SinkError};
+
+/// A batch that postgres refused for good, and why.
+pub struct DeadLetter {
+    pub metric: String,
+    pub reason: String,
+    pub datums: Vec<Datum>,
+}
+
+/// Where `send_some` puts batches it gives up on, so losing them is at least visible.
+/// It's bounded: when the drain can't keep up, letters are dropped with an error log.
+#[derive(Clone)]
+pub struct MetricsDLQ {
+    tx: mpsc::Sender<DeadLetter>,
+}
+
+/// Empties a `MetricsDLQ`, keeping tallies and optionally saving the datums to disk.
+pub struct DeadLetterDrain {
+    rx: mpsc::Receiver<DeadLetter>,
+    file_sink: Option<FileFallbackSink>,
+    dropped_datums_total: u64,
+    dropped_datums_by_reason: HashMap<String, u64>,
+}
+
+impl MetricsDLQ {
+    pub fn new(capacity: usize, file_sink: Option<FileFallbackSink>) -> (Self, DeadLetterDrain) {
+        let (tx, rx) = mpsc::channel(capacity.max(1));
+        (
+            Self { tx },
+            DeadLetterDrain {
+                rx,
+                file_sink,
+                dropped_datums_total: 0,
+                dropped_datums_by_reason: HashMap::new(),
+            },
+        )
+    }
+
+    pub fn push(&self, metric: String, reason: String, datums: Vec<Datum>) {
+        match self.tx.try_send(DeadLetter {
+            metric,
+            reason,
+            datums,
+        }) {
+            Ok(_) => {}
+            Err(TrySendError::Full(letter)) => tracing::error!(
+                "dead letter queue is full, losing {} datums of {}: {}",
+                letter.datums.len(),
+                letter.metric,
+                letter.reason
+            ),
+            Err(TrySendError::Closed(letter)) => tracing::error!(
+                "dead letter queue is closed, losing {} datums of {}: {}",
+                letter.datums.len(),
+                letter.metric,
+                letter.reason
+            ),
+        }
+    }
+}
+
+impl DeadLetterDrain {
+    pub async fn drain(mut self) {
+        while let Some(letter) = self.rx.recv().await {
+            let count = letter.datums.len() as u64;
+            self.dropped_datums_total += count;
+            let reason_total = self
+                .dropped_datums_by_reason
+                .entry(letter.reason.clone())
+                .or_default();
+            *reason_total += count;
+
+            tracing::error!(
+                metric = %letter.metric,
+                reason = %letter.reason,
+                datums = count,
+                dropped_datums_total = self.dropped_datums_total,
+                dropped_datums_for_reason = *reason_total,
+                "dead letter"
+            );
+
+            if let Some(file_sink) = &self.file_sink {
+                if let Err(e) = file_sink.persist(&letter.datums).await {
+                    tracing::error!("failed to save dead letter for {}: {e:?}", letter.metric);
+                }
+            }
+        }
+        tracing::info!("dead letter queue closed");
+    }
+}
+
+/// A low-cardinality name for why a batch failed, for tallying
+pub fn dead_letter_reason(error: &SinkError) -> String {
+    match error {
+        SinkError::Postgres(postgres_error) => match postgres_error.as_db_error() {
+            Some(db_error) => format!("sqlstate_{}", db_error.code().code()),
+            None => match std::error::Error::source(postgres_error) {
+                Some(client_error) if client_error.is::<WrongType>() => "wrong_type".to_string(),
+                _ => "postgres_client".to_string(),
+            },
+        },
+        SinkError::DescribedError(_) => "described".to_string(),
+        SinkError::StringError(_) => "string".to_string(),
+        SinkError::MissingColumn(_) => "missing_column".to_string(),
+        SinkError::MissingTable(_) => "missing_table".to_string(),
+        SinkError::ColumnTypeChange(_) => "column_type_change".to_string(),
+        SinkError::BadRow(_) => "bad_row".to_string(),
+        SinkError::OtherError(_) => "other".to_string(),
+    }
+}
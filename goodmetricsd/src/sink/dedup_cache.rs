@@ -0,0 +1,67 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    time::Duration,
+};
+
+use communication::proto::goodmetrics::Datum;
+use tokio::time::Instant;
+
+use crate::self_metrics::DEDUPLICATED_DATUMS;
+
+/// Remembers the datums this sender received recently, so a client resending a batch whose
+/// RPC failed after it was received doesn't write it twice.
+/// Only hashes are kept; a 64 bit collision within the window is unlikely enough to ignore.
+/// Hashes are forgotten once they're older than the window, or oldest first past the cap.
+pub struct DeduplicationCache {
+    window: Duration,
+    max_entries: usize,
+    seen: HashSet<u64>,
+    // Oldest first, for expiring
+    arrivals: VecDeque<(Instant, u64)>,
+}
+
+impl DeduplicationCache {
+    pub fn new(window: Duration, max_entries: usize) -> Self {
+        Self {
+            window,
+            max_entries: max_entries.max(1),
+            seen: HashSet::new(),
+            arrivals: VecDeque::new(),
+        }
+    }
+
+    pub fn filter(&mut self, batch: Vec<Datum>) -> Vec<Datum> {
+        let now = Instant::now();
+        self.expire(now);
+        batch
+            .into_iter()
+            .filter(|datum| {
+                let hash = datum.content_hash();
+                if !self.seen.insert(hash) {
+                    DEDUPLICATED_DATUMS.inc();
+                    return false;
+                }
+                self.arrivals.push_back((now, hash));
+                if self.max_entries < self.arrivals.len() {
+                    self.forget_oldest();
+                }
+                true
+            })
+            .collect()
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((arrived, _)) = self.arrivals.front() {
+            if now.duration_since(*arrived) < self.window {
+                break;
+            }
+            self.forget_oldest();
+        }
+    }
+
+    fn forget_oldest(&mut self) {
+        if let Some((_, hash)) = self.arrivals.pop_front() {
+            self.seen.remove(&hash);
+        }
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    collections::VecDeque,
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use lettre::{message::Mailbox, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use tokio::time::Instant;
+
+use crate::config::options::EmailAlertConfig;
+
+use super::sink_error::SinkError;
+
+const ERROR_RATE_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Emails someone when too many postgres writes fail, and again once they mostly stop failing.
+/// The rate is failed batches over all batches in the last 5 minutes.
+pub struct WriteErrorAlerter {
+    config: EmailAlertConfig,
+    from: Mailbox,
+    to: Vec<Mailbox>,
+    mailer: AsyncSmtpTransport<Tokio1Executor>,
+    state: Mutex<AlertState>,
+}
+
+#[derive(Default)]
+struct AlertState {
+    // (when, whether it failed)
+    outcomes: VecDeque<(Instant, bool)>,
+    last_error: Option<(SystemTime, String)>,
+    alerting: bool,
+    last_email: Option<Instant>,
+}
+
+enum Notification {
+    Alert,
+    Resolved,
+}
+
+impl WriteErrorAlerter {
+    pub fn new(smtp_server: &str, config: EmailAlertConfig) -> Result<Self, SinkError> {
+        // clap makes the from address go with the smtp server
+        let from: Mailbox = config
+            .from_address
+            .as_deref()
+            .unwrap_or_default()
+            .parse()
+            .map_err(|e| SinkError::other("invalid alert from address", Box::new(e)))?;
+        let to = config
+            .to_addresses
+            .iter()
+            .map(|address| {
+                address
+                    .parse()
+                    .map_err(|e| SinkError::other("invalid alert to address", Box::new(e)))
+            })
+            .collect::<Result<Vec<Mailbox>, SinkError>>()?;
+        let mailer = AsyncSmtpTransport::<Tokio1Executor>::relay(smtp_server)
+            .map_err(|e| SinkError::other("invalid smtp server", Box::new(e)))?
+            .build();
+
+        Ok(Self {
+            config,
+            from,
+            to,
+            mailer,
+            state: Mutex::new(AlertState::default()),
+        })
+    }
+
+    pub fn record_success(&self) {
+        self.record(None)
+    }
+
+    pub fn record_failure(&self, error: String) {
+        self.record(Some(error))
+    }
+
+    fn record(&self, error: Option<String>) {
+        let now = Instant::now();
+        // The lock is let go before the email goes out
+        let (notification, error_rate, last_error) = {
+            let mut state = self.state.lock().expect("alert state lock");
+            state.outcomes.push_back((now, error.is_some()));
+            if let Some(error) = error {
+                state.last_error = Some((SystemTime::now(), error));
+            }
+            while let Some((when, _failed)) = state.outcomes.front() {
+                if now.duration_since(*when) <= ERROR_RATE_WINDOW {
+                    break;
+                }
+                state.outcomes.pop_front();
+            }
+
+            let failures = state
+                .outcomes
+                .iter()
+                .filter(|(_when, failed)| *failed)
+                .count();
+            let error_rate = failures as f64 / state.outcomes.len() as f64;
+            let may_email = state
+                .last_email
+                .map(|last_email| now.duration_since(last_email) >= self.config.alert_interval)
+                .unwrap_or(true);
+
+            let notification = if !may_email {
+                None
+            } else if !state.alerting && self.config.error_rate_threshold < error_rate {
+                state.alerting = true;
+                Some(Notification::Alert)
+            } else if state.alerting && error_rate < self.config.error_rate_threshold / 2.0 {
+                state.alerting = false;
+                Some(Notification::Resolved)
+            } else {
+                None
+            };
+            if notification.is_some() {
+                state.last_email = Some(now);
+            }
+            (notification, error_rate, state.last_error.clone())
+        };
+
+        if let Some(notification) = notification {
+            self.send(notification, error_rate, last_error);
+        }
+    }
+
+    // The email goes out on its own task, so a slow smtp relay doesn't hold up the writes
+    fn send(
+        &self,
+        notification: Notification,
+        error_rate: f64,
+        last_error: Option<(SystemTime, String)>,
+    ) {
+        let (subject, headline) = match notification {
+            Notification::Alert => (
+                "goodmetrics: postgres write errors",
+                "Postgres writes are failing more than the alert threshold.",
+            ),
+            Notification::Resolved => (
+                "goodmetrics: postgres write errors resolved",
+                "Postgres write errors are back under half of the alert threshold.",
+            ),
+        };
+        let last_error = match last_error {
+            Some((when, error)) => format!(
+                "Last error, at {}:\n{error}",
+                humantime::format_rfc3339_seconds(when)
+            ),
+            None => "No errors recorded.".to_string(),
+        };
+        let body = format!(
+            "{headline}\n\nError rate over the last 5 minutes: {:.2}% (threshold: {:.2}%)\nAt: {}\n\n{last_error}\n",
+            error_rate * 100.0,
+            self.config.error_rate_threshold * 100.0,
+            humantime::format_rfc3339_seconds(SystemTime::now()),
+        );
+
+        let mut builder = Message::builder().from(self.from.clone()).subject(subject);
+        for to in &self.to {
+            builder = builder.to(to.clone());
+        }
+        let message = match builder.body(body) {
+            Ok(message) => message,
+            Err(e) => {
+                tracing::error!("could not build alert email: {e:?}");
+                return;
+            }
+        };
+        let mailer = self.mailer.clone();
+        tokio::spawn(async move {
+            match mailer.send(message).await {
+                Ok(_) => tracing::info!("sent alert email: {subject}"),
+                Err(e) => tracing::error!("could not send alert email: {e:?}"),
+            }
+        });
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use communication::proto::goodmetrics::Datum;
+
+use super::sink_error::SinkError;
+
+const PENDING_EXTENSION: &str = "ndjson";
+// Files that couldn't be read at all, kept for a person to look at rather than retried forever
+const BAD_EXTENSION: &str = "bad";
+
+/// Keeps batches that could not reach postgres on disk, one newline-delimited json Datum
+/// per line, so they can be replayed once postgres is back.
+/// File names start with their creation time, so sorting them by name is chronological.
+pub struct FileFallbackSink {
+    directory: PathBuf,
+    max_directory_bytes: u64,
+    sequence: AtomicU64,
+    persisted: AtomicBool,
+}
+
+impl FileFallbackSink {
+    pub async fn new(directory: PathBuf, max_directory_bytes: u64) -> Result<Self, SinkError> {
+        tokio::fs::create_dir_all(&directory).await.map_err(|e| {
+            SinkError::other(
+                format!("could not create fallback directory {directory:?}"),
+                Box::new(e),
+            )
+        })?;
+
+        Ok(Self {
+            directory,
+            max_directory_bytes,
+            sequence: AtomicU64::new(0),
+            persisted: AtomicBool::new(false),
+        })
+    }
+
+    pub async fn persist(&self, datums: &[Datum]) -> Result<(), SinkError> {
+        let mut contents = Vec::with_capacity(datums.len() * 256);
+        for datum in datums {
+            serde_json::to_writer(&mut contents, datum)
+                .map_err(|e| SinkError::other("failed to serialize datum", Box::new(e)))?;
+            contents.push(b'\n');
+        }
+
+        let now_nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos();
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let path = self
+            .directory
+            .join(format!("{now_nanos:020}-{sequence:06}.{PENDING_EXTENSION}"));
+
+        // Written aside and renamed into place so a replay never picks up a half-written file
+        let temporary_path = path.with_extension("tmp");
+        tokio::fs::write(&temporary_path, contents)
+            .await
+            .map_err(|e| SinkError::other("failed to write fallback file", Box::new(e)))?;
+        tokio::fs::rename(&temporary_path, &path)
+            .await
+            .map_err(|e| SinkError::other("failed to move fallback file", Box::new(e)))?;
+        self.persisted.store(true, Ordering::Relaxed);
+        tracing::info!("saved {} datums to {:?}", datums.len(), path);
+
+        self.enforce_size_limit().await
+    }
+
+    /// Whether anything was persisted since the last time this was asked
+    pub fn take_persisted_flag(&self) -> bool {
+        self.persisted.swap(false, Ordering::Relaxed)
+    }
+
+    /// Oldest first
+    pub async fn pending_files(&self) -> Result<Vec<PathBuf>, SinkError> {
+        Ok(self
+            .pending_files_with_sizes()
+            .await?
+            .into_iter()
+            .map(|(path, _size)| path)
+            .collect())
+    }
+
+    /// A crash can leave the last line cut off, and a file may not have been written by this
+    /// sink, so lines that aren't datums are skipped rather than failing the whole file.
+    pub async fn read_file_skipping_malformed(path: &Path) -> Result<Vec<Datum>, SinkError> {
+        let contents = tokio::fs::read_to_string(path)
+            .await
+            .map_err(|e| SinkError::other("failed to read fallback file", Box::new(e)))?;
+        Ok(contents
+            .lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .filter_map(|(index, line)| match serde_json::from_str(line) {
+                Ok(datum) => Some(datum),
+                Err(e) => {
+                    tracing::warn!(path = ?path, line = index + 1, "skipping malformed datum: {e}");
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Moves a file out of the pending files, so it isn't tried again
+    pub async fn set_aside(&self, path: &Path) {
+        let bad_path = path.with_extension(BAD_EXTENSION);
+        match tokio::fs::rename(path, &bad_path).await {
+            Ok(()) => tracing::warn!("set aside unreadable fallback file as {bad_path:?}"),
+            Err(e) => tracing::error!("failed to set aside fallback file {path:?}: {e:?}"),
+        }
+    }
+
+    pub async fn remove(&self, path: &Path) {
+        if let Err(e) = tokio::fs::remove_file(path).await {
+            tracing::error!("failed to remove fallback file {path:?}: {e:?}");
+        }
+    }
+
+    async fn enforce_size_limit(&self) -> Result<(), SinkError> {
+        let files = self.pending_files_with_sizes().await?;
+        let mut total_bytes: u64 = files.iter().map(|(_path, size)| size).sum();
+        for (path, size) in files {
+            if total_bytes <= self.max_directory_bytes {
+                break;
+            }
+            tracing::warn!(
+                "fallback directory is over {} bytes, deleting oldest file {:?}",
+                self.max_directory_bytes,
+                path
+            );
+            self.remove(&path).await;
+            total_bytes -= size;
+        }
+        Ok(())
+    }
+
+    async fn pending_files_with_sizes(&self) -> Result<Vec<(PathBuf, u64)>, SinkError> {
+        let mut entries = tokio::fs::read_dir(&self.directory)
+            .await
+            .map_err(|e| SinkError::other("failed to list fallback directory", Box::new(e)))?;
+        let mut files = Vec::new();
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .map_err(|e| SinkError::other("failed to list fallback directory", Box::new(e)))?
+        {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some(PENDING_EXTENSION) {
+                continue;
+            }
+            let metadata = entry
+                .metadata()
+                .await
+                .map_err(|e| SinkError::other("failed to stat fallback file", Box::new(e)))?;
+            files.push((path, metadata.len()));
+        }
+        files.sort();
+        Ok(files)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use communication::proto::goodmetrics::Datum;
+
+    use super::FileFallbackSink;
+
+    #[tokio::test]
+    async fn set_aside_files_are_no_longer_pending() {
+        let directory = std::env::temp_dir().join(format!(
+            "goodmetrics-fallback-set-aside-{}",
+            std::process::id()
+        ));
+        let sink = FileFallbackSink::new(directory.clone(), u64::MAX)
+            .await
+            .unwrap();
+        let datum = Datum {
+            metric: "requests".to_string(),
+            ..Default::default()
+        };
+        sink.persist(std::slice::from_ref(&datum)).await.unwrap();
+        sink.persist(&[datum]).await.unwrap();
+
+        let pending = sink.pending_files().await.unwrap();
+        assert_eq!(2, pending.len());
+        sink.set_aside(&pending[0]).await;
+        assert_eq!(pending[1..], sink.pending_files().await.unwrap());
+        assert!(pending[0].with_extension("bad").exists());
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn malformed_lines_are_skipped() {
+        let path = std::env::temp_dir().join(format!(
+            "goodmetrics-fallback-malformed-{}.ndjson",
+            std::process::id()
+        ));
+        let line = |metric: &str| {
+            serde_json::to_string(&Datum {
+                metric: metric.to_string(),
+                ..Default::default()
+            })
+            .unwrap()
+        };
+        // Including a last line cut off by a crash
+        let contents = format!(
+            "{}\nnot a datum\n\n{}\n{{\"metr",
+            line("requests"),
+            line("errors")
+        );
+        std::fs::write(&path, contents).unwrap();
+        let datums = FileFallbackSink::read_file_skipping_malformed(&path)
+            .await
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let metrics: Vec<&str> = datums.iter().map(|datum| datum.metric.as_str()).collect();
+        assert_eq!(["requests", "errors"], metrics[..]);
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use communication::proto::goodmetrics::{Datum, MetricsRequest};
+use itertools::Itertools;
+use prost::Message;
+use rdkafka::{
+    producer::{FutureProducer, FutureRecord},
+    util::Timeout,
+    ClientConfig,
+};
+use tokio::time::{sleep, timeout_at, Instant};
+
+use crate::config::options::KafkaOptions;
+
+use super::{metricssendqueue::MetricsReceiveQueue, sink_error::SinkError};
+
+const GOODMETRICS_PROTO: &str = include_str!("../../../proto/metrics/goodmetrics.proto");
+const MAX_PRODUCE_RETRIES: u32 = 5;
+
+/// Publishes each metric's datums to `{prefix}.{metric}`, one MetricsRequest record per
+/// metric per batch. Consumers decode the same proto the grpc server accepts.
+pub struct KafkaSender {
+    rx: MetricsReceiveQueue,
+    producer: FutureProducer,
+    topic_prefix: String,
+    schema_registry: Option<SchemaRegistry>,
+}
+
+impl KafkaSender {
+    pub fn new_connection(
+        bootstrap_servers: &str,
+        rx: MetricsReceiveQueue,
+        options: &KafkaOptions,
+    ) -> Result<KafkaSender, SinkError> {
+        let producer: FutureProducer = ClientConfig::new()
+            .set("bootstrap.servers", bootstrap_servers)
+            .set("acks", &options.acks)
+            .set("compression.codec", &options.compression)
+            .create()
+            .map_err(|e| SinkError::other("could not create kafka producer", Box::new(e)))?;
+
+        Ok(KafkaSender {
+            rx,
+            producer,
+            topic_prefix: options.topic_prefix.clone(),
+            schema_registry: options
+                .schema_registry_url
+                .as_ref()
+                .map(|url| SchemaRegistry::new(url.clone())),
+        })
+    }
+
+    pub async fn consume_stuff(mut self) -> Result<u32, SinkError> {
+        tracing::info!("started kafka consumer");
+
+        while let Some(mut batch) = self.rx.recv().await {
+            tracing::info!("Sender woke. Trying to collect a batch...");
+
+            let deadline = Instant::now() + Duration::from_secs(1);
+            let mut api_calls: u32 = 1;
+            while let Ok(Some(mut extras)) = timeout_at(deadline, self.rx.recv()).await {
+                api_calls += 1;
+                batch.append(&mut extras);
+            }
+
+            let grouped_metrics: Vec<(String, Vec<Datum>)> = batch
+                .into_iter()
+                .sorted_by(|a, b| a.metric.cmp(&b.metric))
+                .group_by(|datum| datum.metric.clone())
+                .into_iter()
+                .map(|(metric, datums)| (metric, datums.collect()))
+                .collect();
+            tracing::info!(
+                "Publishing some metrics. metrics: {}, api calls: {}",
+                grouped_metrics.len(),
+                api_calls,
+            );
+
+            for (metric, datums) in grouped_metrics {
+                self.publish(metric, datums).await;
+            }
+            self.rx.batch_done();
+        }
+        tracing::info!("ended consumer");
+        Ok(1)
+    }
+
+    async fn publish(&mut self, metric: String, datums: Vec<Datum>) {
+        let topic = format!("{}.{}", self.topic_prefix, metric);
+        let mut payload = match &mut self.schema_registry {
+            Some(schema_registry) => match schema_registry.wire_header(&topic).await {
+                Ok(header) => header,
+                Err(e) => {
+                    tracing::error!(
+                        "Dropping {metric} because its schema is not registered: {e:?}"
+                    );
+                    return;
+                }
+            },
+            None => Vec::new(),
+        };
+        let datum_count = datums.len();
+        payload.extend(
+            MetricsRequest {
+                shared_dimensions: HashMap::new(),
+                metrics: datums,
+            }
+            .encode_to_vec(),
+        );
+
+        let mut backoff = Duration::from_millis(100);
+        for attempt in 0..=MAX_PRODUCE_RETRIES {
+            let record = FutureRecord::to(&topic).key(&metric).payload(&payload);
+            match self
+                .producer
+                .send(record, Timeout::After(Duration::from_secs(5)))
+                .await
+            {
+                Ok((partition, offset)) => {
+                    tracing::info!("published {datum_count} datums to {topic} partition {partition} offset {offset}");
+                    return;
+                }
+                Err((e, _record)) => {
+                    tracing::warn!("failed to publish to {topic}, attempt {attempt}: {e:?}");
+                    sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+        tracing::error!("Dropping {datum_count} datums after failing to publish to {topic}");
+    }
+}
+
+/// Registers the goodmetrics proto with a Confluent Schema Registry, once per topic.
+struct SchemaRegistry {
+    url: String,
+    client: reqwest::Client,
+    schema_ids: HashMap<String, u32>,
+}
+
+impl SchemaRegistry {
+    fn new(url: String) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            client: reqwest::Client::new(),
+            schema_ids: HashMap::new(),
+        }
+    }
+
+    // Confluent's framing: a zero magic byte, the big endian schema id, then the
+    // message indexes. MetricsRequest is the first message in the file, which is just a 0.
+    async fn wire_header(&mut self, topic: &str) -> Result<Vec<u8>, SinkError> {
+        let schema_id = match self.schema_ids.get(topic) {
+            Some(schema_id) => *schema_id,
+            None => {
+                let schema_id = self.register(topic).await?;
+                self.schema_ids.insert(topic.to_string(), schema_id);
+                schema_id
+            }
+        };
+        let mut header = Vec::with_capacity(6);
+        header.push(0);
+        header.extend(schema_id.to_be_bytes());
+        header.push(0);
+        Ok(header)
+    }
+
+    async fn register(&self, topic: &str) -> Result<u32, SinkError> {
+        let body = serde_json::json!({
+            "schemaType": "PROTOBUF",
+            "schema": GOODMETRICS_PROTO,
+        });
+        let response = self
+            .client
+            .post(format!("{}/subjects/{topic}-value/versions", self.url))
+            .header("Content-Type", "application/vnd.schemaregistry.v1+json")
+            .body(body.to_string())
+            .send()
+            .await
+            .map_err(|e| SinkError::other("could not reach schema registry", Box::new(e)))?
+            .error_for_status()
+            .map_err(|e| SinkError::other("schema registry refused schema", Box::new(e)))?
+            .text()
+            .await
+            .map_err(|e| SinkError::other("could not read schema registry", Box::new(e)))?;
+
+        let registered: serde_json::Value = serde_json::from_str(&response)
+            .map_err(|e| SinkError::other("unexpected schema registry response", Box::new(e)))?;
+        let schema_id = registered["id"].as_u64().ok_or_else(|| {
+            SinkError::other(
+                format!("schema registry response has no id: {response}"),
+                "missing id".into(),
+            )
+        })?;
+        tracing::info!("registered {topic} schema as id {schema_id}");
+        Ok(schema_id as u32)
+    }
+}
@@ -0,0 +1,110 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio_postgres::Client;
+
+use crate::postgres_things::tls::parse_connection_string;
+
+use super::sink_error::SinkError;
+
+const CONTENTION_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Bounds how many batches write to postgres at once, and optionally halves that while
+/// postgres has too many active connections waiting on locks or IO.
+pub struct LoadAwareWriter {
+    permits: Arc<Semaphore>,
+    concurrency: usize,
+}
+
+impl LoadAwareWriter {
+    pub fn new(concurrency: usize) -> Self {
+        Self {
+            permits: Arc::new(Semaphore::new(concurrency)),
+            concurrency,
+        }
+    }
+
+    pub async fn acquire(&self) -> Result<SemaphorePermit<'_>, SinkError> {
+        self.permits
+            .acquire()
+            .await
+            .map_err(|e| SinkError::other("write permits closed", Box::new(e)))
+    }
+
+    /// Checks pg_stat_activity on its own connection, so it still works when the pool is busy.
+    pub fn watch_contention(&self, connection_string: String, max_contended_connections: usize) {
+        let permits = self.permits.clone();
+        let withheld = self.concurrency / 2;
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CONTENTION_CHECK_INTERVAL);
+            let mut backed_off = false;
+            let mut client: Option<Client> = None;
+            loop {
+                interval.tick().await;
+                if client.is_none() {
+                    match connect(&connection_string).await {
+                        Ok(connected) => client = Some(connected),
+                        Err(e) => {
+                            tracing::warn!("could not connect to check postgres contention: {e:?}");
+                            continue;
+                        }
+                    }
+                }
+                let Some(connected) = &client else {
+                    continue;
+                };
+                let contended = match count_contended_connections(connected).await {
+                    Ok(contended) => contended,
+                    Err(e) => {
+                        tracing::warn!("could not check postgres contention: {e:?}");
+                        client = None;
+                        continue;
+                    }
+                };
+
+                if !backed_off && max_contended_connections < contended {
+                    tracing::warn!(
+                        contended_connections = contended,
+                        max_contended_connections,
+                        "postgres is contended, halving write concurrency"
+                    );
+                    // Waits for in-flight writes to hand their permits back
+                    match permits.acquire_many(withheld as u32).await {
+                        Ok(permit) => permit.forget(),
+                        Err(_closed) => return,
+                    }
+                    backed_off = true;
+                } else if backed_off && contended <= max_contended_connections {
+                    tracing::info!(
+                        contended_connections = contended,
+                        "postgres contention cleared, restoring write concurrency"
+                    );
+                    permits.add_permits(withheld);
+                    backed_off = false;
+                }
+            }
+        });
+    }
+}
+
+async fn connect(connection_string: &str) -> Result<Client, SinkError> {
+    let (config, tls) = parse_connection_string(connection_string)?;
+    let (client, connection) = config.connect(tls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            tracing::warn!("contention check connection failed: {e:?}");
+        }
+    });
+    Ok(client)
+}
+
+async fn count_contended_connections(client: &Client) -> Result<usize, SinkError> {
+    let row = client
+        .query_one(
+            "SELECT count(*) FROM pg_stat_activity WHERE wait_event_type IN ('Lock', 'IO') AND state = 'active'",
+            &[],
+        )
+        .await?;
+    let contended: i64 = row.get(0);
+    Ok(contended as usize)
+}
@@ -1,24 +1,65 @@
-use tokio::sync::broadcast::{Receiver, Sender};
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    sync::{
+        broadcast::{error::RecvError, Receiver, Sender},
+        oneshot,
+    },
+    time::{sleep_until, Instant},
+};
 
 use communication::proto::goodmetrics::Datum;
 
 use super::{ErrorCode, MetricsSink};
 
+/// What goes through the queue. Every receiver gets every item, so a flush's signal is
+/// shared and taken by whichever receiver answers it.
+#[derive(Debug, Clone)]
+pub enum Queued {
+    /// With when they were sent
+    Datums(Vec<Datum>, Instant),
+    Flush(Arc<Mutex<Option<oneshot::Sender<()>>>>),
+}
+
 #[derive(Debug, Clone)]
 pub struct MetricsSendQueue {
-    pub tx: Sender<Vec<Datum>>,
+    pub tx: Sender<Queued>,
+    // Datums sent but not yet received by the queue's primary receiver
+    queued_datums: Arc<AtomicUsize>,
 }
 
 pub struct MetricsReceiveQueue {
-    pub rx: Receiver<Vec<Datum>>,
+    pub rx: Receiver<Queued>,
+    // Only the receiver made with the queue counts down its depth and answers flushes
+    queued_datums: Option<Arc<AtomicUsize>>,
+    // Received datums that the consumer hasn't called batch_done for yet
+    holding_datums: bool,
+    // Datums the consumer kept past batch_done, like rows held for a bigger COPY
+    consumer_holding: bool,
+    // When the oldest of the held datums was sent
+    oldest_held: Option<Instant>,
+    pending_flushes: Vec<oneshot::Sender<()>>,
+    // Fell behind since last caught up, so the depth counts overwritten batches too
+    lagged: bool,
 }
 
 impl MetricsSink for MetricsSendQueue {
     fn drain(&self, metrics: Vec<Datum>) -> Result<String, super::ErrorCode> {
-        match self.tx.send(metrics) {
+        let datum_count = metrics.len();
+        // Counted before sending so a fast receiver can't count them down first
+        self.queued_datums.fetch_add(datum_count, Ordering::Relaxed);
+        match self.tx.send(Queued::Datums(metrics, Instant::now())) {
             Ok(_) => Ok("collected".to_string()),
             Err(e) => {
-                log::warn!("queue error: {:?}", e);
+                count_down(&self.queued_datums, datum_count);
+                tracing::warn!("queue error: {:?}", e);
                 Err(ErrorCode::QueueFull)
             }
         }
@@ -28,19 +69,258 @@ impl MetricsSink for MetricsSendQueue {
 impl MetricsSendQueue {
     pub fn new() -> (MetricsSendQueue, MetricsReceiveQueue) {
         let (tx, rx) = tokio::sync::broadcast::channel(4096);
+        let queued_datums = Arc::new(AtomicUsize::new(0));
+
+        (
+            MetricsSendQueue {
+                tx,
+                queued_datums: queued_datums.clone(),
+            },
+            MetricsReceiveQueue {
+                rx,
+                queued_datums: Some(queued_datums),
+                holding_datums: false,
+                consumer_holding: false,
+                oldest_held: None,
+                pending_flushes: Vec::new(),
+                lagged: false,
+            },
+        )
+    }
+
+    /// Another receiver of everything sent from now on. It doesn't count toward `queued_datums`.
+    pub fn subscribe(&self) -> MetricsReceiveQueue {
+        MetricsReceiveQueue {
+            rx: self.tx.subscribe(),
+            queued_datums: None,
+            holding_datums: false,
+            consumer_holding: false,
+            oldest_held: None,
+            pending_flushes: Vec::new(),
+            lagged: false,
+        }
+    }
 
-        (MetricsSendQueue { tx }, MetricsReceiveQueue { rx })
+    /// Resolves once the primary receiver's consumer is done with everything sent before
+    /// this, or right away if nothing is receiving.
+    pub fn flush(&self) -> impl Future<Output = ()> {
+        let (flushed, wait) = oneshot::channel();
+        // When the send fails the signal is dropped, which also ends the wait
+        let _ = self
+            .tx
+            .send(Queued::Flush(Arc::new(Mutex::new(Some(flushed)))));
+        async move {
+            let _ = wait.await;
+        }
+    }
+
+    /// Datums waiting for the primary receiver
+    pub fn queued_datums(&self) -> usize {
+        self.queued_datums.load(Ordering::Relaxed)
     }
 }
 
 impl MetricsReceiveQueue {
     pub async fn recv(&mut self) -> Option<Vec<Datum>> {
-        match self.rx.recv().await {
-            Ok(some_datums) => Some(some_datums),
-            Err(error) => {
-                log::error!("failed to receive some datums: {:?}", error);
-                None
+        self.recv_with_sent_at()
+            .await
+            .map(|(some_datums, _sent_at)| some_datums)
+    }
+
+    /// The next datums, with when they were sent
+    pub async fn recv_with_sent_at(&mut self) -> Option<(Vec<Datum>, Instant)> {
+        loop {
+            match self.rx.recv().await {
+                Ok(Queued::Datums(some_datums, sent_at)) => {
+                    if let Some(queued_datums) = &self.queued_datums {
+                        count_down(queued_datums, some_datums.len());
+                        // Overwritten batches never get counted down, so start over once caught up
+                        if self.lagged && self.rx.is_empty() {
+                            queued_datums.store(0, Ordering::Relaxed);
+                            self.lagged = false;
+                        }
+                    }
+                    if !self.holding_datums {
+                        self.oldest_held = Some(sent_at);
+                    }
+                    self.holding_datums = true;
+                    return Some((some_datums, sent_at));
+                }
+                Ok(Queued::Flush(signal)) => {
+                    if self.queued_datums.is_none() {
+                        continue;
+                    }
+                    let Some(flushed) = signal.lock().ok().and_then(|mut signal| signal.take())
+                    else {
+                        continue;
+                    };
+                    if self.holding_datums || self.consumer_holding {
+                        self.pending_flushes.push(flushed);
+                    } else {
+                        let _ = flushed.send(());
+                    }
+                }
+                Err(RecvError::Closed) => {
+                    tracing::info!("send queue closed");
+                    return None;
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    self.lagged = true;
+                    // The rest of the queue is still good; ending here would strand it
+                    tracing::error!(skipped, "fell behind, lost some batches of datums");
+                }
             }
         }
     }
+
+    /// Resolves once the oldest datum received since the last `batch_done` was sent max_age
+    /// ago, or never if there isn't one. It doesn't borrow the queue, so it can race `recv`.
+    pub fn first_item_aged(&self, max_age: Duration) -> impl Future<Output = ()> {
+        let aged_at = self.oldest_held.map(|sent_at| sent_at + max_age);
+        async move {
+            match aged_at {
+                Some(aged_at) => sleep_until(aged_at).await,
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    /// For the consumer to call when it's finished with what it has received, so flushes
+    /// sent after those datums can resolve.
+    pub fn batch_done(&mut self) {
+        self.holding_datums = false;
+        self.oldest_held = None;
+        self.resolve_flushes();
+    }
+
+    /// For a consumer that keeps some of what it received past `batch_done`: flushes wait
+    /// until it says it's let go of them.
+    pub fn hold_flushes(&mut self, holding: bool) {
+        self.consumer_holding = holding;
+        self.resolve_flushes();
+    }
+
+    /// Whether a flush is waiting on the consumer, which should write what it's holding
+    pub fn flush_requested(&self) -> bool {
+        !self.pending_flushes.is_empty()
+    }
+
+    fn resolve_flushes(&mut self) {
+        if self.holding_datums || self.consumer_holding {
+            return;
+        }
+        for flushed in self.pending_flushes.drain(..) {
+            let _ = flushed.send(());
+        }
+    }
+}
+
+fn count_down(queued_datums: &AtomicUsize, datum_count: usize) {
+    // Saturating, since a reset after lagging can leave in-flight sends uncounted
+    let _ = queued_datums.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+        Some(queued.saturating_sub(datum_count))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use communication::proto::goodmetrics::Datum;
+    use tokio::time::timeout;
+
+    use super::MetricsSendQueue;
+    use crate::sink::MetricsSink;
+
+    fn datums(metrics: &[&str]) -> Vec<Datum> {
+        metrics
+            .iter()
+            .map(|metric| Datum {
+                metric: metric.to_string(),
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn flush_waits_for_the_consumer() {
+        let (send_queue, mut receive_queue) = MetricsSendQueue::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let consumer = tokio::spawn({
+            let seen = seen.clone();
+            async move {
+                while let Some(datums) = receive_queue.recv().await {
+                    seen.lock()
+                        .unwrap()
+                        .extend(datums.into_iter().map(|datum| datum.metric));
+                    receive_queue.batch_done();
+                }
+            }
+        });
+
+        send_queue.drain(datums(&["a", "b"])).unwrap();
+        send_queue.drain(datums(&["c"])).unwrap();
+        send_queue.flush().await;
+        assert_eq!(*seen.lock().unwrap(), ["a", "b", "c"]);
+        assert_eq!(send_queue.queued_datums(), 0);
+
+        drop(send_queue);
+        consumer.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn flush_waits_for_held_datums() {
+        let (send_queue, mut receive_queue) = MetricsSendQueue::new();
+        send_queue.drain(datums(&["a"])).unwrap();
+        let mut flushed = tokio::spawn(send_queue.flush());
+
+        assert_eq!(receive_queue.recv().await.unwrap().len(), 1);
+        receive_queue.hold_flushes(true);
+        receive_queue.batch_done();
+        // Takes the flush, then waits for more datums
+        let waiting = timeout(Duration::from_millis(50), receive_queue.recv()).await;
+        assert!(waiting.is_err());
+        assert!(receive_queue.flush_requested());
+        assert!(timeout(Duration::from_millis(50), &mut flushed)
+            .await
+            .is_err());
+
+        receive_queue.hold_flushes(false);
+        flushed.await.unwrap();
+        assert!(!receive_queue.flush_requested());
+    }
+
+    #[tokio::test]
+    async fn queued_datums_recover_after_lagging() {
+        let (send_queue, mut receive_queue) = MetricsSendQueue::new();
+        // More batches than the queue holds, so the oldest are overwritten
+        let batches = 4096 + 10;
+        for _ in 0..batches {
+            send_queue.drain(datums(&["a", "b"])).unwrap();
+        }
+        assert_eq!(send_queue.queued_datums(), 2 * batches);
+
+        let mut received = 0;
+        while !receive_queue.rx.is_empty() {
+            received += receive_queue.recv().await.unwrap().len();
+            receive_queue.batch_done();
+        }
+        assert_eq!(received, 2 * 4096);
+        assert_eq!(send_queue.queued_datums(), 0);
+
+        send_queue.drain(datums(&["a", "b", "c"])).unwrap();
+        assert_eq!(send_queue.queued_datums(), 3);
+        assert_eq!(receive_queue.recv().await.unwrap().len(), 3);
+        assert_eq!(send_queue.queued_datums(), 0);
+    }
+
+    #[tokio::test]
+    async fn flush_without_a_receiver_resolves() {
+        let (send_queue, receive_queue) = MetricsSendQueue::new();
+        drop(receive_queue);
+        send_queue.flush().await;
+    }
 }
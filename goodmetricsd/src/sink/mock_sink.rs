@@ -0,0 +1,63 @@
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc, Mutex,
+};
+
+use communication::proto::goodmetrics::Datum;
+
+use super::{ErrorCode, MetricsSink};
+
+/// Stands in for the send queue in tests. Everything drained into it is kept,
+/// and failures can be injected to exercise the callers' error handling.
+pub struct MockSink {
+    handle: MockSinkHandle,
+}
+
+/// Shares the mock's state, so assertions can be made after the sink has been moved away.
+#[derive(Debug, Clone, Default)]
+pub struct MockSinkHandle {
+    received: Arc<Mutex<Vec<Datum>>>,
+    failures_remaining: Arc<AtomicU32>,
+}
+
+impl MockSink {
+    pub fn new() -> (MockSink, MockSinkHandle) {
+        let handle = MockSinkHandle::default();
+        (
+            MockSink {
+                handle: handle.clone(),
+            },
+            handle,
+        )
+    }
+
+    /// The next n drains return ErrorCode::QueueFull and record nothing
+    pub fn fail_next_n(&self, n: u32) {
+        self.handle.failures_remaining.store(n, Ordering::Relaxed);
+    }
+}
+
+impl MockSinkHandle {
+    pub fn received(&self) -> Vec<Datum> {
+        self.received.lock().expect("mock sink lock").clone()
+    }
+}
+
+impl MetricsSink for MockSink {
+    fn drain(&self, mut metrics: Vec<Datum>) -> Result<String, ErrorCode> {
+        let failing = self
+            .handle
+            .failures_remaining
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok();
+        if failing {
+            return Err(ErrorCode::QueueFull);
+        }
+        self.handle
+            .received
+            .lock()
+            .expect("mock sink lock")
+            .append(&mut metrics);
+        Ok("collected".to_string())
+    }
+}
@@ -1,9 +1,27 @@
 use communication::proto::goodmetrics::Datum;
 
+pub mod anomaly_validator;
+pub mod batch_sizer;
+pub mod cardinality_guard;
+pub mod circuit_breaker;
+pub mod dead_letter_queue;
+pub mod dedup_cache;
+pub mod email_alerter;
+pub mod file_sink;
+pub mod kafka_sink;
+pub mod load_aware_writer;
 pub mod metricssendqueue;
+#[cfg(test)]
+pub mod mock_sink;
+pub mod multitenant_sink;
 pub mod opentelemetry_sink;
 pub mod postgres_sink;
+pub mod pre_aggregation;
+pub mod rate_limiter;
+pub mod redis_dedup_cache;
 pub mod sink_error;
+pub mod table_write_buffer;
+pub mod write_ahead_log;
 
 pub trait MetricsSink: Send {
     fn drain(&self, metrics: Vec<Datum>) -> Result<String, ErrorCode>;
@@ -0,0 +1,395 @@
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hasher,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use communication::proto::goodmetrics::{dimension, Datum};
+use tokio::{task::JoinHandle, time::Instant};
+
+use crate::{
+    config::options::Options,
+    fnv::Fnv1a,
+    postgres_things::ddl::clean_id,
+    self_metrics::TENANT_DATUMS_REJECTED,
+    servers::health::Readiness,
+    shutdown::ShutdownToken,
+    sink::{
+        metricssendqueue::{MetricsReceiveQueue, MetricsSendQueue},
+        postgres_sink::PostgresSender,
+        sink_error::{SinkError, StringError},
+        MetricsSink,
+    },
+};
+
+// Postgres silently truncates longer identifiers
+const MAX_TENANT_ID_BYTES: usize = 63;
+// So a flood of unknown tenants can't grow the set of ones already warned about without end
+const MAX_WARNED_TENANTS: usize = 1000;
+const FIRST_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(5 * 60);
+
+/// Which database each tenant's metrics go to, from a toml file of
+/// `tenant = "postgres connection string"`.
+pub struct TenantRegistry {
+    connection_strings: HashMap<String, String>,
+}
+
+impl TenantRegistry {
+    pub fn load(path: &Path) -> Result<Self, SinkError> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| SinkError::other("failed to read tenant registry", Box::new(e)))?;
+        let table: toml::Table = contents
+            .parse()
+            .map_err(|e| SinkError::other("failed to parse tenant registry", Box::new(e)))?;
+        let mut connection_strings = HashMap::new();
+        for (tenant, connection_string) in table {
+            let toml::Value::String(connection_string) = connection_string else {
+                return Err(SinkError::StringError(StringError {
+                    message: format!("tenant registry: {tenant} is not a connection string"),
+                }));
+            };
+            connection_strings.insert(tenant, connection_string);
+        }
+        Ok(Self { connection_strings })
+    }
+
+    fn connection_string(&self, tenant: &str) -> Option<&str> {
+        self.connection_strings.get(tenant).map(String::as_str)
+    }
+}
+
+/// Consumes the send queue in place of a single postgres sender, and hands each datum to the
+/// postgres sender for its tenant's database. Senders start when their tenant's first datum
+/// arrives, and run on the sink runtime alongside the router.
+pub struct MultiTenantRouter {
+    rx: MetricsReceiveQueue,
+    options: Options,
+    tenant_dimension: String,
+    registry: TenantRegistry,
+    // Datums without the tenant dimension go to --connection-string's sender
+    default_tenant: Option<MetricsSendQueue>,
+    tenants: HashMap<String, MetricsSendQueue>,
+    // Started tenants by their tenant_id, so 2 tenants can't share a schema or files
+    claimed: HashMap<String, String>,
+    // Tenants whose sender failed to start, and when to try again
+    failed: HashMap<String, FailedStart>,
+    senders: Vec<JoinHandle<Result<u64, SinkError>>>,
+    shutdown: ShutdownToken,
+    // So a chatty unknown tenant is logged once rather than every batch
+    rejected: HashSet<String>,
+    warned_without_tenant: bool,
+}
+
+struct FailedStart {
+    retry_at: Instant,
+    backoff: Duration,
+}
+
+impl MultiTenantRouter {
+    pub async fn new(
+        rx: MetricsReceiveQueue,
+        options: Options,
+        tenant_dimension: String,
+        readiness: Readiness,
+        shutdown: ShutdownToken,
+    ) -> Result<Self, SinkError> {
+        let registry = match &options.tenant_registry {
+            Some(path) => TenantRegistry::load(path)?,
+            None => TenantRegistry {
+                connection_strings: HashMap::new(),
+            },
+        };
+        tracing::info!(
+            tenant_dimension = %tenant_dimension,
+            tenants = registry.connection_strings.len(),
+            "routing datums to a database per tenant"
+        );
+        let mut router = Self {
+            rx,
+            options,
+            tenant_dimension,
+            registry,
+            default_tenant: None,
+            tenants: HashMap::new(),
+            claimed: HashMap::new(),
+            failed: HashMap::new(),
+            senders: Vec::new(),
+            shutdown,
+            rejected: HashSet::new(),
+            warned_without_tenant: false,
+        };
+        if let Some(connection_string) = router.options.connection_string.clone() {
+            let (queue, sender) = new_sender(&connection_string, router.options.clone()).await?;
+            readiness.set_postgres(sender.connector());
+            router.start(sender);
+            router.default_tenant = Some(queue);
+        }
+        Ok(router)
+    }
+
+    pub async fn route(mut self) -> Result<(), SinkError> {
+        tracing::info!("started multi-tenant router");
+        while let Some(datums) = self.rx.recv().await {
+            let mut by_tenant: HashMap<Option<String>, Vec<Datum>> = HashMap::new();
+            for datum in datums {
+                by_tenant
+                    .entry(tenant_of(&datum, &self.tenant_dimension))
+                    .or_default()
+                    .push(datum);
+            }
+            for (tenant, datums) in by_tenant {
+                let Some(queue) = self.queue_for(tenant.as_deref()).await else {
+                    TENANT_DATUMS_REJECTED.inc_by(datums.len() as u64);
+                    continue;
+                };
+                if let Err(e) = queue.drain(datums) {
+                    tracing::warn!(tenant = ?tenant, "dropping datums for a full tenant queue: {e:?}");
+                }
+            }
+            self.rx.batch_done();
+        }
+
+        // Closing the tenants' queues lets their senders flush and return
+        self.default_tenant = None;
+        self.tenants.clear();
+        for sender in self.senders {
+            match sender.await {
+                Ok(Ok(rows)) => tracing::info!(rows, "tenant postgres sender finished"),
+                Ok(Err(e)) => tracing::error!("tenant postgres sender failed: {e:?}"),
+                Err(e) => tracing::error!("tenant postgres sender panicked: {e:?}"),
+            }
+        }
+        Ok(())
+    }
+
+    async fn queue_for(&mut self, tenant: Option<&str>) -> Option<&MetricsSendQueue> {
+        let Some(tenant) = tenant else {
+            if self.default_tenant.is_none() && !self.warned_without_tenant {
+                self.warned_without_tenant = true;
+                tracing::warn!(
+                    tenant_dimension = %self.tenant_dimension,
+                    "dropping datums without the tenant dimension, there's no --connection-string for them"
+                );
+            }
+            return self.default_tenant.as_ref();
+        };
+        if self.tenants.contains_key(tenant) {
+            return self.tenants.get(tenant);
+        }
+        let now = Instant::now();
+        if let Some(failed) = self.failed.get(tenant) {
+            if now < failed.retry_at {
+                return None;
+            }
+        }
+        if 0 < self.options.max_tenants && self.options.max_tenants <= self.tenants.len() {
+            self.warn_once(
+                tenant,
+                "CARDINALITY_EXCEEDED: dropping datums for a tenant past --max-tenants",
+            );
+            return None;
+        }
+        let tenant_id = tenant_id(tenant);
+        if let Some(claimant) = self.claimed.get(&tenant_id) {
+            let claimant = claimant.clone();
+            if self.rejected.len() < MAX_WARNED_TENANTS && self.rejected.insert(tenant.to_string())
+            {
+                tracing::error!(
+                    tenant,
+                    claimant,
+                    tenant_id,
+                    "dropping datums for a tenant whose schema and files another tenant has"
+                );
+            }
+            return None;
+        }
+
+        match self.start_tenant(tenant, &tenant_id).await {
+            Ok(Some(queue)) => {
+                self.failed.remove(tenant);
+                self.claimed.insert(tenant_id, tenant.to_string());
+                self.tenants.insert(tenant.to_string(), queue);
+                self.tenants.get(tenant)
+            }
+            Ok(None) => {
+                self.warn_once(
+                    tenant,
+                    "dropping datums for a tenant that isn't in the registry",
+                );
+                None
+            }
+            Err(e) => {
+                // Backed off, so a tenant that can't start doesn't hold up every batch
+                let backoff = self
+                    .failed
+                    .get(tenant)
+                    .map(|failed| (failed.backoff * 2).min(MAX_RETRY))
+                    .unwrap_or(FIRST_RETRY);
+                tracing::error!(
+                    tenant,
+                    retry_in = ?backoff,
+                    "failed to start a postgres sender for tenant: {e:?}"
+                );
+                self.failed.insert(
+                    tenant.to_string(),
+                    FailedStart {
+                        retry_at: now + backoff,
+                        backoff,
+                    },
+                );
+                None
+            }
+        }
+    }
+
+    fn warn_once(&mut self, tenant: &str, message: &str) {
+        if self.rejected.len() < MAX_WARNED_TENANTS && self.rejected.insert(tenant.to_string()) {
+            tracing::warn!(tenant, "{message}");
+        }
+    }
+
+    async fn start_tenant(
+        &mut self,
+        tenant: &str,
+        tenant_id: &str,
+    ) -> Result<Option<MetricsSendQueue>, SinkError> {
+        if let Some(connection_string) = self.registry.connection_string(tenant) {
+            let options = tenant_options(&self.options, tenant_id, None);
+            let (queue, sender) = new_sender(connection_string, options).await?;
+            tracing::info!(tenant, "started postgres sender for tenant");
+            self.start(sender);
+            return Ok(Some(queue));
+        }
+        if !self.options.provision_unknown_tenants {
+            return Ok(None);
+        }
+        let Some(connection_string) = self.options.connection_string.clone() else {
+            return Ok(None);
+        };
+
+        let schema = tenant_id.to_string();
+        let options = tenant_options(&self.options, tenant_id, Some(schema.clone()));
+        let (queue, sender) = new_sender(&connection_string, options).await?;
+        sender
+            .connector()
+            .use_connection()
+            .await?
+            .execute(&format!("create schema if not exists {schema}"), &[])
+            .await?;
+        tracing::info!(tenant, schema = %schema, "provisioned a schema for tenant");
+        self.start(sender);
+        Ok(Some(queue))
+    }
+
+    fn start(&mut self, sender: PostgresSender) {
+        self.senders
+            .push(tokio::spawn(sender.consume_stuff(self.shutdown.clone())));
+    }
+}
+
+async fn new_sender(
+    connection_string: &str,
+    options: Options,
+) -> Result<(MetricsSendQueue, PostgresSender), SinkError> {
+    let (send_queue, receive_queue) = MetricsSendQueue::new();
+    let sender = PostgresSender::new_connection(connection_string, receive_queue, options).await?;
+    Ok((send_queue, sender))
+}
+
+// Strings, numbers and booleans are all fine tenant ids
+fn tenant_of(datum: &Datum, tenant_dimension: &str) -> Option<String> {
+    match datum.dimensions.get(tenant_dimension)?.value.as_ref()? {
+        dimension::Value::String(s) => Some(s.clone()),
+        dimension::Value::Number(n) => Some(n.to_string()),
+        dimension::Value::Boolean(b) => Some(b.to_string()),
+    }
+}
+
+/// A tenant's name for its schema and files. One that isn't a plain identifier already gets a
+/// hash of itself as well, so "Acme" and "acme", or "a-b" and "a_b", don't come out the same.
+fn tenant_id(tenant: &str) -> String {
+    let mut id = clean_id(tenant);
+    if id == tenant {
+        return id;
+    }
+    let mut hasher = Fnv1a::default();
+    hasher.write(tenant.as_bytes());
+    let suffix = format!("_{:016x}", hasher.finish());
+    // clean_id's output is ascii, so any byte is a char boundary
+    id.truncate(MAX_TENANT_ID_BYTES - suffix.len());
+    id + &suffix
+}
+
+/// The same options, but with files of their own so tenants' senders don't share a write ahead
+/// log or fallback directory
+fn tenant_options(options: &Options, tenant_id: &str, schema_name: Option<String>) -> Options {
+    let mut options = options.clone();
+    options.fallback_directory = options
+        .fallback_directory
+        .map(|directory| format!("{directory}/{tenant_id}"));
+    options.dead_letter_directory = options
+        .dead_letter_directory
+        .map(|directory| format!("{directory}/{tenant_id}"));
+    options.wal_path = options
+        .wal_path
+        .map(|path| PathBuf::from(format!("{}.{tenant_id}", path.display())));
+    if schema_name.is_some() {
+        options.schema_name = schema_name;
+    }
+    options
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use clap::Parser;
+
+    use crate::config::options::Options;
+
+    use super::{tenant_id, tenant_options, MAX_TENANT_ID_BYTES};
+
+    #[test]
+    fn plain_tenant_ids_are_unchanged() {
+        assert_eq!("acme", tenant_id("acme"));
+        assert_eq!("a_b", tenant_id("a_b"));
+    }
+
+    #[test]
+    fn tenants_that_clean_the_same_get_distinct_ids() {
+        let tenants = ["acme", "Acme", "ACME", "a-b", "a_b", "a.b", "a b"];
+        let ids: HashSet<String> = tenants.iter().map(|tenant| tenant_id(tenant)).collect();
+        assert_eq!(tenants.len(), ids.len(), "{ids:?}");
+    }
+
+    #[test]
+    fn long_tenant_ids_fit_an_identifier() {
+        let a = tenant_id(&format!("{}-a", "x".repeat(100)));
+        let b = tenant_id(&format!("{}-b", "x".repeat(100)));
+        assert!(a.len() <= MAX_TENANT_ID_BYTES, "{a}");
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn tenant_options_give_tenants_their_own_files() {
+        let options = Options::parse_from([
+            "goodmetricsd",
+            "--connection-string",
+            "host=test",
+            "--fallback-directory",
+            "/tmp/fallback",
+            "--wal-path",
+            "/tmp/wal.dat",
+        ]);
+        let acme = tenant_options(&options, &tenant_id("acme"), None);
+        let upper_acme = tenant_options(&options, &tenant_id("Acme"), None);
+        assert_ne!(acme.fallback_directory, upper_acme.fallback_directory);
+        assert_ne!(acme.wal_path, upper_acme.wal_path);
+        assert_eq!(
+            Some("/tmp/fallback/acme".to_string()),
+            acme.fallback_directory
+        );
+    }
+}
@@ -41,10 +41,10 @@ impl OtelSender {
     }
 
     pub async fn consume_stuff(mut self) -> Result<u32, SinkError> {
-        log::info!("started opentelemetry consumer");
+        tracing::info!("started opentelemetry consumer");
 
         while let Some(mut batch) = self.rx.recv().await {
-            log::info!("Sender woke. Trying to collect a batch...");
+            tracing::info!("Sender woke. Trying to collect a batch...");
             sleep(Duration::from_secs(5)).await;
 
             let deadline = Instant::now() + Duration::from_secs(1);
@@ -115,9 +115,21 @@ impl OtelSender {
                                                 histogram_data_point(h, datum.unix_nanos, &dimensions),
                                             ],
                                         }),
+                                        goodmetrics::measurement::Value::ExponentialHistogram(e) => opentelemetry_metrics::metric::Data::ExponentialHistogram(opentelemetry_metrics::ExponentialHistogram {
+                                            aggregation_temporality: opentelemetry_metrics::AggregationTemporality::Delta as i32,
+                                            data_points: vec![
+                                                exponential_histogram_data_point(e, datum.unix_nanos, &dimensions),
+                                            ],
+                                        }),
                                         goodmetrics::measurement::Value::Tdigest(t) => {
                                             unimplemented!("tdigest for opentelemetry is not supported: {t:?}")
                                         },
+                                        // Opentelemetry has nowhere to keep the counts, so this sends just the rate
+                                        goodmetrics::measurement::Value::Ratio(r) => opentelemetry_metrics::metric::Data::Gauge(opentelemetry_metrics::Gauge {
+                                            data_points: vec![
+                                                float_data_point(r.numerator as f64 / r.denominator as f64, datum.unix_nanos, &dimensions),
+                                            ],
+                                        }),
                                     }),
                                 }
                             })
@@ -146,16 +158,17 @@ impl OtelSender {
                 .await
             {
                 Ok(response) => {
-                    log::info!(
+                    tracing::info!(
                         "Sent {} batched calls to otel. Response: {:?}",
                         api_calls,
                         response
                     );
                 }
                 Err(error) => {
-                    log::error!("Error from otel: {:?}", error);
+                    tracing::error!("Error from otel: {:?}", error);
                 }
             }
+            self.rx.batch_done();
         }
 
         Ok(1)
@@ -241,3 +254,40 @@ fn histogram_data_point(
         explicit_bounds: buckets.keys().map(|bucket| *bucket as f64).collect(),
     }
 }
+
+fn exponential_histogram_data_point(
+    e: goodmetrics::ExponentialHistogram,
+    nano_time: u64,
+    dimensions: &[KeyValue],
+) -> opentelemetry_metrics::ExponentialHistogramDataPoint {
+    let upper_bounds = e.upper_bounds();
+    opentelemetry_metrics::ExponentialHistogramDataPoint {
+        attributes: dimensions.to_owned(),
+        start_time_unix_nano: 0,
+        time_unix_nano: nano_time,
+        exemplars: vec![],
+        flags: 0,
+        count: upper_bounds.iter().map(|(_, count)| count).sum(),
+
+        // Like histograms' sums, approximate and over-estimated
+        sum: upper_bounds
+            .iter()
+            .map(|(bound, count)| bound * *count as f64)
+            .sum(),
+
+        scale: e.scale,
+        zero_count: e.zero_count,
+        positive: Some(
+            opentelemetry_metrics::exponential_histogram_data_point::Buckets {
+                offset: e.positive_offset,
+                bucket_counts: e.positive,
+            },
+        ),
+        negative: Some(
+            opentelemetry_metrics::exponential_histogram_data_point::Buckets {
+                offset: e.negative_offset,
+                bucket_counts: e.negative,
+            },
+        ),
+    }
+}
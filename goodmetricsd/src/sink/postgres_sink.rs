@@ -1,46 +1,90 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
     error::Error,
+    fmt::Write,
+    path::PathBuf,
     pin::pin,
-    rc::Rc,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
     time::{Duration, SystemTime},
 };
 
 use crate::{
-    config::options::Options,
+    config::options::{
+        CopyFormat, IdentifierMode, Options, TimeConstraint, TimescaleMode, TimestampPrecision,
+    },
     postgres_things::{
-        ddl::{self, clean_id},
-        histogram::{get_or_create_histogram_type, to_jsonmap},
-        postgres_connector::PostgresConnector,
+        connection_string_provider::StaticProvider,
+        copy_writer::CopyRowWriter,
+        ddl::{
+            self, catalog_identifier, derived_id, identifier, metric_table_name,
+            qualified_table_name, unqualified_table_name, DdlAudit, DdlAuditEvent, IndexMethod,
+            SchemaChange, TAGS_COLUMN, TIME_NS_REMAINDER_COLUMN,
+        },
+        exponential_histogram::SqlExponentialHistogram,
+        histogram::{compress, get_or_create_histogram_type, write_jsonmap},
+        postgres_connector::{PostgresConnector, RotatingConnectionManager},
+        ratio::{get_or_create_ratio_type, SqlRatio},
+        schema_cache::SchemaCache,
         statistic_set::get_or_create_statistic_set_type,
         tdigest::SqlTdigest,
-        type_conversion::TypeConverter,
+        type_conversion::{compatible_types, TypeConflict, TypeConverter},
+    },
+    self_metrics::{
+        ANALYZE_OPERATIONS, BATCHES_PROCESSED, COPY_DURATION, COPY_TIMEOUTS, DDL_OPERATIONS,
+        DEDUPLICATED_DATUMS, QUEUE_DEPTH, ROWS_WRITTEN, SINK_ERRORS,
     },
-    sink::sink_error::{DescribedError, MissingColumn, MissingTable},
+    shutdown::ShutdownToken,
+    sink::sink_error::{BadRow, ColumnTypeChange, DescribedError, MissingColumn, MissingTable},
 };
 use crate::{postgres_things::statistic_set::SqlStatisticSet, sink::sink_error::StringError};
 use bb8::PooledConnection;
-use bb8_postgres::PostgresConnectionManager;
 use communication::proto::goodmetrics::{dimension, measurement, Datum, Dimension, Measurement};
 use futures::SinkExt;
 use itertools::Itertools;
 use lazy_static::lazy_static;
 use regex::Regex;
 use tokio::{
+    signal::unix::{signal, SignalKind},
     task,
-    time::{timeout_at, Instant},
+    time::{sleep_until, timeout, Instant},
 };
 use tokio_postgres::{
     error::SqlState,
-    types::{Type, WrongType},
-    CopyInSink, GenericClient, NoTls,
+    types::{ToSql, Type, WrongType},
+    CopyInSink, GenericClient,
 };
 
-use super::{metricssendqueue::MetricsReceiveQueue, sink_error::SinkError};
+use super::{
+    anomaly_validator::AnomalyValidator,
+    batch_sizer::BatchSizer,
+    cardinality_guard::CardinalityGuard,
+    circuit_breaker::CircuitBreaker,
+    dead_letter_queue::{dead_letter_reason, DeadLetterDrain, MetricsDLQ},
+    dedup_cache::DeduplicationCache,
+    email_alerter::WriteErrorAlerter,
+    file_sink::FileFallbackSink,
+    load_aware_writer::LoadAwareWriter,
+    metricssendqueue::MetricsReceiveQueue,
+    pre_aggregation::pre_aggregate,
+    rate_limiter::MetricRateLimiter,
+    redis_dedup_cache::RedisDedupCache,
+    sink_error::{ContextualSinkError, ErrorContext, SinkError},
+    table_write_buffer::TableWriteBuffer,
+    write_ahead_log::{WalEntry, WalReplay, WriteAheadLog},
+};
 
 lazy_static! {
-    // column "available_messages" of relation "table_name" does not exist
+    // column "available_messages" of relation "table_name" does not exist (never schema-qualified)
     static ref UNDEFINED_COLUMN: Regex = Regex::new(r#"column "(?P<column>.+)" of relation "(?P<table>.+)" does not exist"#).expect("regex compiles");
+    // COPY table_name, line 1, column column_name: "{...}"
+    static ref COPY_COLUMN: Regex = Regex::new(r#"^COPY [^,]+, line \d+, column (?P<column>[^:]+):"#).expect("regex compiles");
+    // COPY table_name, line 3: "..." when the row itself is bad rather than 1 of its values
+    static ref COPY_LINE: Regex = Regex::new(r#"^COPY [^,]+, line (?P<line>\d+)"#).expect("regex compiles");
+    // relation "schema_name.table_name" does not exist, qualified as it was in the query
     static ref UNDEFINED_TABLE: Regex = Regex::new(r#"relation "(?P<table>.+)" does not exist"#).expect("regex compiles");
 }
 
@@ -48,440 +92,1912 @@ lazy_static! {
 struct PostgresConfig {
     pub default_retention: Duration,
     pub compress_new_tables: bool,
+    pub auto_index_dimensions: bool,
+    pub index_tags: bool,
+    pub vacuum_analyze_threshold: u64,
+    pub time_constraint: TimeConstraint,
+    pub timescale_mode: TimescaleMode,
+    pub copy_format: CopyFormat,
+    pub timestamp_precision: TimestampPrecision,
+    pub schema_name: Option<String>,
+    pub table_prefix: String,
+    pub identifier_mode: IdentifierMode,
+    pub histogram_max_buckets: Option<usize>,
+    pub per_table_write_timeout: Duration,
+    pub insert_below_rows: usize,
+    pub per_metric_latency: bool,
+    pub idempotent_writes: bool,
+    pub dry_run: bool,
 }
 
-pub struct PostgresSender {
+impl PostgresConfig {
+    fn create_table_options(&self) -> ddl::CreateTableOptions {
+        ddl::CreateTableOptions {
+            primary_key_time: self.time_constraint.primary_key,
+            unique_time_index: self.time_constraint.unique_index,
+            include_retention_policy: Some(self.default_retention),
+            compress: self.compress_new_tables,
+            index_tags: self.index_tags,
+        }
+    }
+}
+
+// Everything the sends for a batch share
+struct SenderState {
+    configuration: PostgresConfig,
     connector: PostgresConnector,
-    rx: MetricsReceiveQueue,
     type_converter: TypeConverter,
-    configuration: PostgresConfig,
+    schema_cache: SchemaCache,
+    ddl_audit: Option<DdlAudit>,
+    dedup_cache: Option<RedisDedupCache>,
+    file_fallback: Option<FileFallbackSink>,
+    dead_letters: MetricsDLQ,
+    write_error_alerter: Option<WriteErrorAlerter>,
+    write_ahead_log: Option<WriteAheadLog>,
+    writer: LoadAwareWriter,
+    circuit_breaker: CircuitBreaker,
+    batch_sizer: BatchSizer,
+    // Over the sender's life, for drain_and_shutdown to report
+    rows_written: AtomicU64,
+}
+
+impl SenderState {
+    // Once a batch is in postgres, or somewhere else on disk, it doesn't need replaying
+    fn commit_wal(&self, wal_entry: Option<WalEntry>) {
+        if let (Some(write_ahead_log), Some(wal_entry)) = (&self.write_ahead_log, wal_entry) {
+            write_ahead_log.commit(wal_entry);
+        }
+    }
+
+    // Lets the circuit breaker see every attempt to get a connection
+    async fn use_connection(
+        &self,
+    ) -> Result<PooledConnection<'_, RotatingConnectionManager>, SinkError> {
+        match self.connector.use_connection().await {
+            Ok(connection) => {
+                self.circuit_breaker.record_success();
+                Ok(connection)
+            }
+            Err(e) => {
+                self.circuit_breaker.record_failure();
+                Err(e)
+            }
+        }
+    }
+}
+
+pub struct PostgresSender {
+    rx: MetricsReceiveQueue,
+    state: Arc<SenderState>,
+    anomaly_validator: Option<AnomalyValidator>,
+    rate_limiter: Option<MetricRateLimiter>,
+    // Unlike the redis dedup cache, only what this sender received, and before it's written
+    recent_datums: Option<DeduplicationCache>,
+    pre_aggregation_window: Option<Duration>,
+    cardinality_guard: CardinalityGuard,
+    dedup_within_batch: bool,
+    // Writes a batch early once its first datum has waited this long
+    max_batch_age: Duration,
+    // Small tables' rows, held across batches
+    table_buffer: Option<TableWriteBuffer>,
+    // Taken when the consumer starts
+    dead_letter_drain: Option<DeadLetterDrain>,
+    // Uncommitted datums found in the write ahead log on startup
+    wal_replay: WalReplay,
 }
 
+// Bounds how much replayed data gets piled onto a single batch after an outage
+const FALLBACK_FILES_PER_BATCH: usize = 8;
+
 impl PostgresSender {
     pub async fn new_connection(
         connection_string: &str,
         rx: MetricsReceiveQueue,
         options: Options,
     ) -> Result<PostgresSender, SinkError> {
-        log::debug!("new_connection: {:?}", connection_string);
+        tracing::debug!("new_connection: {:?}", connection_string);
+        if options.time_constraint.primary_key && options.timescale_mode.enabled {
+            return Err(SinkError::StringError(StringError {
+                message: "--time-primary-key makes plain postgres tables: it needs --timescale-mode=false. Use --time-unique-index with hypertables".to_string(),
+            }));
+        }
+        if options.dry_run {
+            // The types are still made, since they have to exist to have oids to encode rows for
+            tracing::warn!("dry run: no tables, columns or rows will be written to postgres");
+        }
         let max_conns = 16;
-        let mut connector =
-            PostgresConnector::new(connection_string.to_string(), max_conns).await?;
+        let mut connector = PostgresConnector::new(
+            Box::new(StaticProvider::new(connection_string.to_string())),
+            options.connection_string_refresh_interval,
+            max_conns,
+            options.connection_health_check_interval,
+        )
+        .await?;
+        if let Err(e) = connector.recommend_pool_size(options.num_instances).await {
+            tracing::warn!("could not check postgres connection limits: {e:?}");
+        }
 
         let type_converter = {
             let statistic_set_type = get_or_create_statistic_set_type(&mut connector).await?;
             let histogram_types = get_or_create_histogram_type(&mut connector).await?;
+            let ratio_type = get_or_create_ratio_type(&mut connector).await?;
 
             TypeConverter {
                 statistic_set_type,
                 histogram_type: histogram_types.histogram_type,
                 tdigest_type: histogram_types.tdigest_type,
+                ratio_type,
+                dimension_type_conflict: options.dimension_type_conflict,
+            }
+        };
+
+        let dedup_cache = match &options.redis_dedup_url {
+            Some(redis_url) => {
+                Some(RedisDedupCache::new_connection(redis_url, options.redis_dedup_ttl).await?)
+            }
+            None => None,
+        };
+
+        let file_fallback = match &options.fallback_directory {
+            Some(directory) => {
+                Some(FileFallbackSink::new(directory.into(), options.fallback_max_bytes).await?)
+            }
+            None => None,
+        };
+
+        let dead_letter_file_sink = match &options.dead_letter_directory {
+            Some(directory) => {
+                Some(FileFallbackSink::new(directory.into(), options.fallback_max_bytes).await?)
             }
+            None => None,
         };
+        let (dead_letters, dead_letter_drain) =
+            MetricsDLQ::new(options.dead_letter_capacity, dead_letter_file_sink);
+
+        let write_error_alerter = match &options.alerting.smtp_server {
+            Some(smtp_server) => Some(WriteErrorAlerter::new(
+                smtp_server,
+                options.alerting.clone(),
+            )?),
+            None => None,
+        };
+
+        let (write_ahead_log, wal_replay) = match options.wal_path {
+            Some(wal_path) => {
+                let (write_ahead_log, wal_replay) = WriteAheadLog::open(wal_path)?;
+                (Some(write_ahead_log), wal_replay)
+            }
+            None => (None, WalReplay::default()),
+        };
+
+        let max_concurrent_copies = options.max_concurrent_copies.unwrap_or(max_conns).max(1);
+        if max_conns < max_concurrent_copies {
+            tracing::warn!(
+                max_concurrent_copies,
+                pool_size = max_conns,
+                "more concurrent copies than pooled connections, some will wait for a connection"
+            );
+        }
+        let writer = LoadAwareWriter::new(max_concurrent_copies);
+        if let Some(max_contended_connections) = options.max_contended_connections {
+            writer.watch_contention(connection_string.to_string(), max_contended_connections);
+        }
+
+        let anomaly_validator = options.detect_anomalies.then(|| {
+            AnomalyValidator::new(options.anomaly_sigma_threshold, options.drop_anomalies)
+        });
+        let rate_limiter = (0 < options.max_datums_per_metric_per_second
+            || 0 < options.max_new_metrics_per_second)
+            .then(|| {
+                MetricRateLimiter::new(
+                    options.max_datums_per_metric_per_second,
+                    options.max_new_metrics_per_second,
+                    options.rate_limiter_tracked_metrics,
+                )
+            });
+        let recent_datums = (!options.dedup_window.is_zero())
+            .then(|| DeduplicationCache::new(options.dedup_window, options.max_dedup_entries));
+        // Rows are logged as they're sent, so held rows would be lost to a crash all the same
+        if write_ahead_log.is_some() && 0 < options.min_copy_rows {
+            tracing::info!("not holding rows for min-copy-rows, since there's a write ahead log");
+        }
+        let table_buffer = (0 < options.min_copy_rows && write_ahead_log.is_none())
+            .then(|| TableWriteBuffer::new(options.min_copy_rows, options.max_buffer_age));
 
         Ok(PostgresSender {
-            connector,
             rx,
-            type_converter,
-            configuration: PostgresConfig {
-                default_retention: options.default_retention,
-                compress_new_tables: options.compress_new_tables,
-            },
+            anomaly_validator,
+            rate_limiter,
+            recent_datums,
+            pre_aggregation_window: options.pre_aggregation_window,
+            cardinality_guard: CardinalityGuard::new(options.max_dimension_cardinality),
+            dedup_within_batch: options.dedup_within_batch,
+            max_batch_age: options.max_batch_age,
+            table_buffer,
+            dead_letter_drain: Some(dead_letter_drain),
+            wal_replay,
+            state: Arc::new(SenderState {
+                configuration: PostgresConfig {
+                    default_retention: options.default_retention,
+                    compress_new_tables: options.compress_new_tables,
+                    auto_index_dimensions: options.auto_index_dimensions,
+                    index_tags: options.index_tags,
+                    vacuum_analyze_threshold: options.vacuum_analyze_threshold,
+                    time_constraint: options.time_constraint,
+                    timescale_mode: options.timescale_mode,
+                    copy_format: options.copy_format,
+                    timestamp_precision: options.timestamp_precision,
+                    schema_name: options.schema_name,
+                    table_prefix: options.table_prefix,
+                    identifier_mode: options.identifier_mode,
+                    histogram_max_buckets: options.histogram_max_buckets,
+                    per_table_write_timeout: options.per_table_write_timeout,
+                    insert_below_rows: options.insert_below_rows,
+                    per_metric_latency: !options.disable_per_metric_latency,
+                    idempotent_writes: options.idempotent_writes,
+                    dry_run: options.dry_run,
+                },
+                connector,
+                type_converter,
+                schema_cache: SchemaCache::new(),
+                ddl_audit: options.enable_ddl_audit.then(DdlAudit::default),
+                dedup_cache,
+                file_fallback,
+                dead_letters,
+                write_error_alerter,
+                write_ahead_log,
+                writer,
+                circuit_breaker: CircuitBreaker::new(
+                    options.circuit_breaker_failures,
+                    options.circuit_breaker_backoff,
+                ),
+                batch_sizer: BatchSizer::new(options.target_copy_duration),
+                rows_written: AtomicU64::new(0),
+            }),
         })
     }
 
-    pub async fn consume_stuff(mut self) -> Result<u32, SinkError> {
-        log::info!("started postgres consumer");
-        let connector = Rc::new(self.connector);
-        let type_converter = Rc::new(self.type_converter);
+    pub fn connector(&self) -> PostgresConnector {
+        self.state.connector.clone()
+    }
+
+    /// Writes batches as they come until shutdown, then drains what's left
+    pub async fn consume_stuff(mut self, shutdown: ShutdownToken) -> Result<u64, SinkError> {
+        tracing::info!("started postgres consumer");
+        if let Some(write_ahead_log) = &self.state.write_ahead_log {
+            let rotate_requested = write_ahead_log.rotate_requested();
+            let mut rotate_signal = signal(SignalKind::user_defined1())
+                .map_err(|e| SinkError::other("failed to listen for SIGUSR1", Box::new(e)))?;
+            tokio::spawn(async move {
+                while rotate_signal.recv().await.is_some() {
+                    tracing::info!("write ahead log rotation requested");
+                    rotate_requested.store(true, Ordering::Relaxed);
+                }
+            });
+        }
+        if let Some(dead_letter_drain) = self.dead_letter_drain.take() {
+            tokio::spawn(dead_letter_drain.drain());
+        }
+
+        let mut shutdown = pin!(shutdown.wait());
+        loop {
+            let buffer_deadline = self
+                .table_buffer
+                .as_ref()
+                .and_then(TableWriteBuffer::next_deadline);
+            let batch = tokio::select! {
+                batch = self.rx.recv_with_sent_at() => batch,
+                _ = sleep_until(buffer_deadline.unwrap_or_else(Instant::now)), if buffer_deadline.is_some() => {
+                    self.write_aged_tables().await;
+                    continue;
+                }
+                _ = &mut shutdown => break,
+            };
+            let Some((batch, sent_at)) = batch else {
+                break;
+            };
+            self.write_batch(batch, sent_at, Instant::now() + Duration::from_secs(5))
+                .await;
+        }
+        self.drain_and_shutdown().await
+    }
 
-        while let Some(mut batch) = self.rx.recv().await {
-            log::info!("Sender woke. Trying to collect a batch...");
+    /// Writes a file of newline-delimited json datums, like the fallback directory's, straight
+    /// to postgres. Once every table's rows are in, the file is renamed with a .replayed suffix
+    /// so there's a record of it, and so the fallback directory won't replay it again.
+    /// Resolves with the rows written.
+    pub async fn replay_from_file(&self, path: PathBuf) -> Result<u64, SinkError> {
+        let datums = FileFallbackSink::read_file_skipping_malformed(&path).await?;
+        tracing::info!(datums = datums.len(), path = ?path, "replaying file");
 
-            let deadline = Instant::now() + Duration::from_secs(5);
-            let mut api_calls: u32 = 1;
-            while let Ok(Some(mut extras)) = timeout_at(deadline, self.rx.recv()).await {
-                api_calls += 1;
-                batch.append(&mut extras);
+        let mut rows = 0;
+        for (metric, datums) in group_metrics(datums) {
+            let conflicts = self.state.type_converter.check_type_conflicts(&datums);
+            for datums in split_type_conflicts(&self.state.type_converter, &conflicts, datums) {
+                let connection = self.state.use_connection().await?;
+                let written =
+                    PostgresSender::run_a_batch(&connection, &self.state, &metric, &datums).await?;
+                tracing::info!(metric = %metric, rows = written, "replayed rows");
+                rows += written as u64;
             }
+        }
+
+        let mut replayed_path = path.clone().into_os_string();
+        replayed_path.push(".replayed");
+        tokio::fs::rename(&path, &replayed_path)
+            .await
+            .map_err(|e| SinkError::other("failed to rename replayed file", Box::new(e)))?;
+        tracing::info!(rows, path = ?replayed_path, "replayed file");
+        Ok(rows)
+    }
 
-            let batch_tasks = task::LocalSet::new();
+    /// Writes everything still in the send queue, without waiting to fill batches, until the
+    /// queue closes: once the servers and anything else sending have let go of it. Each batch's
+    /// sends finish before the next starts, so nothing is in flight once this returns.
+    /// Resolves with the rows written over the sender's life.
+    pub async fn drain_and_shutdown(mut self) -> Result<u64, SinkError> {
+        tracing::info!("draining postgres consumer");
+        while let Some((batch, sent_at)) = self.rx.recv_with_sent_at().await {
+            // A deadline that's already passed collects only what is queued right now
+            self.write_batch(batch, sent_at, Instant::now()).await;
+        }
+        if let Some(table_buffer) = &mut self.table_buffer {
+            let tables = table_buffer.take_all();
+            self.send_tables(tables).await;
+            self.rx.hold_flushes(false);
+        }
+        let rows = self.state.rows_written.load(Ordering::Relaxed);
+        tracing::info!(rows, "ended consumer");
+        Ok(rows)
+    }
 
-            let batch_connector = connector.clone();
-            let batch_type_converter = type_converter.clone();
-            let batch_configuration = self.configuration.clone();
-            batch_tasks
-                .run_until(async move {
-                    let batchlen = batch.len();
-                    let grouped_metrics = group_metrics(batch);
-                    log::info!(
-                        "Sending some metrics. batch size: {}, metrics: {}, api calls: {}",
-                        batchlen,
-                        grouped_metrics.len(),
-                        api_calls,
-                    );
+    /// Adds whatever else arrives before the deadline, or before the batch's first datum is
+    /// --max-batch-age old, to the batch, up to the batch size limit, and writes it.
+    /// Returns once all of its tables' sends are done.
+    async fn write_batch(&mut self, batch: Vec<Datum>, sent_at: Instant, deadline: Instant) {
+        tracing::info!("Sender woke. Trying to collect a batch...");
 
-                    for (metric, datums) in grouped_metrics.into_iter() {
-                        task::spawn_local(PostgresSender::send_some(
-                            batch_configuration.clone(),
-                            batch_connector.clone(),
-                            batch_type_converter.clone(),
-                            metric,
-                            datums,
-                        ));
+        let mut batch = self.rate_limit(batch, sent_at);
+        let mut api_calls: u32 = 1;
+        let batch_limit = self.state.batch_sizer.limit();
+        let mut deadline = pin!(sleep_until(deadline));
+        let mut aged = pin!(self.rx.first_item_aged(self.max_batch_age));
+        while batch.len() < batch_limit {
+            tokio::select! {
+                extras = self.rx.recv_with_sent_at() => match extras {
+                    Some((extras, sent_at)) => {
+                        api_calls += 1;
+                        batch.append(&mut self.rate_limit(extras, sent_at));
                     }
-                })
-                .await;
+                    None => break,
+                },
+                _ = &mut deadline => break,
+                _ = &mut aged => break,
+            }
+        }
+        // Before replays are folded in: those were seen and checked before, and still need writing
+        if let Some(recent_datums) = &mut self.recent_datums {
+            batch = recent_datums.filter(batch);
+        }
+        if let Some(anomaly_validator) = &mut self.anomaly_validator {
+            batch = anomaly_validator.filter(batch);
+        }
+        let wal_replay = std::mem::take(&mut self.wal_replay);
+        if !wal_replay.datums.is_empty() {
+            let mut replayed = wal_replay.datums;
+            replayed.append(&mut batch);
+            batch = replayed;
+        }
+
+        // Once a batch goes through without falling back to disk, postgres is back:
+        // fold some of what was saved during the outage into this batch, oldest first.
+        let mut replayed_files = Vec::new();
+        if let Some(file_fallback) = &self.state.file_fallback {
+            if !file_fallback.take_persisted_flag() {
+                let pending_files = file_fallback.pending_files().await.unwrap_or_else(|e| {
+                    tracing::error!("failed to look for fallback files: {e:?}");
+                    Vec::new()
+                });
+                let mut replayed = Vec::new();
+                // Only files that were read count, so unreadable ones can't hold up the rest
+                for path in pending_files {
+                    if FALLBACK_FILES_PER_BATCH <= replayed_files.len() {
+                        break;
+                    }
+                    match FileFallbackSink::read_file_skipping_malformed(&path).await {
+                        Ok(mut datums) => {
+                            tracing::info!(datums = datums.len(), path = ?path, "replaying fallback file");
+                            replayed.append(&mut datums);
+                            replayed_files.push(path);
+                        }
+                        Err(e) => {
+                            tracing::error!("unreadable fallback file {path:?}: {e:?}");
+                            file_fallback.set_aside(&path).await;
+                        }
+                    }
+                }
+                replayed.append(&mut batch);
+                batch = replayed;
+            }
+        }
 
-            batch_tasks.await;
+        if self.dedup_within_batch {
+            batch = deduplicate(batch);
+        }
+        if let Some(window) = self.pre_aggregation_window {
+            batch = pre_aggregate(batch, window);
+        }
+
+        BATCHES_PROCESSED.inc();
+        QUEUE_DEPTH.set(self.rx.rx.len() as i64);
+
+        let batchlen = batch.len();
+        let grouped_metrics = group_metrics(batch);
+        tracing::info!(
+            batch_size = batchlen,
+            metrics = grouped_metrics.len(),
+            api_calls,
+            "Sending some metrics"
+        );
+
+        let now = Instant::now();
+        // Replayed files are only removed once their datums are written, so none are held
+        let hold = replayed_files.is_empty();
+        let mut tables = Vec::with_capacity(grouped_metrics.len());
+        for (metric, mut datums) in grouped_metrics.into_iter() {
+            self.cardinality_guard.enforce(&metric, &mut datums);
+            match &mut self.table_buffer {
+                Some(table_buffer) if hold => tables.extend(table_buffer.add(metric, datums, now)),
+                _ => tables.push((metric, datums)),
+            }
+        }
+        if let Some(table_buffer) = &mut self.table_buffer {
+            if hold {
+                tables.extend(table_buffer.take_aged(now));
+            } else {
+                tables.extend(table_buffer.take_all());
+            }
+        }
+        let all_sent = self.send_tables(tables).await;
+
+        // Anything that failed again was saved to a new file. A send that bailed out might not
+        // have saved its datums anywhere, so the files stay to be replayed again.
+        if let Some(file_fallback) = &self.state.file_fallback {
+            if all_sent {
+                for path in replayed_files {
+                    file_fallback.remove(&path).await;
+                }
+            } else if !replayed_files.is_empty() {
+                tracing::warn!(
+                    files = replayed_files.len(),
+                    "keeping replayed fallback files after a failed send"
+                );
+            }
+        }
+        // The same goes for the write ahead log's replayed entries, which stay uncommitted
+        if all_sent {
+            for entry in wal_replay.entries {
+                self.state.commit_wal(Some(entry));
+            }
+        }
+        self.write_held_tables_for_flushes().await;
+        self.rx.batch_done();
+    }
+
+    // Before replays are folded in, like the other filters: those were admitted already
+    fn rate_limit(&mut self, datums: Vec<Datum>, sent_at: Instant) -> Vec<Datum> {
+        match &mut self.rate_limiter {
+            Some(rate_limiter) => rate_limiter.filter(datums, sent_at),
+            None => datums,
+        }
+    }
+
+    async fn write_aged_tables(&mut self) {
+        let Some(table_buffer) = &mut self.table_buffer else {
+            return;
+        };
+        let tables = table_buffer.take_aged(Instant::now());
+        tracing::info!(tables = tables.len(), "writing tables' held rows");
+        self.send_tables(tables).await;
+        self.write_held_tables_for_flushes().await;
+    }
+
+    // A flush waits for held rows too, so they're all written once one is waiting
+    async fn write_held_tables_for_flushes(&mut self) {
+        let Some(table_buffer) = &mut self.table_buffer else {
+            return;
+        };
+        if self.rx.flush_requested() {
+            let tables = table_buffer.take_all();
+            self.send_tables(tables).await;
+        }
+        let holding = self
+            .table_buffer
+            .as_ref()
+            .is_some_and(|table_buffer| !table_buffer.is_empty());
+        self.rx.hold_flushes(holding);
+    }
+
+    // Tables are sent concurrently, and in parallel on a multi-threaded sink runtime.
+    // Returns whether every table was written, dead lettered or saved to disk.
+    async fn send_tables(&self, tables: Vec<(String, Vec<Datum>)>) -> bool {
+        let mut all_sent = true;
+        let mut batch_tasks = task::JoinSet::new();
+        for (metric, datums) in tables {
+            batch_tasks.spawn(PostgresSender::send_some(
+                self.state.clone(),
+                metric,
+                datums,
+            ));
+        }
+        while let Some(sent) = batch_tasks.join_next().await {
+            match sent {
+                Ok(Ok(())) => (),
+                Ok(Err(e)) => {
+                    all_sent = false;
+                    e.log()
+                }
+                Err(e) => {
+                    all_sent = false;
+                    tracing::error!("a table's send panicked: {e:?}")
+                }
+            }
         }
-        log::info!("ended consumer");
-        Ok(1)
+        self.state.batch_sizer.adjust();
+        all_sent
     }
 
+    // Datums that disagree about a column's type go in separate COPYs, one after the other,
+    // so the rows that match the table still get written. Each is retried on its own.
     async fn send_some(
-        configuration: PostgresConfig,
-        connector: Rc<PostgresConnector>,
-        type_converter: Rc<TypeConverter>,
+        state: Arc<SenderState>,
         metric: String,
         datums: Vec<Datum>,
-    ) -> Result<(), SinkError> {
+    ) -> Result<(), ContextualSinkError> {
+        let conflicts = state.type_converter.check_type_conflicts(&datums);
+        if conflicts.is_empty() {
+            return PostgresSender::send_datums(state, metric, datums).await;
+        }
+        tracing::warn!(metric = %metric, ?conflicts, "splitting a batch with conflicting column types");
+        for datums in split_type_conflicts(&state.type_converter, &conflicts, datums) {
+            PostgresSender::send_datums(state.clone(), metric.clone(), datums).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_datums(
+        state: Arc<SenderState>,
+        metric: String,
+        datums: Vec<Datum>,
+    ) -> Result<(), ContextualSinkError> {
+        let datums = match &state.dedup_cache {
+            Some(dedup_cache) => dedup_cache.filter_unseen(datums).await,
+            None => datums,
+        };
+        if datums.is_empty() {
+            return Ok(());
+        }
+        let wal_entry = match &state.write_ahead_log {
+            Some(write_ahead_log) => match write_ahead_log.append(&datums) {
+                Ok(wal_entry) => Some(wal_entry),
+                Err(e) => {
+                    tracing::error!("sending without logging to the write ahead log: {e:?}");
+                    None
+                }
+            },
+            None => None,
+        };
+
+        let context = |operation| ErrorContext::new(&metric, datums.len(), operation);
+        let _write_permit = state
+            .writer
+            .acquire()
+            .await
+            .map_err(|e| e.with_context(context("acquire_writer")))?;
         let mut try_again = true;
         while try_again {
-            let connection = match connector.use_connection().await {
+            if !state.circuit_breaker.allow() {
+                if let Some(file_fallback) = &state.file_fallback {
+                    tracing::warn!("postgres circuit breaker is open, saving {metric} to disk");
+                    file_fallback
+                        .persist(&datums)
+                        .await
+                        .map_err(|e| e.with_context(context("save_to_disk")))?;
+                    state.commit_wal(wal_entry);
+                } else {
+                    tracing::warn!("postgres circuit breaker is open, dropping {metric}");
+                }
+                return Ok(());
+            }
+            let connection = match state.use_connection().await {
                 Ok(connection) => connection,
                 Err(error) => {
-                    log::error!(
+                    if let Some(file_fallback) = &state.file_fallback {
+                        tracing::error!(
+                            "Saving metrics to disk because I can't get a connection: {:?}",
+                            error
+                        );
+                        if let Some(alerter) = &state.write_error_alerter {
+                            alerter.record_failure(format!("{error:?}"));
+                        }
+                        file_fallback
+                            .persist(&datums)
+                            .await
+                            .map_err(|e| e.with_context(context("save_to_disk")))?;
+                        state.commit_wal(wal_entry);
+                        return Ok(());
+                    }
+                    tracing::error!(
                         "Dropping metrics because I can't get a connection: {:?}",
                         error
                     );
                     continue;
                 }
             };
-            try_again =
-                match PostgresSender::run_a_batch(&connection, &type_converter, &metric, &datums)
-                    .await
-                {
-                    Ok(rows) => {
-                        log::info!("committed rows: {rows}", rows = rows);
-
-                        false
-                    }
-                    Err(e) => {
-                        drop(connection);
-                        let connection = connector.use_connection().await?;
-                        match PostgresSender::handle_error_and_should_it_retry(
-                            &configuration,
-                            &connection,
-                            e,
-                        )
+            let copy_started = Instant::now();
+            let copy_attempt = timeout(
+                state.configuration.per_table_write_timeout,
+                PostgresSender::run_a_batch(&connection, &state, &metric, &datums),
+            )
+            .await;
+            let latency_label = if state.configuration.per_metric_latency {
+                metric.as_str()
+            } else {
+                "all"
+            };
+            COPY_DURATION
+                .with_label_values(&[latency_label])
+                .observe(copy_started.elapsed().as_secs_f64());
+            let copy_result = match copy_attempt {
+                Ok(copy_result) => copy_result,
+                Err(_) => {
+                    let elapsed = copy_started.elapsed();
+                    tracing::error!(metric = %metric, ?elapsed, "copy timed out, dropping the batch");
+                    COPY_TIMEOUTS.inc();
+                    // Dropping the COPY aborts it client side; this stops postgres working on it
+                    if let Err(e) = state
+                        .connector
+                        .cancel_query(connection.cancel_token())
                         .await
-                        {
-                            Ok(should_retry) => should_retry,
-                            Err(retry_failure) => {
-                                log::error!("failed to handle error: {:?}", retry_failure);
+                    {
+                        tracing::warn!("failed to cancel the timed out copy: {e:?}");
+                    }
+                    if let Some(alerter) = &state.write_error_alerter {
+                        alerter.record_failure(format!("copy into {metric} timed out"));
+                    }
+                    state
+                        .dead_letters
+                        .push(metric, "copy_timeout".to_string(), datums);
+                    state.commit_wal(wal_entry);
+                    return Ok(());
+                }
+            };
+            try_again = match copy_result {
+                Ok(rows) => {
+                    tracing::info!(metric = %metric, rows, "committed rows");
+                    state.rows_written.fetch_add(rows as u64, Ordering::Relaxed);
+                    state.batch_sizer.record_copy(copy_started.elapsed());
+                    if let Some(dedup_cache) = &state.dedup_cache {
+                        dedup_cache.mark_written(&datums).await;
+                    }
+                    if let Some(alerter) = &state.write_error_alerter {
+                        alerter.record_success();
+                    }
+                    state.commit_wal(wal_entry);
 
-                                false
+                    false
+                }
+                Err(e) => {
+                    drop(connection);
+                    let connection = state
+                        .use_connection()
+                        .await
+                        .map_err(|e| e.with_context(context("reconnect_after_error")))?;
+                    let reason = dead_letter_reason(&e);
+                    let error_message = format!("{e:?}");
+                    tracing::warn!(
+                        metric = %metric,
+                        batch_size = datums.len(),
+                        operation = "copy",
+                        "writing a batch failed: {e:?}"
+                    );
+                    match PostgresSender::handle_error_and_should_it_retry(
+                        &state,
+                        &connection,
+                        &metric,
+                        e,
+                    )
+                    .await
+                    {
+                        Ok(true) => true,
+                        Ok(false) => {
+                            if let Some(alerter) = &state.write_error_alerter {
+                                alerter.record_failure(error_message);
+                            }
+                            state.dead_letters.push(metric, reason, datums);
+                            state.commit_wal(wal_entry);
+                            return Ok(());
+                        }
+                        Err(retry_failure) => {
+                            tracing::error!("failed to handle error: {:?}", retry_failure);
+                            if let Some(alerter) = &state.write_error_alerter {
+                                alerter.record_failure(error_message);
                             }
+                            state.dead_letters.push(metric, reason, datums);
+                            state.commit_wal(wal_entry);
+                            return Ok(());
                         }
                     }
                 }
+            }
         }
         Ok(())
     }
 
     async fn run_a_batch(
-        client: &PooledConnection<'_, PostgresConnectionManager<NoTls>>,
-        type_converter: &TypeConverter,
+        client: &PooledConnection<'_, RotatingConnectionManager>,
+        state: &SenderState,
         metric: &str,
         datums: &[Datum],
     ) -> Result<usize, SinkError> {
+        let SenderState {
+            connector,
+            configuration,
+            type_converter,
+            schema_cache,
+            ddl_audit,
+            ..
+        } = state;
         let mut rows = 0;
 
         let dimension_types = type_converter.get_dimension_type_map(datums);
         let measurement_types = type_converter.get_measurement_type_map(datums);
 
-        let all_column_names = get_all_column_names(&dimension_types, &measurement_types);
+        let identifier_mode = configuration.identifier_mode;
+        // Rows without tags leave the column null, so it's left out of batches without any
+        let has_tags = datums.iter().any(|datum| !datum.tags.is_empty());
+        let all_column_names = get_all_column_names(
+            configuration.timestamp_precision,
+            identifier_mode,
+            has_tags,
+            &dimension_types,
+            &measurement_types,
+        );
+        let table_name = qualified_table_name(
+            configuration.schema_name.as_deref(),
+            &metric_table_name(identifier_mode, &configuration.table_prefix, metric),
+        );
+        let copy_format = configuration.copy_format;
+        let mut column_ddl_types = get_column_ddl_types(datums, &dimension_types, identifier_mode);
+        if configuration.timestamp_precision == TimestampPrecision::NanosecondsInSeparateColumn {
+            column_ddl_types.insert(
+                identifier(identifier_mode, TIME_NS_REMAINDER_COLUMN),
+                "int8",
+            );
+        }
+        // New tables are made with it, but older ones get it the first time they're sent tags
+        let tags_column = identifier(identifier_mode, TAGS_COLUMN);
+        if has_tags {
+            column_ddl_types.insert(tags_column.clone(), "jsonb");
+        }
 
-        let sink: CopyInSink<bytes::Bytes> = match client
-            .copy_in(&format!(
-                "copy {table_name} ({all_columns}) from stdin with (format csv, header false)", // with binary",
-                table_name = clean_id(metric),
-                all_columns = all_column_names.join(","),
-            ))
-            .await
+        // Everything the table is missing is made up front, together, rather than failing a
+        // COPY per missing column.
+        let mut schema_changes = Vec::new();
+        if schema_cache.known_columns(&table_name).is_none()
+            && !PostgresSender::preflight_table(
+                client,
+                type_converter,
+                schema_cache,
+                identifier_mode,
+                &table_name,
+            )
+            .await?
         {
-            Ok(sink) => sink,
-            Err(postgres_error) => match postgres_error.as_db_error() {
-                Some(dberror) => match *dberror.code() {
-                    SqlState::UNDEFINED_COLUMN => {
-                        let (table, column) = match UNDEFINED_COLUMN.captures(dberror.message()) {
-                            Some(pair) => {
-                                let table =
-                                    pair.name("table").map(|m| m.as_str()).unwrap_or_default();
-                                let column =
-                                    pair.name("column").map(|m| m.as_str()).unwrap_or_default();
-                                log::info!(
-                                    "missing column: {table}.{column}",
-                                    table = table,
-                                    column = column
-                                );
-                                (table, column)
-                            }
-                            None => {
-                                return Err(SinkError::StringError(StringError {
-                                    message: format!(
-                                        "unable to find table and column in error: {dberror:?}"
-                                    ),
-                                }));
-                            }
-                        };
-                        let the_type = datums
-                            .iter()
-                            .filter_map(|d| match d.dimensions.get(column) {
-                                Some(dim) => Some(sql_dimension_type_string(dim)),
-                                None => match d.measurements.get(column) {
-                                    Some(measurement) => Some(sql_data_type_string(measurement)),
-                                    None => None,
-                                },
-                            })
-                            .next();
-                        match the_type {
-                            Some(t) => {
-                                return Err(SinkError::MissingColumn(MissingColumn {
-                                    table: table.to_string(),
-                                    column: column.to_string(),
-                                    data_type: t.to_string(),
-                                }))
-                            }
-                            None => {
-                                return Err(SinkError::DescribedError(DescribedError {
-                                    message: "Type not foud, can't add column".to_string(),
-                                    inner: postgres_error,
-                                }))
-                            }
-                        }
-                    }
-                    SqlState::UNDEFINED_TABLE => {
-                        let table = match UNDEFINED_TABLE.captures(dberror.message()) {
-                            Some(table_captures) => table_captures
-                                .name("table")
-                                .map(|f| f.as_str())
-                                .unwrap_or_default(),
-                            None => "__unknown__",
-                        };
-                        log::info!("missing table: {table}", table = table);
-
-                        return Err(SinkError::MissingTable(MissingTable {
-                            table: table.to_string(),
-                        }));
-                    }
-                    _ => {
-                        return Err(SinkError::Postgres(postgres_error));
-                    }
-                },
-                None => return Err(SinkError::Postgres(postgres_error)),
-            },
+            schema_changes.push(SchemaChange::AddTable(table_name.clone()));
+        }
+        let known_columns = schema_cache.known_columns(&table_name).unwrap_or_default();
+        let new_columns: Vec<(String, &str)> = column_ddl_types
+            .iter()
+            .filter(|(column, _)| !known_columns.contains(*column))
+            .map(|(column, data_type)| (column.clone(), *data_type))
+            .collect();
+        for (column, data_type) in &new_columns {
+            schema_changes.push(SchemaChange::AddColumn {
+                table: table_name.clone(),
+                column: column.clone(),
+                data_type: data_type.to_string(),
+            });
+        }
+        let remember_batch_columns = || {
+            schema_cache.remember_columns(
+                &table_name,
+                dimension_types
+                    .iter()
+                    .chain(measurement_types.iter())
+                    .map(|(name, sql_type)| (identifier(identifier_mode, name), sql_type.clone())),
+            );
+            if configuration.timestamp_precision == TimestampPrecision::NanosecondsInSeparateColumn
+            {
+                schema_cache.remember_columns(
+                    &table_name,
+                    [(
+                        identifier(identifier_mode, TIME_NS_REMAINDER_COLUMN),
+                        Type::INT8,
+                    )],
+                );
+            }
+            if has_tags {
+                schema_cache.remember_columns(&table_name, [(tags_column.clone(), Type::JSONB)]);
+            }
         };
 
-        rows += write_and_close(sink, &dimension_types, &measurement_types, datums).await?;
-
-        Ok(rows)
-    }
-
-    async fn handle_error_and_should_it_retry(
-        configuration: &PostgresConfig,
-        connection: &PooledConnection<'_, PostgresConnectionManager<NoTls>>,
-        e: SinkError,
-    ) -> Result<bool, SinkError> {
-        return match e {
-            SinkError::Postgres(postgres_error) => match postgres_error.as_db_error() {
-                Some(dberror) => match *dberror.code() {
-                    SqlState::INSUFFICIENT_PRIVILEGE => {
-                        log::error!(
-                            "Do you need to grant permissions or reset the table's owner? {:?}",
-                            dberror
-                        );
-
-                        Ok(false)
-                    }
-                    _ => {
-                        log::error!("unhandled db error: ${err:?}", err = dberror);
-
-                        Ok(false)
+        if configuration.dry_run {
+            for change in &schema_changes {
+                match change {
+                    SchemaChange::AddTable(table) => {
+                        tracing::info!(table = %table, "dry run: would create table")
                     }
-                },
-                None => match postgres_error.source() {
-                    Some(client_error) => {
-                        if client_error.is::<WrongType>() {
-                            log::error!("Dropping batch due to mismatch between postgres type and batch type: {:?}", client_error);
-
-                            Ok(false)
-                        } else {
-                            Ok(false)
-                        }
+                    SchemaChange::AddColumn {
+                        table,
+                        column,
+                        data_type,
+                    } => {
+                        tracing::info!(table = %table, column = %column, data_type = %data_type, "dry run: would add column")
                     }
-                    None => {
-                        log::error!("postgres without cause: ${err:?}", err = postgres_error);
+                }
+            }
+            tracing::info!(table = %table_name, rows = datums.len(), "dry run: would write rows");
+            // So each change is reported once, like it would be made once
+            schema_cache.remember_table(&table_name);
+            remember_batch_columns();
+            return Ok(datums.len());
+        }
 
-                        Ok(false)
-                    }
-                },
-            },
-            SinkError::MissingColumn(what_column) => {
-                log::info!("adding missing column {:?}", what_column);
-                match connection.client().simple_query("select 1").await {
-                    Ok(_) => {
-                        log::info!("using connection for dml")
+        if !schema_changes.is_empty() {
+            for change in &schema_changes {
+                match change {
+                    SchemaChange::AddTable(table) => {
+                        tracing::info!(table = %table, "creating table before copy");
+                        DDL_OPERATIONS.with_label_values(&["create_table"]).inc();
                     }
-                    Err(e) => {
-                        log::info!("connection is hosed: {:?}", e)
+                    SchemaChange::AddColumn { table, column, .. } => {
+                        tracing::info!(table = %table, column = %column, "adding new column before copy");
+                        DDL_OPERATIONS.with_label_values(&["add_column"]).inc();
                     }
                 }
-
-                ddl::add_column(
-                    connection.client(),
-                    &what_column.table,
-                    &what_column.column,
-                    &what_column.data_type,
-                )
-                .await?;
-
-                Ok(true)
-            }
-            SinkError::MissingTable(what_table) => {
-                log::info!("adding missing table {:?}", what_table);
-                ddl::create_table(
-                    connection.client(),
-                    &what_table.table,
-                    &configuration.default_retention,
-                    configuration.compress_new_tables,
-                )
-                .await?;
-
-                Ok(true)
-            }
-            SinkError::DescribedError(e) => {
-                log::error!("error while sending metrics, dropping: {e:?}");
-                Ok(false)
             }
-            SinkError::StringError(e) => {
-                log::error!("error while sending metrics, dropping: {e:?}");
-                Ok(false)
+            let audit_events: Vec<DdlAuditEvent> = schema_changes
+                .iter()
+                .map(|change| DdlAuditEvent::of_change(change, metric))
+                .collect();
+            match ddl::apply_schema_changes(
+                client.client(),
+                schema_changes,
+                &configuration.create_table_options(),
+                &configuration.time_constraint,
+                &configuration.timescale_mode,
+            )
+            .await
+            {
+                Ok(()) => {
+                    schema_cache.remember_table(&table_name);
+                    if let Some(ddl_audit) = ddl_audit {
+                        ddl_audit.record(client.client(), &audit_events).await;
+                    }
+                }
+                Err(SinkError::Postgres(e)) if e.code() == Some(&SqlState::UNDEFINED_TABLE) => {
+                    schema_cache.forget_table(&table_name);
+                    return Err(SinkError::MissingTable(MissingTable { table: table_name }));
+                }
+                Err(e) => return Err(e),
             }
-            SinkError::OtherError(e) => {
-                log::error!("error while sending metrics, dropping: {e:?}");
-                Ok(false)
+        }
+        for (column, data_type) in new_columns {
+            let index_method = if column == tags_column {
+                configuration.index_tags.then_some(IndexMethod::Gin)
+            } else {
+                (configuration.auto_index_dimensions
+                    && matches!(data_type, "text" | "int8")
+                    && is_dimension_column(datums, &column, identifier_mode))
+                .then_some(IndexMethod::Btree)
+            };
+            if let Some(index_method) = index_method {
+                PostgresSender::index_in_background(
+                    connector.clone(),
+                    configuration.timescale_mode.clone(),
+                    table_name.clone(),
+                    column,
+                    index_method,
+                );
             }
-        };
-    }
-}
+        }
 
-async fn write_and_close(
-    sink: CopyInSink<bytes::Bytes>,
-    dimensions: &BTreeMap<String, Type>,
-    measurements: &BTreeMap<String, Type>,
-    data: &[Datum],
-) -> Result<usize, SinkError> {
-    log::debug!("writing {} rows", data.len());
+        // Idempotent writes always go through a staging table, even small ones
+        if !configuration.idempotent_writes && datums.len() < configuration.insert_below_rows {
+            let column_types: Vec<&str> = all_column_names
+                .iter()
+                .map(|column| match column.as_str() {
+                    "time" => "timestamptz",
+                    column => column_ddl_types.get(column).copied().unwrap_or("text"),
+                })
+                .collect();
+            let rows_of_fields: Vec<Vec<Option<String>>> = datums
+                .iter()
+                .map(|datum| {
+                    row_fields(
+                        configuration,
+                        has_tags,
+                        &dimension_types,
+                        &measurement_types,
+                        datum,
+                    )
+                })
+                .collect();
+            rows += match insert_rows(
+                client,
+                &table_name,
+                &all_column_names,
+                &column_types,
+                &rows_of_fields,
+            )
+            .await
+            {
+                Ok(rows) => rows,
+                Err(postgres_error) => {
+                    return Err(explain_statement_error(
+                        postgres_error,
+                        table_name,
+                        datums,
+                        identifier_mode,
+                    ))
+                }
+            };
+        } else {
+            // A retried COPY would write again whatever made it in the first time. With
+            // --idempotent-writes the rows are copied to a temp table first, and only the ones
+            // the table doesn't have yet are moved over.
+            let staging_table = if configuration.idempotent_writes {
+                match create_staging_table(client, &table_name).await {
+                    Ok(staging_table) => Some(staging_table),
+                    Err(postgres_error) => {
+                        return Err(explain_statement_error(
+                            postgres_error,
+                            table_name,
+                            datums,
+                            identifier_mode,
+                        ))
+                    }
+                }
+            } else {
+                None
+            };
+            let copy_table = staging_table.as_deref().unwrap_or(&table_name);
+            let sink: CopyInSink<bytes::Bytes> = match client
+                .copy_in(&format!(
+                    "copy {copy_table} ({all_columns}) from stdin with ({copy_options})",
+                    all_columns = all_column_names.join(","),
+                    copy_options = CopyRowWriter::copy_options(copy_format),
+                ))
+                .await
+            {
+                Ok(sink) => sink,
+                Err(postgres_error) => {
+                    return Err(explain_statement_error(
+                        postgres_error,
+                        table_name,
+                        datums,
+                        identifier_mode,
+                    ))
+                }
+            };
 
-    let mut writer = csv::WriterBuilder::new()
-        .buffer_capacity(4 * (1 << 10))
-        .has_headers(false)
-        .from_writer(Vec::with_capacity(4 * (1 << 10)));
+            rows += match write_and_close(
+                sink,
+                configuration,
+                has_tags,
+                &dimension_types,
+                &measurement_types,
+                datums,
+            )
+            .await
+            {
+                Ok(rows) => rows,
+                Err(SinkError::Postgres(postgres_error)) => {
+                    return Err(
+                        match PostgresSender::explain_copy_type_error(
+                            client,
+                            &table_name,
+                            &measurement_types,
+                            identifier_mode,
+                            postgres_error,
+                        )
+                        .await
+                        {
+                            SinkError::Postgres(postgres_error) => {
+                                explain_bad_row(metric, datums, postgres_error)
+                            }
+                            e => e,
+                        },
+                    )
+                }
+                Err(e) => return Err(e),
+            };
+            if let Some(staging_table) = staging_table {
+                let mut identity_columns: Vec<String> = dimension_types
+                    .keys()
+                    .map(|d| identifier(identifier_mode, d))
+                    .collect();
+                if configuration.timestamp_precision
+                    == TimestampPrecision::NanosecondsInSeparateColumn
+                {
+                    identity_columns.push(identifier(identifier_mode, TIME_NS_REMAINDER_COLUMN));
+                }
+                rows = match insert_new_rows(
+                    client,
+                    &table_name,
+                    &staging_table,
+                    &all_column_names,
+                    &identity_columns,
+                )
+                .await
+                {
+                    Ok(rows) => rows,
+                    Err(postgres_error) => {
+                        return Err(explain_statement_error(
+                            postgres_error,
+                            table_name,
+                            datums,
+                            identifier_mode,
+                        ))
+                    }
+                };
+            }
+        }
+        ROWS_WRITTEN.inc_by(rows as u64);
+        if schema_cache.count_rows_for_analyze(
+            &table_name,
+            rows as u64,
+            configuration.vacuum_analyze_threshold,
+        ) {
+            PostgresSender::analyze_in_background(connector.clone(), table_name.clone());
+        }
 
-    for datum in data {
-        let datum_time = humantime::format_rfc3339(
-            SystemTime::UNIX_EPOCH + Duration::from_nanos(datum.unix_nanos),
-        )
-        .to_string();
-        writer
-            .write_field(datum_time)
-            .map_err(|e| SinkError::other("failed writing time in csv", Box::new(e)))?;
-        log::debug!("writing datum: {datum:?}");
-        for dimension_name in dimensions.keys() {
-            if !datum.dimensions.contains_key(dimension_name) {
-                log::warn!("skipping dimension: {}", dimension_name);
-                writer.write_field(b"").map_err(|e| {
-                    SinkError::other("failed writing nonexistent dimension in csv", Box::new(e))
-                })?;
-                continue;
-            }
-
-            let dimension = &datum.dimensions[dimension_name];
-            if let Some(value) = dimension.value.as_ref() {
-                match value {
-                    dimension::Value::String(s) => writer.write_field(s),
-                    dimension::Value::Number(n) => writer.write_field(n.to_string()),
-                    dimension::Value::Boolean(b) => writer.write_field(b.to_string()),
+        remember_batch_columns();
+
+        Ok(rows)
+    }
+
+    // Building an index can take a long time on a big table, so it gets its own connection
+    fn index_in_background(
+        connector: PostgresConnector,
+        timescale_mode: TimescaleMode,
+        table_name: String,
+        column: String,
+        index_method: IndexMethod,
+    ) {
+        tokio::spawn(async move {
+            let connection = match connector.use_connection().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    tracing::warn!(table = %table_name, column = %column, "no connection to index the new column: {e:?}");
+                    return;
                 }
-            } else {
-                writer.write_field(b"")
-            }
-            .map_err(|e| SinkError::other("failed writing dimension in csv", Box::new(e)))?
-        }
-        for measurement_name in measurements.keys() {
-            let measurement = datum.measurements.get(measurement_name);
-            if let Some(m) = measurement {
-                if let Some(value) = &m.value {
-                    match value {
-                        measurement::Value::I64(i) => writer.write_field(i.to_string()),
-                        measurement::Value::I32(i) => writer.write_field(i.to_string()),
-                        measurement::Value::F64(f) => writer.write_field(f.to_string()),
-                        measurement::Value::F32(f) => writer.write_field(f.to_string()),
-                        measurement::Value::StatisticSet(s) => {
-                            let a: SqlStatisticSet = s.clone().into();
-                            writer.write_field(a.to_string())
-                        }
-                        measurement::Value::Histogram(h) => {
-                            writer.write_field(to_jsonmap(h).to_string())
-                        }
-                        measurement::Value::Tdigest(t) => {
-                            writer.write_field(SqlTdigest::from(t).to_string())
-                        }
+            };
+            DDL_OPERATIONS.with_label_values(&["create_index"]).inc();
+            let started = Instant::now();
+            match ddl::create_index(
+                connection.client(),
+                &table_name,
+                &column,
+                index_method,
+                &timescale_mode,
+            )
+            .await
+            {
+                Ok(()) => {
+                    tracing::info!(table = %table_name, column = %column, elapsed = ?started.elapsed(), "indexed new column")
+                }
+                Err(e) => {
+                    tracing::warn!(table = %table_name, column = %column, "failed to index new column: {e:?}")
+                }
+            }
+        });
+    }
+
+    // Big COPYs leave the planner's statistics stale. ANALYZE reads a sample of the table, so it
+    // gets its own connection rather than holding up the next COPY.
+    fn analyze_in_background(connector: PostgresConnector, table_name: String) {
+        tokio::spawn(async move {
+            let connection = match connector.use_connection().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    tracing::warn!(table = %table_name, "no connection to analyze the table: {e:?}");
+                    return;
+                }
+            };
+            ANALYZE_OPERATIONS.inc();
+            let started = Instant::now();
+            match connection
+                .execute(&format!("analyze verbose {table_name}"), &[])
+                .await
+            {
+                Ok(_) => {
+                    tracing::info!(table = %table_name, elapsed = ?started.elapsed(), "analyzed table")
+                }
+                Err(e) => tracing::warn!(table = %table_name, "failed to analyze table: {e:?}"),
+            }
+        });
+    }
+
+    // The first time a table comes up, learn its columns, so the first COPY doesn't have to
+    // fail to find out. False when the table doesn't exist yet.
+    async fn preflight_table(
+        client: &PooledConnection<'_, RotatingConnectionManager>,
+        type_converter: &TypeConverter,
+        schema_cache: &SchemaCache,
+        identifier_mode: IdentifierMode,
+        table_name: &str,
+    ) -> Result<bool, SinkError> {
+        let columns = ddl::table_columns(client.client(), table_name).await?;
+        if columns.is_empty() {
+            return Ok(false);
+        }
+        tracing::debug!(table = %table_name, columns = columns.len(), "found existing table");
+        schema_cache.remember_columns(
+            table_name,
+            columns.into_iter().map(|(column, oid)| {
+                (
+                    catalog_identifier(identifier_mode, &column),
+                    type_converter.type_from_oid(oid),
+                )
+            }),
+        );
+        Ok(true)
+    }
+
+    // A column that used to get statistic_sets can't parse the histograms a newer client sends.
+    // That shows up as a bad value in the COPY, which is recognized here so it can be migrated.
+    async fn explain_copy_type_error(
+        client: &PooledConnection<'_, RotatingConnectionManager>,
+        table_name: &str,
+        measurement_types: &BTreeMap<String, Type>,
+        identifier_mode: IdentifierMode,
+        postgres_error: tokio_postgres::Error,
+    ) -> SinkError {
+        let column = match postgres_error.as_db_error() {
+            Some(dberror)
+                if *dberror.code() == SqlState::INVALID_TEXT_REPRESENTATION
+                    || *dberror.code() == SqlState::DATATYPE_MISMATCH =>
+            {
+                dberror
+                    .where_()
+                    .and_then(|context| COPY_COLUMN.captures(context))
+                    .and_then(|captures| captures.name("column"))
+                    .map(|column| column.as_str().to_string())
+            }
+            _ => None,
+        };
+        let column = match column {
+            Some(column) => column,
+            None => return SinkError::Postgres(postgres_error),
+        };
+        let column_identifier = catalog_identifier(identifier_mode, &column);
+        let sends_histogram = measurement_types.iter().any(|(name, sql_type)| {
+            identifier(identifier_mode, name) == column_identifier && *sql_type == Type::JSONB
+        });
+        if !sends_histogram {
+            return SinkError::Postgres(postgres_error);
+        }
+
+        match client
+            .query_opt(
+                "select t.typname::text from pg_attribute a join pg_type t on t.oid = a.atttypid where a.attrelid = to_regclass($1) and a.attname = $2",
+                &[&table_name, &column],
+            )
+            .await
+        {
+            Ok(Some(row)) if row.get::<_, String>(0) == "statistic_set" => {
+                SinkError::ColumnTypeChange(ColumnTypeChange {
+                    table: table_name.to_string(),
+                    column: column_identifier,
+                    from_type: "statistic_set".to_string(),
+                    to_type: "histogram".to_string(),
+                })
+            }
+            Ok(_) => SinkError::Postgres(postgres_error),
+            Err(e) => {
+                tracing::warn!("could not look up the type of {table_name}.{column}: {e:?}");
+                SinkError::Postgres(postgres_error)
+            }
+        }
+    }
+
+    async fn handle_error_and_should_it_retry(
+        state: &SenderState,
+        connection: &PooledConnection<'_, RotatingConnectionManager>,
+        metric: &str,
+        e: SinkError,
+    ) -> Result<bool, SinkError> {
+        let SenderState {
+            configuration,
+            schema_cache,
+            ddl_audit,
+            ..
+        } = state;
+        SINK_ERRORS
+            .with_label_values(&[dead_letter_reason(&e).as_str()])
+            .inc();
+        if !e.is_transient() {
+            log_dropped_batch(&e);
+            return Ok(false);
+        }
+        match e {
+            SinkError::MissingColumn(what_column) => {
+                tracing::info!("adding missing column {:?}", what_column);
+                match connection.client().simple_query("select 1").await {
+                    Ok(_) => {
+                        tracing::info!("using connection for dml")
                     }
-                } else {
-                    writer.write_field(b"")
+                    Err(e) => {
+                        tracing::info!("connection is hosed: {:?}", e)
+                    }
+                }
+
+                DDL_OPERATIONS.with_label_values(&["add_column"]).inc();
+                ddl::add_column(
+                    connection.client(),
+                    &what_column.table,
+                    &what_column.column,
+                    &what_column.data_type,
+                    None,
+                )
+                .await?;
+                if let Some(ddl_audit) = ddl_audit {
+                    let event = DdlAuditEvent {
+                        operation: "add_column",
+                        table_name: what_column.table,
+                        column_name: Some(what_column.column),
+                        data_type: Some(what_column.data_type),
+                        triggered_by: metric.to_string(),
+                    };
+                    ddl_audit.record(connection.client(), &[event]).await;
+                }
+
+                Ok(true)
+            }
+            SinkError::MissingTable(what_table) => {
+                tracing::info!("adding missing table {:?}", what_table);
+                schema_cache.forget_table(&what_table.table);
+                DDL_OPERATIONS.with_label_values(&["create_table"]).inc();
+                ddl::create_table(
+                    connection.client(),
+                    &what_table.table,
+                    &configuration.create_table_options(),
+                    &configuration.time_constraint,
+                    &configuration.timescale_mode,
+                )
+                .await?;
+                // The retry can add all of the batch's columns before its COPY
+                schema_cache.remember_table(&what_table.table);
+                if let Some(ddl_audit) = ddl_audit {
+                    let event =
+                        DdlAuditEvent::of_change(&SchemaChange::AddTable(what_table.table), metric);
+                    ddl_audit.record(connection.client(), &[event]).await;
+                }
+
+                Ok(true)
+            }
+            SinkError::ColumnTypeChange(change) => {
+                tracing::info!("upgrading statistic_set column to histogram {:?}", change);
+                DDL_OPERATIONS.with_label_values(&["alter_column"]).inc();
+                ddl::upgrade_statistic_set_to_histogram(
+                    connection.client(),
+                    &change.table,
+                    &change.column,
+                )
+                .await?;
+                schema_cache.forget_table(&change.table);
+                if let Some(ddl_audit) = ddl_audit {
+                    let event = DdlAuditEvent {
+                        operation: "alter_column",
+                        table_name: change.table,
+                        column_name: Some(change.column),
+                        data_type: Some(change.to_type),
+                        triggered_by: metric.to_string(),
+                    };
+                    ddl_audit.record(connection.client(), &[event]).await;
+                }
+
+                Ok(true)
+            }
+            other => {
+                tracing::error!("transient error without a fix, dropping: {other:?}");
+                Ok(false)
+            }
+        }
+    }
+}
+
+fn log_dropped_batch(e: &SinkError) {
+    match e {
+        SinkError::Postgres(postgres_error) => match postgres_error.as_db_error() {
+            Some(dberror) => match *dberror.code() {
+                SqlState::INSUFFICIENT_PRIVILEGE => {
+                    tracing::error!(
+                        "Do you need to grant permissions or reset the table's owner? {:?}",
+                        dberror
+                    );
+                }
+                _ => {
+                    tracing::error!("unhandled db error: ${err:?}", err = dberror);
+                }
+            },
+            None => match postgres_error.source() {
+                Some(client_error) => {
+                    if client_error.is::<WrongType>() {
+                        tracing::error!("Dropping batch due to mismatch between postgres type and batch type: {:?}", client_error);
+                    }
+                }
+                None => {
+                    tracing::error!("postgres without cause: ${err:?}", err = postgres_error);
+                }
+            },
+        },
+        SinkError::ColumnTypeChange(change) => {
+            tracing::error!("unsupported column type change, dropping: {change:?}");
+        }
+        SinkError::BadRow(bad_row) => {
+            tracing::error!(
+                metric = %bad_row.metric,
+                row = bad_row.row,
+                column = ?bad_row.column,
+                datum = %bad_row.datum,
+                "postgres rejected a row, dropping the batch: {:?}",
+                bad_row.inner
+            );
+        }
+        SinkError::DescribedError(e) => {
+            tracing::error!("error while sending metrics, dropping: {e:?}");
+        }
+        SinkError::StringError(e) => {
+            tracing::error!("error while sending metrics, dropping: {e:?}");
+        }
+        SinkError::OtherError(e) => {
+            tracing::error!("error while sending metrics, dropping: {e:?}");
+        }
+        SinkError::MissingColumn(_) | SinkError::MissingTable(_) => {
+            tracing::error!("dropping: {e:?}");
+        }
+    }
+}
+
+// Missing tables and columns look the same whether a COPY or an INSERT found them
+fn explain_statement_error(
+    postgres_error: tokio_postgres::Error,
+    table_name: String,
+    datums: &[Datum],
+    identifier_mode: IdentifierMode,
+) -> SinkError {
+    let Some(dberror) = postgres_error.as_db_error() else {
+        return SinkError::Postgres(postgres_error);
+    };
+    match *dberror.code() {
+        SqlState::UNDEFINED_COLUMN => {
+            let column = match UNDEFINED_COLUMN.captures(dberror.message()) {
+                Some(pair) => {
+                    let table = pair.name("table").map(|m| m.as_str()).unwrap_or_default();
+                    let column = pair.name("column").map(|m| m.as_str()).unwrap_or_default();
+                    tracing::info!(table, column, "missing column");
+                    column
+                }
+                None => {
+                    return SinkError::StringError(StringError {
+                        message: format!("unable to find table and column in error: {dberror:?}"),
+                    });
                 }
+            };
+            let the_type = if column == TIME_NS_REMAINDER_COLUMN {
+                Some("int8")
+            } else if column == TAGS_COLUMN {
+                Some("jsonb")
             } else {
-                writer.write_field(b"")
+                datums
+                    .iter()
+                    .filter_map(|d| match d.dimensions.get(column) {
+                        Some(dim) => Some(sql_dimension_type_string(dim)),
+                        None => d.measurements.get(column).map(sql_data_type_string),
+                    })
+                    .reduce(wider_sql_type_string)
+            };
+            match the_type {
+                Some(t) => SinkError::MissingColumn(MissingColumn {
+                    // The message names the relation without its schema
+                    table: table_name,
+                    column: catalog_identifier(identifier_mode, column),
+                    data_type: t.to_string(),
+                }),
+                None => SinkError::DescribedError(DescribedError {
+                    message: "Type not foud, can't add column".to_string(),
+                    inner: postgres_error,
+                }),
+            }
+        }
+        SqlState::UNDEFINED_TABLE => {
+            let table = match UNDEFINED_TABLE.captures(dberror.message()) {
+                Some(table_captures) => table_captures
+                    .name("table")
+                    .map(|f| f.as_str())
+                    .unwrap_or_default(),
+                None => "__unknown__",
+            };
+            tracing::info!(table, "missing table");
+
+            // The message has the name unquoted, which loses its case with quoted identifiers
+            SinkError::MissingTable(MissingTable { table: table_name })
+        }
+        _ => SinkError::Postgres(postgres_error),
+    }
+}
+
+// A temp table is only visible to its connection, so each connection has its own. It's
+// remade each time so it picks up the table's new columns.
+async fn create_staging_table(
+    client: &PooledConnection<'_, RotatingConnectionManager>,
+    table_name: &str,
+) -> Result<String, tokio_postgres::Error> {
+    let unqualified_table = unqualified_table_name(table_name);
+    let staging_table = derived_id(&format!("goodmetrics_staging_{unqualified_table}"));
+    client
+        .batch_execute(&format!(
+            "drop table if exists pg_temp.{staging_table};
+            create temp table {staging_table} (like {table_name} including defaults)"
+        ))
+        .await?;
+    Ok(staging_table)
+}
+
+// A row is already written when one with the same time and dimensions is in the table.
+// Measurements aren't compared: not every measurement type has an equality operator. The
+// on conflict covers a --time-unique-index table rejecting a row another writer just added.
+async fn insert_new_rows(
+    client: &PooledConnection<'_, RotatingConnectionManager>,
+    table_name: &str,
+    staging_table: &str,
+    column_names: &[String],
+    identity_columns: &[String],
+) -> Result<usize, tokio_postgres::Error> {
+    let columns = column_names.join(",");
+    // Time is never null, and plain equality there lets postgres use the time index.
+    // Dimensions a datum left out are null, which have to match each other.
+    let same_row = std::iter::once("written.time = staged.time".to_string())
+        .chain(
+            identity_columns
+                .iter()
+                .map(|column| format!("written.{column} is not distinct from staged.{column}")),
+        )
+        .join(" and ");
+    let inserted = client
+        .execute(
+            &format!(
+                "insert into {table_name} ({columns})
+                select {columns} from {staging_table} staged
+                where not exists (select 1 from {table_name} written where {same_row})
+                on conflict do nothing"
+            ),
+            &[],
+        )
+        .await?;
+    client
+        .batch_execute(&format!("drop table if exists pg_temp.{staging_table}"))
+        .await?;
+    Ok(inserted as usize)
+}
+
+// Each value goes over as text and is cast to its column's type, the same way COPY parses it
+async fn insert_rows(
+    client: &PooledConnection<'_, RotatingConnectionManager>,
+    table_name: &str,
+    column_names: &[String],
+    column_types: &[&str],
+    rows_of_fields: &[Vec<Option<String>>],
+) -> Result<usize, tokio_postgres::Error> {
+    tracing::debug!(rows = rows_of_fields.len(), "inserting rows");
+    let mut parameter = 0;
+    let values = rows_of_fields
+        .iter()
+        .map(|_| {
+            let row = column_types
+                .iter()
+                .map(|column_type| {
+                    parameter += 1;
+                    format!("${parameter}::text::{column_type}")
+                })
+                .join(",");
+            format!("({row})")
+        })
+        .join(",");
+    let parameters: Vec<&(dyn ToSql + Sync)> = rows_of_fields
+        .iter()
+        .flatten()
+        .map(|field| field as &(dyn ToSql + Sync))
+        .collect();
+    let inserted = client
+        .execute(
+            &format!(
+                "insert into {table_name} ({columns}) values {values}",
+                columns = column_names.join(","),
+            ),
+            &parameters,
+        )
+        .await?;
+    Ok(inserted as usize)
+}
+
+// The text of each column in a row, in the order get_all_column_names lists them
+fn row_fields(
+    configuration: &PostgresConfig,
+    has_tags: bool,
+    dimensions: &BTreeMap<String, Type>,
+    measurements: &BTreeMap<String, Type>,
+    datum: &Datum,
+) -> Vec<Option<String>> {
+    let mut fields = Vec::with_capacity(3 + dimensions.len() + measurements.len());
+    let mut buffer = String::new();
+    let _: Result<(), Infallible> = for_each_field(
+        configuration,
+        has_tags,
+        dimensions,
+        measurements,
+        datum,
+        &mut buffer,
+        |field| {
+            fields.push(field.map(str::to_string));
+            Ok(())
+        },
+    );
+    fields
+}
+
+/// Formats each of the datum's columns, in column order, into `buffer` and hands it to
+/// `write_field`. The buffer is reused from field to field and row to row, so a batch costs
+/// a handful of allocations rather than a few per field.
+fn for_each_field<E>(
+    configuration: &PostgresConfig,
+    has_tags: bool,
+    dimensions: &BTreeMap<String, Type>,
+    measurements: &BTreeMap<String, Type>,
+    datum: &Datum,
+    buffer: &mut String,
+    mut write_field: impl FnMut(Option<&str>) -> Result<(), E>,
+) -> Result<(), E> {
+    // Writing to a String can't fail, so the fmt::Results below are ignored
+    buffer.clear();
+    match configuration.timestamp_precision {
+        TimestampPrecision::Microseconds => {
+            let _ = write!(
+                buffer,
+                "{}",
+                humantime::format_rfc3339(
+                    SystemTime::UNIX_EPOCH + Duration::from_nanos(datum.unix_nanos),
+                )
+            );
+            write_field(Some(buffer.as_str()))?;
+        }
+        TimestampPrecision::NanosecondsInSeparateColumn => {
+            // Truncated rather than left for postgres to round, so time + remainder is exact
+            let remainder = datum.unix_nanos % 1000;
+            let _ = write!(
+                buffer,
+                "{}",
+                humantime::format_rfc3339(
+                    SystemTime::UNIX_EPOCH + Duration::from_nanos(datum.unix_nanos - remainder),
+                )
+            );
+            write_field(Some(buffer.as_str()))?;
+            buffer.clear();
+            let _ = write!(buffer, "{remainder}");
+            write_field(Some(buffer.as_str()))?;
+        }
+    }
+    if has_tags {
+        if datum.tags.is_empty() {
+            write_field(None)?;
+        } else {
+            // A map of strings always serializes
+            let tags = serde_json::to_string(&datum.tags).unwrap_or_default();
+            write_field(Some(&tags))?;
+        }
+    }
+    for (dimension_name, column_type) in dimensions {
+        let Some(value) = datum
+            .dimensions
+            .get(dimension_name)
+            .map(|dimension| dimension.value.as_ref())
+        else {
+            tracing::warn!("skipping dimension: {}", dimension_name);
+            write_field(None)?;
+            continue;
+        };
+        buffer.clear();
+        // A text column takes any value. The losers of a first-wins or last-wins type conflict
+        // are left null rather than failing the COPY.
+        let text_column = *column_type == Type::TEXT;
+        match value {
+            Some(dimension::Value::String(s)) if text_column => write_field(Some(s))?,
+            // Number is a uint64 on the wire but the column is int8. Reinterpreting the bits lets
+            // an i64 sent as u64 read back negative, and keeps values above i64::MAX from failing
+            // the whole COPY as out of range.
+            Some(dimension::Value::Number(n)) if text_column || *column_type == Type::INT8 => {
+                let _ = write!(buffer, "{}", *n as i64);
+                write_field(Some(buffer.as_str()))?
+            }
+            Some(dimension::Value::Boolean(b)) if text_column || *column_type == Type::BOOL => {
+                write_field(Some(if *b { "true" } else { "false" }))?
             }
-            .map_err(|e| SinkError::other("failed writing measurement in csv", Box::new(e)))?;
+            _ => write_field(None)?,
         }
-        writer
-            // write the end of the csv record: a \n
-            .write_record(None::<&[u8]>)
-            .map_err(|e| SinkError::other("failed writing end record in csv", Box::new(e)))?;
     }
-    let buffer = writer
-        .into_inner()
-        .map_err(|e| SinkError::other("failed fetching csv buffer", Box::new(e)))?;
+    for measurement_name in measurements.keys() {
+        let Some(value) = datum
+            .measurements
+            .get(measurement_name)
+            .and_then(|m| m.value.as_ref())
+        else {
+            // Columns come from every datum in the batch, so sparse datums leave some null
+            write_field(None)?;
+            continue;
+        };
+        buffer.clear();
+        let _ = match value {
+            measurement::Value::I64(i) => write!(buffer, "{i}"),
+            measurement::Value::I32(i) => write!(buffer, "{i}"),
+            measurement::Value::F64(f) => write!(buffer, "{f}"),
+            measurement::Value::F32(f) => write!(buffer, "{f}"),
+            measurement::Value::StatisticSet(s) => {
+                write!(buffer, "{}", SqlStatisticSet::from(s.clone()))
+            }
+            measurement::Value::Histogram(h) => {
+                match configuration.histogram_max_buckets {
+                    Some(max_buckets) => write_jsonmap(&compress(h, max_buckets), buffer),
+                    None => write_jsonmap(h, buffer),
+                };
+                Ok(())
+            }
+            measurement::Value::Tdigest(t) => write!(buffer, "{}", SqlTdigest::from(t)),
+            measurement::Value::Ratio(r) => write!(buffer, "{}", SqlRatio::from(r.clone())),
+            measurement::Value::ExponentialHistogram(e) => {
+                write!(buffer, "{}", SqlExponentialHistogram::from(e.clone()))
+            }
+        };
+        write_field(Some(buffer.as_str()))?;
+    }
+    Ok(())
+}
+
+async fn write_and_close(
+    sink: CopyInSink<bytes::Bytes>,
+    configuration: &PostgresConfig,
+    has_tags: bool,
+    dimensions: &BTreeMap<String, Type>,
+    measurements: &BTreeMap<String, Type>,
+    data: &[Datum],
+) -> Result<usize, SinkError> {
+    tracing::debug!(rows = data.len(), "writing rows");
+
+    let buffer = copy_rows(configuration, has_tags, dimensions, measurements, data);
     let mut sink = pin!(sink);
     sink.send(bytes::Bytes::from(buffer)).await?;
     sink.finish().await?;
     Ok(data.len())
 }
 
-// time, dimensions[], measurements[]
+// The COPY's data, in the configured format
+fn copy_rows(
+    configuration: &PostgresConfig,
+    has_tags: bool,
+    dimensions: &BTreeMap<String, Type>,
+    measurements: &BTreeMap<String, Type>,
+    data: &[Datum],
+) -> Vec<u8> {
+    let mut writer = CopyRowWriter::new(configuration.copy_format);
+    let mut buffer = String::with_capacity(256);
+
+    for datum in data {
+        tracing::debug!("writing datum: {datum:?}");
+        let _: Result<(), Infallible> = for_each_field(
+            configuration,
+            has_tags,
+            dimensions,
+            measurements,
+            datum,
+            &mut buffer,
+            |field| {
+                match field {
+                    Some(field) => writer.write_field(field),
+                    None => writer.write_null(),
+                }
+                Ok(())
+            },
+        );
+        writer.end_record();
+    }
+    writer.into_inner()
+}
+
+// Rows are written in datum order with no header, so the COPY's line number finds the datum
+fn explain_bad_row(
+    metric: &str,
+    datums: &[Datum],
+    postgres_error: tokio_postgres::Error,
+) -> SinkError {
+    let Some(dberror) = postgres_error.as_db_error() else {
+        return SinkError::Postgres(postgres_error);
+    };
+    let Some(context) = dberror.where_() else {
+        return SinkError::Postgres(postgres_error);
+    };
+    let line = COPY_LINE
+        .captures(context)
+        .and_then(|captures| captures.name("line"))
+        .and_then(|line| line.as_str().parse::<usize>().ok());
+    let Some(row) = line.and_then(|line| line.checked_sub(1)) else {
+        return SinkError::Postgres(postgres_error);
+    };
+    let Some(datum) = datums.get(row) else {
+        return SinkError::Postgres(postgres_error);
+    };
+    let column = COPY_COLUMN
+        .captures(context)
+        .and_then(|captures| captures.name("column"))
+        .map(|column| column.as_str().to_string());
+
+    SinkError::BadRow(BadRow {
+        metric: metric.to_string(),
+        row,
+        column,
+        datum: format!("{datum:?}"),
+        inner: postgres_error,
+    })
+}
+
+// time, [time_ns_remainder], [tags], dimensions[], measurements[]
 fn get_all_column_names(
+    timestamp_precision: TimestampPrecision,
+    identifier_mode: IdentifierMode,
+    has_tags: bool,
     dimension_types: &BTreeMap<String, Type>,
     measurement_types: &BTreeMap<String, Type>,
 ) -> Vec<String> {
     let mut all_column_types: Vec<String> = vec!["time".to_string()];
-    all_column_types.extend(dimension_types.keys().map(|d| clean_id(d)));
-    all_column_types.extend(measurement_types.keys().map(|d| clean_id(d)));
+    if timestamp_precision == TimestampPrecision::NanosecondsInSeparateColumn {
+        all_column_types.push(identifier(identifier_mode, TIME_NS_REMAINDER_COLUMN));
+    }
+    if has_tags {
+        all_column_types.push(identifier(identifier_mode, TAGS_COLUMN));
+    }
+    all_column_types.extend(
+        dimension_types
+            .keys()
+            .map(|d| identifier(identifier_mode, d)),
+    );
+    all_column_types.extend(
+        measurement_types
+            .keys()
+            .map(|d| identifier(identifier_mode, d)),
+    );
     all_column_types
 }
 
-fn group_metrics(batch: Vec<Datum>) -> BTreeMap<String, Vec<Datum>> {
-    let grouped_metrics: BTreeMap<String, Vec<Datum>> = batch
-        .into_iter()
-        // TODO: fix string copying here
-        .sorted_by_key(|d| d.metric.clone())
-        .group_by(|d| d.metric.clone())
-        .into_iter()
-        .map(|(metric, datums_iterable)| (metric, datums_iterable.collect::<Vec<Datum>>()))
+// Cleaned column name -> the sql type to create it with, for every column in the batch
+fn get_column_ddl_types(
+    datums: &[Datum],
+    dimension_types: &BTreeMap<String, Type>,
+    identifier_mode: IdentifierMode,
+) -> BTreeMap<String, &'static str> {
+    let mut column_types: BTreeMap<String, &'static str> = BTreeMap::new();
+    let dimension_columns = dimension_types
+        .iter()
+        .map(|(name, sql_type)| (name, dimension_type_string(sql_type)));
+    let measurement_columns = datums
+        .iter()
+        .flat_map(|d| d.measurements.iter())
+        .map(|(name, measurement)| (name, sql_data_type_string(measurement)));
+    for (name, data_type) in dimension_columns.chain(measurement_columns) {
+        if data_type == "unsupported" {
+            continue;
+        }
+        column_types
+            .entry(identifier(identifier_mode, name))
+            .and_modify(|existing| *existing = wider_sql_type_string(existing, data_type))
+            .or_insert(data_type);
+    }
+    column_types
+}
+
+fn is_dimension_column(datums: &[Datum], column: &str, identifier_mode: IdentifierMode) -> bool {
+    datums
+        .iter()
+        .flat_map(|d| d.dimensions.keys())
+        .any(|name| identifier(identifier_mode, name) == column)
+}
+
+// Groups datums by their types for the conflicting columns. A datum without the column fits
+// in any group, and a group takes on the type of the first of its datums that has it. Numbers
+// of any width fit together, since the group's column is made wide enough for all of them.
+fn split_type_conflicts(
+    type_converter: &TypeConverter,
+    conflicts: &[TypeConflict],
+    datums: Vec<Datum>,
+) -> Vec<Vec<Datum>> {
+    let columns: Vec<&str> = conflicts
+        .iter()
+        .map(|conflict| conflict.column.as_str())
+        .unique()
         .collect();
+    let mut groups: Vec<(Vec<Option<Type>>, Vec<Datum>)> = Vec::new();
+    for datum in datums {
+        let types: Vec<Option<Type>> = columns
+            .iter()
+            .map(|column| match datum.dimensions.get(*column) {
+                Some(dimension) => type_converter.dimension_sql_type(dimension),
+                None => datum
+                    .measurements
+                    .get(*column)
+                    .and_then(|measurement| type_converter.measurement_sql_type(measurement)),
+            })
+            .collect();
+        let fits = |group_types: &[Option<Type>]| {
+            group_types.iter().zip(types.iter()).all(|pair| match pair {
+                (Some(group_type), Some(datum_type)) => compatible_types(group_type, datum_type),
+                _ => true,
+            })
+        };
+        match groups.iter_mut().find(|(group_types, _)| fits(group_types)) {
+            Some((group_types, group)) => {
+                for (group_type, datum_type) in group_types.iter_mut().zip(types) {
+                    if group_type.is_none() {
+                        *group_type = datum_type;
+                    }
+                }
+                group.push(datum);
+            }
+            None => groups.push((types, vec![datum])),
+        }
+    }
+    groups.into_iter().map(|(_, group)| group).collect()
+}
+
+// Retried sends show up as exact duplicates. Equal hashes are compared in full before dropping.
+fn deduplicate(batch: Vec<Datum>) -> Vec<Datum> {
+    let mut seen: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut unique: Vec<Datum> = Vec::with_capacity(batch.len());
+    for datum in batch {
+        let same_hash = seen.entry(datum.content_hash()).or_default();
+        if same_hash.iter().any(|&index| unique[index] == datum) {
+            DEDUPLICATED_DATUMS.inc();
+            continue;
+        }
+        same_hash.push(unique.len());
+        unique.push(datum);
+    }
+    unique
+}
+
+// One pass, with a metric name copied once per metric rather than per datum. Dimension and
+// measurement names are prost Strings owned by each datum, so they can't share one allocation
+// without converting datums out of the generated type. The BTreeMap keeps tables written in
+// the same order batch to batch.
+fn group_metrics(batch: Vec<Datum>) -> BTreeMap<String, Vec<Datum>> {
+    let mut grouped_metrics: BTreeMap<String, Vec<Datum>> = BTreeMap::new();
+    for datum in batch {
+        match grouped_metrics.get_mut(&datum.metric) {
+            Some(datums) => datums.push(datum),
+            None => {
+                grouped_metrics.insert(datum.metric.clone(), vec![datum]);
+            }
+        }
+    }
     grouped_metrics
 }
 
@@ -495,11 +2011,37 @@ fn sql_data_type_string(measurement: &Measurement) -> &'static str {
             measurement::Value::StatisticSet(_) => "statistic_set",
             measurement::Value::Histogram(_) => "histogram",
             measurement::Value::Tdigest(_) => "tdigest",
+            measurement::Value::Ratio(_) => "ratio_t",
+            measurement::Value::ExponentialHistogram(_) => "jsonb",
         },
         None => "unsupported",
     }
 }
 
+// A batch can mix 32 and 64 bit values, or integers and floats, for the same column. Creating
+// the column from whichever datum came first would make the other rows fail until someone fixes
+// it by hand. Same widening as type_conversion::merge_types.
+fn wider_sql_type_string(a: &'static str, b: &'static str) -> &'static str {
+    match (a, b) {
+        ("int4", "int8") | ("float4", "float8") => b,
+        ("int4" | "int8", "float4" | "float8") | ("float4" | "float8", "int4" | "int8") => "float8",
+        _ => a,
+    }
+}
+
+// The ddl for a column of get_dimension_type_map's types
+fn dimension_type_string(sql_type: &Type) -> &'static str {
+    if *sql_type == Type::TEXT {
+        "text"
+    } else if *sql_type == Type::INT8 {
+        "int8"
+    } else if *sql_type == Type::BOOL {
+        "boolean"
+    } else {
+        "unsupported"
+    }
+}
+
 fn sql_dimension_type_string(dimension: &Dimension) -> &'static str {
     match &dimension.value {
         Some(value) => match value {
@@ -510,3 +2052,721 @@ fn sql_dimension_type_string(dimension: &Dimension) -> &'static str {
         None => "unsupported",
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use clap::Parser;
+    use communication::proto::goodmetrics::{
+        dimension, measurement, Datum, Dimension, ExponentialHistogram, Histogram, Measurement,
+        Ratio, StatisticSet, TDigest,
+    };
+    use tokio_postgres::{
+        types::{Kind, Type},
+        NoTls,
+    };
+
+    use super::{
+        copy_rows, get_column_ddl_types, row_fields, split_type_conflicts, wider_sql_type_string,
+        PostgresConfig, PostgresSender,
+    };
+    use crate::{
+        config::options::{
+            CopyFormat, DimensionTypeConflict, IdentifierMode, Options, TimestampPrecision,
+        },
+        postgres_things::type_conversion::TypeConverter,
+        sink::metricssendqueue::MetricsSendQueue,
+    };
+
+    // Tests that need a real postgres are ignored unless run with its connection string, e.g.
+    // GOODMETRICS_TEST_POSTGRES="host=127.0.0.1 user=postgres" cargo test -- --ignored
+    const TEST_POSTGRES: &str = "GOODMETRICS_TEST_POSTGRES";
+
+    fn configuration() -> PostgresConfig {
+        let options = Options::parse_from(["goodmetricsd", "--connection-string", "host=test"]);
+        PostgresConfig {
+            default_retention: options.default_retention,
+            compress_new_tables: false,
+            auto_index_dimensions: false,
+            index_tags: false,
+            vacuum_analyze_threshold: 0,
+            time_constraint: options.time_constraint,
+            timescale_mode: options.timescale_mode,
+            copy_format: CopyFormat::Text,
+            timestamp_precision: TimestampPrecision::Microseconds,
+            schema_name: None,
+            table_prefix: String::new(),
+            identifier_mode: IdentifierMode::LowercaseUnquoted,
+            histogram_max_buckets: None,
+            per_table_write_timeout: options.per_table_write_timeout,
+            insert_below_rows: 0,
+            per_metric_latency: false,
+            idempotent_writes: false,
+            dry_run: false,
+        }
+    }
+
+    // Goodmetrics' own types get their oids from the database, so these stand in for them
+    fn type_converter(dimension_type_conflict: DimensionTypeConflict) -> TypeConverter {
+        let custom_type =
+            |name: &str, oid| Type::new(name.to_string(), oid, Kind::Simple, "public".to_string());
+        TypeConverter {
+            statistic_set_type: custom_type("statistic_set", 100_001),
+            histogram_type: custom_type("histogram", 100_002),
+            tdigest_type: custom_type("tdigest", 100_003),
+            ratio_type: custom_type("ratio_t", 100_004),
+            dimension_type_conflict,
+        }
+    }
+
+    fn number_dimension(number: u64) -> Datum {
+        Datum {
+            metric: "requests".to_string(),
+            unix_nanos: 1_700_000_000_000_000_000,
+            dimensions: [(
+                "shard".to_string(),
+                Dimension {
+                    value: Some(dimension::Value::Number(number)),
+                },
+            )]
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    fn count_datum(unix_nanos: u64, host: Option<&str>) -> Datum {
+        Datum {
+            metric: "requests".to_string(),
+            unix_nanos,
+            dimensions: host
+                .map(|host| {
+                    (
+                        "host".to_string(),
+                        Dimension {
+                            value: Some(dimension::Value::String(host.to_string())),
+                        },
+                    )
+                })
+                .into_iter()
+                .collect(),
+            measurements: [(
+                "count".to_string(),
+                Measurement {
+                    value: Some(measurement::Value::I64(1)),
+                },
+            )]
+            .into(),
+            ..Default::default()
+        }
+    }
+
+    /// A sender writing plain tables to a fresh schema, and a client for reading them back
+    async fn test_sender(schema: &str, args: &[&str]) -> (PostgresSender, tokio_postgres::Client) {
+        let connection_string = std::env::var(TEST_POSTGRES)
+            .unwrap_or_else(|_| panic!("{TEST_POSTGRES} is the test postgres"));
+        let (client, connection) = tokio_postgres::connect(&connection_string, NoTls)
+            .await
+            .expect("test postgres is up");
+        tokio::spawn(connection);
+        client
+            .batch_execute(&format!(
+                // Plain postgres has no tdigest without the timescaledb toolkit, and the sender
+                // looks one up before it starts
+                "do $$ begin create domain tdigest as text; exception when duplicate_object then null; end $$;
+                drop schema if exists {schema} cascade;
+                create schema {schema}"
+            ))
+            .await
+            .expect("schema can be made");
+
+        let options = Options::parse_from(
+            [
+                "goodmetricsd",
+                "--connection-string",
+                &connection_string,
+                "--timescale-mode=false",
+                "--schema-name",
+                schema,
+            ]
+            .iter()
+            .chain(args),
+        );
+        let (_send_queue, receive_queue) = MetricsSendQueue::new();
+        let sender = PostgresSender::new_connection(&connection_string, receive_queue, options)
+            .await
+            .expect("sender connects");
+        (sender, client)
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres at GOODMETRICS_TEST_POSTGRES"]
+    async fn retried_idempotent_copy_writes_no_duplicates() {
+        let (sender, client) = test_sender("gm_test_idempotent", &["--idempotent-writes"]).await;
+        // Including a row without the host dimension, which has to match its own null
+        let datums = vec![
+            count_datum(1_700_000_000_000_000_000, Some("a")),
+            count_datum(1_700_000_000_000_000_000, Some("b")),
+            count_datum(1_700_000_001_000_000_000, None),
+        ];
+        for _ in 0..2 {
+            PostgresSender::send_some(sender.state.clone(), "requests".to_string(), datums.clone())
+                .await
+                .expect("rows are written");
+        }
+
+        let rows: i64 = client
+            .query_one("select count(*) from gm_test_idempotent.requests", &[])
+            .await
+            .expect("table is there")
+            .get(0);
+        assert_eq!(3, rows);
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres at GOODMETRICS_TEST_POSTGRES"]
+    async fn boolean_dimensions_read_back_as_booleans() {
+        for copy_format in ["csv", "text"] {
+            let schema = format!("gm_test_boolean_{copy_format}");
+            let (sender, client) = test_sender(&schema, &["--copy-format", copy_format]).await;
+            let healthy = |unix_nanos, healthy: Option<bool>| {
+                let mut datum = count_datum(unix_nanos, None);
+                if let Some(healthy) = healthy {
+                    datum.dimensions.insert(
+                        "healthy".to_string(),
+                        Dimension {
+                            value: Some(dimension::Value::Boolean(healthy)),
+                        },
+                    );
+                }
+                datum
+            };
+            let datums = vec![
+                healthy(1_700_000_000_000_000_000, Some(true)),
+                healthy(1_700_000_001_000_000_000, Some(false)),
+                healthy(1_700_000_002_000_000_000, None),
+            ];
+            PostgresSender::send_some(sender.state.clone(), "requests".to_string(), datums)
+                .await
+                .expect("rows are written");
+
+            let column_type: String = client
+                .query_one(
+                    "select data_type from information_schema.columns
+                    where table_schema = $1 and table_name = 'requests' and column_name = 'healthy'",
+                    &[&schema],
+                )
+                .await
+                .expect("column is there")
+                .get(0);
+            assert_eq!("boolean", column_type);
+            let healthy: Vec<Option<bool>> = client
+                .query(
+                    &format!("select healthy from {schema}.requests order by time"),
+                    &[],
+                )
+                .await
+                .expect("table is there")
+                .iter()
+                .map(|row| row.get(0))
+                .collect();
+            assert_eq!(
+                vec![Some(true), Some(false), None],
+                healthy,
+                "{copy_format}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres at GOODMETRICS_TEST_POSTGRES"]
+    async fn statistic_sets_read_back_field_for_field() {
+        let sets = [
+            StatisticSet {
+                minimum: 0.5,
+                maximum: 1e20,
+                samplesum: 1e-7,
+                samplecount: 4,
+            },
+            StatisticSet {
+                minimum: f64::NEG_INFINITY,
+                maximum: f64::INFINITY,
+                samplesum: f64::NAN,
+                samplecount: 2,
+            },
+        ];
+        for copy_format in ["csv", "text"] {
+            let schema = format!("gm_test_statistic_set_{copy_format}");
+            let (sender, client) = test_sender(&schema, &["--copy-format", copy_format]).await;
+            let datums = sets
+                .iter()
+                .enumerate()
+                .map(|(i, set)| {
+                    let mut datum = measurements_datum(vec![(
+                        "latency",
+                        measurement::Value::StatisticSet(set.clone()),
+                    )]);
+                    datum.unix_nanos += i as u64 * 1_000_000_000;
+                    datum
+                })
+                .collect();
+            PostgresSender::send_some(sender.state.clone(), "requests".to_string(), datums)
+                .await
+                .expect("rows are written");
+
+            let rows = client
+                .query(
+                    &format!(
+                        "select (latency).minimum, (latency).maximum, (latency).samplesum, (latency).samplecount
+                        from {schema}.requests order by time"
+                    ),
+                    &[],
+                )
+                .await
+                .expect("table is there");
+            assert_eq!(sets.len(), rows.len());
+            for (set, row) in sets.iter().zip(rows) {
+                let (minimum, maximum, samplesum, samplecount): (f64, f64, f64, i64) =
+                    (row.get(0), row.get(1), row.get(2), row.get(3));
+                assert_eq!(set.minimum, minimum, "{copy_format}");
+                assert_eq!(set.maximum, maximum, "{copy_format}");
+                assert!(
+                    set.samplesum == samplesum || (set.samplesum.is_nan() && samplesum.is_nan()),
+                    "{copy_format}: {samplesum}"
+                );
+                assert_eq!(set.samplecount as i64, samplecount, "{copy_format}");
+            }
+        }
+    }
+
+    #[test]
+    fn missing_measurements_and_dimensions_are_null() {
+        let dimensions = BTreeMap::from([("host".to_string(), Type::TEXT)]);
+        let measurements = BTreeMap::from([
+            ("count".to_string(), Type::INT8),
+            ("latency".to_string(), Type::FLOAT8),
+        ]);
+        let configuration = configuration();
+        let row =
+            |datum: &Datum| row_fields(&configuration, false, &dimensions, &measurements, datum);
+
+        // time, host, count, latency
+        let fields = row(&count_datum(1_700_000_000_000_000_000, None));
+        assert_eq!(4, fields.len());
+        assert_eq!(None, fields[1]);
+        assert_eq!(Some("1"), fields[2].as_deref());
+        assert_eq!(None, fields[3]);
+
+        let fields = row(&count_datum(1_700_000_000_000_000_000, Some("a")));
+        assert_eq!(Some("a"), fields[1].as_deref());
+    }
+
+    fn measurements_datum(measurements: Vec<(&str, measurement::Value)>) -> Datum {
+        Datum {
+            metric: "requests".to_string(),
+            unix_nanos: 1_700_000_000_000_000_000,
+            measurements: measurements
+                .into_iter()
+                .map(|(name, value)| (name.to_string(), Measurement { value: Some(value) }))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn column_ddl_type_per_variant() {
+        let datum = measurements_datum(vec![
+            ("i64", measurement::Value::I64(1)),
+            ("i32", measurement::Value::I32(1)),
+            ("f64", measurement::Value::F64(1.0)),
+            ("f32", measurement::Value::F32(1.0)),
+            (
+                "statistic_set",
+                measurement::Value::StatisticSet(StatisticSet::default()),
+            ),
+            (
+                "histogram",
+                measurement::Value::Histogram(Histogram::default()),
+            ),
+            ("tdigest", measurement::Value::Tdigest(TDigest::default())),
+            ("ratio", measurement::Value::Ratio(Ratio::default())),
+            (
+                "exponential_histogram",
+                measurement::Value::ExponentialHistogram(ExponentialHistogram::default()),
+            ),
+        ]);
+        let dimensions = BTreeMap::from([
+            ("host".to_string(), Type::TEXT),
+            ("shard".to_string(), Type::INT8),
+            ("canary".to_string(), Type::BOOL),
+        ]);
+        let column_types =
+            get_column_ddl_types(&[datum], &dimensions, IdentifierMode::LowercaseUnquoted);
+        let expected = BTreeMap::from([
+            ("i64".to_string(), "int8"),
+            ("i32".to_string(), "int4"),
+            ("f64".to_string(), "float8"),
+            ("f32".to_string(), "float4"),
+            ("statistic_set".to_string(), "statistic_set"),
+            ("histogram".to_string(), "histogram"),
+            ("tdigest".to_string(), "tdigest"),
+            ("ratio".to_string(), "ratio_t"),
+            ("exponential_histogram".to_string(), "jsonb"),
+            ("host".to_string(), "text"),
+            ("shard".to_string(), "int8"),
+            ("canary".to_string(), "boolean"),
+        ]);
+        assert_eq!(expected, column_types);
+    }
+
+    #[test]
+    fn mixed_integer_widths_make_one_int8_column() {
+        let i32_datum = measurements_datum(vec![("count", measurement::Value::I32(1))]);
+        let i64_datum = measurements_datum(vec![("count", measurement::Value::I64(1))]);
+        let expected = BTreeMap::from([("count".to_string(), "int8")]);
+        for batch in [
+            [i32_datum.clone(), i64_datum.clone()],
+            [i64_datum.clone(), i32_datum.clone()],
+        ] {
+            // So a table made from either batch has nothing to add for the other
+            assert_eq!(
+                expected,
+                get_column_ddl_types(&batch, &BTreeMap::new(), IdentifierMode::LowercaseUnquoted)
+            );
+        }
+    }
+
+    #[test]
+    fn dimension_type_conflicts_follow_the_strategy() {
+        let env = |value| Datum {
+            metric: "requests".to_string(),
+            unix_nanos: 1_700_000_000_000_000_000,
+            dimensions: [("env".to_string(), Dimension { value: Some(value) })].into(),
+            ..Default::default()
+        };
+        let datums = vec![
+            env(dimension::Value::Number(1)),
+            env(dimension::Value::String("prod".to_string())),
+            env(dimension::Value::Boolean(true)),
+        ];
+        // The losers of first-wins and last-wins are left null
+        for (strategy, column_type, fields) in [
+            (
+                DimensionTypeConflict::FirstWins,
+                Type::INT8,
+                [Some("1"), None, None],
+            ),
+            (
+                DimensionTypeConflict::LastWins,
+                Type::BOOL,
+                [None, None, Some("true")],
+            ),
+            (
+                DimensionTypeConflict::TextFallback,
+                Type::TEXT,
+                [Some("1"), Some("prod"), Some("true")],
+            ),
+        ] {
+            let converter = type_converter(strategy);
+            // Dimensions settle on 1 column type, so they never split the batch
+            let conflicts = converter.check_type_conflicts(&datums);
+            assert!(conflicts.is_empty());
+            assert_eq!(
+                1,
+                split_type_conflicts(&converter, &conflicts, datums.clone()).len()
+            );
+            let dimensions = converter.get_dimension_type_map(&datums);
+            assert_eq!(column_type, dimensions["env"], "{strategy:?}");
+            let written: Vec<Option<String>> = datums
+                .iter()
+                .map(|datum| {
+                    row_fields(
+                        &configuration(),
+                        false,
+                        &dimensions,
+                        &BTreeMap::new(),
+                        datum,
+                    )[1]
+                    .clone()
+                })
+                .collect();
+            assert_eq!(
+                fields.map(|field| field.map(str::to_string)).to_vec(),
+                written,
+                "{strategy:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn conflicting_measurements_split_the_batch() {
+        let converter = type_converter(DimensionTypeConflict::TextFallback);
+        let histogram = || measurement::Value::Histogram(Histogram::default());
+        let datums = vec![
+            measurements_datum(vec![("value", measurement::Value::I64(1))]),
+            measurements_datum(vec![("value", histogram())]),
+            // Numbers don't conflict with each other
+            measurements_datum(vec![("value", measurement::Value::F64(2.0))]),
+            measurements_datum(vec![("value", histogram())]),
+            // Without the column, it fits anywhere
+            measurements_datum(vec![("other", measurement::Value::I64(3))]),
+        ];
+        let conflicts = converter.check_type_conflicts(&datums);
+        assert_eq!(1, conflicts.len());
+        assert_eq!("value", conflicts[0].column);
+
+        let groups = split_type_conflicts(&converter, &conflicts, datums);
+        let group_columns: Vec<Vec<Option<Type>>> = groups
+            .iter()
+            .map(|group| {
+                group
+                    .iter()
+                    .map(|datum| {
+                        datum
+                            .measurements
+                            .get("value")
+                            .and_then(|value| converter.measurement_sql_type(value))
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(
+            vec![
+                vec![Some(Type::INT8), Some(Type::FLOAT8), None],
+                vec![Some(Type::JSONB), Some(Type::JSONB)],
+            ],
+            group_columns
+        );
+        // And each group is conflict-free on its own
+        for group in &groups {
+            assert!(converter.check_type_conflicts(group).is_empty());
+        }
+    }
+
+    #[test]
+    fn mixed_numbers_widen() {
+        assert_eq!("float8", wider_sql_type_string("int8", "float8"));
+        assert_eq!("float8", wider_sql_type_string("float8", "int8"));
+        assert_eq!("float8", wider_sql_type_string("int4", "float4"));
+        assert_eq!("int8", wider_sql_type_string("int4", "int8"));
+        assert_eq!("int8", wider_sql_type_string("int8", "int4"));
+        assert_eq!("float8", wider_sql_type_string("float4", "float8"));
+        // Anything else keeps the first type
+        assert_eq!("histogram", wider_sql_type_string("histogram", "int8"));
+    }
+
+    // Reads `copy ... with (format text)` data back into fields, null as None
+    fn parse_copy_text(data: &[u8]) -> Vec<Vec<Option<String>>> {
+        let unescape = |field: &str| {
+            let mut unescaped = String::with_capacity(field.len());
+            let mut chars = field.chars();
+            while let Some(c) = chars.next() {
+                if c != '\\' {
+                    unescaped.push(c);
+                    continue;
+                }
+                unescaped.push(match chars.next() {
+                    Some('t') => '\t',
+                    Some('n') => '\n',
+                    Some('r') => '\r',
+                    Some('b') => '\u{8}',
+                    Some('f') => '\u{c}',
+                    Some('v') => '\u{b}',
+                    Some(c) => c,
+                    None => panic!("dangling escape in {field}"),
+                });
+            }
+            unescaped
+        };
+        String::from_utf8(data.to_vec())
+            .expect("copy data is utf-8")
+            .lines()
+            .map(|line| {
+                line.split('\t')
+                    .map(|field| (field != "\\N").then(|| unescape(field)))
+                    .collect()
+            })
+            .collect()
+    }
+
+    // Reads `copy ... with (format csv)` data back into fields. Only an unquoted empty field
+    // is null, so this tracks quoting rather than leaning on a csv reader.
+    fn parse_copy_csv(data: &[u8]) -> Vec<Vec<Option<String>>> {
+        let data = String::from_utf8(data.to_vec()).expect("copy data is utf-8");
+        let mut rows = Vec::new();
+        let mut row = Vec::new();
+        let mut field = String::new();
+        let mut quoted = false;
+        let mut in_quotes = false;
+        let mut chars = data.chars().peekable();
+        while let Some(c) = chars.next() {
+            match (in_quotes, c) {
+                (true, '"') if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                (true, '"') => in_quotes = false,
+                (true, c) => field.push(c),
+                (false, '"') => {
+                    quoted = true;
+                    in_quotes = true;
+                }
+                (false, ',' | '\n') => {
+                    let value = std::mem::take(&mut field);
+                    row.push((quoted || !value.is_empty()).then_some(value));
+                    quoted = false;
+                    if c == '\n' {
+                        rows.push(std::mem::take(&mut row));
+                    }
+                }
+                (false, c) => field.push(c),
+            }
+        }
+        rows
+    }
+
+    // INSERT sends row_fields as its parameters, so they have to be what COPY would have read
+    #[test]
+    fn insert_parameters_match_copy_fields() {
+        let string = |value: &str| Dimension {
+            value: Some(dimension::Value::String(value.to_string())),
+        };
+        let mut datums = vec![
+            measurements_datum(vec![
+                ("count", measurement::Value::I64(-3)),
+                ("latency", measurement::Value::F64(0.25)),
+                (
+                    "stats",
+                    measurement::Value::StatisticSet(StatisticSet {
+                        minimum: f64::NEG_INFINITY,
+                        maximum: 2.0,
+                        samplesum: f64::NAN,
+                        samplecount: 4,
+                    }),
+                ),
+                (
+                    "spread",
+                    measurement::Value::Histogram(Histogram {
+                        buckets: [(10, 2), (20, 1)].into(),
+                    }),
+                ),
+            ]),
+            measurements_datum(vec![("count", measurement::Value::I64(7))]),
+            measurements_datum(vec![("latency", measurement::Value::F64(1e20))]),
+        ];
+        datums[0].dimensions.extend([
+            ("host".to_string(), string("web,01 \"east\"")),
+            (
+                "healthy".to_string(),
+                Dimension {
+                    value: Some(dimension::Value::Boolean(true)),
+                },
+            ),
+        ]);
+        datums[1].dimensions.insert(
+            "host".to_string(),
+            string("tab\there\nnewline \\N back\\slash"),
+        );
+        datums[2].dimensions.insert("host".to_string(), string(""));
+        datums[2].unix_nanos += 1_500;
+
+        let converter = type_converter(DimensionTypeConflict::TextFallback);
+        let dimensions = converter.get_dimension_type_map(&datums);
+        let measurements = converter.get_measurement_type_map(&datums);
+        let mut configuration = configuration();
+        let parameters: Vec<Vec<Option<String>>> = datums
+            .iter()
+            .map(|datum| row_fields(&configuration, false, &dimensions, &measurements, datum))
+            .collect();
+        // time, healthy, host, then the measurements
+        assert_eq!(None, parameters[1][1], "healthy is null where it's missing");
+
+        for (copy_format, parse) in [
+            (CopyFormat::Text, parse_copy_text as fn(&[u8]) -> _),
+            (CopyFormat::Csv, parse_copy_csv),
+        ] {
+            configuration.copy_format = copy_format;
+            let copied = copy_rows(&configuration, false, &dimensions, &measurements, &datums);
+            assert_eq!(parameters, parse(&copied), "{copy_format:?}");
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "needs a postgres at GOODMETRICS_TEST_POSTGRES"]
+    async fn insert_and_copy_write_the_same_rows() {
+        let string = |value: &str| Dimension {
+            value: Some(dimension::Value::String(value.to_string())),
+        };
+        let datums: Vec<Datum> = ["web,01 \"east\"", "tab\there\\N", "", "\\."]
+            .into_iter()
+            .enumerate()
+            .map(|(i, host)| {
+                let mut datum = measurements_datum(vec![
+                    ("count", measurement::Value::I64(i as i64)),
+                    (
+                        "stats",
+                        measurement::Value::StatisticSet(StatisticSet {
+                            minimum: f64::NEG_INFINITY,
+                            maximum: 2.0,
+                            samplesum: 0.5,
+                            samplecount: 4,
+                        }),
+                    ),
+                ]);
+                datum.unix_nanos += i as u64 * 1_000_000_000;
+                datum.dimensions.insert("host".to_string(), string(host));
+                datum
+            })
+            .collect();
+
+        let mut written = Vec::new();
+        for (schema, args) in [
+            ("gm_test_insert", ["--insert-below-rows", "100"]),
+            ("gm_test_copy_csv", ["--copy-format", "csv"]),
+            ("gm_test_copy_text", ["--copy-format", "text"]),
+        ] {
+            let (sender, client) = test_sender(schema, &args).await;
+            PostgresSender::send_some(sender.state.clone(), "requests".to_string(), datums.clone())
+                .await
+                .expect("rows are written");
+            let rows: Vec<String> = client
+                .query(
+                    &format!("select row(time, host, count, stats)::text from {schema}.requests order by time"),
+                    &[],
+                )
+                .await
+                .expect("table is there")
+                .iter()
+                .map(|row| row.get(0))
+                .collect();
+            written.push((schema, rows));
+        }
+
+        let (_, inserted) = &written[0];
+        assert_eq!(datums.len(), inserted.len());
+        for (schema, copied) in &written[1..] {
+            assert_eq!(inserted, copied, "{schema}");
+        }
+    }
+
+    #[test]
+    fn number_dimensions_reinterpret_as_int8() {
+        let dimensions = BTreeMap::from([("shard".to_string(), Type::INT8)]);
+        let shard = |number| {
+            row_fields(
+                &configuration(),
+                false,
+                &dimensions,
+                &BTreeMap::new(),
+                &number_dimension(number),
+            )[1]
+            .clone()
+        };
+        assert_eq!(shard(42).as_deref(), Some("42"));
+        assert_eq!(shard(u64::MAX).as_deref(), Some("-1"));
+        assert_eq!(
+            shard(i64::MIN as u64).as_deref(),
+            Some("-9223372036854775808")
+        );
+    }
+}
@@ -0,0 +1,123 @@
+use std::{collections::HashMap, time::Duration};
+
+use communication::proto::goodmetrics::{dimension, measurement, Datum, Measurement};
+
+use crate::postgres_things::{histogram::merge_histograms, statistic_set::merge_statistic_sets};
+
+#[derive(Hash, PartialEq, Eq)]
+enum DimensionKey {
+    String(String),
+    Number(u64),
+    Boolean(bool),
+    Unset,
+}
+
+#[derive(Hash, PartialEq, Eq)]
+struct AggregationKey {
+    metric: String,
+    time_bucket: u64,
+    dimensions: Vec<(String, DimensionKey)>,
+    // A measurement that's a histogram in 1 datum and a statistic_set in another can't be folded
+    histograms: Vec<String>,
+}
+
+/// Folds datums with the same metric, time window and dimensions into one row.
+/// Only datums made entirely of statistic_sets and histograms are folded; anything carrying
+/// a raw number would lose data, so it passes through untouched. A folded row keeps the first
+/// datum's tags.
+pub fn pre_aggregate(batch: Vec<Datum>, window: Duration) -> Vec<Datum> {
+    let mut aggregated: Vec<Datum> = Vec::with_capacity(batch.len());
+    let mut positions: HashMap<AggregationKey, usize> = HashMap::new();
+
+    for mut datum in batch {
+        if datum.measurements.is_empty() || !datum.measurements.values().all(is_aggregatable) {
+            aggregated.push(datum);
+            continue;
+        }
+
+        datum.unix_nanos = time_bucket(datum.unix_nanos, window);
+        let key = aggregation_key(&datum);
+        match positions.get(&key) {
+            Some(position) => merge_into(&mut aggregated[*position], datum),
+            None => {
+                positions.insert(key, aggregated.len());
+                aggregated.push(datum);
+            }
+        }
+    }
+    aggregated
+}
+
+fn time_bucket(unix_nanos: u64, window: Duration) -> u64 {
+    let window_nanos = window.as_nanos() as u64;
+    if window_nanos == 0 {
+        return unix_nanos;
+    }
+    unix_nanos - unix_nanos % window_nanos
+}
+
+fn is_aggregatable(measurement: &Measurement) -> bool {
+    matches!(
+        measurement.value,
+        Some(measurement::Value::StatisticSet(_) | measurement::Value::Histogram(_))
+    )
+}
+
+fn aggregation_key(datum: &Datum) -> AggregationKey {
+    let mut dimensions: Vec<(String, DimensionKey)> = datum
+        .dimensions
+        .iter()
+        .map(|(name, dimension)| {
+            let value = match &dimension.value {
+                Some(dimension::Value::String(s)) => DimensionKey::String(s.clone()),
+                Some(dimension::Value::Number(n)) => DimensionKey::Number(*n),
+                Some(dimension::Value::Boolean(b)) => DimensionKey::Boolean(*b),
+                None => DimensionKey::Unset,
+            };
+            (name.clone(), value)
+        })
+        .collect();
+    dimensions.sort_by(|a, b| a.0.cmp(&b.0));
+    let mut histograms: Vec<String> = datum
+        .measurements
+        .iter()
+        .filter(|(_, measurement)| {
+            matches!(measurement.value, Some(measurement::Value::Histogram(_)))
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+    histograms.sort();
+
+    AggregationKey {
+        metric: datum.metric.clone(),
+        time_bucket: datum.unix_nanos,
+        dimensions,
+        histograms,
+    }
+}
+
+fn merge_into(target: &mut Datum, source: Datum) {
+    for (name, measurement) in source.measurements {
+        match target.measurements.get_mut(&name) {
+            Some(existing) => merge_measurement(existing, measurement),
+            None => {
+                target.measurements.insert(name, measurement);
+            }
+        }
+    }
+}
+
+fn merge_measurement(target: &mut Measurement, source: Measurement) {
+    match (&mut target.value, &source.value) {
+        (
+            Some(measurement::Value::StatisticSet(target_set)),
+            Some(measurement::Value::StatisticSet(source_set)),
+        ) => *target_set = merge_statistic_sets(target_set, source_set),
+        (
+            Some(measurement::Value::Histogram(target_histogram)),
+            Some(measurement::Value::Histogram(source_histogram)),
+        ) => *target_histogram = merge_histograms(target_histogram, source_histogram),
+        // The aggregation key keeps histograms and statistic_sets apart
+        _ => {}
+    }
+}
@@ -0,0 +1,250 @@
+use std::{num::NonZeroUsize, time::Duration};
+
+use communication::proto::goodmetrics::Datum;
+use lru::LruCache;
+use tokio::time::Instant;
+
+use crate::self_metrics::RATE_LIMITED;
+
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// Drops a metric's datums past a limit per second, so 1 noisy client can't crowd out the rest,
+/// and datums of new metric names past a limit per second, so 1 can't create tables without end.
+/// Each metric's rate is a sliding window: the last full second's count, weighted by how much
+/// of it still overlaps the window, plus this second's count so far.
+pub struct MetricRateLimiter {
+    max_datums_per_second: u64,
+    max_new_metrics_per_second: u64,
+    new_metrics: NewMetricBudget,
+    // Metrics that haven't been seen in a while are forgotten, and start counting again
+    windows: LruCache<String, SlidingWindow>,
+}
+
+struct SlidingWindow {
+    started: Instant,
+    current: u64,
+    previous: u64,
+    // So a limited metric is only warned about once
+    warned: bool,
+}
+
+impl SlidingWindow {
+    fn new(now: Instant) -> Self {
+        Self {
+            started: now,
+            current: 0,
+            previous: 0,
+            warned: false,
+        }
+    }
+
+    fn advance(&mut self, now: Instant) {
+        // Saturates for datums sent before the window started
+        let elapsed = now.duration_since(self.started);
+        if elapsed < WINDOW {
+            return;
+        }
+        // A whole window went by without datums: the previous window is empty
+        self.previous = if elapsed < 2 * WINDOW {
+            self.current
+        } else {
+            0
+        };
+        self.current = 0;
+        let whole_windows = elapsed.as_nanos() / WINDOW.as_nanos();
+        self.started += WINDOW * whole_windows as u32;
+    }
+
+    fn estimate(&self, now: Instant) -> f64 {
+        let overlap = 1.0 - now.duration_since(self.started).as_secs_f64() / WINDOW.as_secs_f64();
+        self.previous as f64 * overlap.max(0.0) + self.current as f64
+    }
+}
+
+impl MetricRateLimiter {
+    pub fn new(
+        max_datums_per_second: u64,
+        max_new_metrics_per_second: u64,
+        tracked_metrics: usize,
+    ) -> Self {
+        Self {
+            max_datums_per_second,
+            max_new_metrics_per_second,
+            // A restart sees every metric as new, so a whole cache's worth are let in at once
+            new_metrics: NewMetricBudget::new(
+                tracked_metrics as f64,
+                max_new_metrics_per_second as f64,
+            ),
+            windows: LruCache::new(NonZeroUsize::new(tracked_metrics.max(1)).expect("nonzero")),
+        }
+    }
+
+    /// Datums count toward the second they were sent in, so the time a batch spends
+    /// collecting doesn't squeeze them into fewer seconds.
+    pub fn filter(&mut self, batch: Vec<Datum>, sent_at: Instant) -> Vec<Datum> {
+        batch
+            .into_iter()
+            .filter(|datum| self.admit(&datum.metric, sent_at))
+            .collect()
+    }
+
+    fn admit(&mut self, metric: &str, now: Instant) -> bool {
+        if !self.windows.contains(metric) {
+            if !self.admit_new_metric(metric, now) {
+                return false;
+            }
+            // Only allocates the key for a metric that isn't tracked yet
+            self.windows
+                .put(metric.to_string(), SlidingWindow::new(now));
+        }
+        let Some(window) = self.windows.get_mut(metric) else {
+            return true;
+        };
+        if !admit_in(window, self.max_datums_per_second, now) {
+            if !window.warned {
+                window.warned = true;
+                tracing::warn!(
+                    metric,
+                    max_datums_per_second = self.max_datums_per_second,
+                    "rate limiting metric"
+                );
+            }
+            RATE_LIMITED.with_label_values(&["metric_rate"]).inc();
+            return false;
+        }
+        true
+    }
+
+    // Every new name could be a new table, so a client making names up is cut off here
+    fn admit_new_metric(&mut self, metric: &str, now: Instant) -> bool {
+        if self.max_new_metrics_per_second == 0 || self.new_metrics.take(now) {
+            return true;
+        }
+        if !self.new_metrics.warned {
+            self.new_metrics.warned = true;
+            tracing::warn!(
+                metric,
+                max_new_metrics_per_second = self.max_new_metrics_per_second,
+                "rate limiting new metric names"
+            );
+        }
+        RATE_LIMITED.with_label_values(&["new_metrics"]).inc();
+        false
+    }
+}
+
+// A limit of 0 is no limit
+fn admit_in(window: &mut SlidingWindow, max_per_second: u64, now: Instant) -> bool {
+    window.advance(now);
+    if max_per_second != 0 && max_per_second as f64 <= window.estimate(now) {
+        return false;
+    }
+    window.current += 1;
+    true
+}
+
+// A token bucket: names the limiter isn't tracking spend a token each, and tokens come back at
+// a steady rate up to the bucket's size
+struct NewMetricBudget {
+    tokens: f64,
+    capacity: f64,
+    per_second: f64,
+    refilled: Option<Instant>,
+    warned: bool,
+}
+
+impl NewMetricBudget {
+    fn new(capacity: f64, per_second: f64) -> Self {
+        Self {
+            tokens: capacity,
+            capacity,
+            per_second,
+            refilled: None,
+            warned: false,
+        }
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        if let Some(refilled) = self.refilled {
+            let elapsed = now.duration_since(refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.per_second).min(self.capacity);
+        }
+        self.refilled = Some(now);
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use communication::proto::goodmetrics::Datum;
+    use tokio::time::Instant;
+
+    use super::MetricRateLimiter;
+
+    fn datums(metric: &str, count: usize) -> Vec<Datum> {
+        vec![
+            Datum {
+                metric: metric.to_string(),
+                ..Default::default()
+            };
+            count
+        ]
+    }
+
+    fn admitted(batch: &[Datum], metric: &str) -> usize {
+        batch.iter().filter(|datum| datum.metric == metric).count()
+    }
+
+    #[test]
+    fn a_noisy_metric_is_limited_and_the_rest_are_not() {
+        let mut limiter = MetricRateLimiter::new(100, 0, 10);
+        let now = Instant::now();
+        let mut batch = datums("noisy", 150);
+        batch.extend(datums("quiet", 50));
+        let admitted_batch = limiter.filter(batch, now);
+        assert_eq!(admitted(&admitted_batch, "noisy"), 100);
+        assert_eq!(admitted(&admitted_batch, "quiet"), 50);
+
+        // The next second only gets what the sliding window leaves of the limit
+        let next = limiter.filter(datums("noisy", 100), now + Duration::from_millis(1500));
+        assert_eq!(next.len(), 50);
+    }
+
+    #[test]
+    fn datums_count_toward_when_they_were_sent() {
+        // 80 a second is under a limit of 100 a second, whenever the batch gets processed
+        let mut limiter = MetricRateLimiter::new(100, 0, 10);
+        let start = Instant::now();
+        let admitted: usize = (0..50)
+            .map(|tenth| {
+                let sent_at = start + Duration::from_millis(100 * tenth);
+                limiter.filter(datums("steady", 8), sent_at).len()
+            })
+            .sum();
+        assert_eq!(admitted, 400);
+    }
+
+    #[test]
+    fn new_metric_names_are_limited_after_a_burst() {
+        let mut limiter = MetricRateLimiter::new(0, 2, 3);
+        let now = Instant::now();
+        let names: Vec<Datum> = (0..5)
+            .flat_map(|i| datums(&format!("made_up_{i}"), 1))
+            .collect();
+        assert_eq!(limiter.filter(names, now).len(), 3);
+        // Names already admitted aren't new
+        assert_eq!(limiter.filter(datums("made_up_0", 10), now).len(), 10);
+
+        let later = now + Duration::from_secs(1);
+        let names: Vec<Datum> = (5..10)
+            .flat_map(|i| datums(&format!("made_up_{i}"), 1))
+            .collect();
+        assert_eq!(limiter.filter(names, later).len(), 2);
+    }
+}
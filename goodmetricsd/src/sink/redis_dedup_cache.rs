@@ -0,0 +1,104 @@
+use std::{hash::Hasher, time::Duration};
+
+use communication::proto::goodmetrics::{dimension, Datum};
+use redis::aio::ConnectionManager;
+
+use crate::fnv::Fnv1a;
+
+use super::sink_error::SinkError;
+
+/// Remembers which datums have been written, in a redis shared by every goodmetricsd.
+/// When a client's grpc retry lands on a different server, the second write is skipped.
+pub struct RedisDedupCache {
+    connection: ConnectionManager,
+    ttl: Duration,
+}
+
+impl RedisDedupCache {
+    pub async fn new_connection(redis_url: &str, ttl: Duration) -> Result<Self, SinkError> {
+        let client = redis::Client::open(redis_url)
+            .map_err(|e| SinkError::other("invalid redis url", Box::new(e)))?;
+        let connection = ConnectionManager::new(client)
+            .await
+            .map_err(|e| SinkError::other("could not connect to redis", Box::new(e)))?;
+
+        Ok(Self { connection, ttl })
+    }
+
+    /// Drops datums that were already written. If redis is unhappy, everything is kept:
+    /// a duplicate is better than a hole.
+    pub async fn filter_unseen(&self, datums: Vec<Datum>) -> Vec<Datum> {
+        let keys: Vec<String> = datums.iter().map(dedup_key).collect();
+        let mut pipe = redis::pipe();
+        for key in &keys {
+            pipe.exists(key);
+        }
+
+        let mut connection = self.connection.clone();
+        match pipe.query_async::<_, Vec<bool>>(&mut connection).await {
+            Ok(seen) => {
+                let before = datums.len();
+                let unseen: Vec<Datum> = datums
+                    .into_iter()
+                    .zip(seen)
+                    .filter_map(|(datum, seen)| (!seen).then_some(datum))
+                    .collect();
+                if unseen.len() < before {
+                    tracing::info!("skipping {} datums already written", before - unseen.len());
+                }
+                unseen
+            }
+            Err(e) => {
+                tracing::warn!("redis dedup check failed, writing everything: {e:?}");
+                datums
+            }
+        }
+    }
+
+    pub async fn mark_written(&self, datums: &[Datum]) {
+        let mut pipe = redis::pipe();
+        for datum in datums {
+            pipe.set_ex(dedup_key(datum), 1, self.ttl.as_secs() as usize)
+                .ignore();
+        }
+
+        let mut connection = self.connection.clone();
+        if let Err(e) = pipe.query_async::<_, ()>(&mut connection).await {
+            tracing::warn!("failed to record written datums in redis: {e:?}");
+        }
+    }
+}
+
+// Every server needs to come up with the same key for a datum, so this uses a stable hash
+// over (metric, unix_nanos, dimensions) rather than the randomly seeded std hasher.
+fn dedup_key(datum: &Datum) -> String {
+    let mut hasher = Fnv1a::default();
+    hasher.write(datum.metric.as_bytes());
+    hasher.write_u8(0xff);
+    hasher.write(&datum.unix_nanos.to_le_bytes());
+
+    let mut dimensions: Vec<_> = datum.dimensions.iter().collect();
+    dimensions.sort_by(|a, b| a.0.cmp(b.0));
+    for (name, dimension) in dimensions {
+        hasher.write(name.as_bytes());
+        hasher.write_u8(0xff);
+        match &dimension.value {
+            Some(dimension::Value::String(s)) => {
+                hasher.write_u8(1);
+                hasher.write(s.as_bytes());
+                hasher.write_u8(0xff);
+            }
+            Some(dimension::Value::Number(n)) => {
+                hasher.write_u8(2);
+                hasher.write(&n.to_le_bytes());
+            }
+            Some(dimension::Value::Boolean(b)) => {
+                hasher.write_u8(3);
+                hasher.write_u8(*b as u8);
+            }
+            None => hasher.write_u8(0),
+        }
+    }
+
+    format!("goodmetrics:dedup:{:016x}", hasher.finish())
+}
@@ -19,17 +19,97 @@ pub enum SinkError {
     #[error("i gotta have more table")]
     MissingTable(#[from] MissingTable),
 
+    #[error("a column needs a different type")]
+    ColumnTypeChange(#[from] ColumnTypeChange),
+
+    #[error("postgres rejected a row")]
+    BadRow(#[from] BadRow),
+
     #[error("something else happened")]
     OtherError(#[from] OtherError),
 }
 
 impl SinkError {
-    pub fn other(message: impl Into<String>, inner: Box<dyn std::error::Error>) -> SinkError {
+    pub fn other(
+        message: impl Into<String>,
+        inner: Box<dyn std::error::Error + Send + Sync>,
+    ) -> SinkError {
         SinkError::OtherError(OtherError {
             message: message.into(),
             inner,
         })
     }
+
+    /// Whether the batch is worth sending again once the sender has fixed the schema.
+    /// Missing tables and columns get created, and statistic_set columns upgrade to histograms.
+    /// Everything else, like a column of the wrong type or a lack of privileges, would fail
+    /// the same way again.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            SinkError::MissingColumn(_) | SinkError::MissingTable(_) => true,
+            SinkError::ColumnTypeChange(change) => change.is_supported(),
+            SinkError::Postgres(_)
+            | SinkError::DescribedError(_)
+            | SinkError::StringError(_)
+            | SinkError::BadRow(_)
+            | SinkError::OtherError(_) => false,
+        }
+    }
+
+    pub fn with_context(self, context: ErrorContext) -> ContextualSinkError {
+        ContextualSinkError {
+            context,
+            inner: self,
+        }
+    }
+}
+
+/// What the sender was doing when an error happened
+#[derive(Debug, Clone)]
+pub struct ErrorContext {
+    pub metric: String,
+    pub batch_size: usize,
+    pub operation: &'static str,
+}
+
+impl ErrorContext {
+    pub fn new(metric: &str, batch_size: usize, operation: &'static str) -> Self {
+        Self {
+            metric: metric.to_string(),
+            batch_size,
+            operation,
+        }
+    }
+}
+
+/// A SinkError that made it out of a table's send, with which table and batch it came from
+#[derive(Debug, Error)]
+pub struct ContextualSinkError {
+    pub context: ErrorContext,
+    #[source]
+    pub inner: SinkError,
+}
+
+impl ContextualSinkError {
+    /// Logs the context as fields, so the structured log can be searched by metric
+    pub fn log(&self) {
+        tracing::error!(
+            metric = %self.context.metric,
+            batch_size = self.context.batch_size,
+            operation = self.context.operation,
+            "{self}"
+        );
+    }
+}
+
+impl Display for ContextualSinkError {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            fmt,
+            "{} of {} {} datums failed: {:?}",
+            self.context.operation, self.context.batch_size, self.context.metric, self.inner
+        )
+    }
 }
 
 #[derive(Debug, Error)]
@@ -50,7 +130,7 @@ impl Display for DescribedError {
 #[derive(Debug, Error)]
 pub struct OtherError {
     pub message: String,
-    pub inner: Box<dyn std::error::Error>,
+    pub inner: Box<dyn std::error::Error + Send + Sync>,
 }
 
 impl Display for OtherError {
@@ -103,3 +183,80 @@ impl Display for MissingColumn {
             .finish()
     }
 }
+
+#[derive(Debug, Error)]
+pub struct ColumnTypeChange {
+    pub table: String,
+    pub column: String,
+    pub from_type: String,
+    pub to_type: String,
+}
+
+impl ColumnTypeChange {
+    /// Only statistic_set to histogram has a migration
+    pub fn is_supported(&self) -> bool {
+        self.from_type == "statistic_set" && self.to_type == "histogram"
+    }
+}
+
+impl Display for ColumnTypeChange {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("ColumnTypeChange")
+            .field("table", &self.table)
+            .field("column", &self.column)
+            .field("from_type", &self.from_type)
+            .field("to_type", &self.to_type)
+            .finish()
+    }
+}
+
+/// A COPY that postgres refused because of one row's value, and that row
+#[derive(Debug, Error)]
+pub struct BadRow {
+    pub metric: String,
+    pub row: usize,
+    pub column: Option<String>,
+    pub datum: String,
+    pub inner: tokio_postgres::Error,
+}
+
+impl Display for BadRow {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fmt.debug_struct("BadRow")
+            .field("metric", &self.metric)
+            .field("row", &self.row)
+            .field("column", &self.column)
+            .field("datum", &self.datum)
+            .field("cause", &self.inner)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use super::{ErrorContext, MissingColumn, SinkError};
+
+    #[test]
+    fn context_is_in_the_message() {
+        let error = SinkError::MissingColumn(MissingColumn {
+            table: "other_table".to_string(),
+            column: "latency".to_string(),
+            data_type: "int8".to_string(),
+        })
+        .with_context(ErrorContext::new("requests", 250, "send_batch"));
+
+        let message = error.to_string();
+        assert!(
+            message.starts_with("send_batch of 250 requests datums failed"),
+            "{message}"
+        );
+        assert!(message.contains("latency"), "{message}");
+
+        // The wrapped error is still there for whoever walks the chain
+        let source = error.source().expect("a source").to_string();
+        assert_eq!("i gotta have more column", source);
+        assert!(error.inner.is_transient());
+    }
+}
@@ -0,0 +1,146 @@
+use std::{collections::HashMap, time::Duration};
+
+use communication::proto::goodmetrics::Datum;
+use tokio::time::Instant;
+
+/// Holds tables' datums across batches until there are enough for a COPY worth its overhead,
+/// so a table that gets a couple of rows a batch isn't copied to every time.
+/// A table's buffer is written once it has min_copy_rows, or once its oldest datum has waited
+/// max_buffer_age, whichever comes first.
+pub struct TableWriteBuffer {
+    min_copy_rows: usize,
+    max_buffer_age: Duration,
+    tables: HashMap<String, BufferedTable>,
+}
+
+struct BufferedTable {
+    since: Instant,
+    datums: Vec<Datum>,
+}
+
+impl TableWriteBuffer {
+    pub fn new(min_copy_rows: usize, max_buffer_age: Duration) -> Self {
+        Self {
+            min_copy_rows,
+            max_buffer_age,
+            tables: HashMap::new(),
+        }
+    }
+
+    /// Gives the table back, with what it had buffered first, once there are enough datums
+    pub fn add(
+        &mut self,
+        metric: String,
+        mut datums: Vec<Datum>,
+        now: Instant,
+    ) -> Option<(String, Vec<Datum>)> {
+        let buffered = match self.tables.remove(&metric) {
+            Some(mut table) => {
+                table.datums.append(&mut datums);
+                table
+            }
+            None => BufferedTable { since: now, datums },
+        };
+        if self.min_copy_rows <= buffered.datums.len()
+            || buffered.since + self.max_buffer_age <= now
+        {
+            return Some((metric, buffered.datums));
+        }
+        self.tables.insert(metric, buffered);
+        None
+    }
+
+    /// Takes the tables whose oldest datum has waited max_buffer_age
+    pub fn take_aged(&mut self, now: Instant) -> Vec<(String, Vec<Datum>)> {
+        let aged: Vec<String> = self
+            .tables
+            .iter()
+            .filter(|(_, table)| table.since + self.max_buffer_age <= now)
+            .map(|(metric, _)| metric.clone())
+            .collect();
+        aged.into_iter()
+            .filter_map(|metric| {
+                let table = self.tables.remove(&metric)?;
+                Some((metric, table.datums))
+            })
+            .collect()
+    }
+
+    pub fn take_all(&mut self) -> Vec<(String, Vec<Datum>)> {
+        self.tables
+            .drain()
+            .map(|(metric, table)| (metric, table.datums))
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tables.is_empty()
+    }
+
+    /// When the oldest table has to be written, or None when nothing is buffered
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.tables
+            .values()
+            .map(|table| table.since + self.max_buffer_age)
+            .min()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use communication::proto::goodmetrics::Datum;
+    use tokio::time::Instant;
+
+    use super::TableWriteBuffer;
+
+    fn datums(count: usize) -> Vec<Datum> {
+        vec![Datum::default(); count]
+    }
+
+    #[test]
+    fn written_once_there_are_enough_rows() {
+        let mut buffer = TableWriteBuffer::new(10, Duration::from_secs(30));
+        let now = Instant::now();
+        assert!(buffer.add("cpu".to_string(), datums(4), now).is_none());
+        assert!(buffer.add("cpu".to_string(), datums(5), now).is_none());
+        assert!(buffer.add("memory".to_string(), datums(3), now).is_none());
+
+        let (metric, written) = buffer.add("cpu".to_string(), datums(1), now).unwrap();
+        assert_eq!(metric, "cpu");
+        assert_eq!(written.len(), 10);
+        // The other table keeps waiting
+        assert!(!buffer.is_empty());
+        assert!(buffer.take_aged(now).is_empty());
+        assert_eq!(buffer.take_all().len(), 1);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn written_once_the_oldest_row_is_old_enough() {
+        let max_buffer_age = Duration::from_secs(30);
+        let mut buffer = TableWriteBuffer::new(100, max_buffer_age);
+        let start = Instant::now();
+        assert!(buffer.add("cpu".to_string(), datums(1), start).is_none());
+        let later = start + Duration::from_secs(20);
+        assert!(buffer.add("cpu".to_string(), datums(1), later).is_none());
+        assert!(buffer.add("memory".to_string(), datums(1), later).is_none());
+        // Timed from each table's oldest row, not its newest
+        assert_eq!(buffer.next_deadline(), Some(start + max_buffer_age));
+
+        let aged = buffer.take_aged(start + max_buffer_age);
+        assert_eq!(aged.len(), 1);
+        assert_eq!(aged[0].0, "cpu");
+        assert_eq!(aged[0].1.len(), 2);
+        assert_eq!(buffer.next_deadline(), Some(later + max_buffer_age));
+
+        // A table that's waited long enough goes out with the next rows that come for it
+        let (metric, written) = buffer
+            .add("memory".to_string(), datums(1), later + max_buffer_age)
+            .unwrap();
+        assert_eq!(metric, "memory");
+        assert_eq!(written.len(), 2);
+        assert_eq!(buffer.next_deadline(), None);
+    }
+}
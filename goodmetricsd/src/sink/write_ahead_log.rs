@@ -0,0 +1,410 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs::{File, OpenOptions},
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use communication::proto::goodmetrics::{Datum, MetricsRequest};
+use prost::Message;
+
+use super::sink_error::SinkError;
+
+const BATCH_RECORD: u8 = 1;
+const COMMIT_RECORD: u8 = 2;
+// kind, entry id, payload length
+const HEADER_LENGTH: usize = 1 + 8 + 4;
+
+/// Appends every batch to a file before it is sent, and a commit marker once postgres has it,
+/// so batches that were in flight when goodmetricsd died are sent again when it starts.
+///
+/// Records are `[kind: u8][entry id: u64 le][length: u32 le][MetricsRequest proto]`.
+/// Batches are fsynced before they're sent. Commits are not: losing one only means a
+/// duplicate on replay. The file is not opened with O_DIRECT, which would require every
+/// append to be padded out to the block size; fsync gives the same durability here.
+///
+/// Rotated files, `wal.{timestamp}.dat` beside `wal.dat`, can still have batches in flight
+/// whose commits land in the newer file. Entry ids are never reused, so on startup every file
+/// is read together. A rotated file is deleted once all of its batches are committed.
+pub struct WriteAheadLog {
+    path: PathBuf,
+    files: Mutex<LogFiles>,
+    next_entry: AtomicU64,
+    rotate_requested: Arc<AtomicBool>,
+}
+
+struct LogFiles {
+    current: File,
+    // Entries appended to the current file and not committed yet
+    uncommitted: HashSet<WalEntry>,
+    // Older files that still have uncommitted entries
+    rotated: Vec<(PathBuf, HashSet<WalEntry>)>,
+}
+
+pub type WalEntry = u64;
+
+/// What the log held that was never committed
+#[derive(Default)]
+pub struct WalReplay {
+    pub datums: Vec<Datum>,
+    /// To commit once the datums are sent again
+    pub entries: Vec<WalEntry>,
+}
+
+impl WriteAheadLog {
+    /// Opens the log at `path`, returning whatever it and its rotated files hold that was never
+    /// committed. The old file is rotated aside, since it may end in a partial record.
+    pub fn open(path: PathBuf) -> Result<(Self, WalReplay), SinkError> {
+        let mut files = rotated_files(&path)?;
+        if path.exists() {
+            files.push(rotate_file(&path)?);
+        }
+
+        let mut logged = Vec::with_capacity(files.len());
+        let mut committed = HashSet::new();
+        let mut next_entry = 0;
+        for file in files {
+            let records = read_records(&file)?;
+            if let Some(last) = records.batches.keys().chain(&records.commits).max() {
+                next_entry = next_entry.max(last + 1);
+            }
+            committed.extend(records.commits);
+            logged.push((file, records.batches));
+        }
+
+        let mut replay = WalReplay::default();
+        let mut rotated = Vec::new();
+        for (file, batches) in logged {
+            let uncommitted: BTreeMap<WalEntry, Vec<Datum>> = batches
+                .into_iter()
+                .filter(|(entry, _)| !committed.contains(entry))
+                .collect();
+            if uncommitted.is_empty() {
+                remove_file(&file);
+                continue;
+            }
+            replay.entries.extend(uncommitted.keys());
+            rotated.push((file, uncommitted.keys().copied().collect()));
+            replay.datums.extend(uncommitted.into_values().flatten());
+        }
+        if !replay.datums.is_empty() {
+            tracing::warn!(
+                "replaying {} uncommitted datums from {:?}",
+                replay.datums.len(),
+                path
+            );
+        }
+
+        let current = open_for_append(&path)?;
+        Ok((
+            Self {
+                path,
+                files: Mutex::new(LogFiles {
+                    current,
+                    uncommitted: HashSet::new(),
+                    rotated,
+                }),
+                next_entry: AtomicU64::new(next_entry),
+                rotate_requested: Arc::new(AtomicBool::new(false)),
+            },
+            replay,
+        ))
+    }
+
+    /// Set it to have the log rotated before its next append
+    pub fn rotate_requested(&self) -> Arc<AtomicBool> {
+        self.rotate_requested.clone()
+    }
+
+    pub fn append(&self, datums: &[Datum]) -> Result<WalEntry, SinkError> {
+        if self.rotate_requested.swap(false, Ordering::Relaxed) {
+            self.rotate()?;
+        }
+
+        let entry = self.next_entry.fetch_add(1, Ordering::Relaxed);
+        let payload = MetricsRequest {
+            shared_dimensions: Default::default(),
+            metrics: datums.to_vec(),
+        }
+        .encode_to_vec();
+
+        let mut files = self.files.lock().expect("write ahead log lock");
+        write_record(&mut files.current, BATCH_RECORD, entry, &payload)?;
+        files
+            .current
+            .sync_data()
+            .map_err(|e| SinkError::other("failed to sync write ahead log", Box::new(e)))?;
+        files.uncommitted.insert(entry);
+        Ok(entry)
+    }
+
+    pub fn commit(&self, entry: WalEntry) {
+        let mut files = self.files.lock().expect("write ahead log lock");
+        if let Err(e) = write_record(&mut files.current, COMMIT_RECORD, entry, &[]) {
+            tracing::error!("failed to commit write ahead log entry {entry}: {e:?}");
+            return;
+        }
+        if files.uncommitted.remove(&entry) {
+            return;
+        }
+        // The commit has to be durable before the batch's file can go
+        if let Err(e) = files.current.sync_data() {
+            tracing::error!("failed to sync write ahead log: {e:?}");
+            return;
+        }
+        files.rotated.retain_mut(|(file, uncommitted)| {
+            if !uncommitted.remove(&entry) || !uncommitted.is_empty() {
+                return true;
+            }
+            remove_file(file);
+            false
+        });
+    }
+
+    fn rotate(&self) -> Result<(), SinkError> {
+        let mut files = self.files.lock().expect("write ahead log lock");
+        files
+            .current
+            .sync_all()
+            .map_err(|e| SinkError::other("failed to sync write ahead log", Box::new(e)))?;
+        let rotated = rotate_file(&self.path)?;
+        files.current = open_for_append(&self.path)?;
+        let uncommitted = std::mem::take(&mut files.uncommitted);
+        if uncommitted.is_empty() {
+            remove_file(&rotated);
+        } else {
+            files.rotated.push((rotated.clone(), uncommitted));
+        }
+        tracing::info!("rotated write ahead log to {rotated:?}");
+        Ok(())
+    }
+}
+
+fn open_for_append(path: &Path) -> Result<File, SinkError> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| SinkError::other(format!("failed to open {path:?}"), Box::new(e)))
+}
+
+fn remove_file(path: &Path) {
+    match std::fs::remove_file(path) {
+        Ok(()) => tracing::info!("removed committed write ahead log {path:?}"),
+        Err(e) => tracing::error!("failed to remove committed write ahead log {path:?}: {e:?}"),
+    }
+}
+
+// wal.dat becomes wal.{timestamp}.dat
+fn rotate_file(path: &Path) -> Result<PathBuf, SinkError> {
+    let mut timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let (stem, extension) = name_parts(path);
+    let rotated = loop {
+        let rotated_name = match &extension {
+            Some(extension) => format!("{stem}.{timestamp}.{extension}"),
+            None => format!("{stem}.{timestamp}"),
+        };
+        let rotated = path.with_file_name(rotated_name);
+        // Rotating twice in a millisecond mustn't overwrite the first
+        if !rotated.exists() {
+            break rotated;
+        }
+        timestamp += 1;
+    };
+    std::fs::rename(path, &rotated)
+        .map_err(|e| SinkError::other("failed to rotate write ahead log", Box::new(e)))?;
+    Ok(rotated)
+}
+
+fn name_parts(path: &Path) -> (String, Option<String>) {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().to_string())
+        .unwrap_or_else(|| "wal".to_string());
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_string());
+    (stem, extension)
+}
+
+// The files rotate_file made from this path, oldest first
+fn rotated_files(path: &Path) -> Result<Vec<PathBuf>, SinkError> {
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(SinkError::other(
+                format!("failed to list {directory:?}"),
+                Box::new(e),
+            ))
+        }
+    };
+    let (stem, extension) = name_parts(path);
+    let mut rotated: Vec<(u128, PathBuf)> = entries
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().to_string();
+            let timestamp = name.strip_prefix(&format!("{stem}."))?;
+            let timestamp = match &extension {
+                Some(extension) => timestamp.strip_suffix(&format!(".{extension}"))?,
+                None => timestamp,
+            };
+            Some((timestamp.parse().ok()?, path.with_file_name(name)))
+        })
+        .collect();
+    rotated.sort();
+    Ok(rotated.into_iter().map(|(_, file)| file).collect())
+}
+
+fn write_record(file: &mut File, kind: u8, entry: u64, payload: &[u8]) -> Result<(), SinkError> {
+    let mut record = Vec::with_capacity(HEADER_LENGTH + payload.len());
+    record.push(kind);
+    record.extend(entry.to_le_bytes());
+    record.extend((payload.len() as u32).to_le_bytes());
+    record.extend(payload);
+    file.write_all(&record)
+        .map_err(|e| SinkError::other("failed to write to write ahead log", Box::new(e)))
+}
+
+struct Records {
+    batches: BTreeMap<WalEntry, Vec<Datum>>,
+    commits: HashSet<WalEntry>,
+}
+
+fn read_records(path: &Path) -> Result<Records, SinkError> {
+    let mut contents = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut contents))
+        .map_err(|e| SinkError::other(format!("failed to read {path:?}"), Box::new(e)))?;
+
+    let mut records = Records {
+        batches: BTreeMap::new(),
+        commits: HashSet::new(),
+    };
+    let mut remaining = contents.as_slice();
+    while !remaining.is_empty() {
+        if remaining.len() < HEADER_LENGTH {
+            tracing::warn!("write ahead log ends in a partial record, ignoring it");
+            break;
+        }
+        let kind = remaining[0];
+        let entry = u64::from_le_bytes(remaining[1..9].try_into().expect("8 bytes"));
+        let length = u32::from_le_bytes(remaining[9..13].try_into().expect("4 bytes")) as usize;
+        if remaining.len() < HEADER_LENGTH + length {
+            tracing::warn!("write ahead log ends in a partial record, ignoring it");
+            break;
+        }
+        let payload = &remaining[HEADER_LENGTH..HEADER_LENGTH + length];
+        remaining = &remaining[HEADER_LENGTH + length..];
+
+        match kind {
+            BATCH_RECORD => match MetricsRequest::decode(payload) {
+                Ok(request) => {
+                    records.batches.insert(entry, request.metrics);
+                }
+                Err(e) => tracing::error!("skipping corrupt write ahead log entry {entry}: {e:?}"),
+            },
+            COMMIT_RECORD => {
+                records.commits.insert(entry);
+            }
+            _ => {
+                tracing::error!("unknown write ahead log record kind {kind}, ignoring the rest");
+                break;
+            }
+        }
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, sync::atomic::Ordering};
+
+    use communication::proto::goodmetrics::Datum;
+
+    use super::WriteAheadLog;
+
+    fn log_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("goodmetrics-wal-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        directory
+    }
+
+    fn datums(metric: &str) -> Vec<Datum> {
+        vec![Datum {
+            metric: metric.to_string(),
+            ..Default::default()
+        }]
+    }
+
+    fn files(directory: &PathBuf) -> Vec<String> {
+        let mut files: Vec<String> = std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn batches_in_flight_across_a_rotation_are_replayed() {
+        let directory = log_directory("in-flight");
+        let path = directory.join("wal.dat");
+        let (log, replay) = WriteAheadLog::open(path.clone()).unwrap();
+        assert!(replay.datums.is_empty());
+
+        let in_flight = log.append(&datums("in_flight")).unwrap();
+        log.rotate_requested().store(true, Ordering::Relaxed);
+        let committed = log.append(&datums("committed")).unwrap();
+        log.commit(committed);
+        assert_eq!(files(&directory).len(), 2);
+        // Dies before in_flight is committed
+        drop(log);
+
+        let (log, replay) = WriteAheadLog::open(path.clone()).unwrap();
+        let metrics: Vec<&str> = replay.datums.iter().map(|d| d.metric.as_str()).collect();
+        assert_eq!(metrics, ["in_flight"]);
+        assert_eq!(replay.entries, [in_flight]);
+        // New entries don't reuse the old ids
+        assert!(committed < log.append(&datums("new")).unwrap());
+
+        for entry in replay.entries {
+            log.commit(entry);
+        }
+        assert_eq!(files(&directory), ["wal.dat"]);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[test]
+    fn committed_rotated_files_are_removed() {
+        let directory = log_directory("committed");
+        let path = directory.join("wal.dat");
+        let (log, _) = WriteAheadLog::open(path.clone()).unwrap();
+        let entry = log.append(&datums("cpu")).unwrap();
+        log.commit(entry);
+        log.rotate_requested().store(true, Ordering::Relaxed);
+        let entry = log.append(&datums("cpu")).unwrap();
+        assert_eq!(files(&directory), ["wal.dat"]);
+
+        log.commit(entry);
+        drop(log);
+        let (_log, replay) = WriteAheadLog::open(path).unwrap();
+        assert!(replay.datums.is_empty());
+        assert_eq!(files(&directory), ["wal.dat"]);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}